@@ -1,31 +1,137 @@
 use device;
+use discovery::{self, Subnet};
+use enrollment::{self, Enrolled, Enrollment};
+use exec::Actions;
+use facts;
+use files::FileDirs;
+use policy::LocalPolicy;
+use probes::Probes;
+use proxy::Proxy;
 use futures::{self, Future, Sink, Stream};
 use futures::sync::mpsc::{Receiver, Sender, channel};
-use server_connection;
-use ssh_connection::{SshConnection, SshConnectionChange, SshConnectionSettings};
+use server_connection::{self, ServerConnectionHandle};
+use ssh_connection::{self, FailureKind, ForwardDirection, SshBackend, SshConnection, SshConnectionChange, SshConnectionSettings};
 use ssh_manager::SshManager;
+use runtime::RuntimeDirectory;
+use systemd::Notifier;
 use std;
 use tokio;
+use tokio_timer::Interval;
+use control::{self, ServerStatus};
+use tunnel::TunnelManager;
+use udp_relay::UdpRelayManager;
+use update::{self, Updater, UpdateProgress};
+
+use connectbot_shared::backoff::BackoffPolicy;
+use connectbot_shared::tunnel::TunnelFrame;
+use connectbot_shared::udp::{self, Datagram};
 
 use tokio_rustls::rustls::ClientConfig;
 
 /// Internal object to store information about the client during a connection.
 struct Client {
     id: String,
+    /// The name that we suggest to the server
+    name: String,
     successful_connections: usize,
     sender: Sender<device::ClientMessage>,
     ssh_manager: SshManager,
+    tunnels: TunnelManager,
+    udp_relays: UdpRelayManager,
+    actions: Actions,
+    file_dirs: FileDirs,
+    /// The subnet that the server may ask us to scan, if any
+    discover_subnet: Option<Subnet>,
+    /// Any update that we're downloading
+    updater: Updater,
+    /// Whether to log the messages that we get
+    log_messages: bool,
+    /// What makes our SSH connections
+    ssh_backend: SshBackend,
+    /// How long to wait before connecting SSH connections again after they fail
+    ssh_backoff: BackoffPolicy,
+    /// Where SSH keys and control sockets go
+    runtime: RuntimeDirectory,
+    /// The proxy that SSH connections go through, if there is one
+    proxy: Option<Proxy>,
+    /// The connection to the server, to find out which server we're on
+    server_connection: ServerConnectionHandle,
+    /// The client certificate that we got from the server, if we enroll
+    enrollment: Option<Enrollment>,
+    /// Where to tell systemd that we're up and running
+    notifier: Notifier,
+    /// What the server may ask us to forward
+    local_policy: LocalPolicy,
+}
+
+/// What the person running the client decided, from the command line and the config file.
+pub struct Settings {
+    /// The unique identifier of the device
+    pub id: String,
+    /// The name that we suggest to the server
+    pub name: String,
+    pub actions: Actions,
+    pub file_dirs: FileDirs,
+    pub probes: Probes,
+    /// How often to run the probes
+    pub probe_interval: std::time::Duration,
+    /// The subnet that the server may ask us to scan, if any
+    pub discover_subnet: Option<Subnet>,
+    /// Whether to log the messages that go to and come from the server
+    pub log_messages: bool,
+    /// What makes our SSH connections
+    pub ssh_backend: SshBackend,
+    /// How long to wait before connecting SSH connections again after they fail
+    pub ssh_backoff: BackoffPolicy,
+    /// Where SSH keys and control sockets go
+    pub runtime: RuntimeDirectory,
+    /// The proxy that the connections to the server and SSH connections go through, if there is
+    /// one
+    pub proxy: Option<Proxy>,
+    /// The SSH connections that were left running by a previous run, and that we're taking over
+    pub adopted: Vec<String>,
+    /// Where to listen for `connectbot-client status` and friends
+    pub control_socket: std::path::PathBuf,
+    /// The client certificate that we get from the server, if we enroll instead of being given
+    /// one
+    pub enrollment: Option<Enrollment>,
+    /// Where to tell systemd how we're doing
+    pub notifier: Notifier,
+    /// What the server may ask us to forward
+    pub local_policy: LocalPolicy,
 }
 
 impl Client {
-    fn new(id: String, sender: Sender<device::ClientMessage>) -> Client {
+    fn new(settings: Settings, server_connection: ServerConnectionHandle, sender: Sender<device::ClientMessage>, tunnel_frame_sender: Sender<TunnelFrame>) -> Client {
+        let Settings { id, name, actions, file_dirs, discover_subnet, log_messages, ssh_backend, ssh_backoff, runtime, proxy, adopted, enrollment, notifier, local_policy, .. } = settings;
         let manager = SshManager::new();
+        for id in adopted {
+            // These are already connected. We'll tell the server once we're connected too, and
+            // it'll either ask for them again or tell us to get rid of them.
+            manager.get_ref().update_state(&id, &SshConnectionChange::Connected);
+        }
 
         Client {
             id,
+            name,
             successful_connections: 0,
+            udp_relays: UdpRelayManager::new(sender.clone()),
             sender,
             ssh_manager: manager,
+            tunnels: TunnelManager::new(tunnel_frame_sender),
+            actions,
+            file_dirs,
+            discover_subnet,
+            updater: Updater::new(),
+            log_messages,
+            ssh_backend,
+            ssh_backoff,
+            runtime,
+            proxy,
+            server_connection,
+            enrollment,
+            notifier,
+            local_policy,
         }
     }
 
@@ -33,28 +139,88 @@ impl Client {
     fn on_connected(mut self) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
         self.successful_connections += 1;
 
-        // Send the initialize message.
-        let mut initialize = device::Initialize::new();
-        initialize.set_id(self.id.clone().into());
-        initialize.set_comms_version("1.0".into());
-        let mut client_message = device::ClientMessage::new();
-        client_message.set_initialize(initialize);
+        // Any tunnel streams and UDP associations belonged to the previous connection, and the
+        // server doesn't know about them anymore.
+        self.tunnels.reset();
+        self.udp_relays.reset();
+
+        // Without a certificate, the server won't take us as we are. Ask for one first.
+        if let Some(request) = self.enrollment.as_ref().filter(|enrollment| enrollment.needs_enrollment()).map(|enrollment| enrollment.request(&self.id)) {
+            return match request {
+                Ok(message) => {
+                    println!("! Asking the server for a client certificate");
+                    let f = self.sender.clone().send(message)
+                        .map(|_| self)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+                    Box::new(f)
+                },
+                Err(err) => {
+                    println!("! Failed to enroll: {}", err);
+                    Box::new(futures::future::ok(self))
+                },
+            };
+        }
 
-        let f = self.sender.clone().send(client_message)
-            .map(|_| self)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send ping: {}", e)));
+        // Send the initialize message, along with what we know about ourselves.
+        let f = facts::gather_future()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to gather facts"))
+            .and_then(move |mut facts| {
+                facts.set_server_endpoint(self.server_connection.endpoint().into());
+
+                let mut initialize = device::Initialize::new();
+                initialize.set_id(self.id.clone().into());
+                initialize.set_suggested_name(self.name.clone().into());
+                initialize.set_comms_version("1.0".into());
+                initialize.set_actions(self.actions.names().iter().map(|name| name.as_str().into()).collect::<Vec<_>>().into());
+                initialize.set_file_dirs(self.file_dirs.names().iter().map(|dir| dir.as_str().into()).collect::<Vec<_>>().into());
+                initialize.set_facts(facts);
+                let mut client_message = device::ClientMessage::new();
+                client_message.set_initialize(initialize);
+
+                // Tell the server about any SSH connections that we adopted, so that it knows
+                // what is really running.
+                let adopted: Vec<device::ClientMessage> = self.ssh_manager.adopted().iter()
+                    .map(|id| ssh_status_message(id, device::SshConnectionStatus_State::CONNECTED))
+                    .collect();
+
+                self.sender.clone().send(client_message)
+                    .and_then(|sender| {
+                        futures::stream::iter_ok::<_, futures::sync::mpsc::SendError<device::ClientMessage>>(adopted)
+                            .fold(sender, |sender, message| sender.send(message))
+                    })
+                    .map(|_| {
+                        // We're connected and the server knows who we are, so as far as systemd
+                        // is concerned, we've started. (It doesn't mind hearing this again.)
+                        self.notifier.ready();
+                        self
+                    })
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send ping: {}", e)))
+            });
 
         return Box::new(f);
     }
 
     /// What to do with a message that we have received from the server.
-    fn on_client_message(self, mut message: device::ServerMessage) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
-        if !message.has_ping() && !message.has_pong() {
+    fn on_client_message(mut self, mut message: device::ServerMessage) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
+        if self.log_messages && !message.has_ping() && !message.has_pong() && !message.has_tunnel_data() && !message.has_tunnel_window_update() && !message.has_udp_datagram() && !message.has_update_chunk() && !message.has_file_request() {
             // Log it! Except when it's a ping or a pong. Pings and pongs are used to keep the
             // connection alive, and happen frequently, but just add noise when they are logged.
+            // Same goes for tunnel data, datagrams and file chunks.
             println!("↓ {:?}", message);
         }
 
+        if let Some(frame) = TunnelFrame::from_server_message(&mut message) {
+            // Something for one of the native tunnels.
+            self.tunnels.on_frame(frame);
+            return Box::new(futures::future::ok(self));
+        }
+
+        if let Some(datagram) = Datagram::from_server_message(&mut message) {
+            // Something for one of the UDP relays.
+            self.udp_relays.on_datagram(datagram);
+            return Box::new(futures::future::ok(self));
+        }
+
         if message.has_ping() {
             // If this is a ping from the server, respond with a pong. Keep the connection alive!
             let pong = device::Pong::new();
@@ -68,6 +234,112 @@ impl Client {
             return Box::new(f);
         }
 
+        if message.has_exec_request() {
+            // The server wants us to run one of our actions. Whether we actually do is up to us.
+            let exec_request = message.take_exec_request();
+            self.actions.run(exec_request.get_request_id(), exec_request.get_action(), self.sender.clone());
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_initialized() {
+            // The server knows who we are. If we were just updated, that means the update worked.
+            update::confirm();
+
+            // And if our certificate is getting old, now is a good time to get a new one.
+            if let Some(request) = self.enrollment.as_ref().and_then(|enrollment| enrollment.renewal_request(&self.id)) {
+                return match request {
+                    Ok(message) => {
+                        let f = self.sender.clone().send(message)
+                            .map(|_| self)
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+                        Box::new(f)
+                    },
+                    Err(err) => {
+                        println!("! Failed to renew the client certificate: {}", err);
+                        Box::new(futures::future::ok(self))
+                    },
+                };
+            }
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_enrolled() {
+            // The server answered our request for a certificate.
+            if let Some(ref enrollment) = self.enrollment {
+                match enrollment.on_enrolled(message.get_enrolled()) {
+                    Enrolled::First => {
+                        // The server only takes us with the certificate, so connect again.
+                        println!("! Enrolled, so connecting again with the new certificate");
+                        self.server_connection.reconnect();
+                    },
+                    Enrolled::Renewed => println!("! Renewed the client certificate"),
+                    Enrolled::Failed(err) => println!("! Failed to get a client certificate: {}", err),
+                }
+            }
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_update_available() {
+            // The server wants us to run a different version.
+            return match self.updater.on_update_available(message.get_update_available()) {
+                Some(request) => {
+                    let f = self.sender.clone().send(request)
+                        .map(|_| self)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+                    Box::new(f)
+                },
+                None => Box::new(futures::future::ok(self)),
+            };
+        }
+
+        if message.has_update_chunk() {
+            // More of the new version.
+            return match self.updater.on_chunk(message.get_update_chunk()) {
+                UpdateProgress::Nothing => Box::new(futures::future::ok(self)),
+                UpdateProgress::Request(request) => {
+                    let f = self.sender.clone().send(request)
+                        .map(|_| self)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+                    Box::new(f)
+                },
+                UpdateProgress::Install => {
+                    // This only comes back if the update couldn't be installed.
+                    let err = self.updater.install();
+                    println!("! Failed to install update: {}", err);
+                    Box::new(futures::future::ok(self))
+                },
+            };
+        }
+
+        if message.has_file_request() {
+            // The server wants to read or write a piece of a file. Whether it may is up to us.
+            let file_request = message.take_file_request();
+            let tx = self.sender.clone();
+            let f = self.file_dirs.handle(file_request)
+                .and_then(move |message| {
+                    tx.send(message)
+                        .map(|_| ())
+                        .map_err(|err| println!("{}", err))
+                });
+            tokio::spawn(f);
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_discover_request() {
+            // The server wants to know what's on our network. Scanning takes a while, so do it on
+            // the side.
+            let discover_request = message.take_discover_request();
+            let tx = self.sender.clone();
+            let f = discovery::discover(discover_request.get_request_id(), self.discover_subnet, discover_request.get_include_subnet())
+                .and_then(move |result| {
+                    tx.send(discovery::result_message(result))
+                        .map(|_| ())
+                        .map_err(|err| println!("{}", err))
+                });
+            tokio::spawn(f);
+            return Box::new(futures::future::ok(self));
+        }
+
         if message.has_ssh_connection() {
             // This message is telling us *something* about SSH connections. Could be enabling or
             // disabling a connection.
@@ -77,13 +349,31 @@ impl Client {
                 let enable = ssh_connection.take_enable();
                 let id = ssh_connection.get_id();
 
-                return Box::new(self.on_ssh_enable(id.to_string(), enable))
+                return self.on_ssh_enable(id.to_string(), enable)
+            }
+
+            if ssh_connection.has_local_enable() {
+                // Enabling a local forward. That's an SSH connection too, just going the other way.
+                let local_enable = ssh_connection.take_local_enable();
+                let id = ssh_connection.get_id();
+
+                return self.on_ssh_local_enable(id.to_string(), local_enable)
             }
 
             if ssh_connection.has_disable() {
                 // Disabling one. Tear that thing down now!
-                let id = ssh_connection.get_id();
-                self.on_ssh_disable(id);
+                let id = ssh_connection.get_id().to_string();
+                if self.tunnels.disable(&id) || self.udp_relays.disable(&id) {
+                    // Native tunnels and UDP relays don't have a stream reporting their state, so
+                    // we need to tell the server ourselves.
+                    let message = ssh_status_message(&id, device::SshConnectionStatus_State::DISCONNECTED);
+                    let f = self.sender.clone().send(message)
+                        .map(|_| self)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+
+                    return Box::new(f);
+                }
+                self.on_ssh_disable(&id);
             }
         }
 
@@ -91,24 +381,26 @@ impl Client {
     }
 
     /// Start to establish a new SSH session.
-    fn on_ssh_enable(self, id: String, enable: device::SshConnection_Enable) -> impl Future<Item=Self, Error=std::io::Error> {
-        let state = self.ssh_manager.current_state(&id);
+    fn on_ssh_enable(mut self, id: String, enable: device::SshConnection_Enable) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
+        if let Err(reason) = self.local_policy.check_enable(&enable) {
+            return self.reject_ssh(id, reason);
+        }
+
         let tx = self.sender.clone();
-        let state = if let Some(state) = state {
-            // This SSH connection has already started.
-            match state {
-                SshConnectionChange::Connecting => device::SshConnectionStatus_State::CONNECTING,
-                SshConnectionChange::Connected => device::SshConnectionStatus_State::CONNECTED,
-                SshConnectionChange::Disconnecting => device::SshConnectionStatus_State::DISCONNECTING,
-                SshConnectionChange::Disconnected => device::SshConnectionStatus_State::DISCONNECTED,
-                SshConnectionChange::Failed(_) => device::SshConnectionStatus_State::FAILED,
-            }
+        let state = if enable.get_protocol() == device::SshConnection_Protocol::UDP {
+            // UDP is always relayed over the server connection, and there's nothing to set up
+            // until the first datagram arrives.
+            self.udp_relays.enable(&id, enable.get_forward_host(), enable.get_forward_port() as u16, udp::idle_timeout(enable.get_udp_idle_timeout()));
+            device::SshConnectionStatus_State::CONNECTED
+        }
+        else if enable.get_transport() == device::SshConnection_Transport::NATIVE {
+            // Native tunnels don't need a connection of their own. As soon as we know where to
+            // send the traffic, we're ready for the server to open streams.
+            self.tunnels.enable(&id, enable.get_forward_host(), enable.get_forward_port() as u16);
+            device::SshConnectionStatus_State::CONNECTED
         }
         else {
-            // This must be a new SSH session. So let's kick it off.
-            let manager_ref = self.ssh_manager.get_ref();
-            let tx = tx.clone();
-            let settings = SshConnectionSettings {
+            self.start_ssh(SshConnectionSettings {
                 id: id.clone(),
                 host: enable.get_ssh_host().to_string(),
                 port: enable.get_ssh_port() as u16,
@@ -118,56 +410,156 @@ impl Client {
                 remote_port: enable.get_remote_port() as u16,
                 gateway_port: enable.get_gateway_port(),
                 private_key: enable.get_ssh_key().to_string(),
-            };
-            let future = SshConnection::new(settings);
-            manager_ref.register_handle(&id.clone(), future.handle());
-            let id = id.clone();
-            let future = future.for_each(move |item| {
-                // Every time the SSH session state changes, we should tell the server.
-                manager_ref.update_state(&id.clone(), &item);
-
-                let mut ssh_connection_status = device::SshConnectionStatus::new();
-                let state = match item {
-                    SshConnectionChange::Connecting => device::SshConnectionStatus_State::CONNECTING,
-                    SshConnectionChange::Connected => device::SshConnectionStatus_State::CONNECTED,
-                    SshConnectionChange::Disconnecting => device::SshConnectionStatus_State::DISCONNECTING,
-                    SshConnectionChange::Disconnected => device::SshConnectionStatus_State::DISCONNECTED,
-                    SshConnectionChange::Failed(_) => device::SshConnectionStatus_State::FAILED,
-                };
-                ssh_connection_status.set_id(id.clone().into());
-                ssh_connection_status.set_state(state);
-
-                let mut client_message = device::ClientMessage::new();
-                client_message.set_ssh_status(ssh_connection_status);
-
-                tx.clone().send(client_message)
-                    .map(|_| ())
-                    .map_err(|err| println!("{}", err))
-            });
-            // Spawn the future to handle the SSH connection separately.
-            tokio::spawn(future);
-
-            device::SshConnectionStatus_State::REQUESTED
+                host_keys: enable.get_ssh_host_keys().iter().map(|key| key.to_string()).collect(),
+                direction: match enable.get_dynamic() {
+                    true => ForwardDirection::RemoteDynamic {
+                        permitted_destinations: enable.get_permitted_destinations().iter().map(|destination| destination.to_string()).collect(),
+                    },
+                    false => ForwardDirection::Remote,
+                },
+                backend: self.ssh_backend.clone(),
+                runtime: self.runtime.clone(),
+                backoff: self.ssh_backoff.clone(),
+                proxy: self.proxy.clone(),
+            })
         };
 
         // Alright, let's send a message to the server about the current state. Even if we just
         // started the connection.
-        let mut ssh_connection_status = device::SshConnectionStatus::new();
-        ssh_connection_status.set_id(id.clone().into());
-        ssh_connection_status.set_state(state);
+        Box::new(tx.send(ssh_status_message(&id, state))
+            .map(|_| self)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send message")))
+    }
+
+    /// Start to establish a new SSH session for a local forward.
+    fn on_ssh_local_enable(self, id: String, enable: device::SshConnection_LocalEnable) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
+        if let Err(reason) = self.local_policy.check_local_enable(&enable) {
+            return self.reject_ssh(id, reason);
+        }
+
+        let tx = self.sender.clone();
+        let state = self.start_ssh(SshConnectionSettings {
+            id: id.clone(),
+            host: enable.get_ssh_host().to_string(),
+            port: enable.get_ssh_port() as u16,
+            username: enable.get_ssh_username().to_string(),
+            forward_host: enable.get_forward_host().to_string(),
+            forward_port: enable.get_forward_port() as u16,
+            remote_port: 0,
+            gateway_port: enable.get_gateway_port(),
+            private_key: enable.get_ssh_key().to_string(),
+            host_keys: enable.get_ssh_host_keys().iter().map(|key| key.to_string()).collect(),
+            direction: ForwardDirection::Local { local_port: enable.get_local_port() as u16 },
+            backend: self.ssh_backend.clone(),
+            runtime: self.runtime.clone(),
+            backoff: self.ssh_backoff.clone(),
+            proxy: self.proxy.clone(),
+        });
+
+        Box::new(tx.send(ssh_status_message(&id, state))
+            .map(|_| self)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send message")))
+    }
+
+    /// Refuse to set up a forward that the local policy doesn't allow, and tell the server why.
+    /// Anything that's already running under the id goes down first.
+    fn reject_ssh(mut self, id: String, reason: String) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
+        println!("! Rejecting SSH connection {}: {}", id, reason);
+
+        // Native tunnels and UDP relays go away right away.
+        let tunnel = self.tunnels.disable(&id);
+        let udp_relay = self.udp_relays.disable(&id);
+        if tunnel || udp_relay {
+            println!("! Stopped forwarding {}", id);
+        }
+
+        if self.ssh_manager.reject(&id, &reason) {
+            // The session's stream tells the server once it's down.
+            println!("! Tearing down SSH connection {}", id);
+            return Box::new(futures::future::ok(self));
+        }
 
-        let mut client_message = device::ClientMessage::new();
-        client_message.set_ssh_status(ssh_connection_status);
+        if self.ssh_manager.adopted().iter().any(|adopted| *adopted == id) {
+            // A previous run set this one up, before the policy said otherwise.
+            let manager_ref = self.ssh_manager.get_ref();
+            let id = id.clone();
+            let f = ssh_connection::tear_down(self.runtime.ssh_control(&id))
+                .map(move |_| manager_ref.update_state(&id, &SshConnectionChange::Disconnected));
+            tokio::spawn(f);
+        }
 
-        tx.send(client_message)
+        Box::new(self.sender.clone().send(rejected_message(&id, &reason))
             .map(|_| self)
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send message"))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send message")))
+    }
+
+    /// Start an SSH session, unless it has already been started. Returns the session's current
+    /// state.
+    fn start_ssh(&self, settings: SshConnectionSettings) -> device::SshConnectionStatus_State {
+        if let Some(state) = self.ssh_manager.current_state(&settings.id) {
+            if self.ssh_manager.is_managed(&settings.id) {
+                // This SSH connection has already started.
+                return ssh_connection_state(&state);
+            }
+
+            // We adopted this one, and the server still wants it. Start managing it; the stream
+            // finds the running `ssh` master and picks it up.
+            println!("! Taking over SSH connection {}", settings.id);
+        }
+
+        // This must be a new SSH session. So let's kick it off.
+        let manager_ref = self.ssh_manager.get_ref();
+        let tx = self.sender.clone();
+        let id = settings.id.clone();
+        let future = SshConnection::new(settings);
+        manager_ref.register_handle(&id, future.handle());
+        let future = future.for_each(move |item| {
+            // Every time the SSH session state changes, we should tell the server.
+            manager_ref.update_state(&id, &item);
+
+            // If it went down because the local policy rejected it, that's what the server needs
+            // to hear.
+            let rejection = match item {
+                SshConnectionChange::Disconnected => manager_ref.take_rejection(&id),
+                _ => None,
+            };
+            let message = match rejection {
+                Some(reason) => rejected_message(&id, &reason),
+                None => ssh_change_message(&id, &item),
+            };
+            tx.clone().send(message)
+                .map(|_| ())
+                .map_err(|err| println!("{}", err))
+        });
+        // Spawn the future to handle the SSH connection separately.
+        tokio::spawn(future);
+
+        device::SshConnectionStatus_State::REQUESTED
     }
 
     /// Disable the SSH connection. Because we already have a stream reporting to the server when
     /// the SSH state changes, we don't need to do much here.
     fn on_ssh_disable(&self, id: &str) {
-        self.ssh_manager.disable(id);
+        if self.ssh_manager.disable(id) {
+            return;
+        }
+
+        if self.ssh_manager.adopted().iter().any(|adopted| adopted == id) {
+            // We adopted this one, but the server doesn't want it anymore. There's no stream to
+            // report on it, so tear it down and tell the server ourselves.
+            println!("! Tearing down adopted SSH connection {}", id);
+            let manager_ref = self.ssh_manager.get_ref();
+            let tx = self.sender.clone();
+            let id = id.to_string();
+            let f = ssh_connection::tear_down(self.runtime.ssh_control(&id))
+                .and_then(move |_| {
+                    manager_ref.update_state(&id, &SshConnectionChange::Disconnected);
+                    tx.send(ssh_status_message(&id, device::SshConnectionStatus_State::DISCONNECTED))
+                        .map(|_| ())
+                        .map_err(|err| println!("{}", err))
+                });
+            tokio::spawn(f);
+        }
     }
 
     /// What to do when the connection has been idle for a while. We want to send a Ping to keep
@@ -185,10 +577,71 @@ impl Client {
     }
 }
 
+/// Translate the state of an SSH session into what we tell the server.
+fn ssh_connection_state(change: &SshConnectionChange) -> device::SshConnectionStatus_State {
+    match change {
+        SshConnectionChange::Connecting => device::SshConnectionStatus_State::CONNECTING,
+        SshConnectionChange::Connected => device::SshConnectionStatus_State::CONNECTED,
+        SshConnectionChange::Disconnecting => device::SshConnectionStatus_State::DISCONNECTING,
+        SshConnectionChange::Disconnected => device::SshConnectionStatus_State::DISCONNECTED,
+        SshConnectionChange::Failed(..) => device::SshConnectionStatus_State::FAILED,
+    }
+}
+
+/// Build a message that tells the server about the state of an SSH connection.
+fn ssh_status_message(id: &str, state: device::SshConnectionStatus_State) -> device::ClientMessage {
+    let mut ssh_connection_status = device::SshConnectionStatus::new();
+    ssh_connection_status.set_id(id.into());
+    ssh_connection_status.set_state(state);
+
+    let mut client_message = device::ClientMessage::new();
+    client_message.set_ssh_status(ssh_connection_status);
+
+    client_message
+}
+
+/// Build a message that tells the server that the local policy doesn't allow an SSH connection.
+fn rejected_message(id: &str, reason: &str) -> device::ClientMessage {
+    let mut message = ssh_status_message(id, device::SshConnectionStatus_State::REJECTED);
+    message.mut_ssh_status().set_rejected_reason(reason.into());
+    message
+}
+
+/// Build a message that tells the server how an SSH connection changed, including why it failed
+/// if it did.
+fn ssh_change_message(id: &str, change: &SshConnectionChange) -> device::ClientMessage {
+    let mut client_message = ssh_status_message(id, ssh_connection_state(change));
+
+    if let SshConnectionChange::Failed(failures, reason) = change {
+        let ssh_connection_status = client_message.mut_ssh_status();
+        ssh_connection_status.set_failures(*failures as u32);
+        ssh_connection_status.set_failure_kind(match reason.kind {
+            FailureKind::Unknown => device::SshConnectionStatus_FailureKind::UNKNOWN_FAILURE,
+            FailureKind::AuthDenied => device::SshConnectionStatus_FailureKind::AUTH_DENIED,
+            FailureKind::PortInUse => device::SshConnectionStatus_FailureKind::PORT_IN_USE,
+            FailureKind::HostUnreachable => device::SshConnectionStatus_FailureKind::HOST_UNREACHABLE,
+            FailureKind::HostKeyMismatch => device::SshConnectionStatus_FailureKind::HOST_KEY_MISMATCH,
+            FailureKind::TargetRefused => device::SshConnectionStatus_FailureKind::TARGET_REFUSED,
+        });
+        ssh_connection_status.set_exit_code(reason.exit_code.unwrap_or(-1));
+        ssh_connection_status.set_failure_detail(reason.detail.as_str().into());
+    }
+
+    client_message
+}
+
 /// The primary function that runs the client connection. Returns a future to pass to `tokio::run`.
-pub fn connect(id: String, connection_details: server_connection::ConnectionDetails, tls_config: ClientConfig) -> impl Future<Item=(), Error=()> {
+pub fn connect(settings: Settings, endpoints: Vec<server_connection::ConnectionDetails>, tls_config: ClientConfig, backoff: BackoffPolicy) -> impl Future<Item=(), Error=()> {
+    // Most of the settings end up in the client, but these are for the futures around it.
+    let probes = settings.probes.clone();
+    let probe_interval = settings.probe_interval;
+    let log_messages = settings.log_messages;
+    let control_socket = settings.control_socket.clone();
+    let notifier = settings.notifier.clone();
+
     // Create a new sink/stream for the connection to the server.
-    let server_connection = server_connection::ServerConnection::new(connection_details, tls_config);
+    let server_connection = server_connection::ServerConnection::new(endpoints, tls_config, backoff, settings.proxy.clone());
+    let server_connection_handle = server_connection.handle();
 
     // Split it into the constituent parts.
     let (sink, stream) = server_connection.split();
@@ -201,10 +654,94 @@ pub fn connect(id: String, connection_details: server_connection::ConnectionDeta
     let sink = sink.sink_map_err(|err| panic!("{}", err));
     let rx = rx.map_err(|err| panic!("{:?}", err));
 
-    let client = Client::new(id, tx);
+    // Native tunnel streams produce frames, which get sent to the server just like any other
+    // message.
+    let (tunnel_frame_tx, tunnel_frame_rx) = channel(16);
+    let tunnel_future = tunnel_frame_rx
+        .filter_map(TunnelFrame::into_client_message)
+        .forward(tx.clone().sink_map_err(|_| ()))
+        .map(|_| ())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to forward tunnel frames"));
+
+    // Every so often, tell the server about ourselves again.
+    let facts_tx = tx.clone();
+    let facts_server_connection = server_connection_handle.clone();
+    let facts_future = Interval::new(std::time::Instant::now() + facts::refresh_interval(), facts::refresh_interval())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", err)))
+        .for_each(move |_| {
+            let facts_tx = facts_tx.clone();
+            let endpoint = facts_server_connection.endpoint();
+            facts::gather_future()
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to gather facts"))
+                .and_then(move |mut facts| {
+                    facts.set_server_endpoint(endpoint.into());
+                    let mut message = device::ClientMessage::new();
+                    message.set_facts(facts);
+                    facts_tx.send(message)
+                        .map(|_| ())
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))
+                })
+        });
 
-    let sender_future = rx.inspect(|message| {
-        if !message.has_ping() && !message.has_pong() {
+    // Check every so often whether our certificate needs renewing. That mostly happens when the
+    // server initializes us, but we can stay connected for longer than a certificate lasts.
+    let renewal_future: Box<dyn Future<Item=(), Error=std::io::Error> + Send> = match settings.enrollment {
+        Some(ref enrollment) => {
+            let renewal_tx = tx.clone();
+            let enrollment = enrollment.clone();
+            let id = settings.id.clone();
+            let interval = std::time::Duration::from_secs(enrollment::RENEWAL_CHECK_INTERVAL);
+            Box::new(Interval::new(std::time::Instant::now() + interval, interval)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", err)))
+                .for_each(move |_| -> Box<dyn Future<Item=(), Error=std::io::Error> + Send> {
+                    match enrollment.renewal_request(&id) {
+                        Some(Ok(message)) => Box::new(renewal_tx.clone().send(message)
+                            .map(|_| ())
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))),
+                        Some(Err(err)) => {
+                            println!("! Failed to renew the client certificate: {}", err);
+                            Box::new(futures::future::ok(()))
+                        },
+                        None => Box::new(futures::future::ok(())),
+                    }
+                }))
+        },
+        None => Box::new(futures::future::ok(())),
+    };
+
+    // Run the probes every so often, and send the results to the server.
+    let probes_future: Box<dyn Future<Item=(), Error=std::io::Error> + Send> = if probes.is_empty() {
+        Box::new(futures::future::ok(()))
+    }
+    else {
+        let metrics_tx = tx.clone();
+        Box::new(Interval::new(std::time::Instant::now(), probe_interval)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", err)))
+            .for_each(move |_| {
+                let metrics_tx = metrics_tx.clone();
+                probes.sample_future()
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to run probes"))
+                    .and_then(move |metrics| {
+                        let mut message = device::ClientMessage::new();
+                        message.set_metrics(metrics);
+                        metrics_tx.send(message)
+                            .map(|_| ())
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))
+                    })
+            }))
+    };
+
+    let client = Client::new(settings, server_connection_handle.clone(), tx, tunnel_frame_tx);
+
+    // Let people on the device see what we're up to.
+    let server_status = ServerStatus::new();
+    tokio::spawn(control::listen(&control_socket, server_status.clone(), server_connection_handle.clone(), client.ssh_manager.clone()));
+
+    // And let systemd know, too.
+    tokio::spawn(notifier.watch(server_status.clone(), server_connection_handle, client.ssh_manager.clone()));
+
+    let sender_future = rx.inspect(move |message| {
+        if log_messages && !message.has_ping() && !message.has_pong() && !message.has_tunnel_data() && !message.has_tunnel_window_update() && !message.has_udp_datagram() && !message.has_exec_output() && !message.has_metrics() && !message.has_file_result() {
             // Log all of the messages we send to the server (except the Ping/Pongs; we don't want
             // that noise when logging).
             println!("↑ {:?}", message);
@@ -220,6 +757,8 @@ pub fn connect(id: String, connection_details: server_connection::ConnectionDeta
         });
 
     let stream_future = stream.fold(client, move |client, message| {
+        server_status.on_event(&message);
+
         // Every time we get a message from the server, what do we do with it.
         match message {
             server_connection::ServerConnectionEvent::Connecting => {
@@ -253,7 +792,8 @@ pub fn connect(id: String, connection_details: server_connection::ConnectionDeta
         Box::new(futures::future::ok(client))
     });
 
-    stream_future.join(sender_future)
+    stream_future.join5(sender_future, tunnel_future, facts_future, probes_future)
+        .join(renewal_future)
         .map(|_| ())
         .map_err(|err| panic!("{}", err))
 }
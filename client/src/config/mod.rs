@@ -0,0 +1,358 @@
+//! The client's configuration file. Everything in it can also be given as a command line flag, and
+//! flags win over the file.
+
+use std::collections::BTreeMap;
+use std::default::Default;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use connectbot_shared::backoff::BackoffPolicy;
+use toml;
+
+/// The structure that represents the configuration toml file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ApplicationConfig {
+    /// The unique identifier of the device. If this isn't set, the client makes one up the first
+    /// time it runs (see below).
+    pub id: Option<String>,
+    /// The directories that the server may copy files to and from
+    pub file_dirs: Vec<String>,
+    /// How to reach the server (see below)
+    pub server: Server,
+    /// Who the device is, when it isn't given an id (see below)
+    pub identity: Identity,
+    /// TLS information (see below)
+    pub tls: Tls,
+    /// Getting a client certificate from the server (see below)
+    pub enrollment: Enrollment,
+    /// The proxy that connections go through (see below)
+    pub proxy: Proxy,
+    /// The actions that the server is allowed to run on this device, by name. The commands are
+    /// run with `sh -c`.
+    pub actions: BTreeMap<String, String>,
+    /// How long an action may run for, in seconds, before it gets killed
+    pub action_timeout: u64,
+    /// Looking for services on the device's network (see below)
+    pub discovery: Discovery,
+    /// Metrics (see below)
+    pub probes: Probes,
+    /// How long to wait before reconnecting to the server (see below)
+    pub backoff: Backoff,
+    /// What gets logged (see below)
+    pub logging: Logging,
+    /// How SSH connections get made (see below)
+    pub ssh: Ssh,
+    /// Where the client keeps its own files (see below)
+    pub runtime: Runtime,
+    /// What the server may ask this device to forward (see below)
+    pub local_policy: LocalPolicy,
+}
+
+impl Default for ApplicationConfig {
+    fn default() -> Self {
+        ApplicationConfig {
+            id: None,
+            file_dirs: Vec::new(),
+            server: Default::default(),
+            identity: Default::default(),
+            tls: Default::default(),
+            enrollment: Default::default(),
+            proxy: Default::default(),
+            actions: BTreeMap::new(),
+            action_timeout: 300,
+            discovery: Default::default(),
+            probes: Default::default(),
+            backoff: Default::default(),
+            logging: Default::default(),
+            ssh: Default::default(),
+            runtime: Default::default(),
+            local_policy: Default::default(),
+        }
+    }
+}
+
+impl ApplicationConfig {
+    pub fn from_file(config_file: &Path) -> Result<ApplicationConfig, String> {
+        let mut data = String::new();
+
+        let mut file = File::open(config_file)
+            .map_err(|err| format!("Failed to open {:?}: {}", config_file, err))?;
+
+        file.read_to_string(&mut data)
+            .map_err(|err| format!("Failed to read {:?}: {}", config_file, err))?;
+
+        let config = toml::from_str(&data)
+            .map_err(|err| format!("Failed to parse {:?}: {}", config_file, err))?;
+
+        Ok(config)
+    }
+
+    /// An example config file, with the things that don't have sensible defaults filled in.
+    pub fn example() -> ApplicationConfig {
+        let mut config: ApplicationConfig = Default::default();
+        config.server.host = Some("host.tld".to_string());
+        config
+    }
+}
+
+/// Where the server is
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Server {
+    /// The hostname of the server. This is used both to connect and to check the server's
+    /// certificate.
+    pub host: Option<String>,
+    /// The port of the server
+    pub port: u16,
+    /// An IP address to connect to instead of looking up the host. The certificate is still
+    /// checked against the host.
+    pub resolve: Option<String>,
+    /// Instead of the host and port, a `wss://HOST[:PORT]/PATH` URL to connect to over WebSocket,
+    /// for networks that only let HTTPS out
+    pub url: Option<String>,
+    /// The name to check the server's certificate against, if it isn't the host
+    pub tls_name: Option<String>,
+    /// Other servers to try, in order, when this one can't be reached. The client goes back to
+    /// this one once it's reachable again.
+    pub fallbacks: Vec<Endpoint>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            host: None,
+            port: 4004,
+            resolve: None,
+            url: None,
+            tls_name: None,
+            fallbacks: Vec::new(),
+        }
+    }
+}
+
+/// A fallback server (`[[server.fallbacks]]`)
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Endpoint {
+    /// The hostname of the server
+    pub host: Option<String>,
+    /// The port of the server
+    pub port: u16,
+    /// An IP address to connect to instead of looking up the host
+    pub resolve: Option<String>,
+    /// Instead of the host and port, a `wss://HOST[:PORT]/PATH` URL to connect to over WebSocket
+    pub url: Option<String>,
+    /// The name to check the server's certificate against, if it isn't the host
+    pub tls_name: Option<String>,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint {
+            host: None,
+            port: 4004,
+            resolve: None,
+            url: None,
+            tls_name: None,
+        }
+    }
+}
+
+/// Who the device is
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Identity {
+    /// Where to keep the id that the client makes up. If this isn't set, `client-id` in
+    /// `$STATE_DIRECTORY` is used, and then /var/lib/connectbot/client-id.
+    pub file: Option<String>,
+    /// The name that the server shows for the device until somebody gives it a different one. If
+    /// this isn't set, the device's hostname is used.
+    pub name: Option<String>,
+}
+
+/// TLS configuration
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Tls {
+    /// The certificate authority to validate the server against. If this isn't set, the device's
+    /// root certificate store is used.
+    pub cafile: Option<String>,
+    /// The path to the TLS certificate (pem), if doing client authentication
+    pub cert: Option<String>,
+    /// The path to the TLS key (rsa), if doing client authentication
+    pub key: Option<String>,
+}
+
+/// Getting a client certificate from the server, instead of being given one with `tls.cert`
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Enrollment {
+    /// The one-time bootstrap token (from `connectbot-ctrl create-token`) to get the first
+    /// certificate with. It isn't needed once the device has a certificate.
+    pub token: Option<String>,
+    /// Where to keep the certificate. If this isn't set, `client.crt` in `$STATE_DIRECTORY` is
+    /// used, and then /var/lib/connectbot/client.crt.
+    pub certificate: Option<String>,
+    /// Where to keep the certificate's key. If this isn't set, `client.key` in `$STATE_DIRECTORY`
+    /// is used, and then /var/lib/connectbot/client.key.
+    pub key: Option<String>,
+}
+
+/// An outbound proxy, for networks where that's the only way out. Both the connection to the
+/// server and SSH connections go through it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Proxy {
+    /// The proxy, as `http://HOST:PORT` for an HTTP proxy (which gets asked to CONNECT) or
+    /// `socks5://HOST:PORT` for a SOCKS5 proxy. If this isn't set, connections are made directly.
+    pub url: Option<String>,
+    /// The username to log in to the proxy with, if it needs one
+    pub username: Option<String>,
+    /// The password to log in to the proxy with
+    pub password: Option<String>,
+}
+
+/// Looking for services on the device's network
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Discovery {
+    /// An IPv4 subnet (at most a /24) that the server may ask this device to scan, e.g.
+    /// 192.168.1.0/24. If this isn't set, only the device itself is scanned.
+    pub subnet: Option<String>,
+}
+
+/// Probes, which get reported to the server as metrics
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Probes {
+    /// A file of probes (NAME = COMMAND, or NAME = file:PATH, one per line)
+    pub file: Option<String>,
+    /// How often to run the probes, in seconds
+    pub interval: u64,
+}
+
+impl Default for Probes {
+    fn default() -> Self {
+        Probes {
+            file: None,
+            interval: 60,
+        }
+    }
+}
+
+/// How long to wait before connecting again after a connection fails. The wait doubles with every
+/// failure in a row, but always stays between these.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Backoff {
+    /// The shortest wait, in seconds
+    pub min_delay: u64,
+    /// The longest wait, in seconds
+    pub max_delay: u64,
+    /// Whether to wait a random time up to the doubled wait, so that many devices don't all come
+    /// back at the same moment
+    pub jitter: bool,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            min_delay: 5,
+            max_delay: 120,
+            jitter: true,
+        }
+    }
+}
+
+impl Backoff {
+    /// The backoff policy that these settings describe.
+    pub fn policy(&self) -> BackoffPolicy {
+        BackoffPolicy::new(Duration::from_secs(self.min_delay), Duration::from_secs(self.max_delay), self.jitter)
+    }
+}
+
+/// What gets logged
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Logging {
+    /// Whether to log the messages that are sent to and received from the server. Pings, pongs
+    /// and data are never logged.
+    pub messages: bool,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            messages: true,
+        }
+    }
+}
+
+/// How SSH connections get made
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Ssh {
+    /// `openssh` to run `ssh`, or `native` to hold the SSH session in the client
+    pub backend: SshBackend,
+    /// The known_hosts file that the native backend checks the server's host key against. If this
+    /// isn't set, ~/.ssh/known_hosts is used.
+    pub known_hosts: Option<String>,
+    /// How long to wait before connecting again after an SSH connection fails
+    pub backoff: Backoff,
+}
+
+impl Default for Ssh {
+    fn default() -> Self {
+        Ssh {
+            backend: SshBackend::OpenSsh,
+            known_hosts: None,
+            backoff: Default::default(),
+        }
+    }
+}
+
+/// What makes SSH connections
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SshBackend {
+    OpenSsh,
+    Native,
+}
+
+/// Where the client keeps its own files
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Runtime {
+    /// A private directory for the client's runtime files, like SSH keys and control sockets. If
+    /// this isn't set, `$RUNTIME_DIRECTORY` (set by systemd's `RuntimeDirectory=`) is used, and
+    /// then /tmp/connectbot-client-UID.
+    pub directory: Option<String>,
+    /// The Unix socket that `connectbot-client status` talks to. If this isn't set,
+    /// `control.sock` in the runtime directory is used.
+    pub control_socket: Option<String>,
+}
+
+/// What the server may ask this device to forward. Anything else is refused, and the server is
+/// told why.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct LocalPolicy {
+    /// The targets that may be forwarded, as `host:port` where either may be `*`. A forward
+    /// without a host is to `localhost`. Dynamic forwards have to be limited to destinations that
+    /// are all in here. If this isn't set, anything may be forwarded.
+    pub forwards: Option<Vec<String>>,
+    /// Whether forwards may use gateway ports (be reachable from other hosts than the server, or
+    /// than the device for local forwards)
+    pub gateway_ports: bool,
+}
+
+impl Default for LocalPolicy {
+    fn default() -> Self {
+        LocalPolicy {
+            forwards: None,
+            gateway_ports: true,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! A Unix socket for people on the device to ask the running client what it's doing, and to tell
+//! it a few things. `connectbot-client status` (and friends) talk to it.
+//!
+//! The protocol is one command per connection: the other end sends a line, we send back some text
+//! and close the connection.
+//!
+//! * `status` describes the server connection and every SSH connection
+//! * `disable ID` disconnects an SSH connection (the server is told, like for any other disconnect)
+//! * `reconnect` connects to the server again right away, starting with the primary server
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use futures::{self, Future, Sink, Stream};
+use server_connection::{IDLE_DISCONNECT, ServerConnectionEvent, ServerConnectionHandle};
+use ssh_connection::SshConnectionChange;
+use ssh_manager::SshManager;
+use tokio;
+use tokio::net::UnixListener;
+use tokio_codec::{Decoder, LinesCodec};
+
+/// What the connection to the server is doing, as far as anybody on the device is concerned.
+#[derive(Clone)]
+pub struct ServerStatus {
+    state: Arc<RwLock<ServerStatusState>>,
+}
+
+struct ServerStatusState {
+    /// What's happening
+    state: &'static str,
+    /// When it started happening
+    since: DateTime<Local>,
+    /// The number of consecutive failures
+    failures: usize,
+    /// Why the connection last failed, and how long we waited before trying again
+    last_failure: Option<(String, Duration)>,
+    /// When we last heard from the server
+    last_heard: Option<Instant>,
+}
+
+impl ServerStatus {
+    pub fn new() -> ServerStatus {
+        ServerStatus {
+            state: Arc::new(RwLock::new(ServerStatusState {
+                state: "starting",
+                since: Local::now(),
+                failures: 0,
+                last_failure: None,
+                last_heard: None,
+            })),
+        }
+    }
+
+    /// Keep track of what the server connection is doing.
+    pub fn on_event(&self, event: &ServerConnectionEvent) {
+        let state = match event {
+            ServerConnectionEvent::Connecting => "connecting",
+            ServerConnectionEvent::TcpConnected => "negotiating TLS",
+            ServerConnectionEvent::TlsConnected => "connected",
+            ServerConnectionEvent::ConnectionFailed(_) => "waiting to connect again",
+            ServerConnectionEvent::Item(_) => {
+                self.state.write().unwrap().last_heard = Some(Instant::now());
+                return;
+            },
+            // This doesn't change anything.
+            ServerConnectionEvent::TimeoutWarning => return,
+        };
+
+        let mut status = self.state.write().unwrap();
+        status.state = state;
+        status.since = Local::now();
+        match event {
+            ServerConnectionEvent::TlsConnected => {
+                status.failures = 0;
+                status.last_heard = Some(Instant::now());
+            },
+            ServerConnectionEvent::ConnectionFailed(failed) => {
+                status.failures = failed.failures;
+                status.last_failure = Some((format!("{}", failed.err), failed.duration));
+            },
+            _ => {},
+        }
+    }
+
+    /// What's happening, in a few words.
+    pub fn state(&self) -> &'static str {
+        self.state.read().unwrap().state
+    }
+
+    /// Whether we're connected, and have heard from the server recently enough that the
+    /// connection isn't about to be given up on.
+    pub fn is_healthy(&self) -> bool {
+        let status = self.state.read().unwrap();
+        status.state == "connected" && status.last_heard.map_or(false, |last_heard| last_heard.elapsed() < IDLE_DISCONNECT)
+    }
+
+    fn describe(&self, endpoint: &str) -> String {
+        let status = self.state.read().unwrap();
+        let mut description = format!("Server: {} since {}\n", status.state, status.since.format("%Y-%m-%d %H:%M:%S"));
+        description.push_str(&format!("  Endpoint: {}\n", endpoint));
+        if status.failures > 0 {
+            description.push_str(&format!("  {} failure(s) in a row\n", status.failures));
+        }
+        if let Some((ref err, ref duration)) = status.last_failure {
+            description.push_str(&format!("  Last failure: {} (waited {}s to try again)\n", err, duration.as_secs()));
+        }
+        description
+    }
+}
+
+/// Listen on the control socket. If it can't be set up, the client works fine without it, so that
+/// only gets logged.
+pub fn listen(path: &Path, server_status: ServerStatus, server_connection: ServerConnectionHandle, ssh_manager: SshManager) -> impl Future<Item=(), Error=()> {
+    let listener = bind(path);
+    let path = path.to_path_buf();
+
+    futures::future::result(listener)
+        .map_err(move |err| println!("! Failed to listen on {}: {}", path.display(), err))
+        .and_then(move |listener| {
+            listener.incoming()
+                .map_err(|err| println!("! Control socket failed: {}", err))
+                .for_each(move |stream| {
+                    let server_status = server_status.clone();
+                    let server_connection = server_connection.clone();
+                    let ssh_manager = ssh_manager.clone();
+
+                    let (sink, stream) = LinesCodec::new().framed(stream).split();
+                    let f = stream.into_future()
+                        .map_err(|(err, _)| println!("! Control socket failed: {}", err))
+                        .and_then(move |(line, _)| {
+                            let response = handle(line.as_ref().map(|line| line.as_str()).unwrap_or(""), &server_status, &server_connection, &ssh_manager);
+                            sink.send(response.trim_end().to_string())
+                                .map(|_| ())
+                                .map_err(|err| println!("! Control socket failed: {}", err))
+                        });
+                    tokio::spawn(f);
+
+                    Ok(())
+                })
+        })
+}
+
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    // A socket left behind by a client that didn't get to clean up would be in the way.
+    match fs::remove_file(path) {
+        Ok(()) => {},
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+        Err(err) => return Err(err),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    // Only whoever the client runs as gets to tell it what to do.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn handle(line: &str, server_status: &ServerStatus, server_connection: &ServerConnectionHandle, ssh_manager: &SshManager) -> String {
+    let mut parts = line.trim().splitn(2, ' ');
+    match (parts.next().unwrap_or(""), parts.next()) {
+        ("status", None) => {
+            let mut description = server_status.describe(&server_connection.endpoint());
+            let connections = ssh_manager.current_states();
+            if connections.is_empty() {
+                description.push_str("SSH connections: none\n");
+            }
+            else {
+                description.push_str("SSH connections:\n");
+                for (id, state) in connections {
+                    match state {
+                        Some(SshConnectionChange::Failed(failures, reason)) => {
+                            description.push_str(&format!("  {}: Failed ({} in a row): {}\n", id, failures, reason));
+                        },
+                        Some(state) => description.push_str(&format!("  {}: {:?}\n", id, state)),
+                        None => description.push_str(&format!("  {}: Requested\n", id)),
+                    }
+                }
+            }
+            description
+        },
+        ("disable", Some(id)) => {
+            if ssh_manager.disable(id.trim()) {
+                println!("! Disabling SSH connection {} (asked locally)", id.trim());
+                format!("Disabling {}\n", id.trim())
+            }
+            else {
+                format!("Error: there is no SSH connection {}\n", id.trim())
+            }
+        },
+        ("reconnect", None) => {
+            server_connection.reconnect();
+            "Reconnecting\n".to_string()
+        },
+        _ => format!("Error: unknown command {:?}\n", line),
+    }
+}
+
+/// Send a command to a running client, and get back what it said.
+pub fn send(path: &Path, command: &str) -> Result<String, String> {
+    let mut stream = StdUnixStream::connect(path)
+        .map_err(|err| format!("Failed to connect to {} (is connectbot-client running?): {}", path.display(), err))?;
+    writeln!(stream, "{}", command)
+        .map_err(|err| format!("Failed to send to {}: {}", path.display(), err))?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)
+        .map_err(|err| format!("Failed to read from {}: {}", path.display(), err))?;
+
+    if response.starts_with("Error: ") {
+        Err(response["Error: ".len()..].trim().to_string())
+    }
+    else {
+        Ok(response)
+    }
+}
@@ -0,0 +1,202 @@
+//! Looking for services on the device's network, so that the server can suggest forwards. We try
+//! a list of common TCP ports on the device itself and, if the server asks and the person running
+//! the client configured one, on every address of a small subnet.
+
+use std;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use device;
+use futures::{self, Future, Stream};
+use tokio::net::TcpStream;
+use tokio_io;
+use tokio_timer::Timeout;
+
+/// The ports we try. These are the services people usually want to forward to.
+const COMMON_PORTS: &[u16] = &[
+    21, 22, 23, 80, 443, 502, 554, 1883, 3000, 3389, 5000, 5432, 5900, 8000, 8080, 8443, 8883, 8888, 9000,
+];
+
+/// Ports where we ask for a page instead of waiting for a banner.
+const HTTP_PORTS: &[u16] = &[80, 3000, 5000, 8000, 8080, 8888, 9000];
+
+/// How many connections to try at once.
+const CONCURRENCY: usize = 64;
+
+/// The smallest subnet prefix we're willing to scan. A /24 is 254 addresses.
+const MIN_PREFIX: u8 = 24;
+
+/// How long to wait for each connection.
+fn connect_timeout() -> Duration {
+    Duration::from_millis(500)
+}
+
+/// How long to wait for a service to say something.
+fn banner_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// An IPv4 subnet that the client is allowed to scan.
+#[derive(Debug, Clone, Copy)]
+pub struct Subnet {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Parse a subnet in CIDR notation, like 192.168.1.0/24.
+    pub fn parse(value: &str) -> Result<Subnet, String> {
+        let mut parts = value.splitn(2, '/');
+        let address: Ipv4Addr = parts.next().unwrap_or("").parse()
+            .map_err(|_| format!("'{}' is not an IPv4 subnet like 192.168.1.0/24", value))?;
+        let prefix: u8 = parts.next().unwrap_or("").parse()
+            .map_err(|_| format!("'{}' is not an IPv4 subnet like 192.168.1.0/24", value))?;
+        if prefix < MIN_PREFIX || prefix > 32 {
+            return Err(format!("'{}' is too large to scan; the prefix must be at least /{}", value, MIN_PREFIX));
+        }
+
+        let mask = u32::max_value() << (32 - prefix as u32);
+        Ok(Subnet {
+            network: Ipv4Addr::from(u32::from(address) & mask),
+            prefix,
+        })
+    }
+
+    /// Every host address in the subnet (so not the network or broadcast address, unless the
+    /// subnet is too small to have them).
+    fn hosts(&self) -> Vec<Ipv4Addr> {
+        let network = u32::from(self.network);
+        let size = 1u32 << (32 - self.prefix as u32);
+        if size <= 2 {
+            return (0..size).map(|i| Ipv4Addr::from(network + i)).collect();
+        }
+        (1..size - 1).map(|i| Ipv4Addr::from(network + i)).collect()
+    }
+}
+
+/// Scan for services, and build the result to send back to the server.
+pub fn discover(request_id: u32, subnet: Option<Subnet>, include_subnet: bool) -> impl Future<Item=device::DiscoverResult, Error=()> {
+    let mut targets: Vec<(String, IpAddr)> = vec![("localhost".to_string(), IpAddr::V4(Ipv4Addr::LOCALHOST))];
+    if include_subnet {
+        match subnet {
+            Some(subnet) => {
+                println!("! Scanning localhost and {}", subnet);
+                targets.extend(subnet.hosts().into_iter().map(|host| (host.to_string(), IpAddr::V4(host))));
+            },
+            None => {
+                let mut result = device::DiscoverResult::new();
+                result.set_request_id(request_id);
+                result.set_error("This device is not configured to scan a subnet (see --discover-subnet)".into());
+                return futures::future::Either::A(futures::future::ok(result));
+            },
+        }
+    }
+    else {
+        println!("! Scanning localhost");
+    }
+
+    let probes: Vec<_> = targets.into_iter()
+        .flat_map(|(name, address)| {
+            COMMON_PORTS.iter().map(move |port| (name.clone(), SocketAddr::new(address, *port)))
+        })
+        .collect();
+
+    let f = futures::stream::iter_ok::<_, ()>(probes)
+        .map(|(name, address)| probe(name, address))
+        .buffer_unordered(CONCURRENCY)
+        .filter_map(|service| service)
+        .collect()
+        .map(move |services| {
+            let mut result = device::DiscoverResult::new();
+            result.set_request_id(request_id);
+            result.set_services(services.into());
+            result
+        });
+
+    futures::future::Either::B(f)
+}
+
+/// Try a single port. Resolves to the service, if something is listening.
+fn probe(name: String, address: SocketAddr) -> impl Future<Item=Option<device::DiscoverResult_Service>, Error=()> {
+    let port = address.port();
+    Timeout::new(TcpStream::connect(&address), connect_timeout())
+        .then(move |result| {
+            let stream = match result {
+                Ok(stream) => stream,
+                Err(_) => return futures::future::Either::A(futures::future::ok(None)),
+            };
+
+            let mut service = device::DiscoverResult_Service::new();
+            service.set_host(name.as_str().into());
+            service.set_port(port as u32);
+
+            let f = identify(name, port, stream)
+                .then(move |identity| {
+                    match identity {
+                        Ok(Identity::Banner(banner)) => service.set_banner(banner.into()),
+                        Ok(Identity::HttpTitle(title)) => service.set_http_title(title.into()),
+                        Err(()) => {},
+                    }
+                    Ok(Some(service))
+                });
+            futures::future::Either::B(f)
+        })
+}
+
+/// What we could find out about a service.
+enum Identity {
+    Banner(String),
+    HttpTitle(String),
+}
+
+/// Find out what a service is. HTTP services get asked for their front page; anything else gets
+/// a chance to say hello (SSH, FTP and SMTP servers all do).
+fn identify(host: String, port: u16, stream: TcpStream) -> Box<dyn Future<Item=Identity, Error=()> + Send> {
+    if HTTP_PORTS.contains(&port) {
+        let request = format!("GET / HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", host);
+        let f = tokio_io::io::write_all(stream, request.into_bytes())
+            .and_then(|(stream, _)| tokio_io::io::read(stream, vec![0; 8192]))
+            .map(|(_, buffer, n)| Identity::HttpTitle(http_title(&String::from_utf8_lossy(&buffer[..n]))));
+        Box::new(Timeout::new(f, banner_timeout()).map_err(|_| ()))
+    }
+    else {
+        let f = tokio_io::io::read(stream, vec![0; 256])
+            .map(|(_, buffer, n)| {
+                let banner = String::from_utf8_lossy(&buffer[..n]);
+                Identity::Banner(banner.lines().next().unwrap_or("").trim().to_string())
+            });
+        Box::new(Timeout::new(f, banner_timeout()).map_err(|_| ()))
+    }
+}
+
+/// Pull the <title> out of an HTTP response, if there is one.
+fn http_title(response: &str) -> String {
+    let lower = response.to_lowercase();
+    let start = match lower.find("<title") {
+        Some(start) => start,
+        None => return String::new(),
+    };
+    let start = match lower[start..].find('>') {
+        Some(offset) => start + offset + 1,
+        None => return String::new(),
+    };
+    let end = lower[start..].find("</title").map_or(response.len(), |offset| start + offset);
+
+    // Lowercasing can change byte lengths for some characters, so don't trust the offsets if they
+    // don't line up.
+    response.get(start..end)
+        .map(|title| title.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_default()
+}
+
+/// Send the result of a scan, as a message for the server.
+pub fn result_message(result: device::DiscoverResult) -> device::ClientMessage {
+    let mut message = device::ClientMessage::new();
+    message.set_discover_result(result);
+    message
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
@@ -0,0 +1,253 @@
+//! Running actions for the server. The person running the client decides which actions exist
+//! (with `--action NAME=COMMAND`,
+//! or in the config file), and the server can only ask for them by name. An action that runs for
+//! longer than the timeout (`--action-timeout`) gets killed, along with anything it started.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use device;
+use futures::{Future, Sink};
+use futures::sync::mpsc::Sender;
+use libc;
+
+/// How much output to send to the server at a time.
+const OUTPUT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// How often to check whether a running action has finished.
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The actions that the server is allowed to run, by name.
+#[derive(Debug, Clone, Default)]
+pub struct Actions {
+    commands: HashMap<String, String>,
+    timeout: Duration,
+}
+
+impl Actions {
+    /// Create actions from names and commands. Each one gets killed if it runs for longer than
+    /// `timeout`.
+    pub fn new<I: IntoIterator<Item=(String, String)>>(actions: I, timeout: Duration) -> Actions {
+        Actions {
+            commands: actions.into_iter().collect(),
+            timeout,
+        }
+    }
+
+    /// The names of all of the actions, to tell the server about.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.commands.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Run an action on behalf of the server, and stream what happens back to it. Actions that
+    /// don't exist get rejected.
+    pub fn run(&self, request_id: u32, action: &str, sender: Sender<device::ClientMessage>) {
+        let command = match self.commands.get(action) {
+            Some(command) => command.clone(),
+            None => {
+                println!("! Rejecting unknown action {:?}", action);
+                let mut result = device::ExecResult::new();
+                result.set_request_id(request_id);
+                result.set_status(device::ExecResult_Status::REJECTED);
+                send_blocking_later(sender, result_message(result));
+                return;
+            },
+        };
+
+        println!("! Running action {:?}: {}", action, command);
+
+        // The command might run for a while, and reading its output blocks. So it gets threads of
+        // its own instead of tying up the runtime.
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            let result = run_command(request_id, &command, timeout, &sender);
+            let _ = sender.send(result_message(result)).wait();
+        });
+    }
+}
+
+/// Parse an action from a `NAME=COMMAND` argument.
+pub fn parse_argument(argument: &str) -> Result<(String, String), String> {
+    let mut parts = argument.splitn(2, '=');
+    let name = parts.next().unwrap_or("").trim();
+    let command = parts.next().unwrap_or("").trim();
+    if name.is_empty() || command.is_empty() {
+        return Err(format!("Invalid action {:?}, expected NAME=COMMAND", argument));
+    }
+
+    Ok((name.to_string(), command.to_string()))
+}
+
+/// Run the command to completion, sending its output as it comes. If it takes longer than
+/// `timeout`, it gets killed.
+fn run_command(request_id: u32, command: &str, timeout: Duration, sender: &Sender<device::ClientMessage>) -> device::ExecResult {
+    let mut result = device::ExecResult::new();
+    result.set_request_id(request_id);
+
+    let child = Command::new("sh")
+        .args(&["-c", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // A group of its own, so that a timeout kills whatever the command started too. Otherwise
+        // those would keep the output open and we'd never finish.
+        .process_group(0)
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            result.set_status(device::ExecResult_Status::FAILED);
+            result.set_error(format!("Failed to run: {}", err).into());
+            return result;
+        },
+    };
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stdout_thread = {
+        let sender = sender.clone();
+        thread::spawn(move || stream_output(request_id, device::ExecOutput_Stream::STDOUT, stdout, sender))
+    };
+    let stderr_thread = {
+        let sender = sender.clone();
+        thread::spawn(move || stream_output(request_id, device::ExecOutput_Stream::STDERR, stderr, sender))
+    };
+    let status = wait_with_timeout(&mut child, timeout);
+    let _ = stdout_thread.join();
+    let _ = stderr_thread.join();
+
+    match status {
+        Ok(Some(status)) => {
+            match status.code() {
+                Some(code) => {
+                    result.set_status(device::ExecResult_Status::EXITED);
+                    result.set_exit_code(code);
+                },
+                None => {
+                    result.set_status(device::ExecResult_Status::FAILED);
+                    result.set_error("Killed by a signal".into());
+                },
+            }
+        },
+        Ok(None) => {
+            println!("! Killed action {:?} after {} seconds", command, timeout.as_secs());
+            result.set_status(device::ExecResult_Status::FAILED);
+            result.set_error(format!("Timed out after {} seconds", timeout.as_secs()).into());
+        },
+        Err(err) => {
+            result.set_status(device::ExecResult_Status::FAILED);
+            result.set_error(format!("Failed to wait for the command: {}", err).into());
+        },
+    }
+
+    result
+}
+
+/// Wait for the command to exit, or kill its whole process group once `timeout` has passed. Gives
+/// `None` if it had to be killed.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> ::std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep(::std::cmp::min(WAIT_INTERVAL, deadline - now));
+    }
+
+    // The child leads its own process group, so its pid is also the group's id.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    child.wait()?;
+    Ok(None)
+}
+
+/// Send everything from one of the command's output streams to the server.
+fn stream_output<R: Read>(request_id: u32, stream: device::ExecOutput_Stream, mut reader: R, sender: Sender<device::ClientMessage>) {
+    let mut buffer = vec![0; OUTPUT_CHUNK_SIZE];
+
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+
+        let mut output = device::ExecOutput::new();
+        output.set_request_id(request_id);
+        output.set_stream(stream);
+        output.set_data(Bytes::from(&buffer[..n]));
+
+        let mut message = device::ClientMessage::new();
+        message.set_exec_output(output);
+
+        if sender.clone().send(message).wait().is_err() {
+            // The connection to the server has gone away. Nobody is listening anymore.
+            return;
+        }
+    }
+}
+
+fn result_message(result: device::ExecResult) -> device::ClientMessage {
+    let mut message = device::ClientMessage::new();
+    message.set_exec_result(result);
+    message
+}
+
+/// Send a message from a thread of its own, so that we don't block whoever called us.
+fn send_blocking_later(sender: Sender<device::ClientMessage>, message: device::ClientMessage) {
+    thread::spawn(move || {
+        let _ = sender.send(message).wait();
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::mpsc::channel;
+
+    #[test]
+    fn a_command_that_finishes_in_time_reports_its_exit_code() {
+        let (sender, _receiver) = channel(16);
+        let result = run_command(1, "exit 3", Duration::from_secs(10), &sender);
+
+        assert_eq!(result.get_request_id(), 1);
+        assert_eq!(result.get_status(), device::ExecResult_Status::EXITED);
+        assert_eq!(result.get_exit_code(), 3);
+    }
+
+    #[test]
+    fn a_command_that_runs_too_long_is_killed_and_reported() {
+        let (sender, _receiver) = channel(16);
+        let started = Instant::now();
+        // The background sleep keeps the output open, so this only finishes if the whole group is
+        // killed.
+        let result = run_command(2, "sleep 30 & sleep 30", Duration::from_millis(200), &sender);
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(result.get_request_id(), 2);
+        assert_eq!(result.get_status(), device::ExecResult_Status::FAILED);
+        assert_eq!(result.get_error(), "Timed out after 0 seconds");
+    }
+
+    #[test]
+    fn parse_argument_needs_a_name_and_a_command() {
+        assert_eq!(parse_argument("uptime = uptime -p"), Ok(("uptime".to_string(), "uptime -p".to_string())));
+        assert!(parse_argument("uptime").is_err());
+        assert!(parse_argument("=uptime").is_err());
+        assert!(parse_argument("uptime=").is_err());
+    }
+}
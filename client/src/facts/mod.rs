@@ -0,0 +1,122 @@
+//! Finding out about the device we're running on, so that the server can show it. Anything we
+//! can't find out is left empty; none of it is important enough to fail over.
+
+use std;
+use std::collections::BTreeMap;
+use std::fs;
+use std::process::{Command, Stdio};
+use device;
+use futures::Future;
+use futures::future::poll_fn;
+use tokio_threadpool::blocking;
+
+/// How often to tell the server about ourselves again while we're connected. Uptime and addresses
+/// change.
+pub fn refresh_interval() -> std::time::Duration {
+    std::time::Duration::from_secs(15 * 60)
+}
+
+/// Gather the facts without blocking the runtime. Reading files and running commands blocks.
+pub fn gather_future() -> impl Future<Item=device::Facts, Error=()> {
+    poll_fn(|| {
+        blocking(gather)
+    }).map_err(|_| panic!("the threadpool shut down"))
+}
+
+/// Gather the facts.
+pub fn gather() -> device::Facts {
+    let mut facts = device::Facts::new();
+    facts.set_hostname(hostname().into());
+    facts.set_os(os().into());
+    facts.set_kernel(command_output("uname", &["-r"]).into());
+    facts.set_arch(std::env::consts::ARCH.into());
+    facts.set_client_version(env!("CARGO_PKG_VERSION").into());
+    facts.set_uptime(uptime());
+    facts.set_interfaces(interfaces().into());
+    facts.set_ssh_version(ssh_version().into());
+    facts
+}
+
+/// The device's hostname.
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|hostname| hostname.trim().to_string())
+        .unwrap_or_else(|_| command_output("hostname", &[]))
+}
+
+/// The PRETTY_NAME from os-release, e.g. "Raspbian GNU/Linux 9 (stretch)".
+fn os() -> String {
+    let os_release = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"));
+
+    if let Ok(os_release) = os_release {
+        for line in os_release.lines() {
+            if line.starts_with("PRETTY_NAME=") {
+                return line["PRETTY_NAME=".len()..].trim_matches('"').to_string();
+            }
+        }
+    }
+
+    command_output("uname", &["-s"])
+}
+
+/// Seconds since boot, from the first number in /proc/uptime.
+fn uptime() -> u64 {
+    fs::read_to_string("/proc/uptime").ok()
+        .and_then(|uptime| {
+            uptime.split_whitespace().next()
+                .and_then(|seconds| seconds.parse::<f64>().ok())
+        })
+        .map(|seconds| seconds as u64)
+        .unwrap_or(0)
+}
+
+/// The network interfaces and their addresses, from `ip -o addr show`. Each line looks like
+/// `2: eth0    inet 10.0.0.2/24 brd 10.0.0.255 scope global eth0`.
+fn interfaces() -> Vec<device::Facts_Interface> {
+    let output = command_output("ip", &["-o", "addr", "show"]);
+
+    let mut addresses: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || (fields[2] != "inet" && fields[2] != "inet6") {
+            continue;
+        }
+        let name = fields[1].trim_end_matches(':').to_string();
+        if name == "lo" {
+            continue;
+        }
+        addresses.entry(name).or_insert_with(Vec::new).push(fields[3].to_string());
+    }
+
+    addresses.into_iter()
+        .map(|(name, addresses)| {
+            let mut interface = device::Facts_Interface::new();
+            interface.set_name(name.into());
+            interface.set_addresses(addresses.iter().map(|address| address.as_str().into()).collect::<Vec<_>>().into());
+            interface
+        })
+        .collect()
+}
+
+/// `ssh -V` prints its version to stderr, e.g. `OpenSSH_7.4p1 Raspbian-10+deb9u4, OpenSSL 1.0.2q`.
+fn ssh_version() -> String {
+    Command::new("ssh")
+        .arg("-V")
+        .stdin(Stdio::null())
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stderr).trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Run a command, and return what it printed, or nothing if it didn't work.
+fn command_output(command: &str, args: &[&str]) -> String {
+    Command::new(command)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default()
+}
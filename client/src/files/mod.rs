@@ -0,0 +1,319 @@
+//! Copying files to and from the device for the server. The person running the client decides
+//! which directories the server may touch (with `--file-dir DIR`, or in the config file), and
+//! anything outside of them is rejected.
+//!
+//! The server reads and writes a chunk at a time (see `connectbot_shared::file_transfer`). Written
+//! chunks go into a partial file next to the destination, which only gets moved into place once
+//! the checksum of the whole file matches.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use device;
+use futures::Future;
+use futures::future::poll_fn;
+use tokio_threadpool::blocking;
+
+use connectbot_shared::file_transfer::{Checksum, CHUNK_SIZE, PARTIAL_SUFFIX};
+
+/// The directories that the server is allowed to copy files to and from.
+#[derive(Debug, Clone, Default)]
+pub struct FileDirs {
+    dirs: Vec<PathBuf>,
+}
+
+/// Why a file request didn't work.
+enum FileError {
+    /// The path isn't in one of the directories
+    Rejected(String),
+    Failed(String),
+}
+
+impl FileDirs {
+    /// Parse the directories from `--file-dir` arguments. They have to exist.
+    pub fn from_arguments<'a, I: IntoIterator<Item=&'a str>>(arguments: I) -> Result<FileDirs, String> {
+        let mut dirs = Vec::new();
+
+        for argument in arguments {
+            let dir = fs::canonicalize(argument)
+                .map_err(|err| format!("Invalid file directory {:?}: {}", argument, err))?;
+            if !dir.is_dir() {
+                return Err(format!("Invalid file directory {:?}: not a directory", argument));
+            }
+            dirs.push(dir);
+        }
+
+        Ok(FileDirs { dirs })
+    }
+
+    /// The directories, to tell the server about.
+    pub fn names(&self) -> Vec<String> {
+        self.dirs.iter()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Handle a request from the server without blocking the runtime, and build the result to send
+    /// back.
+    pub fn handle(&self, request: device::FileRequest) -> impl Future<Item=device::ClientMessage, Error=()> {
+        let dirs = self.clone();
+        poll_fn(move || {
+            blocking(|| dirs.handle_blocking(&request))
+        }).map_err(|_| panic!("the threadpool shut down"))
+    }
+
+    fn handle_blocking(&self, request: &device::FileRequest) -> device::ClientMessage {
+        let mut result = device::FileResult::new();
+        result.set_request_id(request.get_request_id());
+
+        let outcome = self.resolve(request.get_path())
+            .and_then(|path| {
+                if request.has_write() {
+                    write(&path, request.get_offset(), request.get_write(), &mut result)
+                }
+                else if request.has_resume() {
+                    resume(&path, &mut result)
+                }
+                else {
+                    read(&path, request.get_offset(), &mut result)
+                }
+            });
+
+        match outcome {
+            Ok(()) => {
+                result.set_status(device::FileResult_Status::SUCCESS);
+            },
+            Err(FileError::Rejected(error)) => {
+                println!("! Rejecting file request: {}", error);
+                result.set_status(device::FileResult_Status::REJECTED);
+                result.set_error(error.into());
+            },
+            Err(FileError::Failed(error)) => {
+                println!("! File request failed: {}", error);
+                result.set_status(device::FileResult_Status::FAILED);
+                result.set_error(error.into());
+            },
+        }
+
+        let mut message = device::ClientMessage::new();
+        message.set_file_result(result);
+        message
+    }
+
+    /// Work out where a path really is (following any symlinks), and make sure that it's inside
+    /// one of the directories. The file itself doesn't have to exist yet, but its directory does.
+    fn resolve(&self, path: &str) -> Result<PathBuf, FileError> {
+        let requested = Path::new(path);
+        if !requested.is_absolute() {
+            return Err(FileError::Rejected(format!("{} is not an absolute path", path)));
+        }
+
+        // This is None for paths that end in "..", which can't be a file anyway.
+        let (parent, file_name) = match (requested.parent(), requested.file_name()) {
+            (Some(parent), Some(file_name)) => (parent, file_name),
+            _ => return Err(FileError::Rejected(format!("{} is not a file", path))),
+        };
+        let parent = fs::canonicalize(parent)
+            .map_err(|err| FileError::Failed(format!("{}: {}", parent.display(), err)))?;
+
+        let resolved = parent.join(file_name);
+        let resolved = fs::canonicalize(&resolved).unwrap_or(resolved);
+
+        if self.dirs.iter().any(|dir| resolved.starts_with(dir) && resolved != *dir) {
+            Ok(resolved)
+        }
+        else {
+            Err(FileError::Rejected(format!("{} is not in an allowed directory", path)))
+        }
+    }
+}
+
+/// Read a chunk of a file. At the end of the file, send the checksum instead, so that the other
+/// end can make sure that it got everything.
+fn read(path: &Path, offset: u64, result: &mut device::FileResult) -> Result<(), FileError> {
+    let mut file = File::open(path).map_err(|err| failed(path, err))?;
+    let metadata = file.metadata().map_err(|err| failed(path, err))?;
+    if !metadata.is_file() {
+        return Err(FileError::Failed(format!("{} is not a regular file", path.display())));
+    }
+
+    let size = metadata.len();
+    result.set_size(size);
+    if offset > size {
+        return Err(FileError::Failed(format!("{} is only {} bytes", path.display(), size)));
+    }
+    if offset == size {
+        let sha256 = Checksum::of_file(path).map_err(|err| failed(path, err))?;
+        result.set_sha256(sha256.into());
+        return Ok(());
+    }
+
+    file.seek(SeekFrom::Start(offset)).map_err(|err| failed(path, err))?;
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    file.take(CHUNK_SIZE as u64).read_to_end(&mut data).map_err(|err| failed(path, err))?;
+    result.set_data(data.into());
+
+    Ok(())
+}
+
+/// Write a chunk to the partial file. On the last chunk, check the whole file and move it into
+/// place.
+fn write(path: &Path, offset: u64, write: &device::FileRequest_Write, result: &mut device::FileResult) -> Result<(), FileError> {
+    let partial = partial_path(path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(&partial)
+        .map_err(|err| failed(&partial, err))?;
+
+    // Chunks have to follow on from what we already have, so that nothing gets skipped. Starting
+    // over is fine, though.
+    let existing = file.metadata().map_err(|err| failed(&partial, err))?.len();
+    result.set_size(existing);
+    if offset != 0 && offset != existing {
+        return Err(FileError::Failed(format!("{} has {} bytes, so the next chunk has to start there (not at {})", partial.display(), existing, offset)));
+    }
+
+    file.set_len(offset).map_err(|err| failed(&partial, err))?;
+    file.seek(SeekFrom::Start(offset)).map_err(|err| failed(&partial, err))?;
+    file.write_all(write.get_data()).map_err(|err| failed(&partial, err))?;
+    let size = offset + write.get_data().len() as u64;
+    result.set_size(size);
+
+    if write.get_sha256().is_empty() {
+        return Ok(());
+    }
+
+    // That was the last chunk.
+    file.sync_all().map_err(|err| failed(&partial, err))?;
+    drop(file);
+
+    let sha256 = Checksum::of_file(&partial).map_err(|err| failed(&partial, err))?;
+    if sha256 != write.get_sha256().to_lowercase() {
+        let _ = fs::remove_file(&partial);
+        result.set_size(0);
+        return Err(FileError::Failed(format!("The checksum of {} does not match ({}, expected {})", path.display(), sha256, write.get_sha256())));
+    }
+
+    fs::rename(&partial, path).map_err(|err| failed(path, err))?;
+    println!("! Received {} ({} bytes)", path.display(), size);
+
+    Ok(())
+}
+
+/// Find out how much of the partial file we have.
+fn resume(path: &Path, result: &mut device::FileResult) -> Result<(), FileError> {
+    let partial = partial_path(path);
+    match fs::metadata(&partial) {
+        Ok(metadata) => result.set_size(metadata.len()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => result.set_size(0),
+        Err(err) => return Err(failed(&partial, err)),
+    }
+
+    Ok(())
+}
+
+/// Where a file gets written to until it's complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.to_path_buf().into_os_string();
+    partial.push(PARTIAL_SUFFIX);
+    PathBuf::from(partial)
+}
+
+fn failed(path: &Path, err: io::Error) -> FileError {
+    FileError::Failed(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::{self, TempDir};
+
+    /// An allowed directory and a directory next to it that isn't allowed.
+    fn dirs() -> (TempDir, PathBuf, PathBuf, FileDirs) {
+        let root = tempfile::tempdir().unwrap();
+        let allowed = root.path().join("allowed");
+        let outside = root.path().join("outside");
+        fs::create_dir_all(allowed.join("sub")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("secret"), "secret").unwrap();
+
+        let file_dirs = FileDirs::from_arguments(vec![allowed.to_str().unwrap()]).unwrap();
+        let allowed = fs::canonicalize(allowed).unwrap();
+        let outside = fs::canonicalize(outside).unwrap();
+        (root, allowed, outside, file_dirs)
+    }
+
+    fn resolve(file_dirs: &FileDirs, path: &Path) -> Result<PathBuf, String> {
+        match file_dirs.resolve(path.to_str().unwrap()) {
+            Ok(path) => Ok(path),
+            Err(FileError::Rejected(error)) => Err(format!("rejected: {}", error)),
+            Err(FileError::Failed(error)) => Err(format!("failed: {}", error)),
+        }
+    }
+
+    #[test]
+    fn paths_in_the_directories_resolve() {
+        let (_root, allowed, _, file_dirs) = dirs();
+
+        assert_eq!(resolve(&file_dirs, &allowed.join("new-file")), Ok(allowed.join("new-file")));
+        assert_eq!(resolve(&file_dirs, &allowed.join("sub/new-file")), Ok(allowed.join("sub/new-file")));
+        assert_eq!(resolve(&file_dirs, &allowed.join("sub/../new-file")), Ok(allowed.join("new-file")));
+    }
+
+    #[test]
+    fn dot_dot_cant_leave_the_directories() {
+        let (_root, allowed, _, file_dirs) = dirs();
+
+        assert!(resolve(&file_dirs, &allowed.join("../outside/secret")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, &allowed.join("sub/../../outside/secret")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, &allowed.join("sub/..")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, &allowed.join("..")).unwrap_err().starts_with("rejected"));
+    }
+
+    #[test]
+    fn paths_have_to_be_absolute_files() {
+        let (_root, allowed, _, file_dirs) = dirs();
+
+        assert!(resolve(&file_dirs, Path::new("allowed/new-file")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, Path::new("new-file")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, Path::new("/")).unwrap_err().starts_with("rejected"));
+        // The directory itself isn't a file in it.
+        assert!(resolve(&file_dirs, &allowed).unwrap_err().starts_with("rejected"));
+    }
+
+    #[test]
+    fn symlinks_are_followed() {
+        let (_root, allowed, outside, file_dirs) = dirs();
+        symlink(outside.join("secret"), allowed.join("to-secret")).unwrap();
+        symlink(&outside, allowed.join("to-outside")).unwrap();
+        symlink(allowed.join("sub"), allowed.join("to-sub")).unwrap();
+
+        assert!(resolve(&file_dirs, &allowed.join("to-secret")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, &allowed.join("to-outside/secret")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, &allowed.join("to-outside/new-file")).unwrap_err().starts_with("rejected"));
+        assert_eq!(resolve(&file_dirs, &allowed.join("to-sub/new-file")), Ok(allowed.join("sub/new-file")));
+    }
+
+    #[test]
+    fn unknown_directories_are_rejected() {
+        let (_root, allowed, outside, file_dirs) = dirs();
+
+        assert!(resolve(&file_dirs, &outside.join("secret")).unwrap_err().starts_with("rejected"));
+        assert!(resolve(&file_dirs, Path::new("/etc/passwd")).unwrap_err().starts_with("rejected"));
+        // A directory that doesn't exist can't be resolved at all.
+        assert!(resolve(&file_dirs, &allowed.join("missing/new-file")).unwrap_err().starts_with("failed"));
+        // Nor can anything be allowed without any directories.
+        assert!(resolve(&FileDirs::default(), &allowed.join("new-file")).unwrap_err().starts_with("rejected"));
+    }
+
+    #[test]
+    fn directories_have_to_exist() {
+        let (_root, allowed, _, _) = dirs();
+
+        assert!(FileDirs::from_arguments(vec![allowed.join("missing").to_str().unwrap()]).is_err());
+        fs::write(allowed.join("file"), "").unwrap();
+        assert!(FileDirs::from_arguments(vec![allowed.join("file").to_str().unwrap()]).is_err());
+    }
+}
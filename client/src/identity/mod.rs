@@ -0,0 +1,262 @@
+//! Working out who we are when nobody told us (with `--id`). The first time the client runs, it
+//! makes up an id and keeps it in a state file, so that it stays the same from then on.
+//!
+//! The id is random. Machine ids (`/etc/machine-id`) aren't good enough on their own: images that
+//! get copied onto many SD cards often have one baked in, so every copy would have the same id.
+//! The state file does remember which machine id it was made for, though, and when that changes
+//! (because the copy generated its own machine id on its first boot), we make a new id instead of
+//! fighting the original device over the old one. The machine id is meant to stay private (see
+//! machine-id(5)), so the state file only keeps a keyed hash of it.
+//!
+//! That can't catch everything: a copy of a card that the client already ran on, which kept both
+//! the machine id and the state file, is the same device as far as we can tell. Such copies need
+//! `--id`, or their state file removed.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use rand::{self, RngCore};
+use ring::{digest, hmac};
+
+/// Where state is kept if systemd doesn't say otherwise.
+const DEFAULT_DIRECTORY: &str = "/var/lib/connectbot";
+
+/// Where the device's machine id is.
+const MACHINE_ID_FILE: &str = "/etc/machine-id";
+
+/// What machine ids get hashed with before they're kept, so that what we keep can't be traced back
+/// to the machine id, or matched up with what other programs keep.
+const MACHINE_ID_KEY: &[u8] = b"connectbot-client device identity";
+
+/// The state file. The first line is the id, and the second line is the hash of the machine id
+/// that it was made for, if any.
+struct State {
+    id: String,
+    machine: Option<String>,
+}
+
+/// Where to keep the id: the given file, or `client-id` in `$STATE_DIRECTORY` (set by systemd's
+/// `StateDirectory=`), or `/var/lib/connectbot/client-id`.
+pub fn state_file(file: Option<&str>) -> PathBuf {
+    match file {
+        Some(file) => PathBuf::from(file),
+        None => state_path("client-id"),
+    }
+}
+
+/// Where to keep a state file with the given name: in `$STATE_DIRECTORY`, or in
+/// `/var/lib/connectbot`.
+pub fn state_path(name: &str) -> PathBuf {
+    match ::std::env::var_os("STATE_DIRECTORY") {
+        // systemd can give us more than one directory, separated by colons.
+        Some(ref directories) if !directories.is_empty() => {
+            let directories = directories.to_string_lossy();
+            let directory = directories.split(':').next().unwrap_or("");
+            Path::new(directory).join(name)
+        },
+        _ => Path::new(DEFAULT_DIRECTORY).join(name),
+    }
+}
+
+/// Load our id from the state file, or make one up and save it there.
+pub fn load_or_create(path: &Path) -> Result<String, String> {
+    load_or_create_for(path, machine_id().as_ref().map(String::as_str))
+}
+
+/// Load our id from the state file, or make one up and save it there, on a device with the given
+/// machine id.
+fn load_or_create_for(path: &Path, machine_id: Option<&str>) -> Result<String, String> {
+    let machine = machine_id.map(hash_machine_id);
+
+    match read_state(path) {
+        Ok(Some(ref state)) if state.machine.is_none() || state.machine == machine => {
+            return Ok(state.id.clone());
+        },
+        Ok(Some(ref state)) if state.machine.as_ref().map(String::as_str) == machine_id => {
+            // Older clients kept the machine id itself. It's the same device, but the machine id
+            // shouldn't be lying around.
+            write_state(path, &State { id: state.id.clone(), machine })
+                .map_err(|err| format!("Failed to save the device id to {}: {}", path.display(), err))?;
+            return Ok(state.id.clone());
+        },
+        Ok(Some(state)) => {
+            println!("! The machine id changed since {} was made for {}, so this is a different device", path.display(), state.id);
+        },
+        Ok(None) => {},
+        Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+    }
+
+    let id = random_id();
+
+    write_state(path, &State { id: id.clone(), machine })
+        .map_err(|err| format!("Failed to save the device id to {}: {}", path.display(), err))?;
+    println!("! This device is {} (saved in {})", id, path.display());
+
+    Ok(id)
+}
+
+fn read_state(path: &Path) -> io::Result<Option<State>> {
+    let mut data = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut data)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut lines = data.lines().map(|line| line.trim());
+    let id = match lines.next() {
+        Some(id) if !id.is_empty() => id.to_string(),
+        _ => return Ok(None),
+    };
+    let machine = lines.next()
+        .filter(|machine| !machine.is_empty())
+        .map(|machine| machine.to_string());
+
+    Ok(Some(State { id, machine }))
+}
+
+/// Write the state file next to where it goes, and then move it into place, so that we never leave
+/// half of an id behind.
+fn write_state(path: &Path, state: &State) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    {
+        let mut file = File::create(&temporary)?;
+        writeln!(file, "{}", state.id)?;
+        if let Some(ref machine) = state.machine {
+            writeln!(file, "{}", machine)?;
+        }
+        file.sync_all()?;
+    }
+
+    fs::rename(&temporary, path)
+}
+
+fn machine_id() -> Option<String> {
+    fs::read_to_string(MACHINE_ID_FILE).ok()
+        .map(|machine| machine.trim().to_string())
+        .filter(|machine| !machine.is_empty())
+}
+
+/// The machine id, hashed with a key of our own (like systemd's app-specific machine ids).
+fn hash_machine_id(machine_id: &str) -> String {
+    let key = hmac::SigningKey::new(&digest::SHA256, MACHINE_ID_KEY);
+    hmac::sign(&key, machine_id.as_bytes()).as_ref().iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// A random UUID (version 4).
+fn random_id() -> String {
+    let mut bytes = [0; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format_uuid(bytes, 4)
+}
+
+fn format_uuid(mut bytes: [u8; 16], version: u8) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | (version << 4);
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    const MACHINE_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn the_first_run_saves_a_new_id() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state").join("client-id");
+
+        let id = load_or_create_for(&path, Some(MACHINE_ID)).unwrap();
+
+        let state = read_state(&path).unwrap().unwrap();
+        assert_eq!(state.id, id);
+        assert_eq!(state.machine, Some(hash_machine_id(MACHINE_ID)));
+    }
+
+    #[test]
+    fn later_runs_keep_the_same_id() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("client-id");
+
+        let id = load_or_create_for(&path, Some(MACHINE_ID)).unwrap();
+
+        assert_eq!(load_or_create_for(&path, Some(MACHINE_ID)).unwrap(), id);
+    }
+
+    #[test]
+    fn a_new_machine_id_means_a_new_device() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("client-id");
+
+        let id = load_or_create_for(&path, Some(MACHINE_ID)).unwrap();
+        let copy = load_or_create_for(&path, Some("fedcba9876543210fedcba9876543210")).unwrap();
+
+        assert_ne!(copy, id);
+        assert_eq!(read_state(&path).unwrap().unwrap().id, copy);
+    }
+
+    #[test]
+    fn a_state_file_without_a_machine_id_is_kept() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("client-id");
+        fs::write(&path, "my-device\n").unwrap();
+
+        assert_eq!(load_or_create_for(&path, Some(MACHINE_ID)).unwrap(), "my-device");
+        assert_eq!(load_or_create_for(&path, None).unwrap(), "my-device");
+    }
+
+    #[test]
+    fn a_machine_id_kept_by_an_older_client_is_replaced_by_its_hash() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("client-id");
+        fs::write(&path, format!("my-device\n{}\n", MACHINE_ID)).unwrap();
+
+        assert_eq!(load_or_create_for(&path, Some(MACHINE_ID)).unwrap(), "my-device");
+
+        let data = fs::read_to_string(&path).unwrap();
+        assert!(!data.contains(MACHINE_ID));
+        assert_eq!(read_state(&path).unwrap().unwrap().machine, Some(hash_machine_id(MACHINE_ID)));
+    }
+
+    #[test]
+    fn machine_ids_are_hashed_with_our_own_key() {
+        let hash = hash_machine_id(MACHINE_ID);
+
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_machine_id(MACHINE_ID));
+        assert_ne!(hash, hash_machine_id("fedcba9876543210fedcba9876543210"));
+        let plain: String = digest::digest(&digest::SHA256, MACHINE_ID.as_bytes()).as_ref().iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_ne!(hash, plain);
+    }
+
+    #[test]
+    fn format_uuid_sets_the_version_and_variant() {
+        assert_eq!(format_uuid([0xff; 16], 4), "ffffffff-ffff-4fff-bfff-ffffffffffff");
+        assert_eq!(format_uuid([0; 16], 4), "00000000-0000-4000-8000-000000000000");
+
+        let id = random_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!(["8", "9", "a", "b"].contains(&&id[19..20]));
+    }
+}
@@ -2,8 +2,16 @@ extern crate bytes;
 extern crate clap;
 extern crate chrono;
 extern crate futures;
+extern crate libc;
 extern crate protobuf;
 extern crate rand;
+extern crate ring;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate ssh2;
+#[cfg(test)]
+extern crate tempfile;
 // extern crate signal_hook;
 extern crate tokio;
 extern crate tokio_codec;
@@ -12,15 +20,33 @@ extern crate tokio_io;
 extern crate tokio_threadpool;
 extern crate tokio_timer;
 extern crate tokio_rustls;
+extern crate toml;
+extern crate untrusted;
 extern crate connectbot_shared;
 extern crate webpki_roots;
 
 mod client;
+mod config;
+mod control;
+mod discovery;
+mod enrollment;
+mod exec;
+mod facts;
+mod files;
+mod identity;
+mod policy;
+mod probes;
+mod proxy;
+mod runtime;
 mod server_connection;
 mod ssh_connection;
 mod ssh_manager;
+mod systemd;
+mod tunnel;
+mod udp_relay;
+mod update;
 
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
 
 use std::net::IpAddr;
 use connectbot_shared::protos::device;
@@ -28,19 +54,30 @@ use connectbot_shared::protos::device;
 use rand::RngCore;
 use std::io::BufReader;
 use tokio_rustls::{
+    webpki,
     rustls::{
         Certificate, ClientConfig, PrivateKey,
-        internal::pemfile::{ certs, rsa_private_keys },
+        sign,
     },
 };
-use std::fs::{self, File};
+use connectbot_shared::keys;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-fn load_certs(path: &str) -> Vec<Certificate> {
-    certs(&mut BufReader::new(File::open(path).unwrap())).unwrap()
+fn load_certs(path: &str) -> Result<Vec<Certificate>, std::io::Error> {
+    Ok(keys::load_certificates(Path::new(path))?.into_iter().map(Certificate).collect())
 }
 
-fn load_keys(path: &str) -> Vec<PrivateKey> {
-    rsa_private_keys(&mut BufReader::new(File::open(path).unwrap())).unwrap()
+fn load_key(path: &str) -> Result<PrivateKey, std::io::Error> {
+    let key = keys::load_private_key(Path::new(path))?;
+    let kind = key.kind;
+    let key = PrivateKey(key.der);
+
+    // rustls panics when it's handed a key that it can't sign with, so find out first.
+    if sign::any_supported_type(&key).is_err() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("The {} key in {} can't be used for TLS. TLS keys can be RSA or ECDSA.", kind, path)));
+    }
+    Ok(key)
 }
 
 fn main() -> Result<(), std::io::Error> {
@@ -48,18 +85,62 @@ fn main() -> Result<(), std::io::Error> {
         .version("1.0")
         .author("Bryan Burgers <bryan@burgers.io>")
         .about("The client")
+        .subcommand(SubCommand::with_name("config")
+                    .about("Generate an example config file"))
+        .subcommand(SubCommand::with_name("status")
+                    .about("Show what the running client is doing"))
+        .subcommand(SubCommand::with_name("disable")
+                    .about("Tell the running client to disconnect an SSH connection")
+                    .arg(Arg::with_name("connection")
+                         .value_name("CONNECTION")
+                         .help("The ID of the SSH connection")
+                         .required(true)))
+        .subcommand(SubCommand::with_name("reconnect")
+                    .about("Tell the running client to connect to the server again right away"))
+        .subcommand(SubCommand::with_name("proxy-connect")
+                    .about("Connect to a host through the proxy in $CONNECTBOT_PROXY, and relay stdin and stdout to it. This is ssh's ProxyCommand when there's a proxy.")
+                    .arg(Arg::with_name("host")
+                         .value_name("HOST")
+                         .required(true))
+                    .arg(Arg::with_name("port")
+                         .value_name("PORT")
+                         .required(true)))
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("The location of the config file. The other options override what's in it.")
+             .takes_value(true))
+        .arg(Arg::with_name("runtime-directory")
+             .long("runtime-directory")
+             .value_name("DIR")
+             .help("A private directory for SSH keys and control sockets. Defaults to $RUNTIME_DIRECTORY, or /tmp/connectbot-client-UID.")
+             .takes_value(true))
+        .arg(Arg::with_name("control-socket")
+             .long("control-socket")
+             .value_name("FILE")
+             .help("The Unix socket that the status, disable and reconnect commands use to talk to the running client. Defaults to control.sock in the runtime directory.")
+             .takes_value(true))
         .arg(Arg::with_name("id")
              .long("id")
              .value_name("IDENTIFIER")
-             .help("Set the unique identifier of the device")
-             .takes_value(true)
-             .required(true))
+             .help("Set the unique identifier of the device. Without this, the client makes one up the first time it runs and keeps it in --id-file.")
+             .takes_value(true))
+        .arg(Arg::with_name("id-file")
+             .long("id-file")
+             .value_name("FILE")
+             .help("Where to keep the identifier that the client makes up. Defaults to client-id in $STATE_DIRECTORY, or /var/lib/connectbot/client-id.")
+             .takes_value(true))
+        .arg(Arg::with_name("name")
+             .long("name")
+             .value_name("NAME")
+             .help("The name that the server shows for the device until somebody gives it a different one. Defaults to the hostname.")
+             .takes_value(true))
         .arg(Arg::with_name("host")
              .long("host")
              .value_name("HOST")
              .help("The hostname of the server. This is used both to determine which server to connect to AND how to do TLS validation. To use only for TLS validation, use --resolve to override the IP address of the server.")
-             .takes_value(true)
-             .required(true))
+             .takes_value(true))
         .arg(Arg::with_name("resolve")
              .long("resolve")
              .value_name("IP")
@@ -70,12 +151,26 @@ fn main() -> Result<(), std::io::Error> {
                      .map(|_| ())
                      .map_err(|_| format!("'{}' could not be parsed as a valid IP address", s))
              }))
+        .arg(Arg::with_name("url")
+             .long("url")
+             .value_name("URL")
+             .help("Connect to the server over WebSocket at a wss://HOST[:PORT]/PATH URL, instead of at --host and --port. This gets through networks that only let HTTPS out.")
+             .takes_value(true))
+        .arg(Arg::with_name("tls-name")
+             .long("tls-name")
+             .value_name("NAME")
+             .help("The name to check the server's TLS certificate against, if it isn't the hostname.")
+             .takes_value(true))
+        .arg(Arg::with_name("proxy")
+             .long("proxy")
+             .value_name("URL")
+             .help("Connect to the server and SSH servers through a proxy, http://HOST:PORT (HTTP CONNECT) or socks5://HOST:PORT. A username and password go in the [proxy] section of the config file.")
+             .takes_value(true))
         .arg(Arg::with_name("port")
              .long("port")
              .value_name("PORT")
              .help("The port of the server.")
              .takes_value(true)
-             .default_value("4004")
              .validator(|s| {
                  s.parse::<u16>()
                      .map(|_| ())
@@ -97,31 +192,252 @@ fn main() -> Result<(), std::io::Error> {
              .value_name("FILE")
              .help("The location of the TLS key file (rsa), if doing client authentication")
              .takes_value(true))
+        .arg(Arg::with_name("enroll-token")
+             .long("enroll-token")
+             .value_name("TOKEN")
+             .help("A one-time bootstrap token (from connectbot-ctrl create-token) to get a client certificate from the server with, instead of being given one with --cert and --key")
+             .takes_value(true)
+             .conflicts_with("cert"))
+        .arg(Arg::with_name("action")
+             .long("action")
+             .value_name("NAME=COMMAND")
+             .help("An action that the server is allowed to run on this device, by name. The command is run with sh -c. Can be given more than once.")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .validator(|s| {
+                 exec::parse_argument(&s)
+                     .map(|_| ())
+             }))
+        .arg(Arg::with_name("action-timeout")
+             .long("action-timeout")
+             .value_name("SECONDS")
+             .help("How long an action may run for before it gets killed")
+             .takes_value(true)
+             .validator(|s| {
+                 match s.parse::<u64>() {
+                     Ok(0) | Err(_) => Err(format!("'{}' could not be parsed as a positive number of seconds", s)),
+                     Ok(_) => Ok(()),
+                 }
+             }))
+        .arg(Arg::with_name("discover-subnet")
+             .long("discover-subnet")
+             .value_name("CIDR")
+             .help("An IPv4 subnet (at most a /24) that the server may ask this device to scan for services, e.g. 192.168.1.0/24. Without this, only the device itself is scanned.")
+             .takes_value(true)
+             .validator(|s| {
+                 discovery::Subnet::parse(&s)
+                     .map(|_| ())
+             }))
+        .arg(Arg::with_name("file-dir")
+             .long("file-dir")
+             .value_name("DIR")
+             .help("A directory that the server may copy files to and from. Can be given more than once.")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("ssh-backend")
+             .long("ssh-backend")
+             .value_name("BACKEND")
+             .help("What makes SSH connections: openssh runs ssh, and native holds the SSH session in the client")
+             .takes_value(true)
+             .possible_values(&["openssh", "native"]))
+        .arg(Arg::with_name("known-hosts")
+             .long("known-hosts")
+             .value_name("FILE")
+             .help("The known_hosts file that the native SSH backend checks the server's host key against. Defaults to ~/.ssh/known_hosts.")
+             .takes_value(true))
+        .arg(Arg::with_name("probes")
+             .long("probes")
+             .value_name("FILE")
+             .help("A file of probes (NAME = COMMAND, or NAME = file:PATH, one per line) to run periodically and report to the server as metrics")
+             .takes_value(true))
+        .arg(Arg::with_name("probe-interval")
+             .long("probe-interval")
+             .value_name("SECONDS")
+             .help("How often to run the probes")
+             .takes_value(true)
+             .validator(|s| {
+                 match s.parse::<u64>() {
+                     Ok(0) | Err(_) => Err(format!("'{}' could not be parsed as a positive number of seconds", s)),
+                     Ok(_) => Ok(()),
+                 }
+             }))
         .get_matches();
 
-    let id = matches.value_of("id").unwrap().to_string();
+    if let Some(_matches) = matches.subcommand_matches("config") {
+        let config = config::ApplicationConfig::example();
+        print!("{}", toml::to_string(&config).unwrap());
+        return Ok(());
+    }
 
-    let address = matches.value_of("host").unwrap().to_string();
-    let port = matches.value_of("port").unwrap().parse().unwrap();
-    let connection = if let Some(resolve) = matches.value_of("resolve") {
-        let resolve = resolve.parse()
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to parse --resolve as an address"))?;
+    if let Some(matches) = matches.subcommand_matches("proxy-connect") {
+        let proxy = proxy::Proxy::from_env()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        let host = matches.value_of("host").unwrap();
+        let port = matches.value_of("port").unwrap().parse()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "The port has to be a number"))?;
+        let stream = proxy.connect(host, port)?;
+        return proxy::relay(stream);
+    }
 
-        server_connection::ConnectionDetails::AddressWithResolve {
-            address: address,
-            resolve: resolve,
-            port: port,
-        }
+    let mut config = match matches.value_of_os("config") {
+        Some(path) => config::ApplicationConfig::from_file(Path::new(path))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?,
+        None => config::ApplicationConfig::default(),
+    };
+
+    // Anything given on the command line wins over the config file.
+    if let Some(id) = matches.value_of("id") {
+        config.id = Some(id.to_string());
     }
-    else {
-        server_connection::ConnectionDetails::Address {
-            address: address,
-            port: port,
+    if let Some(id_file) = matches.value_of("id-file") {
+        config.identity.file = Some(id_file.to_string());
+    }
+    if let Some(name) = matches.value_of("name") {
+        config.identity.name = Some(name.to_string());
+    }
+    if let Some(host) = matches.value_of("host") {
+        config.server.host = Some(host.to_string());
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.server.port = port.parse().unwrap();
+    }
+    if let Some(resolve) = matches.value_of("resolve") {
+        config.server.resolve = Some(resolve.to_string());
+    }
+    if let Some(url) = matches.value_of("url") {
+        config.server.url = Some(url.to_string());
+    }
+    if let Some(tls_name) = matches.value_of("tls-name") {
+        config.server.tls_name = Some(tls_name.to_string());
+    }
+    if let Some(proxy) = matches.value_of("proxy") {
+        config.proxy.url = Some(proxy.to_string());
+    }
+    if let Some(cafile) = matches.value_of("cafile") {
+        config.tls.cafile = Some(cafile.to_string());
+    }
+    if let Some(cert) = matches.value_of("cert") {
+        config.tls.cert = Some(cert.to_string());
+    }
+    if let Some(key) = matches.value_of("key") {
+        config.tls.key = Some(key.to_string());
+    }
+    if let Some(token) = matches.value_of("enroll-token") {
+        config.enrollment.token = Some(token.to_string());
+    }
+    if let Some(values) = matches.values_of("action") {
+        for value in values {
+            let (name, command) = exec::parse_argument(value).unwrap();
+            config.actions.insert(name, command);
         }
+    }
+    if let Some(action_timeout) = matches.value_of("action-timeout") {
+        config.action_timeout = action_timeout.parse().unwrap();
+    }
+    if let Some(values) = matches.values_of("file-dir") {
+        config.file_dirs = values.map(|value| value.to_string()).collect();
+    }
+    if let Some(probes) = matches.value_of("probes") {
+        config.probes.file = Some(probes.to_string());
+    }
+    if let Some(probe_interval) = matches.value_of("probe-interval") {
+        config.probes.interval = probe_interval.parse().unwrap();
+    }
+    if let Some(subnet) = matches.value_of("discover-subnet") {
+        config.discovery.subnet = Some(subnet.to_string());
+    }
+    if let Some(backend) = matches.value_of("ssh-backend") {
+        config.ssh.backend = match backend {
+            "native" => config::SshBackend::Native,
+            _ => config::SshBackend::OpenSsh,
+        };
+    }
+    if let Some(known_hosts) = matches.value_of("known-hosts") {
+        config.ssh.known_hosts = Some(known_hosts.to_string());
+    }
+    if let Some(directory) = matches.value_of("runtime-directory") {
+        config.runtime.directory = Some(directory.to_string());
+    }
+    if let Some(control_socket) = matches.value_of("control-socket") {
+        config.runtime.control_socket = Some(control_socket.to_string());
+    }
+
+    let runtime_directory = config.runtime.directory.as_ref().map(|directory| directory.as_str());
+    let control_socket = config.runtime.control_socket.as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| runtime::RuntimeDirectory::locate(runtime_directory).control_socket());
+
+    // These talk to the client that's already running, instead of being it.
+    let command = match matches.subcommand() {
+        ("status", _) => Some("status".to_string()),
+        ("disable", Some(matches)) => Some(format!("disable {}", matches.value_of("connection").unwrap())),
+        ("reconnect", _) => Some("reconnect".to_string()),
+        _ => None,
+    };
+    if let Some(command) = command {
+        let response = control::send(&control_socket, &command)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        print!("{}", response);
+        return Ok(());
+    }
+
+    let id = match config.id {
+        Some(ref id) => id.clone(),
+        None => identity::load_or_create(&identity::state_file(config.identity.file.as_ref().map(|file| file.as_str())))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?,
+    };
+    let name = config.identity.name.clone().unwrap_or_else(facts::hostname);
+
+    let actions = exec::Actions::new(config.actions.clone(), std::time::Duration::from_secs(config.action_timeout));
+
+    let file_dirs = files::FileDirs::from_arguments(config.file_dirs.iter().map(|dir| dir.as_str()))
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+
+    let probes = match config.probes.file {
+        Some(ref path) => probes::Probes::from_file(path)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?,
+        None => probes::Probes::default(),
+    };
+    if config.probes.interval == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, "The probe interval has to be a positive number of seconds"));
+    }
+    let probe_interval = std::time::Duration::from_secs(config.probes.interval);
+
+    let local_policy = policy::LocalPolicy::new(config.local_policy.forwards.as_ref().map(Vec::as_slice), config.local_policy.gateway_ports)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+
+    let discover_subnet = match config.discovery.subnet {
+        Some(ref subnet) => Some(discovery::Subnet::parse(subnet)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?),
+        None => None,
+    };
+
+    let primary = connection_details(&config.server.host, config.server.port, &config.server.url, &config.server.resolve, &config.server.tls_name)?
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "The client needs to know the server's host (--host or --url, or host or url in the [server] section of the config file)"))?;
+    let mut endpoints = vec![primary];
+    for fallback in &config.server.fallbacks {
+        let fallback = connection_details(&fallback.host, fallback.port, &fallback.url, &fallback.resolve, &fallback.tls_name)?
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Every fallback server needs a host or a url"))?;
+        endpoints.push(fallback);
+    }
+
+    let proxy = match config.proxy.url {
+        Some(ref url) => {
+            let credentials = match (&config.proxy.username, &config.proxy.password) {
+                (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                (None, None) => None,
+                _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "The proxy needs both a username and a password, or neither")),
+            };
+            Some(proxy::Proxy::parse(url, credentials)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?)
+        },
+        None => None,
     };
 
     let mut tls_config = ClientConfig::new();
-    if let Some(cafile) = matches.value_of("cafile") {
+    if let Some(ref cafile) = config.tls.cafile {
         let mut pem = BufReader::new(fs::File::open(cafile)?);
         tls_config.root_store.add_pem_file(&mut pem).unwrap();
     }
@@ -129,17 +445,100 @@ fn main() -> Result<(), std::io::Error> {
         tls_config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
     }
 
-    if let Some(cert) = matches.value_of("cert") {
-        tls_config.set_single_client_cert(load_certs(cert), load_keys(matches.value_of("key").unwrap()).remove(0));
+    // A certificate that we were given wins. Otherwise, we use the one that we got from the
+    // server, if we got one or have a token to get one with.
+    let enrollment = match config.tls.cert {
+        Some(ref cert) => {
+            if config.enrollment.token.is_some() {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "A device with a TLS certificate doesn't need a bootstrap token"));
+            }
+            let key = config.tls.key.as_ref()
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "A TLS certificate needs a key"))?;
+            tls_config.set_single_client_cert(load_certs(cert)?, load_key(key)?);
+            None
+        },
+        None => {
+            let certificate = config.enrollment.certificate.as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| identity::state_path("client.crt"));
+            let key = config.enrollment.key.as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| identity::state_path("client.key"));
+            let enrollment = enrollment::Enrollment::new(config.enrollment.token.clone(), certificate, key)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            tls_config.client_auth_cert_resolver = enrollment.resolver();
+            Some(enrollment)
+        },
+    };
+
+    let ssh_backend = match config.ssh.backend {
+        config::SshBackend::OpenSsh => ssh_connection::SshBackend::OpenSsh,
+        config::SshBackend::Native => {
+            let known_hosts = match config.ssh.known_hosts {
+                Some(ref known_hosts) => PathBuf::from(known_hosts),
+                None => std::env::var_os("HOME")
+                    .map(|home| Path::new(&home).join(".ssh/known_hosts"))
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "There is no home directory to find ~/.ssh/known_hosts in, so the native SSH backend needs --known-hosts"))?,
+            };
+            ssh_connection::SshBackend::Native { known_hosts }
+        },
+    };
+
+    // Sort out whatever a previous run left behind. `ssh` masters that are still running get
+    // adopted, and the server decides whether to keep them.
+    let runtime = runtime::RuntimeDirectory::prepare(runtime_directory)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+    let mut adopted = Vec::new();
+    for id in runtime.clean_up().map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))? {
+        let control = runtime.ssh_control(&id);
+        if ssh_connection::is_alive(&control) {
+            println!("! Adopting SSH connection {}", id);
+            adopted.push(id);
+        }
+        else {
+            println!("! Removing stale SSH control socket {}", control.display());
+            let _ = fs::remove_file(&control);
+        }
     }
 
+    let settings = client::Settings {
+        id,
+        name,
+        actions,
+        file_dirs,
+        probes,
+        probe_interval,
+        discover_subnet,
+        log_messages: config.logging.messages,
+        ssh_backend,
+        ssh_backoff: config.ssh.backoff.policy(),
+        runtime,
+        proxy,
+        adopted,
+        control_socket,
+        enrollment,
+        notifier: systemd::Notifier::from_env(),
+        local_policy,
+    };
+    let backoff = config.backoff.policy();
+
     // Sometimes, Tokio fails to initialize because a thread pool panics. The thread pool panics
     // because the random number generator is not initialized yet. However, Tokio swallows panics,
     // so the application keeps running, doing nothing. In order to work around this, manually try
     // the random number generator, so we can panic BEFORE we get to tokio::run.
     check_rng_initialized()?;
 
-    tokio::run(client::connect(id, connection, tls_config));
+    // If we were just updated, make sure that the new version works out. Otherwise, go back to
+    // the old one.
+    let trial = update::startup()?;
+
+    tokio::run(futures::lazy(move || {
+        if trial {
+            tokio::spawn(update::watchdog());
+        }
+
+        client::connect(settings, endpoints, tls_config, backoff)
+    }));
 
     Ok(())
 
@@ -184,6 +583,65 @@ fn main() -> Result<(), std::io::Error> {
     */
 }
 
+/// Work out how to connect to a server from what the config says about it: a host and port, or a
+/// `wss://` URL. If there's neither, there's no server.
+fn connection_details(host: &Option<String>, port: u16, url: &Option<String>, resolve: &Option<String>, tls_name: &Option<String>) -> Result<Option<server_connection::ConnectionDetails>, std::io::Error> {
+    let (address, port, websocket) = match (url, host) {
+        (Some(url), _) => parse_wss_url(url)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?,
+        (None, Some(host)) => (host.clone(), port, None),
+        (None, None) => return Ok(None),
+    };
+
+    let resolve = match resolve {
+        Some(resolve) => Some(resolve.parse()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to parse {:?} as an address to resolve the server to", resolve)))?),
+        None => None,
+    };
+
+    let details = server_connection::ConnectionDetails {
+        address,
+        port,
+        resolve,
+        tls_name: tls_name.clone(),
+        websocket,
+    };
+    if webpki::DNSNameRef::try_from_ascii_str(details.tls_name()).is_err() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?} isn't a name that a TLS certificate can be checked against", details.tls_name())));
+    }
+
+    Ok(Some(details))
+}
+
+/// Split a `wss://HOST[:PORT][/PATH]` URL into the host, the port (443 if there isn't one) and the
+/// path.
+fn parse_wss_url(url: &str) -> Result<(String, u16, Option<String>), String> {
+    if !url.starts_with("wss://") {
+        return Err(format!("{:?} isn't a wss:// URL", url));
+    }
+    let rest = &url["wss://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rfind(':') {
+        // A colon inside brackets is part of an IPv6 address, not the start of the port.
+        Some(colon) if !authority[colon..].contains(']') => {
+            let port = authority[colon + 1..].parse()
+                .map_err(|_| format!("{:?} isn't a valid port in {:?}", &authority[colon + 1..], url))?;
+            (&authority[..colon], port)
+        },
+        _ => (authority, 443),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(format!("{:?} doesn't have a host", url));
+    }
+
+    Ok((host.to_string(), port, Some(path.to_string())))
+}
+
 fn check_rng_initialized() -> Result<(), std::io::Error> {
     // Sometimes, Tokio fails to initialize because a thread pool panics. The thread pool panics
     // because the random number generator is not initialized yet. However, Tokio swallows panics,
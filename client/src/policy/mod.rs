@@ -0,0 +1,295 @@
+//! What the server may ask this device to forward, decided on the device. The server is trusted to
+//! say which forwards it wants, but if it (or its control port) gets taken over, whoever has it
+//! could otherwise reach anything on every device's network. With a policy, the device only sets up
+//! forwards to the targets that it lists, and refuses the rest.
+//!
+//! Targets are `host:port`, where either may be `*`. Hosts are compared as written (ignoring
+//! case), so `localhost` and `127.0.0.1` are different targets. A forward without a host is to
+//! `localhost`.
+
+use std::fmt;
+use device;
+
+/// What the server may ask for.
+#[derive(Debug, Clone)]
+pub struct LocalPolicy {
+    /// The targets that forwards may reach. If this isn't set, they may reach anything.
+    forwards: Option<Vec<Target>>,
+    /// Whether forwards may be reachable from other hosts than the server (or the device, for
+    /// local forwards)
+    gateway_ports: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Target {
+    /// The host, or None for any host
+    host: Option<String>,
+    /// The port, or None for any port
+    port: Option<u16>,
+}
+
+impl LocalPolicy {
+    /// Make a policy from the config file's targets.
+    pub fn new(forwards: Option<&[String]>, gateway_ports: bool) -> Result<LocalPolicy, String> {
+        let forwards = match forwards {
+            Some(forwards) => Some(forwards.iter()
+                .map(|target| Target::parse(target))
+                .collect::<Result<Vec<_>, _>>()?),
+            None => None,
+        };
+
+        Ok(LocalPolicy { forwards, gateway_ports })
+    }
+
+    /// Why we won't set up a forward that the server asked for, if we won't.
+    pub fn check_enable(&self, enable: &device::SshConnection_Enable) -> Result<(), String> {
+        self.check_gateway_port(enable.get_gateway_port())?;
+
+        if enable.get_dynamic() {
+            // The device reaches whatever it's asked to, so everything that it may be asked to
+            // reach has to be allowed.
+            if self.forwards.is_none() {
+                return Ok(());
+            }
+            if enable.get_permitted_destinations().is_empty() {
+                return Err("dynamic forwards that may reach anything aren't allowed by the local policy".to_string());
+            }
+            for destination in enable.get_permitted_destinations() {
+                let destination = Target::parse(destination)?;
+                if !self.allows(&destination) {
+                    return Err(format!("the destination {} isn't allowed by the local policy", destination));
+                }
+            }
+            return Ok(());
+        }
+
+        let port = enable.get_forward_port();
+        if port == 0 || port > u16::max_value() as u32 {
+            return Err(format!("{} isn't a port that can be forwarded", port));
+        }
+        let target = Target {
+            host: Some(match enable.get_forward_host() {
+                "" => "localhost".to_string(),
+                host => host.trim_start_matches('[').trim_end_matches(']').to_lowercase(),
+            }),
+            port: Some(port as u16),
+        };
+        if !self.allows(&target) {
+            return Err(format!("the target {} isn't allowed by the local policy", target));
+        }
+        Ok(())
+    }
+
+    /// Why we won't set up a local forward that the server asked for, if we won't. Local forwards
+    /// reach something on the server's side, so only where the device listens matters.
+    pub fn check_local_enable(&self, enable: &device::SshConnection_LocalEnable) -> Result<(), String> {
+        self.check_gateway_port(enable.get_gateway_port())
+    }
+
+    fn check_gateway_port(&self, gateway_port: bool) -> Result<(), String> {
+        if gateway_port && !self.gateway_ports {
+            return Err("gateway ports aren't allowed by the local policy".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether a target (which may itself have wildcards) is covered by the policy.
+    fn allows(&self, target: &Target) -> bool {
+        match self.forwards {
+            None => true,
+            Some(ref forwards) => forwards.iter().any(|allowed| allowed.covers(target)),
+        }
+    }
+}
+
+impl Target {
+    /// Parse `host:port`, where either may be `*`.
+    fn parse(target: &str) -> Result<Target, String> {
+        let invalid = || format!("{:?} isn't a valid forward target, expected host:port", target);
+
+        let index = target.rfind(':').ok_or_else(invalid)?;
+        let (host, port) = (&target[..index], &target[index + 1..]);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.chars().any(char::is_whitespace) {
+            return Err(invalid());
+        }
+
+        Ok(Target {
+            host: match host {
+                "*" => None,
+                host => Some(host.to_lowercase()),
+            },
+            port: match port {
+                "*" => None,
+                port => match port.parse::<u16>() {
+                    Ok(port) if port != 0 => Some(port),
+                    _ => return Err(invalid()),
+                },
+            },
+        })
+    }
+
+    /// Whether everything that `other` matches, this matches too.
+    fn covers(&self, other: &Target) -> bool {
+        let host = self.host.is_none() || self.host == other.host;
+        let port = self.port.is_none() || self.port == other.port;
+        host && port
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host {
+            Some(ref host) if host.contains(':') => write!(f, "[{}]", host)?,
+            Some(ref host) => write!(f, "{}", host)?,
+            None => write!(f, "*")?,
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => write!(f, ":*"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: Option<&str>, port: Option<u16>) -> Target {
+        Target { host: host.map(str::to_string), port }
+    }
+
+    fn policy(forwards: &[&str]) -> LocalPolicy {
+        let forwards: Vec<String> = forwards.iter().map(|forward| forward.to_string()).collect();
+        LocalPolicy::new(Some(&forwards), false).unwrap()
+    }
+
+    fn enable(host: &str, port: u32) -> device::SshConnection_Enable {
+        let mut enable = device::SshConnection_Enable::new();
+        enable.set_forward_host(host.into());
+        enable.set_forward_port(port);
+        enable
+    }
+
+    fn dynamic(destinations: &[&str]) -> device::SshConnection_Enable {
+        let mut enable = device::SshConnection_Enable::new();
+        enable.set_dynamic(true);
+        enable.set_permitted_destinations(destinations.iter().map(|destination| (*destination).into()).collect::<Vec<_>>().into());
+        enable
+    }
+
+    #[test]
+    fn targets_parse() {
+        assert_eq!(Target::parse("localhost:22"), Ok(target(Some("localhost"), Some(22))));
+        assert_eq!(Target::parse("LocalHost:22"), Ok(target(Some("localhost"), Some(22))));
+        assert_eq!(Target::parse("*:80"), Ok(target(None, Some(80))));
+        assert_eq!(Target::parse("10.0.0.5:*"), Ok(target(Some("10.0.0.5"), None)));
+        assert_eq!(Target::parse("*:*"), Ok(target(None, None)));
+        assert_eq!(Target::parse("[::1]:22"), Ok(target(Some("::1"), Some(22))));
+        assert_eq!(Target::parse("[fe80::1]:*"), Ok(target(Some("fe80::1"), None)));
+    }
+
+    #[test]
+    fn invalid_targets_are_refused() {
+        for invalid in &["localhost", "localhost:", ":22", "[]:22", "local host:22", "localhost:0", "localhost:65536", "localhost:ssh", "localhost:-1"] {
+            assert!(Target::parse(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn targets_display_like_they_parse() {
+        for display in &["localhost:22", "*:80", "10.0.0.5:*", "[::1]:22"] {
+            assert_eq!(Target::parse(display).unwrap().to_string(), *display);
+        }
+    }
+
+    #[test]
+    fn wildcards_cover_more_specific_targets() {
+        let any_port = target(Some("localhost"), None);
+        let any_host = target(None, Some(22));
+        let exact = target(Some("localhost"), Some(22));
+
+        assert!(target(None, None).covers(&exact));
+        assert!(any_port.covers(&exact));
+        assert!(any_host.covers(&exact));
+        assert!(exact.covers(&exact));
+
+        assert!(!exact.covers(&any_port));
+        assert!(!exact.covers(&any_host));
+        assert!(!any_port.covers(&any_host));
+        assert!(!any_port.covers(&target(Some("127.0.0.1"), Some(22))));
+        assert!(!any_host.covers(&target(Some("localhost"), Some(23))));
+    }
+
+    #[test]
+    fn forwards_must_be_to_listed_targets() {
+        let policy = policy(&["localhost:22", "10.0.0.5:*"]);
+
+        assert_eq!(policy.check_enable(&enable("localhost", 22)), Ok(()));
+        assert_eq!(policy.check_enable(&enable("", 22)), Ok(()));
+        assert_eq!(policy.check_enable(&enable("LOCALHOST", 22)), Ok(()));
+        assert_eq!(policy.check_enable(&enable("10.0.0.5", 8080)), Ok(()));
+        assert!(policy.check_enable(&enable("localhost", 80)).is_err());
+        assert!(policy.check_enable(&enable("127.0.0.1", 22)).is_err());
+    }
+
+    #[test]
+    fn bracketed_ipv6_hosts_match() {
+        let policy = policy(&["[::1]:22"]);
+
+        assert_eq!(policy.check_enable(&enable("[::1]", 22)), Ok(()));
+        assert_eq!(policy.check_enable(&enable("::1", 22)), Ok(()));
+        assert!(policy.check_enable(&enable("[::2]", 22)).is_err());
+    }
+
+    #[test]
+    fn forwards_need_a_real_port() {
+        let anything = LocalPolicy::new(None, false).unwrap();
+
+        assert!(anything.check_enable(&enable("localhost", 0)).is_err());
+        assert!(anything.check_enable(&enable("localhost", 65536)).is_err());
+        assert!(policy(&["*:*"]).check_enable(&enable("localhost", 70000)).is_err());
+        assert_eq!(anything.check_enable(&enable("localhost", 65535)), Ok(()));
+    }
+
+    #[test]
+    fn dynamic_forwards_may_only_reach_a_subset_of_the_targets() {
+        let policy = policy(&["localhost:*", "*:443"]);
+
+        assert_eq!(policy.check_enable(&dynamic(&["localhost:22", "example.com:443"])), Ok(()));
+        assert_eq!(policy.check_enable(&dynamic(&["localhost:*", "*:443"])), Ok(()));
+        assert!(policy.check_enable(&dynamic(&["localhost:22", "example.com:80"])).is_err());
+        assert!(policy.check_enable(&dynamic(&["*:*"])).is_err());
+        assert!(policy.check_enable(&dynamic(&["*:22"])).is_err());
+        assert!(policy.check_enable(&dynamic(&[])).is_err());
+        assert!(policy.check_enable(&dynamic(&["not a target"])).is_err());
+    }
+
+    #[test]
+    fn without_targets_dynamic_forwards_may_reach_anything() {
+        let anything = LocalPolicy::new(None, false).unwrap();
+
+        assert_eq!(anything.check_enable(&dynamic(&[])), Ok(()));
+        assert_eq!(anything.check_enable(&dynamic(&["*:*"])), Ok(()));
+    }
+
+    #[test]
+    fn gateway_ports_must_be_allowed() {
+        let strict = LocalPolicy::new(None, false).unwrap();
+        let lenient = LocalPolicy::new(None, true).unwrap();
+
+        assert_eq!(strict.check_gateway_port(false), Ok(()));
+        assert!(strict.check_gateway_port(true).is_err());
+        assert_eq!(lenient.check_gateway_port(true), Ok(()));
+
+        let mut enable = enable("localhost", 22);
+        enable.set_gateway_port(true);
+        assert!(strict.check_enable(&enable).is_err());
+        assert_eq!(lenient.check_enable(&enable), Ok(()));
+
+        let mut local_enable = device::SshConnection_LocalEnable::new();
+        local_enable.set_gateway_port(true);
+        assert!(strict.check_local_enable(&local_enable).is_err());
+        assert_eq!(lenient.check_local_enable(&local_enable), Ok(()));
+    }
+}
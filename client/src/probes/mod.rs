@@ -0,0 +1,199 @@
+//! Probes: named commands or files that produce a number, like the CPU temperature or how full the
+//! disk is. The client runs them every so often and sends the results to the server, which keeps
+//! a short history of them.
+//!
+//! Probes are read from a file with one probe per line:
+//!
+//! ```text
+//! # Comments and blank lines are ignored.
+//! cpu_temp = file:/sys/class/thermal/thermal_zone0/temp
+//! disk_used = df --output=pcent / | tail -n 1 | tr -d ' %'
+//! ```
+//!
+//! A `file:` probe reads the first number in the file. Anything else is a command that is run with
+//! `sh -c`, and the first number it prints is the value.
+
+use std::fs;
+use std::process::{Command, Stdio};
+use device;
+use futures::Future;
+use futures::future::poll_fn;
+use tokio_threadpool::blocking;
+
+/// A single probe.
+#[derive(Debug, Clone)]
+struct Probe {
+    name: String,
+    source: ProbeSource,
+}
+
+/// Where a probe's value comes from.
+#[derive(Debug, Clone)]
+enum ProbeSource {
+    File(String),
+    Command(String),
+}
+
+/// All of the probes that the client runs.
+#[derive(Debug, Clone, Default)]
+pub struct Probes {
+    probes: Vec<Probe>,
+}
+
+impl Probes {
+    /// Read the probes from a file.
+    pub fn from_file(path: &str) -> Result<Probes, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+
+        Probes::parse(&contents)
+            .map_err(|err| format!("{}: {}", path, err))
+    }
+
+    /// Parse the contents of a probes file.
+    fn parse(contents: &str) -> Result<Probes, String> {
+        let mut probes = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let source = parts.next().unwrap_or("").trim();
+            if name.is_empty() || source.is_empty() {
+                return Err(format!("line {}: expected NAME = COMMAND or NAME = file:PATH", number + 1));
+            }
+
+            let source = if source.starts_with("file:") {
+                ProbeSource::File(source["file:".len()..].trim().to_string())
+            }
+            else {
+                ProbeSource::Command(source.to_string())
+            };
+
+            probes.push(Probe { name: name.to_string(), source });
+        }
+
+        Ok(Probes { probes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty()
+    }
+
+    /// Run all of the probes without blocking the runtime.
+    pub fn sample_future(&self) -> impl Future<Item=device::Metrics, Error=()> {
+        let probes = self.clone();
+        poll_fn(move || {
+            blocking(|| probes.sample())
+        }).map_err(|_| panic!("the threadpool shut down"))
+    }
+
+    /// Run all of the probes. Probes that fail, or that don't produce a number, are left out.
+    pub fn sample(&self) -> device::Metrics {
+        let samples: Vec<device::Metrics_Sample> = self.probes.iter()
+            .filter_map(|probe| {
+                let value = match probe.source {
+                    ProbeSource::File(ref path) => fs::read_to_string(path).ok(),
+                    ProbeSource::Command(ref command) => run(command),
+                };
+
+                match value.as_ref().and_then(|value| first_number(value)) {
+                    Some(value) => {
+                        let mut sample = device::Metrics_Sample::new();
+                        sample.set_name(probe.name.as_str().into());
+                        sample.set_value(value);
+                        Some(sample)
+                    },
+                    None => {
+                        println!("! Probe {} did not produce a number", probe.name);
+                        None
+                    },
+                }
+            })
+            .collect();
+
+        let mut metrics = device::Metrics::new();
+        metrics.set_samples(samples.into());
+        metrics
+    }
+}
+
+/// Run a command, and return what it printed, if it succeeded.
+fn run(command: &str) -> Option<String> {
+    Command::new("sh")
+        .args(&["-c", command])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Find the first thing that looks like a number.
+fn first_number(value: &str) -> Option<f64> {
+    value.split_whitespace()
+        .filter_map(|word| word.parse::<f64>().ok())
+        .find(|value| value.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_parse() {
+        let probes = Probes::parse("# Comments and blank lines are ignored.\n\ncpu_temp = file: /sys/class/thermal/thermal_zone0/temp\n  disk_used=df --output=pcent / | tr -d ' %'  \n").unwrap();
+
+        assert_eq!(probes.probes.len(), 2);
+        assert_eq!(probes.probes[0].name, "cpu_temp");
+        match probes.probes[0].source {
+            ProbeSource::File(ref path) => assert_eq!(path, "/sys/class/thermal/thermal_zone0/temp"),
+            ref source => panic!("expected a file, got {:?}", source),
+        }
+        assert_eq!(probes.probes[1].name, "disk_used");
+        match probes.probes[1].source {
+            // Only the first = separates the name from the command.
+            ProbeSource::Command(ref command) => assert_eq!(command, "df --output=pcent / | tr -d ' %'"),
+            ref source => panic!("expected a command, got {:?}", source),
+        }
+    }
+
+    #[test]
+    fn empty_probe_files_have_no_probes() {
+        assert!(Probes::parse("").unwrap().is_empty());
+        assert!(Probes::parse("# nothing here\n\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn broken_lines_are_refused() {
+        assert_eq!(Probes::parse("ok = true\njust a command\n").unwrap_err(), "line 2: expected NAME = COMMAND or NAME = file:PATH");
+        assert!(Probes::parse("= echo 1").is_err());
+        assert!(Probes::parse("name =").is_err());
+    }
+
+    #[test]
+    fn the_first_number_is_the_value() {
+        assert_eq!(first_number("48312\n"), Some(48312.0));
+        assert_eq!(first_number("  -3.5 degrees"), Some(-3.5));
+        assert_eq!(first_number("load: 0.42 0.37 0.30"), Some(0.42));
+        assert_eq!(first_number("NaN inf 7"), Some(7.0));
+        assert_eq!(first_number("42%"), None);
+        assert_eq!(first_number(""), None);
+    }
+
+    #[test]
+    fn probes_sample_files_and_commands() {
+        let probes = Probes::parse("answer = echo the answer is 42\nfails = false\nwords = echo none\nmissing = file:/nonexistent/probe").unwrap();
+        let metrics = probes.sample();
+
+        let samples: Vec<(&str, f64)> = metrics.get_samples().iter()
+            .map(|sample| (sample.get_name(), sample.get_value()))
+            .collect();
+        assert_eq!(samples, vec![("answer", 42.0)]);
+    }
+}
@@ -0,0 +1,155 @@
+//! The client's private runtime directory, where SSH keys, `ssh` control sockets and our own
+//! control socket live while the client runs. Nobody but the client's user may look inside.
+//!
+//! Things get left behind in here when the client dies without cleaning up: `ssh` masters keep
+//! running in the background, and keys and known_hosts files that were being handed to `ssh` stay
+//! on disk. So at startup, the client goes through the directory and sorts that out (see
+//! `clean_up`).
+
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// What SSH key files start with. The rest is the connection ID.
+const SSH_KEY_PREFIX: &str = "sshkey-";
+
+/// What `ssh` control sockets start with. The rest is the connection ID.
+const SSH_CONTROL_PREFIX: &str = "ssh-";
+
+/// What the logs of `ssh` start with. The rest is the connection ID.
+const SSH_LOG_PREFIX: &str = "sshlog-";
+
+/// What the known_hosts files with the SSH server's host keys start with. The rest is the
+/// connection ID.
+const KNOWN_HOSTS_PREFIX: &str = "known_hosts-";
+
+/// The client's private runtime directory.
+#[derive(Debug, Clone)]
+pub struct RuntimeDirectory {
+    path: PathBuf,
+}
+
+impl RuntimeDirectory {
+    /// Find the runtime directory: the configured one, or `$RUNTIME_DIRECTORY` (set by systemd's
+    /// `RuntimeDirectory=`), or a directory of our own in /tmp. This doesn't touch the directory.
+    pub fn locate(configured: Option<&str>) -> RuntimeDirectory {
+        let path = match configured {
+            Some(directory) => PathBuf::from(directory),
+            None => match env::var_os("RUNTIME_DIRECTORY") {
+                // systemd can give us more than one directory, separated by colons.
+                Some(ref directories) if !directories.is_empty() => {
+                    let directories = directories.to_string_lossy();
+                    PathBuf::from(directories.split(':').next().unwrap_or(""))
+                },
+                _ => PathBuf::from(format!("/tmp/connectbot-client-{}", current_uid().unwrap_or(0))),
+            },
+        };
+
+        RuntimeDirectory { path }
+    }
+
+    /// Find the runtime directory, create it if it isn't there, and make sure that it's ours and
+    /// private.
+    pub fn prepare(configured: Option<&str>) -> Result<RuntimeDirectory, String> {
+        let directory = RuntimeDirectory::locate(configured);
+        let path = &directory.path;
+
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(path)
+            .map_err(|err| format!("Failed to create {}: {}", path.display(), err))?;
+
+        // In a shared place like /tmp, somebody else could have made the directory first.
+        let metadata = fs::symlink_metadata(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        if !metadata.is_dir() {
+            return Err(format!("{} is not a directory", path.display()));
+        }
+        if let Ok(uid) = current_uid() {
+            if metadata.uid() != uid {
+                return Err(format!("{} belongs to somebody else", path.display()));
+            }
+        }
+        if metadata.mode() & 0o077 != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+                .map_err(|err| format!("Failed to make {} private: {}", path.display(), err))?;
+        }
+
+        Ok(directory)
+    }
+
+    /// Where the SSH key for a connection goes while `ssh` needs it.
+    pub fn ssh_key(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}{}", SSH_KEY_PREFIX, id))
+    }
+
+    /// Where the `ssh` master for a connection listens.
+    pub fn ssh_control(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}{}", SSH_CONTROL_PREFIX, id))
+    }
+
+    /// Where `ssh` logs what it has to say about a connection.
+    pub fn ssh_log(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}{}", SSH_LOG_PREFIX, id))
+    }
+
+    /// Where the SSH server's host keys for a connection go while connecting.
+    pub fn known_hosts(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}{}", KNOWN_HOSTS_PREFIX, id))
+    }
+
+    /// Where `connectbot-client status` and friends talk to us.
+    pub fn control_socket(&self) -> PathBuf {
+        self.path.join("control.sock")
+    }
+
+    /// Clean up after a previous run. Any SSH keys and known_hosts files are stale, because `ssh`
+    /// only needs them while it connects. Returns the IDs of the connections that still have an
+    /// `ssh` control socket; their masters may well still be running. Logs of any other
+    /// connections are removed.
+    pub fn clean_up(&self) -> Result<Vec<String>, String> {
+        let entries = fs::read_dir(&self.path)
+            .map_err(|err| format!("Failed to read {}: {}", self.path.display(), err))?;
+
+        let mut controls = Vec::new();
+        let mut logs = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|err| format!("Failed to read {}: {}", self.path.display(), err))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            if name.starts_with(SSH_KEY_PREFIX) {
+                println!("! Removing stale SSH key {}", entry.path().display());
+                if let Err(err) = fs::remove_file(entry.path()) {
+                    println!("! Failed to remove {}: {}", entry.path().display(), err);
+                }
+            }
+            else if name.starts_with(KNOWN_HOSTS_PREFIX) {
+                if let Err(err) = fs::remove_file(entry.path()) {
+                    println!("! Failed to remove {}: {}", entry.path().display(), err);
+                }
+            }
+            else if name.starts_with(SSH_LOG_PREFIX) {
+                logs.push(name[SSH_LOG_PREFIX.len()..].to_string());
+            }
+            else if name.starts_with(SSH_CONTROL_PREFIX) {
+                controls.push(name[SSH_CONTROL_PREFIX.len()..].to_string());
+            }
+        }
+
+        // A master that's still running keeps writing to its log.
+        for id in logs.iter().filter(|id| !controls.contains(id)) {
+            let _ = fs::remove_file(self.ssh_log(id));
+        }
+
+        controls.sort();
+        Ok(controls)
+    }
+}
+
+/// Who we're running as. /proc/self belongs to whoever the process runs as.
+fn current_uid() -> io::Result<u32> {
+    fs::metadata(Path::new("/proc/self")).map(|metadata| metadata.uid())
+}
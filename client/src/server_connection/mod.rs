@@ -1,61 +1,179 @@
-use futures::{Async, Future, Poll, Stream, Sink, StartSend, AsyncSink, stream};
+use futures::{Async, Future, Poll, Stream, Sink, StartSend, AsyncSink};
+use futures::future::{self, poll_fn};
+use futures::task::AtomicTask;
 use std;
+use std::fmt;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
+use tokio;
 use tokio::net::TcpStream;
-use tokio_codec;
+use tokio::reactor::Handle;
+use tokio_codec::{self, Decoder, Encoder};
 use tokio_dns;
+use tokio_threadpool::blocking;
 use tokio_timer::Delay;
+use proxy::Proxy;
 
+use connectbot_shared::backoff::BackoffPolicy;
 use connectbot_shared::codec::Codec;
 use connectbot_shared::protos::device;
 use connectbot_shared::timed_connection::{TimedConnection, TimedConnectionOptions, TimedConnectionItem};
+use connectbot_shared::websocket::{self, WebSocketCodec};
 
 use tokio_rustls::{
-    self,
     TlsConnector,
     rustls::ClientConfig,
     webpki
 };
 
-/// Information about how to connect to the server.
-#[derive(Clone)]
-pub enum ConnectionDetails {
-    /// Connect to the server using an address and port.
-    Address { address: String, port: u16 },
-    /// Connect to the server using an IP address and port, using the address to check the TLS
-    /// certificate.
-    AddressWithResolve { address: String, resolve: IpAddr, port: u16 },
+/// How long we go without hearing from the server before we ping it.
+const IDLE_WARNING: Duration = Duration::from_secs(60);
+/// How long we go without hearing from the server before we give up on the connection.
+pub const IDLE_DISCONNECT: Duration = Duration::from_secs(120);
+
+/// Information about how to connect to a server.
+#[derive(Clone, Debug)]
+pub struct ConnectionDetails {
+    /// The server's address. Unless there's a TLS name, it's also what the server's certificate
+    /// gets checked against.
+    pub address: String,
+    /// The server's port
+    pub port: u16,
+    /// An IP address to connect to instead of looking up the address
+    pub resolve: Option<IpAddr>,
+    /// The name to check the server's certificate against, if it isn't the address
+    pub tls_name: Option<String>,
+    /// The path to upgrade to a WebSocket, if the server is a `wss://` URL instead of a plain TLS
+    /// server
+    pub websocket: Option<String>,
 }
 
 impl ConnectionDetails {
-    fn address(&self) -> &str {
-        match self {
-            ConnectionDetails::Address { address, .. } => &address,
-            ConnectionDetails::AddressWithResolve { address, .. } => &address,
+    /// The name that the server's certificate has to be for.
+    pub fn tls_name(&self) -> &str {
+        self.tls_name.as_ref().unwrap_or(&self.address)
+    }
+
+    /// What goes in the Host header of the WebSocket upgrade.
+    fn host_header(&self) -> String {
+        match self.port {
+            443 => self.address.clone(),
+            port => format!("{}:{}", self.address, port),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.websocket {
+            Some(ref path) => write!(f, "wss://{}:{}{}", self.address, self.port, path)?,
+            None => write!(f, "{}:{}", self.address, self.port)?,
+        }
+        if let Some(resolve) = self.resolve {
+            write!(f, " (at {})", resolve)?;
         }
+        Ok(())
     }
 }
 
 impl<'a> tokio_dns::ToEndpoint<'a> for &'a ConnectionDetails {
     fn to_endpoint(self) -> std::io::Result<tokio_dns::Endpoint<'a>> {
-        match self {
-            ConnectionDetails::Address { address, port } => Ok(tokio_dns::Endpoint::Host(&address, *port)),
-            ConnectionDetails::AddressWithResolve { resolve, port, .. } => Ok(tokio_dns::Endpoint::SocketAddr(SocketAddr::new(resolve.clone(), *port))),
+        match self.resolve {
+            Some(resolve) => Ok(tokio_dns::Endpoint::SocketAddr(SocketAddr::new(resolve, self.port))),
+            None => Ok(tokio_dns::Endpoint::Host(&self.address, self.port)),
         }
     }
 }
 
+/// How often to check whether the primary server is back, while we're connected to a fallback.
+fn primary_check_interval() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// Connect to a server, through the proxy if there is one.
+fn tcp_connect(details: &ConnectionDetails, proxy: &Option<Proxy>) -> tokio_dns::IoFuture<TcpStream> {
+    let proxy = match proxy {
+        Some(proxy) => proxy.clone(),
+        None => return tokio_dns::TcpStream::connect(details),
+    };
+
+    // The proxy looks up the address, unless we already know where to go.
+    let host = details.resolve.map(|resolve| resolve.to_string()).unwrap_or_else(|| details.address.clone());
+    let port = details.port;
+    let future = poll_fn(move || {
+        blocking(|| proxy.connect(&host, port))
+            .map_err(|_| panic!("the threadpool shut down"))
+    })
+        .and_then(|result| result)
+        .and_then(|stream| TcpStream::from_std(stream, &Handle::default()));
+    Box::new(future)
+}
+
+/// Start negotiating TLS on a TCP connection to a server, and then upgrade to a WebSocket if the
+/// server is a `wss://` URL.
+fn handshake(config: &Arc<ClientConfig>, details: &ConnectionDetails, tcp_stream: TcpStream) -> std::io::Result<Handshake> {
+    let tls_name = details.tls_name();
+    let domain = webpki::DNSNameRef::try_from_ascii_str(tls_name)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} isn't a valid TLS name", tls_name)))?;
+    let connector: TlsConnector = config.clone().into();
+    let tls = connector.connect(domain, tcp_stream);
+
+    let handshake: Handshake = match details.websocket {
+        None => Box::new(tls.map(|tls_stream| framed(Codec::new(), tls_stream))),
+        Some(ref path) => {
+            let host = details.host_header();
+            let path = path.clone();
+            Box::new(tls
+                .and_then(move |tls_stream| websocket::connect(tls_stream, &host, &path))
+                .map(|stream| framed(WebSocketCodec::client(Codec::new()), stream)))
+        },
+    };
+    Ok(handshake)
+}
+
+/// Frame a connection with a codec, and split it into where messages go and where they come
+/// from.
+fn framed<S, C>(codec: C, stream: S) -> (SinkType, MessageStream)
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+          C: Decoder<Item=device::ServerMessage, Error=std::io::Error> + Encoder<Item=device::ClientMessage, Error=std::io::Error> + Send + 'static,
+{
+    let (sink, stream) = tokio_codec::Decoder::framed(codec, stream).split();
+    (Box::new(sink), Box::new(stream))
+}
+
 /// A future for the server connection.
+///
+/// There can be more than one server to connect to. The first one is the primary, and the rest are
+/// fallbacks, in the order to try them in. When a connection fails, we try the next server right
+/// away, and only once all of them have failed do we wait, and start over with the primary. While
+/// we're connected to a fallback, we check every so often whether the primary is back, and switch
+/// to it if it is.
 pub struct ServerConnection {
-    connection_details: ConnectionDetails,
+    /// The servers to connect to, primary first
+    endpoints: Vec<ConnectionDetails>,
+    /// Which server we're connected to, or trying to connect to
+    current: usize,
+    /// The server we're on, for anybody with a handle who wants to know
+    endpoint: Arc<RwLock<String>>,
+    /// While we're connected to a fallback, whether the primary is back yet
+    primary_check: Option<PrimaryCheck>,
+    /// The proxy to connect through, if there is one
+    proxy: Option<Proxy>,
     arc_config: Arc<ClientConfig>,
     /// Whether a disconnect has been requested.
     _disconnect: Arc<AtomicBool>,
+    /// Whether somebody wants us to connect again right away
+    reconnect: Arc<AtomicBool>,
+    /// The task to wake up when somebody wants us to connect again
+    task: Arc<AtomicTask>,
     /// The number of consecutive failures, used for backoff
     failures: usize,
+    /// How long to wait between attempts
+    backoff: BackoffPolicy,
+    /// How long the server asked us to wait before connecting again, if it's too busy for us
+    retry_after: Option<Duration>,
     /// The state machine
     state: ServerConnectionStateMachine,
     /// If the server isn't connected, it can cache up to one message to send later. This is needed
@@ -64,24 +182,169 @@ pub struct ServerConnection {
 }
 
 impl ServerConnection {
-    pub fn new(connection_details: ConnectionDetails, tls_config: ClientConfig) -> ServerConnection {
+    /// Create a new server connection. There has to be at least one endpoint, the primary.
+    pub fn new(endpoints: Vec<ConnectionDetails>, tls_config: ClientConfig, backoff: BackoffPolicy, proxy: Option<Proxy>) -> ServerConnection {
+        assert!(!endpoints.is_empty(), "there has to be a server to connect to");
         let disconnect = Arc::new(AtomicBool::new(false));
         let arc_config = Arc::new(tls_config);
+        let endpoint = Arc::new(RwLock::new(endpoints[0].to_string()));
 
         ServerConnection {
-            connection_details,
+            endpoints,
+            current: 0,
+            endpoint,
+            primary_check: None,
+            proxy,
             arc_config,
             _disconnect: disconnect,
+            reconnect: Arc::new(AtomicBool::new(false)),
+            task: Arc::new(AtomicTask::new()),
             failures: 0,
+            backoff,
+            retry_after: None,
             state: ServerConnectionStateMachine::Requested,
             sink_buffer: None,
         }
     }
 
+    /// Create a handle that can be used to make the connection start over.
+    pub fn handle(&self) -> ServerConnectionHandle {
+        ServerConnectionHandle {
+            reconnect: self.reconnect.clone(),
+            task: self.task.clone(),
+            endpoint: self.endpoint.clone(),
+        }
+    }
+
+    /// If somebody asked us to, drop whatever we were doing, and start over with the primary.
+    fn check_reconnect(&mut self) {
+        if self.reconnect.swap(false, Ordering::Relaxed) {
+            // Dropping the old state closes any connection.
+            println!("! Reconnecting now");
+            self.state = ServerConnectionStateMachine::Requested;
+            self.current = 0;
+            self.primary_check = None;
+        }
+    }
+
+    /// Start connecting to the current server.
+    fn connect(&mut self) -> ServerConnectionEvent {
+        let details = &self.endpoints[self.current];
+        *self.endpoint.write().unwrap() = details.to_string();
+        self.state = ServerConnectionStateMachine::TcpConnecting(tcp_connect(details, &self.proxy));
+        ServerConnectionEvent::Connecting
+    }
+
+    /// Start using a connection that just got established.
+    fn establish(&mut self, (mut sink, stream): (SinkType, MessageStream)) -> ServerConnectionEvent {
+        // TimedConnection is something that wraps a regular stream, and notifies
+        // when we haven't heard from the other side for 60s or 120s.
+        let stream = TimedConnection::new(stream, TimedConnectionOptions {
+            warning_level: IDLE_WARNING,
+            disconnect_level: IDLE_DISCONNECT,
+        });
+
+        // If we had something in the buffer that needs to be sent, try to send it.
+        let sink_buffer = std::mem::replace(&mut self.sink_buffer, None);
+        if let Some(item) = sink_buffer {
+            match sink.start_send(item) {
+                Ok(AsyncSink::Ready) => {
+                    // The sink took our buffered item! Yay.
+                    self.sink_buffer = None;
+                },
+                Ok(AsyncSink::NotReady(item)) => {
+                    // The sink didn't take our buffered item.
+                    self.sink_buffer = Some(item);
+                },
+                Err(err) => {
+                    // Is this the right way to handle this?
+                    return self.handle_err(err);
+                }
+            }
+        }
+
+        // Reset the number of failures we've seen, since we successfully
+        // connected. Whatever a server asked of us before doesn't matter anymore either.
+        self.failures = 0;
+        self.retry_after = None;
+        self.primary_check = None;
+        self.state = ServerConnectionStateMachine::Connected(stream, sink);
+        ServerConnectionEvent::TlsConnected
+    }
+
+    /// While we're connected to a fallback, see whether the primary is back. If it is, this is the
+    /// connection to it.
+    fn poll_primary_check(&mut self) -> Option<(SinkType, MessageStream)> {
+        if self.current == 0 {
+            return None;
+        }
+
+        loop {
+            let check = self.primary_check.take()
+                .unwrap_or_else(|| PrimaryCheck::Waiting(Delay::new(Instant::now() + primary_check_interval())));
+
+            match check {
+                PrimaryCheck::Waiting(mut delay) => {
+                    match delay.poll() {
+                        Ok(Async::Ready(_)) => {
+                            // Time to check. Connecting all the way through the handshake is the
+                            // only way to know that the primary is really back.
+                            let primary = self.endpoints[0].clone();
+                            let config = self.arc_config.clone();
+                            let future = tcp_connect(&primary, &self.proxy)
+                                .and_then(move |tcp_stream| future::result(handshake(&config, &primary, tcp_stream)).flatten());
+                            self.primary_check = Some(PrimaryCheck::Connecting(Box::new(future)));
+                        },
+                        Ok(Async::NotReady) => {
+                            self.primary_check = Some(PrimaryCheck::Waiting(delay));
+                            return None;
+                        },
+                        Err(err) => {
+                            // We'll wait again next time.
+                            println!("! Failed to wait to check on the primary server: {}", err);
+                            return None;
+                        },
+                    }
+                },
+                PrimaryCheck::Connecting(mut future) => {
+                    match future.poll() {
+                        Ok(Async::Ready(connection)) => return Some(connection),
+                        Ok(Async::NotReady) => {
+                            self.primary_check = Some(PrimaryCheck::Connecting(future));
+                            return None;
+                        },
+                        Err(_) => {
+                            // The primary is still down. We'll check again later.
+                            self.primary_check = Some(PrimaryCheck::Waiting(Delay::new(Instant::now() + primary_check_interval())));
+                        },
+                    }
+                },
+            }
+        }
+    }
+
     fn handle_err(&mut self, err: std::io::Error) -> ServerConnectionEvent 
     {
         self.failures += 1;
-        let duration = failure_count_to_timeout(self.failures);
+        self.primary_check = None;
+
+        // Try the next server right away. Only once we've been through all of them do we wait,
+        // and then start over with the primary.
+        self.current = (self.current + 1) % self.endpoints.len();
+        let duration = if self.current == 0 {
+            let rounds = (self.failures + self.endpoints.len() - 1) / self.endpoints.len();
+            let mut duration = self.backoff.delay(rounds);
+            if let Some(retry_after) = self.retry_after.take() {
+                // The server knows best how long it needs. Our own wait goes on top, so that
+                // everybody that the server turned away doesn't come back at the same moment.
+                duration += retry_after;
+            }
+            duration
+        }
+        else {
+            println!("! Trying the next server, {}", self.endpoints[self.current]);
+            Duration::from_secs(0)
+        };
         let instant = Instant::now() + duration;
         self.state = ServerConnectionStateMachine::Failed(Delay::new(instant));
 
@@ -93,19 +356,26 @@ impl ServerConnection {
     }
 }
 
-/// How long to wait after a given number of connection failures. Much like an exponential backoff,
-/// but with hand-chosen round numbers as waits.
-fn failure_count_to_timeout(failures: usize) -> Duration {
-    match failures {
-        0 => Duration::from_secs(5),
-        1 => Duration::from_secs(5),
-        2 => Duration::from_secs(5),
-        3 => Duration::from_secs(10),
-        4 => Duration::from_secs(20),
-        5 => Duration::from_secs(30),
-        6 => Duration::from_secs(60),
-        7 => Duration::from_secs(60),
-        _ => Duration::from_secs(120),
+/// A handle that can be used to make the connection start over, e.g. when somebody on the device
+/// knows that the network is back and doesn't want to wait out the backoff.
+#[derive(Clone)]
+pub struct ServerConnectionHandle {
+    reconnect: Arc<AtomicBool>,
+    task: Arc<AtomicTask>,
+    endpoint: Arc<RwLock<String>>,
+}
+
+impl ServerConnectionHandle {
+    /// Drop the current connection (or stop waiting to try again), and connect again right away,
+    /// starting with the primary server.
+    pub fn reconnect(&self) {
+        self.reconnect.store(true, Ordering::Relaxed);
+        self.task.notify();
+    }
+
+    /// The server that we're connected to, or trying to connect to.
+    pub fn endpoint(&self) -> String {
+        self.endpoint.read().unwrap().clone()
     }
 }
 
@@ -136,8 +406,19 @@ pub struct ConnectionFailed {
     pub duration: Duration,
 }
 
-type StreamType = TimedConnection<stream::SplitStream<tokio_codec::Framed<tokio_rustls::TlsStream<TcpStream, tokio_rustls::rustls::ClientSession>, Codec<device::ClientMessage, device::ServerMessage>>>>;
-type SinkType = stream::SplitSink<tokio_codec::Framed<tokio_rustls::TlsStream<TcpStream, tokio_rustls::rustls::ClientSession>, Codec<device::ClientMessage, device::ServerMessage>>>;
+type MessageStream = Box<dyn Stream<Item=device::ServerMessage, Error=std::io::Error> + Send>;
+type StreamType = TimedConnection<MessageStream>;
+type SinkType = Box<dyn Sink<SinkItem=device::ClientMessage, SinkError=std::io::Error> + Send>;
+/// Negotiating TLS (and upgrading to a WebSocket, for `wss://` servers)
+type Handshake = Box<dyn Future<Item=(SinkType, MessageStream), Error=std::io::Error> + Send>;
+
+/// Checking whether the primary server is back
+enum PrimaryCheck {
+    /// Waiting to check again
+    Waiting(Delay),
+    /// Connecting to the primary. If this works out, we switch to this connection.
+    Connecting(Handshake),
+}
 
 /// The state machine that we iterate through every time poll is called on the future
 enum ServerConnectionStateMachine {
@@ -145,8 +426,8 @@ enum ServerConnectionStateMachine {
     Requested, // -> TcpConnecting
     /// The connection is attempting to be established
     TcpConnecting(tokio_dns::IoFuture<TcpStream>), // -> TlsConnecting, Failed
-    /// The TCP connection has succeeded, and TLS is negotiating
-    TlsConnecting(tokio_rustls::Connect<TcpStream>), // -> Connected, Failed
+    /// The TCP connection has succeeded, and TLS (and maybe the WebSocket upgrade) is negotiating
+    TlsConnecting(Handshake), // -> Connected, Failed
     /// The connection is established, and will be checked after the given delay
     Connected(StreamType, SinkType), // -> Checking
     /// The connection has failed, and will be retried after the given delay
@@ -161,24 +442,29 @@ impl Stream for ServerConnection
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         use self::ServerConnectionStateMachine::*;
 
+        self.task.register();
+        self.check_reconnect();
+
         let state = std::mem::replace(&mut self.state, Requested);
 
         match state {
             Requested => {
-                self.state = TcpConnecting(tokio_dns::TcpStream::connect(&self.connection_details));
-                return Ok(Async::Ready(Some(ServerConnectionEvent::Connecting)))
+                return Ok(Async::Ready(Some(self.connect())))
             },
             TcpConnecting(mut future) => {
                 match future.poll() {
                     Ok(Async::Ready(tcp_stream)) => {
                         // TCP connection successfully established. Try to negotiate TLS.
-                        let domain = self.connection_details.address();
-                        let domain = webpki::DNSNameRef::try_from_ascii_str(&domain).unwrap();
-                        let connector: TlsConnector = self.arc_config.clone().into();
-                        let future = connector.connect(domain, tcp_stream);
-
-                        self.state = TlsConnecting(future);
-                        return Ok(Async::Ready(Some(ServerConnectionEvent::TcpConnected)));
+                        match handshake(&self.arc_config, &self.endpoints[self.current], tcp_stream) {
+                            Ok(future) => {
+                                self.state = TlsConnecting(future);
+                                return Ok(Async::Ready(Some(ServerConnectionEvent::TcpConnected)));
+                            },
+                            Err(err) => {
+                                let event = self.handle_err(err);
+                                return Ok(Async::Ready(Some(event)));
+                            },
+                        }
                     },
                     Ok(Async::NotReady) => {
                         // TCP is still connecting.
@@ -194,43 +480,9 @@ impl Stream for ServerConnection
             },
             TlsConnecting(mut future) => {
                 match future.poll() {
-                    Ok(Async::Ready(tls_stream)) => {
+                    Ok(Async::Ready(connection)) => {
                         // TLS connection successfully established. Woop!
-                        let codec: Codec<device::ClientMessage, device::ServerMessage> = Codec::new();
-                        let framed = tokio_codec::Decoder::framed(codec, tls_stream);
-                        let (mut sink, stream) = framed.split();
-                        // TimedConnection is something that wraps a regular stream, and notifies
-                        // when we haven't heard from the other side for 60s or 120s.
-                        let stream = TimedConnection::new(stream, TimedConnectionOptions {
-                            warning_level: Duration::from_millis(60_000),
-                            disconnect_level: Duration::from_millis(120_000),
-                        });
-
-                        // If we had something in the buffer that needs to be sent, try to send it.
-                        let sink_buffer = std::mem::replace(&mut self.sink_buffer, None);
-                        if let Some(item) = sink_buffer {
-                            match sink.start_send(item) {
-                                Ok(AsyncSink::Ready) => {
-                                    // The sink took our buffered item! Yay.
-                                    self.sink_buffer = None;
-                                },
-                                Ok(AsyncSink::NotReady(item)) => {
-                                    // The sink didn't take our buffered item.
-                                    self.sink_buffer = Some(item);
-                                },
-                                Err(err) => {
-                                    // Is this the right way to handle this?
-                                    let event = self.handle_err(err);
-                                    return Ok(Async::Ready(Some(event)));
-                                }
-                            }
-                        }
-
-                        // Reset the number of failures we've seen, since we successfully
-                        // connected.
-                        self.failures = 0;
-                        self.state = Connected(stream, sink);
-                        return Ok(Async::Ready(Some(ServerConnectionEvent::TlsConnected)));
+                        return Ok(Async::Ready(Some(self.establish(connection))));
                     },
                     Ok(Async::NotReady) => {
                         // TLS is still trying to connect
@@ -245,6 +497,17 @@ impl Stream for ServerConnection
                 }
             },
             Connected(mut stream, sink) => {
+                if let Some(connection) = self.poll_primary_check() {
+                    // The primary is back, so we switch to it. Dropping the old stream and sink
+                    // closes the connection to the fallback.
+                    println!("! The primary server is back, switching to {}", self.endpoints[0]);
+                    drop(stream);
+                    drop(sink);
+                    self.current = 0;
+                    *self.endpoint.write().unwrap() = self.endpoints[0].to_string();
+                    return Ok(Async::Ready(Some(self.establish(connection))));
+                }
+
                 match stream.poll() {
                     Ok(Async::Ready(None)) => {
                         // Our connection ran out. Must be done.
@@ -257,7 +520,15 @@ impl Stream for ServerConnection
                         // pass that information up the chain.
                         self.state = Connected(stream, sink);
                         let event = match item {
-                            TimedConnectionItem::Item(message) => ServerConnectionEvent::Item(message),
+                            TimedConnectionItem::Item(message) => {
+                                if message.has_retry_after() {
+                                    // The server is about to hang up on us, because it's too busy.
+                                    let seconds = message.get_retry_after().get_seconds();
+                                    println!("! The server is busy, and asked us to wait {}s before connecting again", seconds);
+                                    self.retry_after = Some(Duration::from_secs(seconds as u64));
+                                }
+                                ServerConnectionEvent::Item(message)
+                            },
                             TimedConnectionItem::Timeout => ServerConnectionEvent::TimeoutWarning,
                         };
                         return Ok(Async::Ready(Some(event)));
@@ -280,8 +551,7 @@ impl Stream for ServerConnection
                 match delay.poll() {
                     Ok(Async::Ready(_)) => {
                         // Timer expired. We can try to connect again!
-                        self.state = TcpConnecting(tokio_dns::TcpStream::connect(&self.connection_details));
-                        return Ok(Async::Ready(Some(ServerConnectionEvent::Connecting)))
+                        return Ok(Async::Ready(Some(self.connect())))
                     },
                     Ok(Async::NotReady) => {
                         // Timer is still running. We'll keep waiting.
@@ -338,6 +608,9 @@ impl Sink for ServerConnection {
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         use self::ServerConnectionStateMachine::*;
 
+        self.task.register();
+        self.check_reconnect();
+
         let state = std::mem::replace(&mut self.state, Requested);
 
         match state {
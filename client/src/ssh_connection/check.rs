@@ -1,6 +1,7 @@
 use super::CommandFuture;
+use super::control_command;
 
-use ::std::process::{Command, Stdio};
+use ::std::path::PathBuf;
 use ::futures::Future;
 use ::futures::Async;
 use ::futures::future::poll_fn;
@@ -14,19 +15,10 @@ pub struct Check {
 }
 
 impl Check {
-    pub fn new(id: String) -> Check {
+    pub fn new(control_socket: PathBuf) -> Check {
         let f = poll_fn(move || {
             blocking(|| {
-                Command::new("ssh").args(&[
-                                         "-O",
-                                         "check",
-                                         "-S",
-                                         &format!("/tmp/connectbot-ssh-{}", id),
-                                         "_@localhost",
-                ])
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
+                control_command(&control_socket, "check")
                     .status()
                     .unwrap()
                     .success()
@@ -1,8 +1,11 @@
 use super::CommandFuture;
 use super::SshConnectionSettings;
+use super::control_command;
+use super::failure::FailureReason;
 
 use std;
 use std::io::Write;
+use std::path::PathBuf;
 use std::os::unix::fs::OpenOptionsExt;
 use ::std::process::{Command, Stdio};
 use ::futures::Future;
@@ -12,7 +15,7 @@ use ::tokio_threadpool::blocking;
 
 /// A future that attempts to establish an ssh connection
 ///
-/// Returns true if the connection already exists or if a new connection succeeds, and false if the
+/// Returns Ok if the connection already exists or if a new connection succeeds, and why not if the
 /// connection cannot be established.
 pub struct Connect {
     settings: SshConnectionSettings,
@@ -39,24 +42,15 @@ enum ConnectData {
     /// Write the SSH private key to a file so it can be used in the connection future.
     WriteKeyFuture(WriteKeyFuture),
     /// Run the command to establish the connection.
-    ConnectFuture(CommandFuture<bool>),
+    ConnectFuture(CommandFuture<Result<(), FailureReason>>),
 }
 
 impl ConnectData {
     /// Create a new half of a future that checks whether a connection is already active.
-    fn new_check(id: String) -> ConnectData {
+    fn new_check(control_socket: PathBuf) -> ConnectData {
         let f = poll_fn(move || {
             blocking(|| {
-                Command::new("ssh").args(&[
-                                         "-O",
-                                         "check",
-                                         "-S",
-                                         &format!("/tmp/connectbot-ssh-{}", id),
-                                         "_@localhost",
-                ])
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
+                control_command(&control_socket, "check")
                     .status()
                     .unwrap()
                     .success()
@@ -66,6 +60,8 @@ impl ConnectData {
         ConnectData::CheckFuture(Box::new(f))
     }
 
+    /// Create a new half of a future that writes the private key (and the SSH server's host keys)
+    /// to files for `ssh`.
     fn new_write_key(settings: SshConnectionSettings) -> ConnectData {
         let f = poll_fn(move || {
             blocking(|| {
@@ -84,43 +80,75 @@ impl ConnectData {
                 file.write_all(settings.private_key.as_bytes())
                     .map_err(|err| println!("Failed to write file: {}", err))
                     .unwrap();
+
+                settings.write_known_hosts().map(|_| ())
             }).map_err(|_| panic!("the threadpool shut down"))
-        });
+        }).and_then(|result| result);
 
         ConnectData::WriteKeyFuture(Box::new(f))
     }
 
     /// Create a new half of a future that establishes a new connection.
     fn new_connect(settings: SshConnectionSettings) -> ConnectData {
+        // Without host keys there's nothing to check the SSH server against, and we don't
+        // connect to a server we can't check.
+        let host_key_arguments = vec![
+            "-o".to_string(), "StrictHostKeyChecking=yes".to_string(),
+            "-o".to_string(), format!("UserKnownHostsFile={}", settings.known_hosts_file().display()),
+        ];
+
         let f = poll_fn(move || {
-            let connection = format!("{}{}:{}:{}",
-                                     match settings.gateway_port {
-                                         true => ":",
-                                         false => "",
-                                     },
-                                     settings.remote_port,
-                                     settings.forward_host,
-                                     settings.forward_port);
+            let forward_arguments = settings.forward_arguments();
             blocking(|| {
-                Command::new("ssh").args(&[
-                                         "-f",
-                                         "-N",
-                                         "-R", &connection,
-                                         "-o", "BatchMode=yes",
-                                         "-o", "StrictHostKeyChecking=no",
-                                         "-o", "UserKnownHostsFile=/dev/null",
-                                         "-i", &settings.private_key_file(),
-                                         "-M",
-                                         "-S", &format!("/tmp/connectbot-ssh-{}", settings.id),
+                if settings.host_keys.is_empty() {
+                    return Err(FailureReason::new(None, format!("The host key of {} can't be checked, because the server didn't send any host keys", settings.host)));
+                }
+
+                // Whatever ssh has to say goes to its log, both while it connects and after it
+                // goes into the background. That's where we look when the connection fails.
+                let log = settings.ssh_log_file();
+                let stderr = match std::fs::File::create(&log) {
+                    Ok(file) => Stdio::from(file),
+                    Err(err) => {
+                        println!("! Failed to create {}: {}", log.display(), err);
+                        Stdio::null()
+                    },
+                };
+
+                // To go through a proxy, ssh runs us to make the connection (see `proxy`).
+                let mut proxy_arguments = Vec::new();
+                if settings.proxy.is_some() {
+                    let exe = std::env::current_exe()
+                        .map_err(|err| FailureReason::new(None, format!("Failed to find our own executable for ssh's ProxyCommand: {}", err)))?;
+                    let exe = format!("'{}'", exe.to_string_lossy().replace('\'', "'\\''")).replace('%', "%%");
+                    proxy_arguments.push("-o".to_string());
+                    proxy_arguments.push(format!("ProxyCommand={} proxy-connect %h %p", exe));
+                }
+
+                let status = Command::new("ssh")
+                    .args(&["-f", "-N"])
+                    .args(&forward_arguments)
+                    .args(&["-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes"])
+                    .args(&host_key_arguments)
+                    .args(&proxy_arguments)
+                    .envs(settings.proxy.iter().flat_map(|proxy| proxy.env()))
+                    .arg("-i").arg(settings.private_key_file())
+                    .arg("-M")
+                    .arg("-S").arg(settings.control_socket())
+                    .args(&[
                                          "-p", &format!("{}", settings.port),
                                          &format!("{}@{}", settings.username, settings.host),
                 ])
                     .stdin(Stdio::null())
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .status()
-                    .unwrap()
-                    .success()
+                    .stdout(Stdio::null())
+                    .stderr(stderr)
+                    .status();
+
+                match status {
+                    Ok(ref status) if status.success() => Ok(()),
+                    Ok(status) => Err(FailureReason::from_log(&log, status.code())),
+                    Err(err) => Err(FailureReason::new(None, format!("Failed to run ssh: {}", err))),
+                }
             }).map_err(|_| panic!("the threadpool shut down"))
         });
 
@@ -129,7 +157,7 @@ impl ConnectData {
 }
 
 impl Future for Connect {
-    type Item = bool;
+    type Item = Result<(), FailureReason>;
     type Error = ();
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
@@ -137,7 +165,7 @@ impl Future for Connect {
             let val = ::std::mem::replace(&mut self.data, ConnectData::None);
             match val {
                 ConnectData::None => {
-                    self.data = ConnectData::new_check(self.settings.id.clone());
+                    self.data = ConnectData::new_check(self.settings.control_socket());
                 },
                 ConnectData::CheckFuture(mut future) => {
                     match future.poll()? {
@@ -145,7 +173,7 @@ impl Future for Connect {
                             if result {
                                 // If the check returned true, we're already connected. So we can,
                                 // in good conscience, say that we're connected.
-                                return Ok(Async::Ready(true))
+                                return Ok(Async::Ready(Ok(())))
                             }
 
                             // If the check returned false, we're not already connected. Well,
@@ -160,15 +188,18 @@ impl Future for Connect {
                     }
                 },
                 ConnectData::WriteKeyFuture(mut future) => {
-                    match future.poll().map_err(|_| ())? {
-                        Async::Ready(_) => {
+                    match future.poll() {
+                        Ok(Async::Ready(_)) => {
                             self.data = ConnectData::new_connect(self.settings.clone());
                         },
-                        Async::NotReady => {
+                        Ok(Async::NotReady) => {
                             self.data = ConnectData::WriteKeyFuture(future);
 
                             return Ok(Async::NotReady);
-                        }
+                        },
+                        Err(err) => {
+                            return Ok(Async::Ready(Err(FailureReason::new(None, err))));
+                        },
                     }
                 },
                 ConnectData::ConnectFuture(mut future) => {
@@ -185,6 +216,7 @@ impl Future for Connect {
 impl Drop for Connect {
     fn drop(&mut self) {
         let _ = std::fs::remove_file(self.settings.private_key_file());
+        let _ = std::fs::remove_file(self.settings.known_hosts_file());
     }
 }
 
@@ -1,9 +1,11 @@
 use super::CommandFuture;
+use super::native::Session;
+use super::control_command;
 
-use ::std::process::{Command, Stdio};
+use ::std::path::PathBuf;
 use ::futures::Future;
 use ::futures::Async;
-use ::futures::future::poll_fn;
+use ::futures::future::{self, poll_fn};
 use ::tokio_threadpool::blocking;
 
 /// A future that disconnects a ssh socket
@@ -12,19 +14,10 @@ pub struct Disconnect {
 }
 
 impl Disconnect {
-    pub fn new(id: String) -> Disconnect {
+    pub fn new(control_socket: PathBuf) -> Disconnect {
         let f = poll_fn(move || {
             blocking(|| {
-                Command::new("ssh").args(&[
-                                         "-O",
-                                         "exit",
-                                         "-S",
-                                         &format!("/tmp/connectbot-ssh-{}", id),
-                                         "_@localhost",
-                ])
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
+                control_command(&control_socket, "exit")
                     .status()
                     .unwrap();
 
@@ -38,6 +31,23 @@ impl Disconnect {
     }
 }
 
+impl Disconnect {
+    /// Disconnect a native session, if there is one, and wait for it to end.
+    pub fn native(session: Option<Session>) -> Disconnect {
+        let future: CommandFuture<()> = match session {
+            Some(session) => {
+                session.stop();
+                Box::new(session.map(|_| ()))
+            },
+            None => Box::new(future::ok(())),
+        };
+
+        Disconnect {
+            future,
+        }
+    }
+}
+
 impl Future for Disconnect {
     type Item = ();
     type Error = ();
@@ -0,0 +1,206 @@
+//! Working out why an SSH connection failed, so that whoever looks at the forward on the server
+//! has more to go on than "failed".
+//!
+//! `ssh` doesn't tell us much with its exit code (it's 255 for almost everything), so we go by
+//! what it logged: its stderr goes to a log file in the runtime directory, and when the connection
+//! fails, we look at the last few lines. The native backend's errors are matched the same way.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// How many lines of the log to keep.
+const TAIL_LINES: usize = 5;
+
+/// What went wrong, as far as we can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Unknown,
+    /// The SSH server didn't accept the key
+    AuthDenied,
+    /// The port to listen on is taken (on the server, or on the device for local forwards)
+    PortInUse,
+    /// The SSH server couldn't be reached
+    HostUnreachable,
+    /// The SSH server's host key isn't one that we know
+    HostKeyMismatch,
+    /// The forward's target refused the connection
+    TargetRefused,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            FailureKind::Unknown => "unknown failure",
+            FailureKind::AuthDenied => "authentication denied",
+            FailureKind::PortInUse => "port in use",
+            FailureKind::HostUnreachable => "host unreachable",
+            FailureKind::HostKeyMismatch => "host key mismatch",
+            FailureKind::TargetRefused => "target refused",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// What's worth matching in what `ssh` and the native backend say, first match wins. Host key
+/// problems come first, because `ssh` goes on to say other things about them.
+const PATTERNS: &[(&str, FailureKind)] = &[
+    ("REMOTE HOST IDENTIFICATION HAS CHANGED", FailureKind::HostKeyMismatch),
+    ("Host key verification failed", FailureKind::HostKeyMismatch),
+    ("no matching host key", FailureKind::HostKeyMismatch),
+    ("The host key of", FailureKind::HostKeyMismatch),
+    ("Permission denied", FailureKind::AuthDenied),
+    ("Authentication as", FailureKind::AuthDenied),
+    ("remote port forwarding failed", FailureKind::PortInUse),
+    ("Address already in use", FailureKind::PortInUse),
+    ("Could not request local forwarding", FailureKind::PortInUse),
+    ("wouldn't listen on port", FailureKind::PortInUse),
+    ("Failed to listen on port", FailureKind::PortInUse),
+    ("connect_to ", FailureKind::TargetRefused),
+    ("open failed: connect failed", FailureKind::TargetRefused),
+    ("Could not resolve hostname", FailureKind::HostUnreachable),
+    ("No route to host", FailureKind::HostUnreachable),
+    ("Network is unreachable", FailureKind::HostUnreachable),
+    ("Connection timed out", FailureKind::HostUnreachable),
+    ("Connection refused", FailureKind::HostUnreachable),
+    ("Failed to look up", FailureKind::HostUnreachable),
+    ("Failed to connect to", FailureKind::HostUnreachable),
+    ("The proxy failed to connect to", FailureKind::HostUnreachable),
+];
+
+/// Why an SSH connection failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureReason {
+    pub kind: FailureKind,
+    /// The exit code of `ssh`, if it exited
+    pub exit_code: Option<i32>,
+    /// The last few lines that were said about it
+    pub detail: String,
+}
+
+impl FailureReason {
+    /// Work out why a connection failed from what was said about it.
+    pub fn new(exit_code: Option<i32>, detail: String) -> FailureReason {
+        FailureReason {
+            kind: classify(&detail),
+            exit_code,
+            detail,
+        }
+    }
+
+    /// Work out why a connection failed from the end of `ssh`'s log.
+    pub fn from_log(log: &Path, exit_code: Option<i32>) -> FailureReason {
+        FailureReason::new(exit_code, tail(log))
+    }
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(exit_code) = self.exit_code {
+            write!(f, " (exit code {})", exit_code)?;
+        }
+        if let Some(line) = self.detail.lines().last() {
+            write!(f, ": {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn classify(detail: &str) -> FailureKind {
+    PATTERNS.iter()
+        .find(|(pattern, _)| detail.contains(pattern))
+        .map(|&(_, kind)| kind)
+        .unwrap_or(FailureKind::Unknown)
+}
+
+/// The last few lines of a log, or nothing if there's no log.
+fn tail(log: &Path) -> String {
+    let data = fs::read_to_string(log).unwrap_or_default();
+    let lines: Vec<&str> = data.lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect();
+    let start = lines.len().saturating_sub(TAIL_LINES);
+    lines[start..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile;
+
+    #[test]
+    fn what_ssh_says_is_classified() {
+        let cases: &[(&str, FailureKind)] = &[
+            // Authentication
+            ("pi@ssh.example.com: Permission denied (publickey).", FailureKind::AuthDenied),
+            ("Received disconnect from 203.0.113.5 port 22:2: Too many authentication failures\npi@ssh.example.com: Permission denied (publickey,password).", FailureKind::AuthDenied),
+            ("Authentication as pi failed: [Session(-18)] Username/PublicKey combination invalid", FailureKind::AuthDenied),
+            // Host keys
+            ("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\n@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @\n@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@\nIT IS POSSIBLE THAT SOMEONE IS DOING SOMETHING NASTY!\nHost key verification failed.", FailureKind::HostKeyMismatch),
+            ("No ED25519 host key is known for ssh.example.com and you have requested strict checking.\nHost key verification failed.", FailureKind::HostKeyMismatch),
+            ("Unable to negotiate with 203.0.113.5 port 22: no matching host key type found. Their offer: ssh-rsa", FailureKind::HostKeyMismatch),
+            ("The host key of ssh.example.com is not in /root/.ssh/known_hosts", FailureKind::HostKeyMismatch),
+            ("The host key of ssh.example.com can't be checked, because the server didn't send any host keys", FailureKind::HostKeyMismatch),
+            // A changed host key, with ssh carrying on to fail the login
+            ("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @\nPassword authentication is disabled to avoid man-in-the-middle attacks.\npi@ssh.example.com: Permission denied (publickey).", FailureKind::HostKeyMismatch),
+            // Refused and timed out
+            ("ssh: connect to host ssh.example.com port 22: Connection refused", FailureKind::HostUnreachable),
+            ("ssh: connect to host 203.0.113.5 port 22: Connection timed out", FailureKind::HostUnreachable),
+            ("ssh: connect to host 203.0.113.5 port 22: No route to host", FailureKind::HostUnreachable),
+            ("ssh: connect to host 203.0.113.5 port 22: Network is unreachable", FailureKind::HostUnreachable),
+            ("ssh: Could not resolve hostname ssh.example.com: Name or service not known", FailureKind::HostUnreachable),
+            ("Failed to connect to 203.0.113.5:22: Connection refused (os error 111)", FailureKind::HostUnreachable),
+            // Ports
+            ("Error: remote port forwarding failed for listen port 2222", FailureKind::PortInUse),
+            ("bind [127.0.0.1]:8080: Address already in use\nchannel_setup_fwd_listener_tcpip: cannot listen to port: 8080\nCould not request local forwarding.", FailureKind::PortInUse),
+            ("The server wouldn't listen on port 2222: [Session(-34)] Unable to complete request for channel-forward", FailureKind::PortInUse),
+            // Targets
+            ("connect_to localhost port 8080: failed.", FailureKind::TargetRefused),
+            ("channel 2: open failed: connect failed: Connection refused", FailureKind::TargetRefused),
+            // Anything else
+            ("kex_exchange_identification: read: Connection reset by peer", FailureKind::Unknown),
+            ("Connection closed by 203.0.113.5 port 22", FailureKind::Unknown),
+            ("", FailureKind::Unknown),
+        ];
+
+        for &(detail, kind) in cases {
+            assert_eq!(classify(detail), kind, "{:?}", detail);
+        }
+    }
+
+    #[test]
+    fn every_pattern_is_reachable() {
+        for &(pattern, kind) in PATTERNS {
+            assert_eq!(classify(pattern), kind, "{:?} is shadowed by an earlier pattern", pattern);
+        }
+    }
+
+    #[test]
+    fn reasons_show_the_last_line() {
+        let reason = FailureReason::new(Some(255), "debug1: something\npi@ssh.example.com: Permission denied (publickey).".to_string());
+
+        assert_eq!(reason.to_string(), "authentication denied (exit code 255): pi@ssh.example.com: Permission denied (publickey).");
+        assert_eq!(FailureReason::new(None, String::new()).to_string(), "unknown failure");
+    }
+
+    #[test]
+    fn only_the_end_of_the_log_counts() {
+        let mut log = tempfile::NamedTempFile::new().unwrap();
+        write!(log, "pi@ssh.example.com: Permission denied (publickey).\n\n").unwrap();
+        for i in 0..TAIL_LINES {
+            writeln!(log, "line {}   ", i).unwrap();
+        }
+        writeln!(log, "ssh: connect to host ssh.example.com port 22: Connection refused").unwrap();
+
+        let reason = FailureReason::from_log(log.path(), Some(255));
+        assert_eq!(reason.kind, FailureKind::HostUnreachable);
+        assert_eq!(reason.detail.lines().count(), TAIL_LINES);
+        assert_eq!(reason.detail.lines().next(), Some("line 1"));
+
+        let missing = FailureReason::from_log(Path::new("/nonexistent/ssh.log"), None);
+        assert_eq!(missing, FailureReason { kind: FailureKind::Unknown, exit_code: None, detail: String::new() });
+    }
+}
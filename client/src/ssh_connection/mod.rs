@@ -8,6 +8,11 @@ use ::std::time::Instant;
 use ::std::time::Duration;
 use ::std::sync::Arc;
 use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::path::{Path, PathBuf};
+use ::std::process::{Command, Stdio};
+use proxy::Proxy;
+use runtime::RuntimeDirectory;
+use connectbot_shared::backoff::BackoffPolicy;
 
 mod check;
 use self::check::Check;
@@ -15,6 +20,9 @@ mod connect;
 use self::connect::Connect;
 mod disconnect;
 use self::disconnect::Disconnect;
+mod failure;
+pub use self::failure::{FailureKind, FailureReason};
+mod native;
 
 /// Information about an SSH connection that we need to establish.
 #[derive(Debug, Clone)]
@@ -40,12 +48,132 @@ pub struct SshConnectionSettings {
     pub gateway_port: bool,
     /// The private key that the server sent down for us to connect with.
     pub private_key: String,
+    /// The SSH server's host keys that the server sent down, as in a known_hosts file. We only
+    /// connect to an SSH server that has one of them. Without any, the `ssh` backend doesn't
+    /// connect at all, and the native one checks its known_hosts file instead.
+    pub host_keys: Vec<String>,
+    /// Which way the tunnel goes.
+    pub direction: ForwardDirection,
+    /// What makes the connection.
+    pub backend: SshBackend,
+    /// Where the key file and control socket go.
+    pub runtime: RuntimeDirectory,
+    /// How long to wait before connecting again after the connection fails.
+    pub backoff: BackoffPolicy,
+    /// The proxy to connect to the SSH server through, if there is one.
+    pub proxy: Option<Proxy>,
+}
+
+/// What makes SSH connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SshBackend {
+    /// Spawn `ssh` in the background, and check on it every so often.
+    OpenSsh,
+    /// Hold the SSH session in the client (see `native`). Unless the server sent host keys, the
+    /// server's host key has to be in the known_hosts file. Dynamic forwards still use `ssh`, because they need its SOCKS5 proxy.
+    Native { known_hosts: PathBuf },
+}
+
+/// Which way an SSH tunnel goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardDirection {
+    /// `ssh -R`: `remote_port` on the server reaches `forward_host:forward_port` from here.
+    Remote,
+    /// `ssh -R port`: `remote_port` on the server is a SOCKS5 proxy that comes out here, and can
+    /// reach the permitted destinations (or anything, if there aren't any). `forward_host` and
+    /// `forward_port` are unused.
+    RemoteDynamic { permitted_destinations: Vec<String> },
+    /// `ssh -L`: `local_port` on this device reaches `forward_host:forward_port` as seen from the
+    /// server. `remote_port` is unused.
+    Local { local_port: u16 },
 }
 
 impl SshConnectionSettings {
     /// The name of the private key file we create, based on the ID of the connection.
-    pub fn private_key_file(&self) -> String {
-        format!("/tmp/connectbot-sshkey-{}", self.id)
+    pub fn private_key_file(&self) -> PathBuf {
+        self.runtime.ssh_key(&self.id)
+    }
+
+    /// The control socket of the `ssh` master, based on the ID of the connection.
+    pub fn control_socket(&self) -> PathBuf {
+        self.runtime.ssh_control(&self.id)
+    }
+
+    /// Where `ssh` logs what it has to say, based on the ID of the connection.
+    pub fn ssh_log_file(&self) -> PathBuf {
+        self.runtime.ssh_log(&self.id)
+    }
+
+    /// The known_hosts file with the SSH server's host keys, based on the ID of the connection.
+    pub fn known_hosts_file(&self) -> PathBuf {
+        self.runtime.known_hosts(&self.id)
+    }
+
+    /// Write the SSH server's host keys to the known_hosts file for the connection. Returns the
+    /// file, or None if the server didn't send any host keys.
+    pub fn write_known_hosts(&self) -> Result<Option<PathBuf>, String> {
+        if self.host_keys.is_empty() {
+            return Ok(None);
+        }
+
+        let host = match self.port {
+            22 => self.host.clone(),
+            port => format!("[{}]:{}", self.host, port),
+        };
+        let mut data = String::new();
+        for key in &self.host_keys {
+            // Every key is a line of its own, so it mustn't be able to sneak in more lines.
+            let key = key.trim();
+            if key.is_empty() || key.contains('\n') || key.contains('\r') {
+                return Err(format!("The server sent a bad host key for {}: {:?}", self.host, key));
+            }
+            data.push_str(&format!("{} {}\n", host, key));
+        }
+
+        let path = self.known_hosts_file();
+        std::fs::write(&path, data)
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+        Ok(Some(path))
+    }
+
+    /// The arguments that tell `ssh` what to forward.
+    pub fn forward_arguments(&self) -> Vec<String> {
+        let gateway = match self.gateway_port {
+            true => ":",
+            false => "",
+        };
+        let forward_host = match self.forward_host.as_str() {
+            // An empty host means localhost, wherever the traffic comes out.
+            "" => "localhost",
+            host => host,
+        };
+
+        match self.direction {
+            ForwardDirection::Remote => {
+                vec![
+                    "-R".to_string(),
+                    format!("{}{}:{}:{}", gateway, self.remote_port, forward_host, self.forward_port),
+                ]
+            },
+            ForwardDirection::Local { local_port } => {
+                vec![
+                    "-L".to_string(),
+                    format!("{}{}:{}:{}", gateway, local_port, forward_host, self.forward_port),
+                ]
+            },
+            ForwardDirection::RemoteDynamic { ref permitted_destinations } => {
+                let mut arguments = vec![
+                    "-R".to_string(),
+                    format!("{}{}", gateway, self.remote_port),
+                ];
+                if !permitted_destinations.is_empty() {
+                    // ssh does the SOCKS5 part, so it gets to enforce the destinations too.
+                    arguments.push("-o".to_string());
+                    arguments.push(format!("PermitRemoteOpen={}", permitted_destinations.join(" ")));
+                }
+                arguments
+            },
+        }
     }
 }
 
@@ -71,7 +199,11 @@ pub struct SshConnection {
 
 impl SshConnection {
     /// Create a new SSH connection object
-    pub fn new(settings: SshConnectionSettings) -> SshConnection {
+    pub fn new(mut settings: SshConnectionSettings) -> SshConnection {
+        if let ForwardDirection::RemoteDynamic { .. } = settings.direction {
+            settings.backend = SshBackend::OpenSsh;
+        }
+
         SshConnection {
             connection_settings: settings,
             disconnect: Arc::new(AtomicBool::new(false)),
@@ -124,8 +256,8 @@ pub enum SshConnectionChange {
     Disconnected,
     /// A connection failed (either failed to connect initially, or was connected and has dropped).
     /// The value is how many times *consecutively* the failure has occurred (i.e., this value is
-    /// reset to 0 every time a successful connection has been made).
-    Failed(usize),
+    /// reset to 0 every time a successful connection has been made), and why it failed this time.
+    Failed(usize, FailureReason),
 }
 
 /// Internal connection state.
@@ -137,8 +269,12 @@ enum SshConnectionStateMachine {
     Requested, // -> Connecting
     /// The connection is attempting to be established
     Connecting(Connect), // -> Connected, Failed
+    /// The connection is attempting to be established natively
+    NativeConnecting(native::Connect), // -> NativeConnected, Failed
     /// The connection is established, and will be checked after the given delay
     Connected(Delay), // -> Checking
+    /// The connection is established natively, and we'll hear as soon as it drops
+    NativeConnected(native::Session), // -> Failed
     /// The connection is being checked to see if it still active
     Checking(Check), // -> Connected, Failed
     /// The connection is being disconnected. Note that we can get to this state from any other
@@ -163,24 +299,19 @@ impl Stream for SshConnection {
 
             match (disconnecting, state) {
                 (false, Requested) => {
-                    self.state = Connecting(Connect::new(self.connection_settings.clone()));
+                    self.state = self.connect();
                     return Ok(Async::Ready(Some(SshConnectionChange::Connecting)))
                 },
                 (false, Connecting(mut delay)) => {
                     match delay.poll().map_err(|_| ())? {
-                        Async::Ready(result) => {
-                            if result {
-                                let instant = Instant::now() + Duration::from_millis(10_000);
-                                self.failures = 0;
-                                self.state = Connected(Delay::new(instant));
-                                return Ok(Async::Ready(Some(SshConnectionChange::Connected)));
-                            }
-                            else {
-                                self.failures += 1;
-                                let instant = Instant::now() + Duration::from_millis(self.failures as u64 * 1_000);
-                                self.state = Failed(Delay::new(instant));
-                                return Ok(Async::Ready(Some(SshConnectionChange::Failed(self.failures))));
-                            }
+                        Async::Ready(Ok(())) => {
+                            let instant = Instant::now() + Duration::from_millis(10_000);
+                            self.failures = 0;
+                            self.state = Connected(Delay::new(instant));
+                            return Ok(Async::Ready(Some(SshConnectionChange::Connected)));
+                        },
+                        Async::Ready(Err(reason)) => {
+                            return Ok(Async::Ready(Some(self.fail(reason))));
                         },
                         Async::NotReady => {
                             self.state = Connecting(delay);
@@ -188,10 +319,38 @@ impl Stream for SshConnection {
                         }
                     }
                 },
+                (false, NativeConnecting(mut future)) => {
+                    match future.poll()? {
+                        Async::Ready(Ok(session)) => {
+                            self.failures = 0;
+                            self.state = NativeConnected(session);
+                            return Ok(Async::Ready(Some(SshConnectionChange::Connected)));
+                        },
+                        Async::Ready(Err(reason)) => {
+                            return Ok(Async::Ready(Some(self.fail(reason))));
+                        },
+                        Async::NotReady => {
+                            self.state = NativeConnecting(future);
+                            return Ok(Async::NotReady);
+                        }
+                    }
+                },
+                (false, NativeConnected(mut session)) => {
+                    match session.poll()? {
+                        Async::Ready(reason) => {
+                            let reason = FailureReason::new(None, format!("the session ended: {}", reason));
+                            return Ok(Async::Ready(Some(self.fail(reason))));
+                        },
+                        Async::NotReady => {
+                            self.state = NativeConnected(session);
+                            return Ok(Async::NotReady);
+                        }
+                    }
+                },
                 (false, Connected(mut delay)) => {
                     match delay.poll().map_err(|_| ())? {
                         Async::Ready(_) => {
-                            self.state = Checking(Check::new(self.connection_settings.id.clone()));
+                            self.state = Checking(Check::new(self.connection_settings.control_socket()));
                         },
                         Async::NotReady => {
                             self.state = Connected(delay);
@@ -208,10 +367,9 @@ impl Stream for SshConnection {
                                 self.state = Connected(Delay::new(instant));
                             }
                             else {
-                                self.failures += 1;
-                                let instant = Instant::now() + Duration::from_millis(self.failures as u64 * 1_000);
-                                self.state = Failed(Delay::new(instant));
-                                return Ok(Async::Ready(Some(SshConnectionChange::Failed(self.failures))));
+                                // The master went away. Whatever it said last is why.
+                                let reason = FailureReason::from_log(&self.connection_settings.ssh_log_file(), None);
+                                return Ok(Async::Ready(Some(self.fail(reason))));
                             }
                         },
                         Async::NotReady => {
@@ -223,7 +381,7 @@ impl Stream for SshConnection {
                 (false, Failed(mut delay)) => {
                     match delay.poll().map_err(|_| ())? {
                         Async::Ready(_) => {
-                            self.state = Connecting(Connect::new(self.connection_settings.clone()));
+                            self.state = self.connect();
                             return Ok(Async::Ready(Some(SshConnectionChange::Connecting)));
                         },
                         Async::NotReady => {
@@ -235,6 +393,7 @@ impl Stream for SshConnection {
                 (_, Disconnecting(mut future)) => {
                     match future.poll().map_err(|_| ())? {
                         Async::Ready(_) => {
+                            let _ = std::fs::remove_file(self.connection_settings.ssh_log_file());
                             self.state = Disconnected;
                             return Ok(Async::Ready(Some(SshConnectionChange::Disconnected)));
                         },
@@ -247,8 +406,17 @@ impl Stream for SshConnection {
                 (_, Disconnected) => {
                     return Ok(Async::Ready(None));
                 }
+                (true, NativeConnected(session)) => {
+                    self.state = Disconnecting(Disconnect::native(Some(session)));
+                    return Ok(Async::Ready(Some(SshConnectionChange::Disconnecting)));
+                },
                 (true, _) => {
-                    self.state = Disconnecting(Disconnect::new(self.connection_settings.id.clone()));
+                    self.state = match self.connection_settings.backend {
+                        SshBackend::OpenSsh => Disconnecting(Disconnect::new(self.connection_settings.control_socket())),
+                        // Whatever state we were in was holding on to any session, and dropping it
+                        // already ended it.
+                        SshBackend::Native { .. } => Disconnecting(Disconnect::native(None)),
+                    };
                     return Ok(Async::Ready(Some(SshConnectionChange::Disconnecting)));
                 },
             }
@@ -256,4 +424,52 @@ impl Stream for SshConnection {
     }
 }
 
+impl SshConnection {
+    /// Count a failure, and wait a while before connecting again.
+    fn fail(&mut self, reason: FailureReason) -> SshConnectionChange {
+        println!("! SSH connection {} failed: {}", self.connection_settings.id, reason);
+        self.failures += 1;
+        let instant = Instant::now() + self.connection_settings.backoff.delay(self.failures);
+        self.state = SshConnectionStateMachine::Failed(Delay::new(instant));
+        SshConnectionChange::Failed(self.failures, reason)
+    }
+
+    /// Start connecting, with whichever backend we're using.
+    fn connect(&self) -> SshConnectionStateMachine {
+        match self.connection_settings.backend {
+            SshBackend::OpenSsh => {
+                SshConnectionStateMachine::Connecting(Connect::new(self.connection_settings.clone()))
+            },
+            SshBackend::Native { ref known_hosts } => {
+                SshConnectionStateMachine::NativeConnecting(native::Connect::new(self.connection_settings.clone(), known_hosts.clone()))
+            },
+        }
+    }
+}
+
+/// Whether the `ssh` master behind a control socket is still running. This blocks.
+pub fn is_alive(control_socket: &Path) -> bool {
+    control_command(control_socket, "check")
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Make an `ssh` master that nobody wants anymore exit.
+pub fn tear_down(control_socket: PathBuf) -> impl Future<Item=(), Error=()> {
+    Disconnect::new(control_socket)
+}
+
+/// A command that tells the `ssh` master behind a control socket to do something.
+fn control_command(control_socket: &Path, command: &str) -> Command {
+    let mut ssh = Command::new("ssh");
+    ssh.args(&["-O", command, "-S"])
+        .arg(control_socket)
+        .arg("_@localhost")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    ssh
+}
+
 type CommandFuture<T> = Box<dyn Future<Item=T, Error=()> + Send>;
@@ -0,0 +1,532 @@
+//! The native SSH backend: instead of spawning `ssh`, the client holds the SSH session itself
+//! (with libssh2). The private key never leaves memory, the server's host key has to be one that
+//! the server sent or in a known_hosts file, and we notice as soon as the session drops instead of
+//! on the next check.
+//!
+//! libssh2 blocks, so every session gets a thread of its own. The thread sets up the session,
+//! reports whether that worked, and then pumps data between the forwarded channels and their TCP
+//! connections until the session drops or somebody asks it to stop. When there's nothing to pump,
+//! it waits for one of the sockets to be ready. Connecting to where a remote forward goes can take
+//! a while, so that happens on a thread of its own, too.
+
+use super::{FailureReason, ForwardDirection, SshConnectionSettings};
+
+use std;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use libc;
+use ssh2::{self, BlockDirections, CheckResult, KnownHostFileKind};
+
+/// How long to wait for a TCP connection, to the SSH server or to where a forward goes.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to make sure that the SSH server is still there, in seconds.
+const KEEPALIVE_INTERVAL: u32 = 15;
+
+/// How long to wait for a socket to be ready, before looking whether we were asked to stop.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// How long to wait instead while connections to where a remote forward goes are being made.
+const CONNECTING_WAIT: Duration = Duration::from_millis(10);
+
+/// A future that sets up a native SSH session.
+///
+/// Resolves to the session if it was set up, and to why not if it wasn't.
+pub struct Connect {
+    ready: oneshot::Receiver<Result<(), String>>,
+    session: Option<Session>,
+}
+
+impl Connect {
+    pub fn new(settings: SshConnectionSettings, known_hosts: PathBuf) -> Connect {
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (ended_tx, ended_rx) = oneshot::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            // If the server sent the SSH server's host keys, those are the ones that count.
+            let result = settings.write_known_hosts().and_then(|pinned| {
+                let result = establish(&settings, pinned.as_ref().unwrap_or(&known_hosts));
+                if let Some(pinned) = pinned {
+                    let _ = std::fs::remove_file(pinned);
+                }
+                result
+            });
+            let session = match result {
+                Ok(session) => {
+                    let _ = ready_tx.send(Ok(()));
+                    session
+                },
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                },
+            };
+
+            let reason = session.run(&thread_stop);
+            let _ = ended_tx.send(reason);
+        });
+
+        Connect {
+            ready: ready_rx,
+            session: Some(Session { stop, ended: ended_rx }),
+        }
+    }
+}
+
+impl Future for Connect {
+    type Item = Result<Session, FailureReason>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match self.ready.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result,
+            Err(_) => Err("the session thread died".to_string()),
+        };
+
+        match result {
+            Ok(()) => Ok(Async::Ready(Ok(self.session.take().expect("polled a finished Connect")))),
+            Err(err) => Ok(Async::Ready(Err(FailureReason::new(None, err)))),
+        }
+    }
+}
+
+impl Drop for Connect {
+    fn drop(&mut self) {
+        // Nobody is waiting for the session anymore, so don't leave it running.
+        if let Some(ref session) = self.session {
+            session.stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An established native SSH session. As a future, it resolves (to the reason) when the session
+/// ends. Dropping it ends the session.
+pub struct Session {
+    stop: Arc<AtomicBool>,
+    ended: oneshot::Receiver<String>,
+}
+
+impl Session {
+    /// Ask the session to end. Keep polling to find out when it has.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Future for Session {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.ended.poll() {
+            Ok(result) => Ok(result),
+            Err(_) => Ok(Async::Ready("the session thread died".to_string())),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The SSH session, on its own thread.
+struct Established {
+    settings: SshConnectionSettings,
+    session: ssh2::Session,
+    accept: Accept,
+    /// Connections from the SSH server that are waiting for their connection to the target
+    connecting: Vec<Connecting>,
+    forwarded: Vec<Forwarded>,
+}
+
+/// Where forwarded connections come from.
+enum Accept {
+    /// The SSH server listens on the remote port, and opens a channel for every connection (`-R`)
+    Remote(ssh2::Listener),
+    /// We listen on the local port, and open a channel for every connection (`-L`)
+    Local(TcpListener),
+}
+
+/// A connection that the SSH server forwarded to us, while its connection to the target is being
+/// made.
+struct Connecting {
+    channel: ssh2::Channel,
+    stream: mpsc::Receiver<io::Result<TcpStream>>,
+}
+
+/// One forwarded connection: the SSH channel on one side, and the TCP connection on the other.
+struct Forwarded {
+    channel: ssh2::Channel,
+    stream: TcpStream,
+    /// What came from the channel, waiting to be written to the stream
+    to_stream: Vec<u8>,
+    /// What came from the stream, waiting to be written to the channel
+    to_channel: Vec<u8>,
+    /// Whether the channel has nothing more to send
+    channel_done: bool,
+    /// Whether the stream has nothing more to send
+    stream_done: bool,
+}
+
+/// Connect, check the host key, log in and set up the forward.
+fn establish(settings: &SshConnectionSettings, known_hosts: &Path) -> Result<Established, String> {
+    let (tcp, address) = match settings.proxy {
+        Some(ref proxy) => {
+            let tcp = proxy.connect(&settings.host, settings.port)
+                .map_err(|err| format!("Failed to connect to {}:{} through {}: {}", settings.host, settings.port, proxy, err))?;
+            (tcp, format!("{}:{}", settings.host, settings.port))
+        },
+        None => {
+            let address = (settings.host.as_str(), settings.port).to_socket_addrs()
+                .map_err(|err| format!("Failed to look up {}: {}", settings.host, err))?
+                .next()
+                .ok_or_else(|| format!("Failed to look up {}: no addresses", settings.host))?;
+            let tcp = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+                .map_err(|err| format!("Failed to connect to {}: {}", address, err))?;
+            (tcp, address.to_string())
+        },
+    };
+
+    let mut session = ssh2::Session::new()
+        .map_err(|err| format!("Failed to create an SSH session: {}", err))?;
+    session.set_tcp_stream(tcp);
+    session.handshake()
+        .map_err(|err| format!("SSH handshake with {} failed: {}", address, err))?;
+
+    check_host_key(&session, settings, known_hosts)?;
+
+    session.userauth_pubkey_memory(&settings.username, None, &settings.private_key, None)
+        .map_err(|err| format!("Authentication as {} failed: {}", settings.username, err))?;
+
+    let gateway_host = match settings.gateway_port {
+        true => "0.0.0.0",
+        false => "localhost",
+    };
+    let accept = match settings.direction {
+        ForwardDirection::Remote => {
+            let (listener, _) = session.channel_forward_listen(settings.remote_port, Some(gateway_host), None)
+                .map_err(|err| format!("The server wouldn't listen on port {}: {}", settings.remote_port, err))?;
+            Accept::Remote(listener)
+        },
+        ForwardDirection::Local { local_port } => {
+            let listener = TcpListener::bind((gateway_host, local_port))
+                .map_err(|err| format!("Failed to listen on port {}: {}", local_port, err))?;
+            listener.set_nonblocking(true)
+                .map_err(|err| format!("Failed to listen on port {}: {}", local_port, err))?;
+            Accept::Local(listener)
+        },
+        ForwardDirection::RemoteDynamic { .. } => {
+            return Err("dynamic forwards need the openssh backend".to_string());
+        },
+    };
+
+    session.set_keepalive(true, KEEPALIVE_INTERVAL);
+    session.set_blocking(false);
+
+    Ok(Established {
+        settings: settings.clone(),
+        session,
+        accept,
+        connecting: Vec::new(),
+        forwarded: Vec::new(),
+    })
+}
+
+/// Make sure that we're talking to the SSH server that we think we are, before we hand it a key.
+fn check_host_key(session: &ssh2::Session, settings: &SshConnectionSettings, known_hosts: &Path) -> Result<(), String> {
+    let (key, _) = session.host_key()
+        .ok_or_else(|| "The SSH server didn't send a host key".to_string())?;
+
+    let mut hosts = session.known_hosts()
+        .map_err(|err| format!("Failed to read {}: {}", known_hosts.display(), err))?;
+    hosts.read_file(known_hosts, KnownHostFileKind::OpenSSH)
+        .map_err(|err| format!("Failed to read {}: {}", known_hosts.display(), err))?;
+
+    match hosts.check_port(&settings.host, settings.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => Err(format!("The host key of {} is not in {}", settings.host, known_hosts.display())),
+        CheckResult::Mismatch => Err(format!("The host key of {} does not match the one in {}", settings.host, known_hosts.display())),
+        CheckResult::Failure => Err(format!("Failed to check the host key of {}", settings.host)),
+    }
+}
+
+impl Established {
+    /// Pump data until the session ends. Returns why it ended.
+    fn run(mut self, stop: &AtomicBool) -> String {
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                self.session.set_blocking(true);
+                let _ = self.session.disconnect(None, "disconnecting", None);
+                return "disconnected".to_string();
+            }
+
+            let mut busy = match self.accept().and_then(|accepted| Ok(self.finish_connecting()? || accepted)) {
+                Ok(busy) => busy,
+                Err(err) => return err,
+            };
+
+            let mut i = 0;
+            while i < self.forwarded.len() {
+                match self.forwarded[i].pump() {
+                    Ok(pumped) => {
+                        busy |= pumped;
+                        i += 1;
+                    },
+                    Err(_) => {
+                        // That connection is done, one way or another.
+                        let mut forwarded = self.forwarded.swap_remove(i);
+                        let _ = forwarded.channel.close();
+                        let _ = forwarded.stream.shutdown(Shutdown::Both);
+                    },
+                }
+            }
+
+            // This is what notices a session that went away without telling us.
+            match self.session.keepalive_send() {
+                Ok(_) => {},
+                Err(ref err) if would_block(err) => {},
+                Err(err) => return format!("the session dropped: {}", err),
+            }
+
+            if !busy {
+                self.wait();
+            }
+        }
+    }
+
+    /// Where remote forwards go.
+    fn forward_host(&self) -> &str {
+        match self.settings.forward_host.as_str() {
+            "" => "localhost",
+            host => host,
+        }
+    }
+
+    /// Take any new connection. Returns whether there was one, and an error if the session is
+    /// gone.
+    fn accept(&mut self) -> Result<bool, String> {
+        let (channel, stream) = match self.accept {
+            Accept::Remote(ref mut listener) => {
+                let channel = match listener.accept() {
+                    Ok(channel) => channel,
+                    Err(ref err) if would_block(err) => return Ok(false),
+                    Err(err) => return Err(format!("the session dropped: {}", err)),
+                };
+                let (sender, receiver) = mpsc::channel();
+                let target = (self.forward_host().to_string(), self.settings.forward_port);
+                thread::spawn(move || {
+                    let _ = sender.send(connect_target(&target.0, target.1));
+                });
+                self.connecting.push(Connecting { channel, stream: receiver });
+                return Ok(true);
+            },
+            Accept::Local(ref listener) => {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) => return Err(format!("failed to accept a connection: {}", err)),
+                };
+                // Opening the channel is a round trip to the server, so wait for it.
+                self.session.set_blocking(true);
+                let channel = self.session.channel_direct_tcpip(&self.settings.forward_host, self.settings.forward_port, None);
+                self.session.set_blocking(false);
+                match channel {
+                    Ok(channel) => (channel, stream),
+                    Err(err) => {
+                        println!("! SSH connection {} failed to reach {}:{}: {}", self.settings.id, self.settings.forward_host, self.settings.forward_port, err);
+                        return Ok(true);
+                    },
+                }
+            },
+        };
+
+        self.forward(channel, stream)?;
+        Ok(true)
+    }
+
+    /// Take the connections to the target that have been made since we last looked. Returns
+    /// whether there were any.
+    fn finish_connecting(&mut self) -> Result<bool, String> {
+        let mut busy = false;
+        let mut i = 0;
+        while i < self.connecting.len() {
+            let result = match self.connecting[i].stream.try_recv() {
+                Ok(result) => result,
+                Err(mpsc::TryRecvError::Empty) => {
+                    i += 1;
+                    continue;
+                },
+                Err(mpsc::TryRecvError::Disconnected) => Err(io::Error::new(io::ErrorKind::Other, "the connecting thread died")),
+            };
+
+            busy = true;
+            let Connecting { mut channel, .. } = self.connecting.swap_remove(i);
+            match result {
+                Ok(stream) => self.forward(channel, stream)?,
+                Err(err) => {
+                    println!("! SSH connection {} failed to connect to {}:{}: {}", self.settings.id, self.forward_host(), self.settings.forward_port, err);
+                    let _ = channel.close();
+                },
+            }
+        }
+        Ok(busy)
+    }
+
+    /// Start pumping data for a new forwarded connection.
+    fn forward(&mut self, channel: ssh2::Channel, stream: TcpStream) -> Result<(), String> {
+        stream.set_nonblocking(true)
+            .map_err(|err| format!("failed to set up a forwarded connection: {}", err))?;
+        self.forwarded.push(Forwarded {
+            channel,
+            stream,
+            to_stream: Vec::new(),
+            to_channel: Vec::new(),
+            channel_done: false,
+            stream_done: false,
+        });
+        Ok(())
+    }
+
+    /// Wait until the SSH server, the local listener or one of the forwarded connections has
+    /// something for us.
+    fn wait(&self) {
+        // Reading one channel can pull in data for the others, and then that data is waiting in
+        // libssh2 instead of the socket.
+        let buffered = self.forwarded.iter()
+            .any(|forwarded| forwarded.to_stream.is_empty() && !forwarded.channel_done && (forwarded.channel.read_window().available > 0 || forwarded.channel.eof()));
+        if buffered {
+            return;
+        }
+
+        let session_events = match self.session.block_directions() {
+            BlockDirections::Outbound | BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+            _ => libc::POLLIN,
+        };
+        let mut fds = vec![poll_fd(self.session.as_raw_fd(), session_events)];
+        if let Accept::Local(ref listener) = self.accept {
+            fds.push(poll_fd(listener.as_raw_fd(), libc::POLLIN));
+        }
+        for forwarded in &self.forwarded {
+            let mut events = 0;
+            if forwarded.to_channel.is_empty() && !forwarded.stream_done {
+                events |= libc::POLLIN;
+            }
+            if !forwarded.to_stream.is_empty() {
+                events |= libc::POLLOUT;
+            }
+            fds.push(poll_fd(forwarded.stream.as_raw_fd(), events));
+        }
+
+        // We don't hear about connections to the target being made, so look again soon.
+        let timeout = match self.connecting.is_empty() {
+            true => IDLE_WAIT,
+            false => CONNECTING_WAIT,
+        };
+        let timeout = timeout.as_secs() as libc::c_int * 1000 + timeout.subsec_millis() as libc::c_int;
+
+        // Whatever comes back, the next time around the loop finds out what's ready.
+        unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout);
+        }
+    }
+}
+
+fn poll_fd(fd: ::std::os::unix::io::RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd { fd, events, revents: 0 }
+}
+
+/// Connect to where a remote forward goes, trying every address it has.
+fn connect_target(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+impl Forwarded {
+    /// Move whatever data is ready in both directions. Returns whether anything moved, and an
+    /// error once the connection is finished.
+    fn pump(&mut self) -> io::Result<bool> {
+        let mut busy = false;
+        let mut buffer = [0; 16 * 1024];
+
+        if self.to_stream.is_empty() && !self.channel_done {
+            match self.channel.read(&mut buffer) {
+                Ok(0) => {
+                    if self.channel.eof() {
+                        self.channel_done = true;
+                        self.stream.shutdown(Shutdown::Write)?;
+                    }
+                },
+                Ok(n) => self.to_stream.extend_from_slice(&buffer[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                Err(err) => return Err(err),
+            }
+        }
+        busy |= write_some(&mut self.stream, &mut self.to_stream)?;
+
+        if self.to_channel.is_empty() && !self.stream_done {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    match self.channel.send_eof() {
+                        Ok(()) => self.stream_done = true,
+                        // Reading the stream again gets us back here to try again.
+                        Err(ref err) if would_block(err) => {},
+                        Err(err) => return Err(err.into()),
+                    }
+                },
+                Ok(n) => self.to_channel.extend_from_slice(&buffer[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                Err(err) => return Err(err),
+            }
+        }
+        busy |= write_some(&mut self.channel, &mut self.to_channel)?;
+
+        if self.channel_done && self.stream_done && self.to_stream.is_empty() && self.to_channel.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(busy)
+    }
+}
+
+/// Write as much of the buffer as the other end will take right now.
+fn write_some<W: Write>(writer: &mut W, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    if buffer.is_empty() {
+        return Ok(false);
+    }
+
+    match writer.write(buffer) {
+        Ok(n) => {
+            buffer.drain(..n);
+            Ok(true)
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// libssh2's "would block".
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// Whether an error only means that libssh2 would have had to wait.
+fn would_block(err: &ssh2::Error) -> bool {
+    err.code() == ssh2::ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
+}
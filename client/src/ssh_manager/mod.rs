@@ -3,6 +3,7 @@ use std::sync::{RwLock, Arc};
 use ssh_connection::{SshConnectionChange, SshConnectionHandle};
 
 /// An object that manages all of the SSH connections for a client.
+#[derive(Clone)]
 pub struct SshManager {
     state: Arc<RwLock<SshManagerState>>,
 }
@@ -29,15 +30,71 @@ impl SshManager {
         None
     }
 
-    /// Disable a specific connection.
-    pub fn disable(&self, id: &str) {
+    /// Get the current state of every connection, sorted by ID.
+    pub fn current_states(&self) -> Vec<(String, Option<SshConnectionChange>)> {
+        let manager = self.state.read().unwrap();
+
+        let mut states: Vec<_> = manager.connections.iter()
+            .map(|(id, connection)| (id.clone(), connection.last_change.clone()))
+            .collect();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+
+    /// Whether a connection is being kept up by an SSH connection stream. Adopted connections
+    /// aren't, until the server asks for them again.
+    pub fn is_managed(&self, id: &str) -> bool {
+        let manager = self.state.read().unwrap();
+
+        manager.connections.get(&id.to_string())
+            .map(|connection| connection.handle.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Get the IDs of the connections that were adopted and are still waiting for the server to
+    /// ask for them.
+    pub fn adopted(&self) -> Vec<String> {
+        let manager = self.state.read().unwrap();
+
+        let mut ids: Vec<String> = manager.connections.iter()
+            .filter(|&(_, connection)| connection.handle.is_none() && connection.last_change == Some(SshConnectionChange::Connected))
+            .map(|(id, _)| id.clone())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Disable a specific connection. Returns whether there was a connection to disable.
+    pub fn disable(&self, id: &str) -> bool {
         let manager = self.state.read().unwrap();
 
         if let Some(connection) = manager.connections.get(&id.to_string()) {
             if let Some(ref handle) = connection.handle {
                 handle.disconnect();
+                return true;
             }
         }
+        false
+    }
+
+    /// Disable a specific connection, because the local policy doesn't allow it. Its stream
+    /// reports the rejection instead of the disconnect. Returns whether there was a running
+    /// connection to disable.
+    pub fn reject(&self, id: &str, reason: &str) -> bool {
+        let mut manager = self.state.write().unwrap();
+
+        if let Some(connection) = manager.connections.get_mut(&id.to_string()) {
+            if connection.last_change == Some(SshConnectionChange::Disconnected) {
+                // Its stream is done, so there's nobody left to report anything.
+                return false;
+            }
+            if let Some(ref handle) = connection.handle {
+                connection.rejected = Some(reason.to_string());
+                handle.disconnect();
+                return true;
+            }
+        }
+        false
     }
 
     /// Get a reference to the SSH manager.
@@ -62,7 +119,7 @@ impl SshManagerRef {
     pub fn update_state(&self, id: &str, state: &SshConnectionChange) {
         let mut manager = self.state.write().unwrap();
         let item = manager.connections.entry(id.to_string())
-            .or_insert_with(|| SshManagerItem { last_change: None, handle: None });
+            .or_insert_with(|| SshManagerItem { last_change: None, handle: None, rejected: None });
 
         item.last_change = Some(state.clone());
     }
@@ -72,10 +129,19 @@ impl SshManagerRef {
     pub fn register_handle(&self, id: &str, handle: SshConnectionHandle) {
         let mut manager = self.state.write().unwrap();
         let item = manager.connections.entry(id.to_string())
-            .or_insert_with(|| SshManagerItem { last_change: None, handle: None });
+            .or_insert_with(|| SshManagerItem { last_change: None, handle: None, rejected: None });
 
         item.handle = Some(handle);
     }
+
+    /// Why the local policy rejected a connection that was being torn down, if it did. Only
+    /// reported once.
+    pub fn take_rejection(&self, id: &str) -> Option<String> {
+        let mut manager = self.state.write().unwrap();
+
+        manager.connections.get_mut(&id.to_string())
+            .and_then(|connection| connection.rejected.take())
+    }
 }
 
 struct SshManagerState {
@@ -85,6 +151,8 @@ struct SshManagerState {
 struct SshManagerItem {
     last_change: Option<SshConnectionChange>,
     handle: Option<SshConnectionHandle>,
+    /// Why the local policy rejected the connection, while it's being torn down
+    rejected: Option<String>,
 }
 
 impl SshManagerState {
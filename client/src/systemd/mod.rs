@@ -0,0 +1,158 @@
+//! Telling systemd how we're doing, for when the client runs as a `Type=notify` service (see
+//! sd_notify(3)). systemd gives us a datagram socket in `$NOTIFY_SOCKET`, and we send it lines of
+//! `NAME=VALUE`:
+//!
+//! * `READY=1` once we're connected to the server and have told it who we are
+//! * `WATCHDOG=1` every so often, but only while the server connection is healthy, so that
+//!   `WatchdogSec=` gets a client that's stuck restarted
+//! * `STATUS=...` with what the server connection and the SSH connections are doing, which
+//!   `systemctl status` shows
+//!
+//! Without `$NOTIFY_SOCKET`, none of this does anything.
+
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::{self, Future, Stream};
+use tokio_timer::Interval;
+
+use control::ServerStatus;
+use server_connection::ServerConnectionHandle;
+use ssh_connection::SshConnectionChange;
+use ssh_manager::SshManager;
+
+/// How often to update the status when there's no watchdog to keep up with.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where to tell systemd how we're doing.
+#[derive(Clone)]
+pub struct Notifier {
+    socket: Option<Arc<(UnixDatagram, PathBuf)>>,
+    /// How often systemd wants to hear from us, if it's watching
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Find the socket that systemd gave us, if it gave us one.
+    pub fn from_env() -> Notifier {
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(ref path) if !path.is_empty() => PathBuf::from(path),
+            _ => return Notifier { socket: None, watchdog: None },
+        };
+
+        // systemd never uses abstract sockets for services, and the standard library can't send
+        // to them anyway.
+        if path.to_string_lossy().starts_with('@') {
+            println!("! $NOTIFY_SOCKET is an abstract socket ({}), which isn't supported, so systemd won't hear from us", path.display());
+            return Notifier { socket: None, watchdog: None };
+        }
+
+        let socket = match UnixDatagram::unbound() {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("! Failed to make a socket to talk to systemd: {}", err);
+                return Notifier { socket: None, watchdog: None };
+            },
+        };
+
+        Notifier {
+            socket: Some(Arc::new((socket, path))),
+            watchdog: watchdog_timeout(),
+        }
+    }
+
+    /// Tell systemd something. It's not the end of the world if it doesn't hear it.
+    pub fn notify(&self, state: &str) {
+        if let Some(ref socket) = self.socket {
+            let (ref socket, ref path) = **socket;
+            if let Err(err) = socket.send_to(state.as_bytes(), path) {
+                println!("! Failed to notify systemd ({}): {}", state.lines().next().unwrap_or(""), err);
+            }
+        }
+    }
+
+    /// Tell systemd that we're up and running.
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Keep telling systemd what we're doing and, if it's watching, that we're healthy. Returns a
+    /// future that runs forever.
+    pub fn watch(self, server_status: ServerStatus, server_connection: ServerConnectionHandle, ssh_manager: SshManager) -> Box<dyn Future<Item=(), Error=()> + Send> {
+        if self.socket.is_none() {
+            return Box::new(futures::future::ok(()));
+        }
+
+        // systemd suggests going twice as often as it wants to hear from us.
+        let interval = self.watchdog.map_or(STATUS_INTERVAL, |watchdog| watchdog / 2);
+        let mut last_status = String::new();
+        let future = Interval::new(Instant::now() + interval, interval)
+            .map_err(|err| println!("! The systemd timer failed: {}", err))
+            .for_each(move |_| {
+                let status = status(&server_status, &server_connection, &ssh_manager);
+                let mut state = Vec::new();
+                if self.watchdog.is_some() && server_status.is_healthy() {
+                    state.push("WATCHDOG=1".to_string());
+                }
+                if status != last_status {
+                    state.push(format!("STATUS={}", status));
+                    last_status = status;
+                }
+                if !state.is_empty() {
+                    self.notify(&state.join("\n"));
+                }
+                Ok(())
+            });
+
+        Box::new(future)
+    }
+}
+
+/// How long systemd waits to hear from us before restarting us, if it's watching us (and not some
+/// other process).
+fn watchdog_timeout() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_string_lossy().parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC").ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|&usec| usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// A line about what we're doing, e.g. "connected (host.tld:4004); SSH connections: 2 connected,
+/// 1 failed".
+fn status(server_status: &ServerStatus, server_connection: &ServerConnectionHandle, ssh_manager: &SshManager) -> String {
+    let mut status = format!("{} ({})", server_status.state(), server_connection.endpoint());
+
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for (_, state) in ssh_manager.current_states() {
+        let state = match state {
+            Some(SshConnectionChange::Connecting) => "connecting",
+            Some(SshConnectionChange::Connected) => "connected",
+            Some(SshConnectionChange::Disconnecting) => "disconnecting",
+            Some(SshConnectionChange::Disconnected) => "disconnected",
+            Some(SshConnectionChange::Failed(..)) => "failed",
+            None => "requested",
+        };
+        match counts.iter_mut().find(|(name, _)| *name == state) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((state, 1)),
+        }
+    }
+
+    if counts.is_empty() {
+        status.push_str("; SSH connections: none");
+    }
+    else {
+        let counts: Vec<String> = counts.iter().map(|(state, count)| format!("{} {}", count, state)).collect();
+        status.push_str(&format!("; SSH connections: {}", counts.join(", ")));
+    }
+    status
+}
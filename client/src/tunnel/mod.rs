@@ -0,0 +1,133 @@
+//! The client half of native tunnels. The server accepts connections on the remote port of a
+//! NATIVE forward and tells us about them with `TunnelOpen`. We connect to the forward's target
+//! and pump data over the server connection.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use futures::Future;
+use futures::sync::mpsc::Sender;
+use tokio;
+use tokio_dns;
+
+use connectbot_shared::tunnel::{event_channel, EventSender, TunnelFrame, TunnelStream};
+
+type Streams = Arc<Mutex<HashMap<u32, EventSender>>>;
+
+/// Where a NATIVE forward sends its traffic.
+#[derive(Debug, Clone)]
+struct TunnelTarget {
+    host: String,
+    port: u16,
+}
+
+/// All of the NATIVE forwards that the server has enabled, and their open streams.
+pub struct TunnelManager {
+    /// Where streams send their frames. These get wrapped up into messages for the server.
+    frame_sender: Sender<TunnelFrame>,
+    /// The target of every enabled forward, by connection ID
+    targets: HashMap<String, TunnelTarget>,
+    /// The event channel of every open stream
+    streams: Streams,
+}
+
+impl TunnelManager {
+    /// Create a new tunnel manager that sends its frames to the given channel.
+    pub fn new(frame_sender: Sender<TunnelFrame>) -> TunnelManager {
+        TunnelManager {
+            frame_sender,
+            targets: HashMap::new(),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Allow the server to open streams for the given forward. This is idempotent.
+    pub fn enable(&mut self, id: &str, forward_host: &str, forward_port: u16) {
+        let host = match forward_host {
+            "" => "localhost".to_string(),
+            host => host.to_string(),
+        };
+
+        self.targets.insert(id.to_string(), TunnelTarget { host, port: forward_port });
+    }
+
+    /// Stop allowing streams for the given forward. Returns whether the forward was enabled.
+    pub fn disable(&mut self, id: &str) -> bool {
+        self.targets.remove(id).is_some()
+    }
+
+    /// Abort every open stream. Stream IDs belong to a single server connection, so this needs to
+    /// happen whenever we reconnect.
+    pub fn reset(&self) {
+        self.streams.lock().unwrap().clear();
+    }
+
+    /// Handle a tunnel frame that the server sent. Must be called from within a tokio runtime.
+    pub fn on_frame(&self, frame: TunnelFrame) {
+        match frame {
+            TunnelFrame::Open { stream_id, connection_id } => {
+                self.open(stream_id, &connection_id);
+            },
+            frame => {
+                let stream_id = frame.stream_id();
+                let event = match frame.into_event() {
+                    Some(event) => event,
+                    None => return,
+                };
+
+                let mut streams = self.streams.lock().unwrap();
+                let failed = match streams.get(&stream_id) {
+                    Some(sender) => sender.send(event).is_err(),
+                    None => false,
+                };
+
+                if failed {
+                    // The server sent more than the window allows, or the stream is finishing up.
+                    println!("! Dropping tunnel stream {}", stream_id);
+                    streams.remove(&stream_id);
+                }
+            },
+        }
+    }
+
+    /// Connect a new stream to its forward's target.
+    fn open(&self, stream_id: u32, connection_id: &str) {
+        let frame_sender = self.frame_sender.clone();
+
+        let target = match self.targets.get(connection_id) {
+            Some(target) => target.clone(),
+            None => {
+                // We don't know about this forward (anymore). Tell the server to give up.
+                println!("! Tunnel stream {} is for unknown connection {}", stream_id, connection_id);
+                let _ = frame_sender.clone().try_send(TunnelFrame::Close { stream_id });
+                return;
+            },
+        };
+
+        let (event_sender, event_receiver) = event_channel();
+        self.streams.lock().unwrap().insert(stream_id, event_sender);
+
+        let streams = self.streams.clone();
+        let future = tokio_dns::TcpStream::connect((target.host.as_str(), target.port))
+            .then(move |result| {
+                match result {
+                    Ok(socket) => {
+                        let future = TunnelStream::new(stream_id, socket, event_receiver, frame_sender)
+                            .then(move |result| {
+                                streams.lock().unwrap().remove(&stream_id);
+                                result
+                            });
+                        tokio::spawn(future);
+                    },
+                    Err(err) => {
+                        println!("! Tunnel stream {} failed to connect to {}:{}: {}", stream_id, target.host, target.port, err);
+                        streams.lock().unwrap().remove(&stream_id);
+                        let _ = frame_sender.clone().try_send(TunnelFrame::Close { stream_id });
+                    },
+                }
+
+                Ok(())
+            });
+
+        tokio::spawn(future);
+    }
+}
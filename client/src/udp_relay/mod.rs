@@ -0,0 +1,276 @@
+//! The client half of UDP forwards. The server relays datagrams from remote peers to us, and we
+//! send each one to the forward's target from a local socket that belongs to the peer's
+//! association, so replies find their way back to the right peer.
+
+use std;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use device;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::future::poll_fn;
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use tokio;
+use tokio::net::UdpSocket;
+use tokio_threadpool::blocking;
+use tokio_timer::Interval;
+
+use connectbot_shared::udp::{self, Datagram, MAX_DATAGRAM_SIZE};
+
+/// How many datagrams from the server can be waiting to go out of a single association.
+const INCOMING_BUFFER: usize = 64;
+
+type Associations = Arc<Mutex<HashMap<(String, u32), Sender<Bytes>>>>;
+
+/// Where a UDP forward sends its datagrams.
+#[derive(Debug, Clone)]
+struct UdpTarget {
+    host: String,
+    port: u16,
+    /// How long an association may go without any traffic
+    idle_timeout: Duration,
+}
+
+/// All of the UDP forwards that the server has enabled, and their active associations.
+pub struct UdpRelayManager {
+    /// The channel on which to send messages to the server
+    sender: Sender<device::ClientMessage>,
+    /// The target of every enabled forward, by connection ID
+    targets: HashMap<String, UdpTarget>,
+    /// The datagram channel of every active association, by connection ID and association ID
+    associations: Associations,
+}
+
+impl UdpRelayManager {
+    /// Create a new relay manager that sends datagrams to the server on the given channel.
+    pub fn new(sender: Sender<device::ClientMessage>) -> UdpRelayManager {
+        UdpRelayManager {
+            sender,
+            targets: HashMap::new(),
+            associations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Start relaying datagrams for the given forward. This is idempotent.
+    pub fn enable(&mut self, id: &str, forward_host: &str, forward_port: u16, idle_timeout: Duration) {
+        let host = match forward_host {
+            "" => "localhost".to_string(),
+            host => host.to_string(),
+        };
+
+        self.targets.insert(id.to_string(), UdpTarget { host, port: forward_port, idle_timeout });
+    }
+
+    /// Stop relaying datagrams for the given forward. Returns whether the forward was enabled.
+    pub fn disable(&mut self, id: &str) -> bool {
+        // Dropping the senders ends the associations.
+        self.associations.lock().unwrap().retain(|(connection_id, _), _| connection_id != id);
+        self.targets.remove(id).is_some()
+    }
+
+    /// Forget every association. Association IDs belong to a single server connection, so this
+    /// needs to happen whenever we reconnect.
+    pub fn reset(&self) {
+        self.associations.lock().unwrap().clear();
+    }
+
+    /// Handle a datagram from the server. Must be called from within a tokio runtime.
+    pub fn on_datagram(&self, datagram: Datagram) {
+        let key = (datagram.connection_id.clone(), datagram.association_id);
+
+        {
+            let mut associations = self.associations.lock().unwrap();
+            if let Some(sender) = associations.get_mut(&key) {
+                // If the association is backed up, drop the datagram.
+                let _ = sender.try_send(datagram.data);
+                return;
+            }
+        }
+
+        let target = match self.targets.get(&datagram.connection_id) {
+            Some(target) => target.clone(),
+            None => {
+                println!("! Dropping datagram for unknown connection {}", datagram.connection_id);
+                return;
+            },
+        };
+
+        // A new association. Queue up this first datagram, and then get a socket for it.
+        let (mut association_sender, association_receiver) = channel(INCOMING_BUFFER);
+        let _ = association_sender.try_send(datagram.data);
+        self.associations.lock().unwrap().insert(key.clone(), association_sender);
+
+        let associations = self.associations.clone();
+        let sender = self.sender.clone();
+        let idle_timeout = target.idle_timeout;
+        let resolve = poll_fn(move || {
+            let target = target.clone();
+            blocking(move || {
+                (target.host.as_str(), target.port).to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+            }).map_err(|_| panic!("the threadpool shut down"))
+        });
+
+        let future = resolve.then(move |result| {
+            let (connection_id, association_id) = key.clone();
+            let socket = match result {
+                Ok(Some(addr)) => connect_socket(&addr),
+                Ok(None) => Err(std::io::Error::new(std::io::ErrorKind::Other, "failed to resolve target")),
+                Err(()) => Err(std::io::Error::new(std::io::ErrorKind::Other, "failed to resolve target")),
+            };
+
+            match socket {
+                Ok(socket) => {
+                    let association = UdpAssociation::new(connection_id, association_id, socket, association_receiver, sender, idle_timeout);
+                    let future = association.then(move |_| {
+                        associations.lock().unwrap().remove(&key);
+                        Ok(())
+                    });
+                    tokio::spawn(future);
+                },
+                Err(err) => {
+                    println!("! UDP relay {} failed to reach its target: {}", connection_id, err);
+                    associations.lock().unwrap().remove(&key);
+                },
+            }
+
+            Ok(())
+        });
+
+        tokio::spawn(future);
+    }
+}
+
+/// Bind a local socket and connect it to the target, so that it only receives the target's
+/// replies.
+fn connect_socket(target: &SocketAddr) -> Result<UdpSocket, std::io::Error> {
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(&local)?;
+    socket.connect(target)?;
+    Ok(socket)
+}
+
+/// A future that relays datagrams between the server and the target for a single association.
+/// Resolves once the association has been idle for too long, or the forward is disabled.
+struct UdpAssociation {
+    connection_id: String,
+    association_id: u32,
+    socket: UdpSocket,
+    /// Datagrams from the server
+    incoming: Receiver<Bytes>,
+    /// The channel on which to send messages to the server
+    outgoing: Sender<device::ClientMessage>,
+    /// A message for the server that is waiting for room on `outgoing`
+    pending_message: Option<device::ClientMessage>,
+    /// A datagram from the server that is waiting for the socket
+    pending_send: Option<Bytes>,
+    last_activity: Instant,
+    /// How long we may go without any traffic before we're done
+    idle_timeout: Duration,
+    /// When to check whether we've been idle for too long
+    idle_check: Interval,
+    /// Where replies from the target get read into
+    buffer: Vec<u8>,
+}
+
+impl UdpAssociation {
+    fn new(connection_id: String, association_id: u32, socket: UdpSocket, incoming: Receiver<Bytes>, outgoing: Sender<device::ClientMessage>, idle_timeout: Duration) -> UdpAssociation {
+        UdpAssociation {
+            connection_id,
+            association_id,
+            socket,
+            incoming,
+            outgoing,
+            pending_message: None,
+            pending_send: None,
+            last_activity: Instant::now(),
+            idle_timeout,
+            idle_check: Interval::new_interval(Duration::from_secs(5)),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    fn poll_inner(&mut self) -> Poll<(), std::io::Error> {
+        loop {
+            let mut progress = false;
+
+            while let Ok(Async::Ready(Some(_))) = self.idle_check.poll() {
+                if udp::is_idle(self.last_activity, Instant::now(), self.idle_timeout) {
+                    return Ok(Async::Ready(()));
+                }
+            }
+
+            // Send anything to the server that is waiting.
+            if let Some(message) = self.pending_message.take() {
+                match self.outgoing.start_send(message).map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))? {
+                    AsyncSink::Ready => progress = true,
+                    AsyncSink::NotReady(message) => self.pending_message = Some(message),
+                }
+            }
+            self.outgoing.poll_complete().map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+
+            // Read a reply from the target, if the server connection has room for it.
+            if self.pending_message.is_none() {
+                if let Async::Ready(n) = self.socket.poll_recv(&mut self.buffer)? {
+                    let datagram = Datagram {
+                        connection_id: self.connection_id.clone(),
+                        association_id: self.association_id,
+                        data: Bytes::from(&self.buffer[..n]),
+                    };
+                    self.pending_message = Some(datagram.into_client_message());
+                    self.last_activity = Instant::now();
+                    progress = true;
+                }
+            }
+
+            // Send a datagram from the server to the target.
+            if self.pending_send.is_none() {
+                match self.incoming.poll() {
+                    Ok(Async::Ready(Some(data))) => {
+                        self.pending_send = Some(data);
+                        self.last_activity = Instant::now();
+                        progress = true;
+                    },
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        // The forward was disabled.
+                        return Ok(Async::Ready(()));
+                    },
+                    Ok(Async::NotReady) => {},
+                }
+            }
+            if let Some(data) = self.pending_send.take() {
+                match self.socket.poll_send(&data) {
+                    Ok(Async::Ready(_)) => progress = true,
+                    Ok(Async::NotReady) => self.pending_send = Some(data),
+                    Err(err) => {
+                        // Probably an ICMP unreachable from an earlier datagram. UDP doesn't
+                        // promise delivery, so drop this one and keep going.
+                        println!("! UDP relay {} failed to send: {}", self.connection_id, err);
+                        progress = true;
+                    },
+                }
+            }
+
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+impl Future for UdpAssociation {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.poll_inner().map_err(|err| {
+            println!("! UDP relay {} association {} stopped: {}", self.connection_id, self.association_id, err);
+        })
+    }
+}
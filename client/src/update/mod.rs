@@ -0,0 +1,416 @@
+//! Updating ourselves. The server tells us when it has a newer version for us, and we download it
+//! over the server connection. If its signature (of the version and the SHA-256 digest of the
+//! binary) checks out against the public key that we were built with, we put it in place of the
+//! current binary and exec it. We never go back to an older version, so that an old binary with a
+//! valid signature can't be used to bring back whatever was wrong with it.
+//!
+//! The new version starts out on trial. If it can't connect to the server and get its Initialize
+//! acknowledged within `trial_timeout()`, it puts the old binary back and execs that instead. If the
+//! new version dies before then and gets restarted by whatever supervises us, it notices that the
+//! trial never finished and rolls back straight away.
+//!
+//! Clients only accept updates if they were built with `CONNECTBOT_UPDATE_PUBLIC_KEY` set to the
+//! hex-encoded Ed25519 public key that updates are signed with.
+
+use std;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use connectbot_shared::update::{compare_versions, signed_message};
+use device;
+use futures::Future;
+use ring;
+use tokio_timer::Delay;
+use untrusted;
+
+/// Set in the environment of a new version that is on trial.
+const TRIAL_ENV: &str = "CONNECTBOT_UPDATE_TRIAL";
+
+/// Whether the server has acknowledged our Initialize since we started.
+static CONFIRMED: AtomicBool = AtomicBool::new(false);
+
+/// How long a new version has to prove that it works.
+fn trial_timeout() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// The public key that updates have to be signed with, if we were built with one.
+fn public_key() -> Option<Vec<u8>> {
+    option_env!("CONNECTBOT_UPDATE_PUBLIC_KEY").and_then(decode_hex)
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    if value.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..value.len()).step_by(2)
+        .map(|i| value.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Where all of the files involved in an update live. They're all next to the current binary, so
+/// that renaming between them is atomic.
+struct Paths {
+    /// The binary that we're running
+    current: PathBuf,
+    /// Where the new version gets downloaded to
+    new: PathBuf,
+    /// Where the previous version is kept while the new version is on trial
+    old: PathBuf,
+    /// Exists while a new version is on trial
+    marker: PathBuf,
+}
+
+fn paths() -> io::Result<Paths> {
+    let current = std::env::current_exe()?;
+    let with_extension = |extension: &str| {
+        let mut path = current.clone().into_os_string();
+        path.push(extension);
+        PathBuf::from(path)
+    };
+
+    Ok(Paths {
+        new: with_extension(".new"),
+        old: with_extension(".old"),
+        marker: with_extension(".trial"),
+        current,
+    })
+}
+
+/// Check on any update that's in progress. Call this before doing anything else.
+///
+/// Returns whether we're a new version on trial, in which case `watchdog()` needs to run. If the
+/// previous trial didn't finish, this rolls back and never returns (unless it fails).
+pub fn startup() -> io::Result<bool> {
+    let paths = paths()?;
+    if !paths.marker.exists() {
+        return Ok(false);
+    }
+
+    if std::env::var_os(TRIAL_ENV).is_some() {
+        // We were just installed. Don't pass that on to anything we run.
+        std::env::remove_var(TRIAL_ENV);
+        println!("! Trying out the new version");
+        return Ok(true);
+    }
+
+    // The new version was started by something other than the update, which means the trial run
+    // died.
+    println!("! The new version did not start successfully. Rolling back.");
+    Err(rollback(&paths))
+}
+
+/// Roll back to the previous version if the new version hasn't proven itself in time.
+pub fn watchdog() -> impl Future<Item=(), Error=()> {
+    Delay::new(Instant::now() + trial_timeout())
+        .then(|_| {
+            if !CONFIRMED.load(Ordering::SeqCst) {
+                println!("! The new version did not connect in time. Rolling back.");
+                match paths() {
+                    Ok(paths) => println!("! Failed to roll back: {}", rollback(&paths)),
+                    Err(err) => println!("! Failed to roll back: {}", err),
+                }
+            }
+            Ok(())
+        })
+}
+
+/// The server acknowledged our Initialize. If we're on trial, we passed.
+pub fn confirm() {
+    if CONFIRMED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    if let Ok(paths) = paths() {
+        if paths.marker.exists() {
+            println!("! The new version works");
+            let _ = fs::remove_file(&paths.marker);
+            let _ = fs::remove_file(&paths.old);
+        }
+    }
+}
+
+/// Put the previous version back, and exec it. Only returns if that fails.
+fn rollback(paths: &Paths) -> io::Error {
+    if let Err(err) = fs::rename(&paths.old, &paths.current) {
+        return err;
+    }
+    let _ = fs::remove_file(&paths.marker);
+
+    Command::new(&paths.current)
+        .args(std::env::args_os().skip(1))
+        .env_remove(TRIAL_ENV)
+        .exec()
+}
+
+/// An update that is being downloaded.
+struct Download {
+    version: String,
+    size: u64,
+    signature: Vec<u8>,
+    file: File,
+    received: u64,
+}
+
+/// What to do after a chunk of an update arrived.
+pub enum UpdateProgress {
+    /// Nothing
+    Nothing,
+    /// Ask the server for more
+    Request(device::ClientMessage),
+    /// The download is complete. Call `install()`.
+    Install,
+}
+
+/// Keeps track of downloading an update.
+pub struct Updater {
+    download: Option<Download>,
+}
+
+impl Updater {
+    pub fn new() -> Updater {
+        Updater {
+            download: None,
+        }
+    }
+
+    /// The server has a different version for us. Returns the request for the first chunk we
+    /// need, if we want it. If we already started downloading that version (and got
+    /// disconnected), we pick up where we left off.
+    pub fn on_update_available(&mut self, update: &device::UpdateAvailable) -> Option<device::ClientMessage> {
+        if public_key().is_none() {
+            println!("! Ignoring update to {}: this client was built without an update key", update.get_version());
+            return None;
+        }
+        if compare_versions(update.get_version(), env!("CARGO_PKG_VERSION")) != cmp::Ordering::Greater {
+            println!("! Ignoring update to {}: it isn't newer than this version ({})", update.get_version(), env!("CARGO_PKG_VERSION"));
+            return None;
+        }
+
+        if let Some(ref download) = self.download {
+            if download.version == update.get_version() && download.size == update.get_size() {
+                return Some(chunk_request(&download.version, download.received));
+            }
+        }
+
+        let file = paths().and_then(|paths| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o700)
+                .open(&paths.new)
+        });
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                println!("! Failed to start downloading update {}: {}", update.get_version(), err);
+                return None;
+            },
+        };
+
+        println!("! Downloading update {} ({} bytes)", update.get_version(), update.get_size());
+        self.download = Some(Download {
+            version: update.get_version().to_string(),
+            size: update.get_size(),
+            signature: update.get_signature().to_vec(),
+            file,
+            received: 0,
+        });
+
+        Some(chunk_request(update.get_version(), 0))
+    }
+
+    /// A piece of the update arrived.
+    pub fn on_chunk(&mut self, chunk: &device::UpdateChunk) -> UpdateProgress {
+        let expected = self.download.as_ref()
+            .map_or(false, |download| download.version == chunk.get_version() && download.received == chunk.get_offset());
+        if !expected {
+            // Something we're not waiting for. Maybe left over from before a reconnect.
+            return UpdateProgress::Nothing;
+        }
+
+        let result = Self::write_chunk(self.download.as_mut().unwrap(), chunk);
+
+        match result {
+            Ok(true) => UpdateProgress::Install,
+            Ok(false) => {
+                let download = self.download.as_ref().unwrap();
+                UpdateProgress::Request(chunk_request(&download.version, download.received))
+            },
+            Err(err) => {
+                println!("! Failed to download update: {}", err);
+                self.download = None;
+                if let Ok(paths) = paths() {
+                    let _ = fs::remove_file(&paths.new);
+                }
+                UpdateProgress::Nothing
+            },
+        }
+    }
+
+    /// Write a chunk to the file. Returns whether the download is complete.
+    fn write_chunk(download: &mut Download, chunk: &device::UpdateChunk) -> Result<bool, String> {
+        if !chunk.get_error().is_empty() {
+            return Err(chunk.get_error().to_string());
+        }
+        if chunk.get_data().is_empty() {
+            return Err(format!("The update ended after {} of {} bytes", download.received, download.size));
+        }
+
+        download.file.write_all(chunk.get_data())
+            .map_err(|err| format!("Failed to write the update: {}", err))?;
+        download.received += chunk.get_data().len() as u64;
+
+        Ok(download.received >= download.size)
+    }
+
+    /// Check the downloaded update, put it in place, and exec it. Only returns if something went
+    /// wrong, in which case we keep running the current version.
+    pub fn install(&mut self) -> String {
+        let download = match self.download.take() {
+            Some(download) => download,
+            None => return "There is no update to install".to_string(),
+        };
+
+        let paths = match paths() {
+            Ok(paths) => paths,
+            Err(err) => return format!("Failed to find the current binary: {}", err),
+        };
+
+        match Self::verify(download, &paths) {
+            Ok(version) => {
+                println!("! Installing update {}", version);
+            },
+            Err(err) => {
+                let _ = fs::remove_file(&paths.new);
+                return err;
+            },
+        }
+
+        // Keep the current version around in case the new one doesn't work, then swap the new
+        // one in. The rename is atomic, so the current path always has a working binary.
+        let _ = fs::remove_file(&paths.old);
+        if let Err(err) = fs::hard_link(&paths.current, &paths.old) {
+            return format!("Failed to keep the current version: {}", err);
+        }
+        if let Err(err) = File::create(&paths.marker) {
+            return format!("Failed to start the trial: {}", err);
+        }
+        if let Err(err) = fs::rename(&paths.new, &paths.current) {
+            let _ = fs::remove_file(&paths.marker);
+            return format!("Failed to install the update: {}", err);
+        }
+
+        let err = Command::new(&paths.current)
+            .args(std::env::args_os().skip(1))
+            .env(TRIAL_ENV, "1")
+            .exec();
+
+        // We couldn't even start the new version. Go back to the old one.
+        println!("! Failed to run the new version: {}", err);
+        format!("Failed to roll back: {}", rollback(&paths))
+    }
+
+    /// Check the downloaded binary's signature. Returns its version.
+    fn verify(download: Download, paths: &Paths) -> Result<String, String> {
+        let Download { version, signature, file, .. } = download;
+        file.sync_all()
+            .map_err(|err| format!("Failed to write the update: {}", err))?;
+        drop(file);
+
+        let mut data = Vec::new();
+        File::open(&paths.new)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|err| format!("Failed to read the update: {}", err))?;
+
+        let public_key = public_key()
+            .ok_or_else(|| "This client was built without an update key".to_string())?;
+        check_signature(&public_key, &version, &data, &signature)?;
+
+        fs::set_permissions(&paths.new, fs::Permissions::from_mode(0o755))
+            .map_err(|err| format!("Failed to make the update executable: {}", err))?;
+
+        Ok(version)
+    }
+}
+
+/// Check that an update's signature covers its version and its binary.
+fn check_signature(public_key: &[u8], version: &str, binary: &[u8], signature: &[u8]) -> Result<(), String> {
+    ring::signature::verify(
+        &ring::signature::ED25519,
+        untrusted::Input::from(public_key),
+        untrusted::Input::from(&signed_message(version, binary)),
+        untrusted::Input::from(signature),
+    ).map_err(|_| format!("The signature of update {} is not valid", version))
+}
+
+fn chunk_request(version: &str, offset: u64) -> device::ClientMessage {
+    let mut request = device::UpdateChunkRequest::new();
+    request.set_version(version.into());
+    request.set_offset(offset);
+
+    let mut message = device::ClientMessage::new();
+    message.set_update_chunk_request(request);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8)).unwrap()
+    }
+
+    #[test]
+    fn signatures_of_the_version_and_binary_are_accepted() {
+        let key_pair = key_pair();
+        let signature = key_pair.sign(&signed_message("1.2.0", b"binary"));
+
+        assert!(check_signature(key_pair.public_key_bytes(), "1.2.0", b"binary", signature.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn signatures_of_another_version_are_rejected() {
+        let key_pair = key_pair();
+        let signature = key_pair.sign(&signed_message("1.0.0", b"binary"));
+
+        assert!(check_signature(key_pair.public_key_bytes(), "1.2.0", b"binary", signature.as_ref()).is_err());
+    }
+
+    #[test]
+    fn signatures_of_another_binary_are_rejected() {
+        let key_pair = key_pair();
+        let signature = key_pair.sign(&signed_message("1.2.0", b"binary"));
+
+        assert!(check_signature(key_pair.public_key_bytes(), "1.2.0", b"tampered binary", signature.as_ref()).is_err());
+    }
+
+    #[test]
+    fn signatures_of_just_the_binary_are_rejected() {
+        let key_pair = key_pair();
+        let signature = key_pair.sign(b"binary");
+
+        assert!(check_signature(key_pair.public_key_bytes(), "1.2.0", b"binary", signature.as_ref()).is_err());
+    }
+
+    #[test]
+    fn signatures_by_another_key_are_rejected() {
+        let signature = key_pair().sign(&signed_message("1.2.0", b"binary"));
+
+        assert!(check_signature(key_pair().public_key_bytes(), "1.2.0", b"binary", signature.as_ref()).is_err());
+        assert!(check_signature(&[0; 32], "1.2.0", b"binary", &[0; 64]).is_err());
+    }
+}
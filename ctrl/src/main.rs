@@ -6,8 +6,15 @@ extern crate tokio_codec;
 extern crate connectbot_shared;
 
 use clap::{App, AppSettings, Arg, SubCommand};
-use futures::Future;
+use futures::{Future, Stream};
+use futures::future::{self, Loop};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use connectbot_shared::client::Client as CommsClient;
+use connectbot_shared::file_transfer::{Checksum, CHUNK_SIZE, PARTIAL_SUFFIX};
+use connectbot_shared::protos::control;
 
 fn main() {
     let matches = App::new("connectbot-ctrl")
@@ -44,6 +51,68 @@ fn main() {
                          })
                          .help("The local port to forward")
                          .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("transport")
+                         .short("t")
+                         .long("transport")
+                         .help("How to carry the forward: `ssh` spawns ssh on the device, `native` tunnels over the device connection")
+                         .takes_value(true)
+                         .possible_values(&["ssh", "native"])
+                         .default_value("ssh"))
+                    .arg(Arg::with_name("protocol")
+                         .long("protocol")
+                         .help("The protocol to forward. `udp` always tunnels over the device connection")
+                         .takes_value(true)
+                         .possible_values(&["tcp", "udp"])
+                         .default_value("tcp")))
+        .subcommand(SubCommand::with_name("connect-dynamic")
+                    .about("Open a SOCKS5 proxy into a device's network")
+                    .arg(Arg::with_name("device")
+                         .help("The id of the device to connect")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("allow")
+                         .short("a")
+                         .long("allow")
+                         .help("A destination (host:port, either may be *) that the proxy may reach. Can be given more than once")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)))
+        .subcommand(SubCommand::with_name("connect-local")
+                    .about("Give a device access to a service that the server can reach")
+                    .arg(Arg::with_name("device")
+                         .help("The id of the device to connect")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("host")
+                         .help("The host to forward to, as seen from the server")
+                         .required(true)
+                         .takes_value(true)
+                         .default_value("localhost"))
+                    .arg(Arg::with_name("port")
+                         .short("p")
+                         .long("port")
+                         .validator(|item| {
+                             match item.parse::<u16>() {
+                                 Ok(0) => Err("Port must be a valid port number".to_string()),
+                                 Ok(_) => Ok(()),
+                                 Err(_) => Err("Port must be a valid port number".to_string()),
+                             }
+                         })
+                         .help("The port to forward to")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("local-port")
+                         .short("l")
+                         .long("local-port")
+                         .validator(|item| {
+                             match item.parse::<u16>() {
+                                 Ok(0) => Err("Port must be a valid port number".to_string()),
+                                 Ok(_) => Ok(()),
+                                 Err(_) => Err("Port must be a valid port number".to_string()),
+                             }
+                         })
+                         .help("The port on the device to listen on. Defaults to the forwarded port")
                          .takes_value(true)))
         .subcommand(SubCommand::with_name("disconnect")
                     .about("Disconnect an SSH connection")
@@ -81,6 +150,12 @@ fn main() {
                          .help("The id of the device to create")
                          .required(true)
                          .takes_value(true)))
+        .subcommand(SubCommand::with_name("create-token")
+                    .about("Create a one-time token that a device can enroll with, to get its client certificate")
+                    .arg(Arg::with_name("device")
+                         .long("device")
+                         .help("Only let this device enroll with the token")
+                         .takes_value(true)))
         .subcommand(SubCommand::with_name("set-name")
                     .about("Change the human-friendly name of a device")
                     .arg(Arg::with_name("device")
@@ -91,6 +166,57 @@ fn main() {
                          .help("The new name of the device")
                          .required(true)
                          .takes_value(true)))
+        .subcommand(SubCommand::with_name("discover")
+                    .about("Ask a device to look for services on its network")
+                    .arg(Arg::with_name("device")
+                         .help("The id of the device")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("subnet")
+                         .long("subnet")
+                         .help("Also scan the subnet that the device was configured with")))
+        .subcommand(SubCommand::with_name("metrics")
+                    .about("Dump the recent values of a device's metrics")
+                    .arg(Arg::with_name("device")
+                         .help("The id of the device")
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("exec")
+                    .about("Run one of a device's actions, and exit with its exit code")
+                    .arg(Arg::with_name("device")
+                         .help("The id of the device to run the action on")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("action")
+                         .help("The name of the action to run")
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("push")
+                    .about("Copy a file to a device. An interrupted push picks up where it left off.")
+                    .arg(Arg::with_name("device")
+                         .help("The id of the device to copy the file to")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("path")
+                         .help("The absolute path on the device, in one of the device's file directories")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("local")
+                         .help("The file to copy. Defaults to the file name of the path on the device.")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("pull")
+                    .about("Copy a file from a device. An interrupted pull picks up where it left off.")
+                    .arg(Arg::with_name("device")
+                         .help("The id of the device to copy the file from")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("path")
+                         .help("The absolute path on the device, in one of the device's file directories")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("local")
+                         .help("Where to put the file. Defaults to the file name of the path on the device.")
+                         .takes_value(true)))
         .get_matches();
 
     // let id = matches.value_of("id").unwrap();
@@ -99,12 +225,20 @@ fn main() {
 
     match matches.subcommand() {
         ("connect", Some(matches)) => connect(client, matches),
+        ("connect-dynamic", Some(matches)) => connect_dynamic(client, matches),
+        ("connect-local", Some(matches)) => connect_local(client, matches),
         ("disconnect", Some(matches)) => disconnect(client, matches),
         ("extend", Some(matches)) => extend(client, matches),
         ("query", Some(matches)) => query(client, matches),
         ("create", Some(matches)) => create(client, matches),
         ("remove", Some(matches)) => remove(client, matches),
+        ("create-token", Some(matches)) => create_token(client, matches),
         ("set-name", Some(matches)) => set_name(client, matches),
+        ("discover", Some(matches)) => discover(client, matches),
+        ("metrics", Some(matches)) => metrics(client, matches),
+        ("exec", Some(matches)) => exec(client, matches),
+        ("push", Some(matches)) => push(client, matches),
+        ("pull", Some(matches)) => pull(client, matches),
         _ => {},
     }
 }
@@ -113,7 +247,43 @@ fn connect(client: CommsClient, matches: &clap::ArgMatches) {
     let device_id = matches.value_of("device").unwrap();
     let host = matches.value_of("host").unwrap();
     let port = matches.value_of("port").unwrap().parse().unwrap();
-    let future = client.connect_device(device_id, host, port)
+    let transport = match matches.value_of("transport").unwrap() {
+        "native" => control::SshConnection_Transport::NATIVE,
+        _ => control::SshConnection_Transport::SSH,
+    };
+    let protocol = match matches.value_of("protocol").unwrap() {
+        "udp" => control::SshConnection_Protocol::UDP,
+        _ => control::SshConnection_Protocol::TCP,
+    };
+    let future = client.connect_device(device_id, host, port, transport, protocol)
+        .map(|response| {
+            println!("{:#?}", response);
+        })
+        .map_err(|e| println!("Error: {}", e));
+
+    tokio::run(future);
+}
+
+fn connect_dynamic(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device").unwrap();
+    let permitted_destinations: Vec<String> = matches.values_of("allow")
+        .map(|values| values.map(|value| value.to_string()).collect())
+        .unwrap_or_else(Vec::new);
+    let future = client.connect_device_dynamic(device_id, &permitted_destinations)
+        .map(|response| {
+            println!("{:#?}", response);
+        })
+        .map_err(|e| println!("Error: {}", e));
+
+    tokio::run(future);
+}
+
+fn connect_local(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device").unwrap();
+    let host = matches.value_of("host").unwrap();
+    let port = matches.value_of("port").unwrap().parse().unwrap();
+    let local_port = matches.value_of("local-port").map(|port| port.parse().unwrap()).unwrap_or(port);
+    let future = client.connect_device_local(device_id, host, port, local_port)
         .map(|response| {
             println!("{:#?}", response);
         })
@@ -167,6 +337,22 @@ fn create(client: CommsClient, matches: &clap::ArgMatches) {
     tokio::run(future);
 }
 
+fn create_token(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device");
+    let future = client.create_bootstrap_token(device_id)
+        .map(|response| {
+            if response.get_token().is_empty() {
+                println!("Error: {}", response.get_error());
+                std::process::exit(1);
+            }
+            println!("{}", response.get_token());
+            eprintln!("The token can be used once, until {} (seconds since the epoch).", response.get_expires());
+        })
+        .map_err(|e| println!("Error: {}", e));
+
+    tokio::run(future);
+}
+
 fn remove(client: CommsClient, matches: &clap::ArgMatches) {
     let device_id = matches.value_of("device").unwrap();
     let future = client.remove_device(device_id)
@@ -189,3 +375,230 @@ fn set_name(client: CommsClient, matches: &clap::ArgMatches) {
 
     tokio::run(future);
 }
+
+fn discover(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device").unwrap();
+    let future = client.discover(device_id, matches.is_present("subnet"))
+        .map(|response| {
+            println!("{:#?}", response);
+        })
+        .map_err(|e| println!("Error: {}", e));
+
+    tokio::run(future);
+}
+
+fn metrics(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device").unwrap();
+    let future = client.get_metrics(device_id)
+        .map(|response| {
+            println!("{:#?}", response);
+        })
+        .map_err(|e| println!("Error: {}", e));
+
+    tokio::run(future);
+}
+
+fn exec(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device").unwrap();
+    let action = matches.value_of("action").unwrap().to_string();
+
+    // If we never hear how the action finished, something went wrong.
+    let exit_code = Arc::new(Mutex::new(1));
+    let final_exit_code = exit_code.clone();
+
+    let future = client.exec(device_id, &action)
+        .for_each(move |response| {
+            match response.get_status() {
+                control::ExecResponse_Status::OUTPUT => {
+                    let _ = match response.get_stream() {
+                        control::ExecResponse_Stream::STDOUT => std::io::stdout().write_all(response.get_data()),
+                        control::ExecResponse_Stream::STDERR => std::io::stderr().write_all(response.get_data()),
+                    };
+                },
+                control::ExecResponse_Status::EXITED => {
+                    *exit_code.lock().unwrap() = response.get_exit_code();
+                },
+                control::ExecResponse_Status::REJECTED => {
+                    eprintln!("The device does not allow the action {:?}", action);
+                },
+                control::ExecResponse_Status::FAILED | control::ExecResponse_Status::UNKNOWN_STATUS => {
+                    eprintln!("Error: {}", response.get_error());
+                },
+            }
+            Ok(())
+        })
+        .map_err(|e| eprintln!("Error: {}", e));
+
+    tokio::run(future);
+
+    let exit_code = *final_exit_code.lock().unwrap();
+    std::process::exit(exit_code);
+}
+
+/// The local file for a push or pull: the one that was given, or the file name of the path on the
+/// device.
+fn local_path(matches: &clap::ArgMatches) -> String {
+    match matches.value_of("local") {
+        Some(local) => local.to_string(),
+        None => {
+            let path = matches.value_of("path").unwrap();
+            Path::new(path).file_name()
+                .map(|file_name| file_name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.to_string())
+        },
+    }
+}
+
+/// Turn a response that wasn't successful into an error message.
+fn file_transfer_error(response: &control::FileTransferResponse) -> Option<String> {
+    match response.get_status() {
+        control::FileTransferResponse_Status::SUCCESS => None,
+        control::FileTransferResponse_Status::REJECTED => Some(format!("The device does not allow the path: {}", response.get_error())),
+        control::FileTransferResponse_Status::FAILED | control::FileTransferResponse_Status::UNKNOWN_STATUS => Some(response.get_error().to_string()),
+    }
+}
+
+fn push(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device").unwrap().to_string();
+    let path = matches.value_of("path").unwrap().to_string();
+    let local = local_path(matches);
+
+    let size = match fs::metadata(&local) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => {
+            eprintln!("Error: {}: {}", local, err);
+            std::process::exit(1);
+        },
+    };
+    let sha256 = match Checksum::of_file(&local) {
+        Ok(sha256) => sha256,
+        Err(err) => {
+            eprintln!("Error: {}: {}", local, err);
+            std::process::exit(1);
+        },
+    };
+
+    let result = Arc::new(Mutex::new(Err("The push did not finish".to_string())));
+    let final_result = result.clone();
+
+    // Pick up where a previous push left off, unless the device somehow has more than we do.
+    let future = client.resume_file(&device_id, &path)
+        .map_err(|e| format!("{}", e))
+        .and_then(move |response| {
+            if let Some(error) = file_transfer_error(&response) {
+                return future::Either::A(future::err(error));
+            }
+            let offset = if response.get_size() <= size { response.get_size() } else { 0 };
+            if offset > 0 {
+                println!("Resuming at {} of {} bytes", offset, size);
+            }
+
+            let f = future::loop_fn(offset, move |offset| {
+                let mut data = Vec::with_capacity(CHUNK_SIZE);
+                let read = File::open(&local)
+                    .and_then(|mut file| {
+                        file.seek(SeekFrom::Start(offset))?;
+                        file.take(CHUNK_SIZE as u64).read_to_end(&mut data)
+                    });
+                if let Err(err) = read {
+                    return future::Either::A(future::err(format!("{}: {}", local, err)));
+                }
+
+                let last = offset + data.len() as u64 >= size;
+                let f = client.write_file(&device_id, &path, offset, data, if last { &sha256 } else { "" })
+                    .map_err(|e| format!("{}", e))
+                    .and_then(move |response| {
+                        if let Some(error) = file_transfer_error(&response) {
+                            return Err(error);
+                        }
+                        if last {
+                            Ok(Loop::Break(response.get_size()))
+                        }
+                        else {
+                            Ok(Loop::Continue(response.get_size()))
+                        }
+                    });
+                future::Either::B(f)
+            });
+            future::Either::B(f)
+        })
+        .then(move |outcome| {
+            *result.lock().unwrap() = outcome;
+            Ok::<(), ()>(())
+        });
+
+    tokio::run(future);
+
+    let result = final_result.lock().unwrap().clone();
+    match result {
+        Ok(size) => println!("Pushed {} bytes", size),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn pull(client: CommsClient, matches: &clap::ArgMatches) {
+    let device_id = matches.value_of("device").unwrap().to_string();
+    let path = matches.value_of("path").unwrap().to_string();
+    let local = local_path(matches);
+    let partial = format!("{}{}", local, PARTIAL_SUFFIX);
+
+    // Pick up where a previous pull left off.
+    let offset = fs::metadata(&partial).map(|metadata| metadata.len()).unwrap_or(0);
+    if offset > 0 {
+        println!("Resuming at {} bytes", offset);
+    }
+
+    let result = Arc::new(Mutex::new(Err("The pull did not finish".to_string())));
+    let final_result = result.clone();
+
+    let future = future::loop_fn(offset, move |offset| {
+        let partial = partial.clone();
+        let local = local.clone();
+        client.read_file(&device_id, &path, offset)
+            .map_err(|e| format!("{}", e))
+            .and_then(move |response| {
+                if let Some(error) = file_transfer_error(&response) {
+                    return Err(error);
+                }
+
+                if response.get_data().is_empty() {
+                    // That's everything. Make sure it all made it.
+                    let sha256 = Checksum::of_file(&partial)
+                        .map_err(|err| format!("{}: {}", partial, err))?;
+                    if sha256 != response.get_sha256() {
+                        let _ = fs::remove_file(&partial);
+                        return Err(format!("The checksum does not match ({}, expected {}). The file may have changed on the device; try again.", sha256, response.get_sha256()));
+                    }
+                    fs::rename(&partial, &local)
+                        .map_err(|err| format!("{}: {}", local, err))?;
+                    return Ok(Loop::Break(offset));
+                }
+
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&partial)
+                    .and_then(|mut file| file.write_all(response.get_data()))
+                    .map_err(|err| format!("{}: {}", partial, err))?;
+                Ok(Loop::Continue(offset + response.get_data().len() as u64))
+            })
+    })
+        .then(move |outcome| {
+            *result.lock().unwrap() = outcome;
+            Ok::<(), ()>(())
+        });
+
+    tokio::run(future);
+
+    let result = final_result.lock().unwrap().clone();
+    match result {
+        Ok(size) => println!("Pulled {} bytes", size),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            std::process::exit(1);
+        },
+    }
+}
@@ -5,6 +5,8 @@ use std::io::Read;
 use std::sync::Arc;
 use toml;
 
+use connectbot_shared::enrollment::CertificateAuthority;
+
 pub type SharedConfig = Arc<ApplicationConfig>;
 
 /// The structure that represents the configuration toml file.
@@ -14,12 +16,30 @@ pub struct ApplicationConfig {
     pub address: String,
     /// The address/port to listen on for control (connectbot-ctrl, connectbot-web) connections.
     pub control_address: String,
+    /// The most devices that may be connected at once. Devices beyond that are told to come back
+    /// later. If this isn't set, any number of devices may connect.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// How long devices that were turned away should wait before connecting again, in seconds.
+    /// They add a random wait of their own on top.
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u32,
     /// TLS information (see below)
     pub tls: Tls,
     /// SSH information (see below)
     pub ssh: Ssh,
     /// Client authentication information (see below)
     pub client_authentication: Option<ClientAuthentication>,
+    /// Where devices can connect over WebSocket (see below). If this isn't set, they can't.
+    #[serde(default)]
+    pub websocket: Option<WebSocket>,
+    /// How devices get client certificates (see below). If this isn't set, they can't get them
+    /// from the server.
+    #[serde(default)]
+    pub enrollment: Option<Enrollment>,
+    /// Client binaries to offer devices (see below)
+    #[serde(default)]
+    pub updates: Vec<Update>,
 }
 
 impl Default for ApplicationConfig {
@@ -28,12 +48,21 @@ impl Default for ApplicationConfig {
             tls: Default::default(),
             ssh: Default::default(),
             client_authentication: Some(Default::default()),
+            websocket: None,
+            enrollment: None,
             address: "[::]:4004".to_string(),
             control_address: "[::1]:12345".to_string(),
+            updates: Vec::new(),
+            max_connections: None,
+            retry_after: default_retry_after(),
         }
     }
 }
 
+fn default_retry_after() -> u32 {
+    60
+}
+
 impl ApplicationConfig {
     pub fn from_file(config_file: &Path) -> Result<ApplicationConfig, String> {
         let mut data = String::new();
@@ -87,6 +116,81 @@ impl Default for ClientAuthentication {
     }
 }
 
+/// A listener for devices that can only get out over HTTPS. They send the same messages, as binary
+/// WebSocket messages.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebSocket {
+    /// The address/port to listen on, e.g. "[::]:443".
+    pub address: String,
+    /// The path that devices ask to upgrade. Anything else gets a 404.
+    #[serde(default = "default_websocket_path")]
+    pub path: String,
+    /// Whether to do TLS (wss://) with the server's certificate. Turn this off when a reverse
+    /// proxy in front of the server takes care of TLS.
+    #[serde(default = "default_websocket_tls")]
+    pub tls: bool,
+    /// Without TLS, the request header that the reverse proxy puts the client's certificate in,
+    /// as URL-encoded PEM (like nginx's `$ssl_client_escaped_cert`). Whatever is in it is
+    /// believed, so the proxy has to check the certificate against `client_authentication.ca`
+    /// and never pass the header on from the client. The server won't start with `tls = false`
+    /// and `client_authentication.required` unless this is set.
+    #[serde(default)]
+    pub client_certificate_header: Option<String>,
+}
+
+fn default_websocket_path() -> String {
+    "/".to_string()
+}
+
+fn default_websocket_tls() -> bool {
+    true
+}
+
+/// A CA that issues client certificates to devices that present a bootstrap token (made with
+/// `connectbot-ctrl create-token`). `client_authentication.ca` has to trust this CA for the
+/// certificates to be any use.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Enrollment {
+    /// The path to the CA's certificate
+    pub ca_certificate: String,
+    /// The path to the CA's private key (RSA or ECDSA)
+    pub ca_key: String,
+    /// How long the certificates that are issued are valid for, in days. Devices renew them when
+    /// there's less than a third of that left.
+    #[serde(default = "default_certificate_days")]
+    pub certificate_days: u32,
+    /// How long bootstrap tokens can be used for, in hours.
+    #[serde(default = "default_token_hours")]
+    pub token_hours: u32,
+    /// The CA, once it's loaded.
+    #[serde(skip_serializing)]
+    #[serde(skip_deserializing)]
+    pub authority: Option<Arc<CertificateAuthority>>,
+}
+
+fn default_certificate_days() -> u32 {
+    90
+}
+
+fn default_token_hours() -> u32 {
+    24
+}
+
+/// A connectbot-client binary to offer to devices that are running an older version.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Update {
+    /// The version of the binary, as the client reports it (its Cargo version).
+    pub version: String,
+    /// The architecture the binary was built for, as the client reports it (e.g. "arm"). If this
+    /// isn't set, the binary is offered to every device.
+    pub arch: Option<String>,
+    /// The path to the binary.
+    pub binary: String,
+    /// The path to the detached Ed25519 signature (64 raw bytes) of the version followed by the
+    /// SHA-256 digest of the binary.
+    pub signature: String,
+}
+
 /// Information about how to establish SSH connections
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Ssh {
@@ -98,6 +202,12 @@ pub struct Ssh {
     pub user: Option<String>,
     /// The path to the SSH private key to send clients.
     pub private_key: Option<String>,
+    /// The SSH server's host public keys, as in a known_hosts file (`ssh-ed25519 AAAA...`).
+    /// Clients only connect to an SSH server that has one of these. While rotating host keys, list
+    /// both the old and the new key. If this is empty, clients with the `ssh` backend can't
+    /// connect, and the native backend checks its own known_hosts file.
+    #[serde(default)]
+    pub host_keys: Vec<String>,
     /// The start of the port range to use for non-web forwards.
     pub port_start: u16,
     /// The end of the port range (inclusive) to use for non-web forwards.
@@ -106,6 +216,21 @@ pub struct Ssh {
     pub web_port_start: u16,
     /// The end of the port range (inclusive) to use for web forwards.
     pub web_port_end: u16,
+    /// The start of the port range to use for UDP forwards.
+    #[serde(default = "default_udp_port_start")]
+    pub udp_port_start: u16,
+    /// The end of the port range (inclusive) to use for UDP forwards.
+    #[serde(default = "default_udp_port_end")]
+    pub udp_port_end: u16,
+    /// How many seconds a peer of a UDP forward may go without any traffic before the server and
+    /// the device forget about it.
+    #[serde(default = "default_udp_idle_timeout")]
+    pub udp_idle_timeout: u32,
+    /// The destinations (`host:port`, where either may be `*`) that dynamic forwards may reach.
+    /// Dynamic forwards may ask for a subset of these, and get all of them if they don't ask. If
+    /// this isn't set, dynamic forwards may reach anything.
+    #[serde(default)]
+    pub dynamic_destinations: Option<Vec<String>>,
     /// The contents of the SSH private key.
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
@@ -119,11 +244,29 @@ impl Default for Ssh {
             port: Some(22),
             user: Some("reversessh".into()),
             private_key: Some("/home/reversessh/.ssh/id_rsa".into()),
+            host_keys: Vec::new(),
             private_key_data: None,
             port_start: 10000,
             port_end: 10999,
             web_port_start: 7000,
             web_port_end: 7016,
+            udp_port_start: default_udp_port_start(),
+            udp_port_end: default_udp_port_end(),
+            udp_idle_timeout: default_udp_idle_timeout(),
+            dynamic_destinations: None,
         }
     }
 }
+
+// UDP forwards came later than the other port ranges, so existing config files won't have these.
+fn default_udp_port_start() -> u16 {
+    11000
+}
+
+fn default_udp_port_end() -> u16 {
+    11999
+}
+
+fn default_udp_idle_timeout() -> u32 {
+    60
+}
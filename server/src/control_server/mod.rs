@@ -6,22 +6,29 @@ use tokio;
 use tokio::net::TcpStream;
 use tokio_codec;
 use futures::{self, Stream, Sink, Future};
+use chrono::{self, Utc};
 
 use connectbot_shared::codec::Codec;
 use connectbot_shared::protos::control;
 
+use super::config::SharedConfig;
+use super::device_server::discovery::Discovery;
+use super::device_server::exec::{ExecEvent, ExecStream};
+use super::device_server::files::{FileOperation, FileRequest, FileResult};
 use super::world::{self, SharedWorld};
 
 /// The control server.
 pub struct Server {
     world: SharedWorld,
+    config: SharedConfig,
 }
 
 impl Server {
     /// Create a new control server
-    pub fn new(world: world::SharedWorld) -> Server {
+    pub fn new(world: world::SharedWorld, config: SharedConfig) -> Server {
         Server {
             world: world,
+            config: config,
         }
     }
 
@@ -45,6 +52,7 @@ impl Server {
         }));
 
         let world = self.world.clone();
+        let config = self.config.clone();
 
         stream.for_each(move |mut message| -> Box<dyn Future<Item=(), Error=std::io::Error> + Send> {
             if message.has_clients_request() {
@@ -57,6 +65,8 @@ impl Server {
                         let mut client_data = control::ClientsResponse_Client::new();
                         client_data.set_id(device.id.clone().into());
                         client_data.set_name(device.name.clone().into());
+                        client_data.set_actions(device.actions.iter().map(|action| action.as_str().into()).collect::<Vec<_>>().into());
+                        client_data.set_file_dirs(device.file_dirs.iter().map(|dir| dir.as_str().into()).collect::<Vec<_>>().into());
                         if let world::ConnectionStatus::Connected { ref address } = device.connection_status {
                             client_data.set_address(address.to_string().into());
                         }
@@ -72,6 +82,7 @@ impl Server {
                                 world::SshForwardClientState::Disconnecting => control::ClientsResponse_ClientState::DISCONNECTING,
                                 world::SshForwardClientState::Disconnected => control::ClientsResponse_ClientState::DISCONNECTED,
                                 world::SshForwardClientState::Failed => control::ClientsResponse_ClientState::FAILED,
+                                world::SshForwardClientState::Rejected => control::ClientsResponse_ClientState::REJECTED,
                             });
                             match forward.server_state {
                                 world::SshForwardServerState::Active { until } => {
@@ -87,6 +98,44 @@ impl Server {
                             connection.set_forward_port(forward.forward_port as u32);
                             connection.set_remote_port(forward.remote_port.as_ref().map_or(0, |item| item.value() as u32));
                             connection.set_gateway_port(forward.gateway_port);
+                            connection.set_transport(match forward.transport {
+                                world::SshForwardTransport::Ssh => control::SshConnection_Transport::SSH,
+                                world::SshForwardTransport::Native => control::SshConnection_Transport::NATIVE,
+                            });
+                            connection.set_protocol(match forward.protocol {
+                                world::SshForwardProtocol::Tcp => control::SshConnection_Protocol::TCP,
+                                world::SshForwardProtocol::Udp => control::SshConnection_Protocol::UDP,
+                            });
+                            match forward.direction {
+                                world::SshForwardDirection::Remote => {
+                                    connection.set_direction(control::SshConnection_Direction::REMOTE);
+                                },
+                                world::SshForwardDirection::Local { local_port } => {
+                                    connection.set_direction(control::SshConnection_Direction::LOCAL);
+                                    connection.set_local_port(local_port as u32);
+                                },
+                            }
+                            connection.set_dynamic(forward.dynamic);
+                            connection.set_permitted_destinations(forward.permitted_destinations.iter().map(|destination| destination.as_str().into()).collect::<Vec<_>>().into());
+                            if let Some(ref failure) = forward.last_failure {
+                                let mut last_failure = control::ClientsResponse_Failure::new();
+                                last_failure.set_failures(failure.failures);
+                                last_failure.set_kind(match failure.kind {
+                                    world::SshForwardFailureKind::Unknown => control::ClientsResponse_FailureKind::UNKNOWN_FAILURE,
+                                    world::SshForwardFailureKind::AuthDenied => control::ClientsResponse_FailureKind::AUTH_DENIED,
+                                    world::SshForwardFailureKind::PortInUse => control::ClientsResponse_FailureKind::PORT_IN_USE,
+                                    world::SshForwardFailureKind::HostUnreachable => control::ClientsResponse_FailureKind::HOST_UNREACHABLE,
+                                    world::SshForwardFailureKind::HostKeyMismatch => control::ClientsResponse_FailureKind::HOST_KEY_MISMATCH,
+                                    world::SshForwardFailureKind::TargetRefused => control::ClientsResponse_FailureKind::TARGET_REFUSED,
+                                });
+                                last_failure.set_exit_code(failure.exit_code.unwrap_or(-1));
+                                last_failure.set_detail(failure.detail.as_str().into());
+                                last_failure.set_reported_at(failure.reported_at.timestamp() as u64);
+                                connection.set_last_failure(last_failure);
+                            }
+                            if let Some(ref reason) = forward.rejected_reason {
+                                connection.set_rejected_reason(reason.as_str().into());
+                            }
                             connections.push(connection);
                         }
                         client_data.set_connections(connections.into());
@@ -111,6 +160,54 @@ impl Server {
                         }
                         client_data.set_connection_history(connection_history.into());
 
+                        let exec_history: Vec<_> = device.exec_history.iter()
+                            .map(|item| {
+                                let mut history_item = control::ClientsResponse_ExecHistoryItem::new();
+                                history_item.set_request_id(item.request_id);
+                                history_item.set_action(item.action.as_str().into());
+                                match item.outcome {
+                                    world::ExecOutcome::Exited(exit_code) => {
+                                        history_item.set_status(control::ExecResponse_Status::EXITED);
+                                        history_item.set_exit_code(exit_code);
+                                    },
+                                    world::ExecOutcome::Rejected => {
+                                        history_item.set_status(control::ExecResponse_Status::REJECTED);
+                                    },
+                                    world::ExecOutcome::Failed(ref error) => {
+                                        history_item.set_status(control::ExecResponse_Status::FAILED);
+                                        history_item.set_error(error.as_str().into());
+                                    },
+                                }
+                                history_item.set_started_at(item.started_at.timestamp() as u64);
+                                history_item.set_finished_at(item.finished_at.timestamp() as u64);
+                                history_item
+                            })
+                            .collect();
+                        client_data.set_exec_history(exec_history.into());
+
+                        if let Some(ref facts) = device.facts {
+                            let mut facts_data = control::ClientsResponse_Facts::new();
+                            facts_data.set_hostname(facts.hostname.as_str().into());
+                            facts_data.set_os(facts.os.as_str().into());
+                            facts_data.set_kernel(facts.kernel.as_str().into());
+                            facts_data.set_arch(facts.arch.as_str().into());
+                            facts_data.set_client_version(facts.client_version.as_str().into());
+                            facts_data.set_uptime(facts.uptime);
+                            let interfaces: Vec<_> = facts.interfaces.iter()
+                                .map(|interface| {
+                                    let mut interface_data = control::ClientsResponse_Facts_Interface::new();
+                                    interface_data.set_name(interface.name.as_str().into());
+                                    interface_data.set_addresses(interface.addresses.iter().map(|address| address.as_str().into()).collect::<Vec<_>>().into());
+                                    interface_data
+                                })
+                                .collect();
+                            facts_data.set_interfaces(interfaces.into());
+                            facts_data.set_ssh_version(facts.ssh_version.as_str().into());
+                            facts_data.set_server_endpoint(facts.server_endpoint.as_str().into());
+                            facts_data.set_reported_at(facts.reported_at.timestamp() as u64);
+                            client_data.set_facts(facts_data);
+                        }
+
                         clients.push(client_data);
                     }
                 }
@@ -143,24 +240,111 @@ impl Server {
 
                     let mut backchannel_future = None;
 
-                    if let Some(device) = device {
-                        let forward_host = enable.get_forward_host();
-                        // TODO: Make sure this is non-zero u16
-                        let forward_port = enable.get_forward_port();
-                        let gateway_port = enable.get_gateway_port();
-                        let forward = device.ssh_forwards.create(forward_host.into(), forward_port as u16, gateway_port);
+                    // Dynamic forwards can only reach what the configuration allows.
+                    let permitted_destinations = match enable.get_dynamic() {
+                        true => {
+                            let requested: Vec<String> = enable.get_permitted_destinations().iter().map(|destination| destination.to_string()).collect();
+                            world::permitted_destinations(&requested, config.ssh.dynamic_destinations.as_ref().map(Vec::as_slice))
+                        },
+                        false => Ok(Vec::new()),
+                    };
+
+                    match (device, permitted_destinations) {
+                        (Some(device), Ok(permitted_destinations)) => {
+                            let gateway_port = enable.get_gateway_port();
+                            let forward = if enable.get_dynamic() {
+                                device.ssh_forwards.create_dynamic(gateway_port, permitted_destinations)
+                            }
+                            else {
+                                let forward_host = enable.get_forward_host();
+                                // TODO: Make sure this is non-zero u16
+                                let forward_port = enable.get_forward_port();
+                                let transport = match enable.get_transport() {
+                                    control::SshConnection_Transport::SSH => world::SshForwardTransport::Ssh,
+                                    control::SshConnection_Transport::NATIVE => world::SshForwardTransport::Native,
+                                };
+                                let protocol = match enable.get_protocol() {
+                                    control::SshConnection_Protocol::TCP => world::SshForwardProtocol::Tcp,
+                                    control::SshConnection_Protocol::UDP => world::SshForwardProtocol::Udp,
+                                };
+                                device.ssh_forwards.create(forward_host.into(), forward_port as u16, gateway_port, transport, protocol)
+                            };
+
+                            ssh_connection_response.set_status(control::SshConnectionResponse_Status::SUCCESS);
+                            ssh_connection_response.set_connection_id(forward.id.clone().into());
+                            ssh_connection_response.set_remote_port(forward.remote_port.as_ref().map_or(0, |item| item.value() as u32));
+
+                            if let Some(ref handle) = device.active_connection {
+                                backchannel_future = Some(handle.connect_ssh(&forward.id.clone()));
+                            }
+                        },
+                        (_, Err(err)) => {
+                            println!("! Refusing dynamic forward for {}: {}", device_id, err);
+                            ssh_connection_response.set_status(control::SshConnectionResponse_Status::ERROR);
+                            ssh_connection_response.set_error(err.into());
+                        },
+                        (None, _) => {
+                            ssh_connection_response.set_status(control::SshConnectionResponse_Status::ERROR);
+                            ssh_connection_response.set_error(format!("Unknown device {}", device_id).into());
+                        },
+                    }
 
-                        ssh_connection_response.set_status(control::SshConnectionResponse_Status::SUCCESS);
-                        ssh_connection_response.set_connection_id(forward.id.clone().into());
-                        ssh_connection_response.set_remote_port(forward.remote_port.as_ref().map_or(0, |item| item.value() as u32));
+                    let mut response = control::ServerMessage::new();
+                    response.set_ssh_connection_response(ssh_connection_response);
+                    response.set_in_response_to(message.get_message_id());
 
-                        if let Some(ref handle) = device.active_connection {
-                            backchannel_future = Some(handle.connect_ssh(&forward.id.clone()));
+                    return match backchannel_future {
+                        Some(future) => {
+                            let tx = tx.clone();
+                            let f = future
+                                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send backchannel message"))
+                                .and_then(move |_| {
+                                    tx.clone().send(response)
+                                        .map(|_| ())
+                                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))
+                                });
+
+                            Box::new(f)
+                        },
+                        None => {
+                            let f = tx.clone().send(response)
+                                .map(|_| ())
+                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+
+                            Box::new(f)
                         }
                     }
-                    else {
-                        // ERROR
-                        ssh_connection_response.set_status(control::SshConnectionResponse_Status::ERROR);
+                }
+
+                if ssh_connection.has_local_enable() {
+                    let local_enable = ssh_connection.take_local_enable();
+                    let mut world = world.write().unwrap();
+
+                    let device = world.devices.get_mut(&device_id);
+
+                    let mut ssh_connection_response = control::SshConnectionResponse::new();
+
+                    let mut backchannel_future = None;
+
+                    match device {
+                        Some(device) if local_enable.get_forward_port() != 0 && local_enable.get_local_port() != 0 => {
+                            let forward_host = local_enable.get_forward_host();
+                            let forward_port = local_enable.get_forward_port();
+                            let local_port = local_enable.get_local_port();
+                            let gateway_port = local_enable.get_gateway_port();
+                            let forward = device.ssh_forwards.create_local(forward_host.into(), forward_port as u16, local_port as u16, gateway_port);
+
+                            ssh_connection_response.set_status(control::SshConnectionResponse_Status::SUCCESS);
+                            ssh_connection_response.set_connection_id(forward.id.clone().into());
+
+                            if let Some(ref handle) = device.active_connection {
+                                backchannel_future = Some(handle.connect_ssh(&forward.id.clone()));
+                            }
+                        },
+                        _ => {
+                            // Either the device doesn't exist, or there's no port to forward.
+                            ssh_connection_response.set_status(control::SshConnectionResponse_Status::ERROR);
+                        },
                     }
 
                     let mut response = control::ServerMessage::new();
@@ -299,6 +483,35 @@ impl Server {
                 return Box::new(f);
             }
 
+            if message.has_create_bootstrap_token() {
+                let create_bootstrap_token = message.take_create_bootstrap_token();
+                let device_id = create_bootstrap_token.get_device_id();
+                let device_id = if device_id.is_empty() { None } else { Some(device_id.to_string()) };
+
+                let mut create_bootstrap_token_response = control::CreateBootstrapTokenResponse::new();
+                match config.enrollment {
+                    Some(ref enrollment) => {
+                        let lifetime = chrono::Duration::hours(enrollment.token_hours as i64);
+                        let (token, expires) = world.write().unwrap().bootstrap_tokens.create(device_id, Utc::now(), lifetime);
+                        create_bootstrap_token_response.set_token(token.into());
+                        create_bootstrap_token_response.set_expires(expires.timestamp() as u64);
+                    },
+                    None => {
+                        create_bootstrap_token_response.set_error("This server doesn't enroll devices (there's no [enrollment] section in its config)".into());
+                    },
+                }
+
+                let mut response = control::ServerMessage::new();
+                response.set_create_bootstrap_token_response(create_bootstrap_token_response);
+                response.set_in_response_to(message.get_message_id());
+
+                let f = tx.clone().send(response)
+                    .map(|_| ())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+
+                return Box::new(f);
+            }
+
             if message.has_remove_device() {
                 let device_id: String = message.take_remove_device().get_device_id().into();
 
@@ -365,8 +578,287 @@ impl Server {
                 return Box::new(f);
             }
 
+            if message.has_metrics_request() {
+                let metrics_request = message.take_metrics_request();
+                let device_id = metrics_request.get_device_id();
+
+                let mut metrics_response = control::MetricsResponse::new();
+                {
+                    let world = world.read().unwrap();
+                    if let Some(device) = world.devices.get(device_id) {
+                        let series: Vec<_> = device.metrics.iter()
+                            .map(|(name, samples)| {
+                                let mut series = control::MetricsResponse_Series::new();
+                                series.set_name(name.as_str().into());
+                                let samples: Vec<_> = samples.iter()
+                                    .map(|sample| {
+                                        let mut sample_data = control::MetricsResponse_Sample::new();
+                                        sample_data.set_timestamp(sample.at.timestamp() as u64);
+                                        sample_data.set_value(sample.value);
+                                        sample_data
+                                    })
+                                    .collect();
+                                series.set_samples(samples.into());
+                                series
+                            })
+                            .collect();
+                        metrics_response.set_series(series.into());
+                        metrics_response.set_status(control::MetricsResponse_Status::SUCCESS);
+                    }
+                    else {
+                        metrics_response.set_status(control::MetricsResponse_Status::NOT_FOUND);
+                    }
+                }
+
+                let mut response = control::ServerMessage::new();
+                response.set_metrics_response(metrics_response);
+                response.set_in_response_to(message.get_message_id());
+
+                let f = tx.clone().send(response)
+                    .map(|_| ())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+
+                return Box::new(f);
+            }
+
+            if message.has_discover() {
+                let discover = message.take_discover();
+                let device_id = discover.get_device_id().to_string();
+                let message_id = message.get_message_id();
+
+                let handle = {
+                    let world = world.read().unwrap();
+                    world.devices.get(&device_id).and_then(|device| device.active_connection.clone())
+                };
+
+                let tx = tx.clone();
+                let handle = match handle {
+                    Some(handle) => handle,
+                    None => {
+                        let response = discover_response_message(Err("The device is not connected".to_string()), message_id);
+                        let f = tx.send(response)
+                            .map(|_| ())
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+
+                        return Box::new(f);
+                    },
+                };
+
+                let (sender, receiver) = futures::sync::oneshot::channel();
+                let f = handle.discover(discover.get_include_subnet(), sender)
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send backchannel message"))
+                    .and_then(move |_| {
+                        // If the device goes away before it finishes, the sender gets dropped.
+                        receiver.or_else(|_| Ok::<_, std::io::Error>(Err("The device disconnected".to_string())))
+                    })
+                    .and_then(move |discovery| {
+                        tx.send(discover_response_message(discovery, message_id))
+                            .map(|_| ())
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))
+                    });
+
+                return Box::new(f);
+            }
+
+            if message.has_file_transfer() {
+                let mut file_transfer = message.take_file_transfer();
+                let device_id = file_transfer.get_device_id().to_string();
+                let message_id = message.get_message_id();
+
+                let operation = if file_transfer.has_write() {
+                    let mut write = file_transfer.take_write();
+                    FileOperation::Write { data: write.take_data(), sha256: write.get_sha256().to_string() }
+                }
+                else if file_transfer.has_resume() {
+                    FileOperation::Resume
+                }
+                else {
+                    FileOperation::Read
+                };
+                let request = FileRequest {
+                    path: file_transfer.get_path().to_string(),
+                    offset: file_transfer.get_offset(),
+                    operation,
+                };
+
+                let handle = {
+                    let world = world.read().unwrap();
+                    world.devices.get(&device_id).and_then(|device| device.active_connection.clone())
+                };
+
+                let tx = tx.clone();
+                let handle = match handle {
+                    Some(handle) => handle,
+                    None => {
+                        let response = file_transfer_response_message(FileResult::Failed { error: "The device is not connected".to_string(), size: 0 }, message_id);
+                        let f = tx.send(response)
+                            .map(|_| ())
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+
+                        return Box::new(f);
+                    },
+                };
+
+                let (sender, receiver) = futures::sync::oneshot::channel();
+                let f = handle.file(request, sender)
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send backchannel message"))
+                    .and_then(move |_| {
+                        // If the device goes away before it answers, the sender gets dropped.
+                        receiver.or_else(|_| Ok::<_, std::io::Error>(FileResult::Failed { error: "The device disconnected".to_string(), size: 0 }))
+                    })
+                    .and_then(move |result| {
+                        tx.send(file_transfer_response_message(result, message_id))
+                            .map(|_| ())
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))
+                    });
+
+                return Box::new(f);
+            }
+
+            if message.has_exec() {
+                let exec = message.take_exec();
+                let device_id = exec.get_device_id().to_string();
+                let action = exec.get_action().to_string();
+                let message_id = message.get_message_id();
+
+                let handle = {
+                    let world = world.read().unwrap();
+                    world.devices.get(&device_id).and_then(|device| device.active_connection.clone())
+                };
+
+                let handle = match handle {
+                    Some(handle) => handle,
+                    None => {
+                        let response = exec_response_message(ExecEvent::Failed("The device is not connected".to_string()), message_id);
+                        let f = tx.clone().send(response)
+                            .map(|_| ())
+                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)));
+
+                        return Box::new(f);
+                    },
+                };
+
+                // Stream everything that happens back, as it happens. If the device goes away
+                // before the action finishes, say so.
+                let (sender, receiver) = futures::sync::mpsc::channel::<ExecEvent>(16);
+                let mut finished = false;
+                let responses = receiver
+                    .map(Some)
+                    .chain(futures::stream::once(Ok(None)))
+                    .filter_map(move |event| {
+                        match event {
+                            Some(event) => {
+                                finished = event.is_final();
+                                Some(event)
+                            },
+                            None if !finished => Some(ExecEvent::Failed("The device disconnected".to_string())),
+                            None => None,
+                        }
+                    })
+                    .map(move |event| exec_response_message(event, message_id))
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to receive exec events"));
+
+                let tx = tx.clone();
+                let f = handle.exec(&action, sender)
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to send backchannel message"))
+                    .and_then(move |_| {
+                        responses.forward(tx.sink_map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e))))
+                    })
+                    .map(|_| ());
+
+                return Box::new(f);
+            }
+
             // message_handler::handle_message(message, tx.clone(), new_state.clone())
             Box::new(futures::future::ok(()))
         })
     }
 }
+
+/// Wrap up something that happened while running an action as a response to the given message.
+fn exec_response_message(event: ExecEvent, in_response_to: u32) -> control::ServerMessage {
+    let mut exec_response = control::ExecResponse::new();
+    match event {
+        ExecEvent::Output { stream, data } => {
+            exec_response.set_status(control::ExecResponse_Status::OUTPUT);
+            exec_response.set_stream(match stream {
+                ExecStream::Stdout => control::ExecResponse_Stream::STDOUT,
+                ExecStream::Stderr => control::ExecResponse_Stream::STDERR,
+            });
+            exec_response.set_data(data);
+        },
+        ExecEvent::Exited(exit_code) => {
+            exec_response.set_status(control::ExecResponse_Status::EXITED);
+            exec_response.set_exit_code(exit_code);
+        },
+        ExecEvent::Rejected => {
+            exec_response.set_status(control::ExecResponse_Status::REJECTED);
+        },
+        ExecEvent::Failed(error) => {
+            exec_response.set_status(control::ExecResponse_Status::FAILED);
+            exec_response.set_error(error.into());
+        },
+    }
+
+    let mut response = control::ServerMessage::new();
+    response.set_exec_response(exec_response);
+    response.set_in_response_to(in_response_to);
+    response
+}
+
+/// Wrap up what a device found on its network as a response to the given message.
+fn discover_response_message(discovery: Discovery, in_response_to: u32) -> control::ServerMessage {
+    let mut discover_response = control::DiscoverResponse::new();
+    match discovery {
+        Ok(services) => {
+            let services: Vec<_> = services.into_iter()
+                .map(|service| {
+                    let mut service_data = control::DiscoverResponse_Service::new();
+                    service_data.set_host(service.host.into());
+                    service_data.set_port(service.port as u32);
+                    service_data.set_banner(service.banner.into());
+                    service_data.set_http_title(service.http_title.into());
+                    service_data
+                })
+                .collect();
+            discover_response.set_status(control::DiscoverResponse_Status::SUCCESS);
+            discover_response.set_services(services.into());
+        },
+        Err(error) => {
+            discover_response.set_status(control::DiscoverResponse_Status::FAILED);
+            discover_response.set_error(error.into());
+        },
+    }
+
+    let mut response = control::ServerMessage::new();
+    response.set_discover_response(discover_response);
+    response.set_in_response_to(in_response_to);
+    response
+}
+
+/// Wrap up how a chunk of a file transfer went as a response to the given message.
+fn file_transfer_response_message(result: FileResult, in_response_to: u32) -> control::ServerMessage {
+    let mut file_transfer_response = control::FileTransferResponse::new();
+    match result {
+        FileResult::Success { data, size, sha256 } => {
+            file_transfer_response.set_status(control::FileTransferResponse_Status::SUCCESS);
+            file_transfer_response.set_data(data);
+            file_transfer_response.set_size(size);
+            file_transfer_response.set_sha256(sha256.into());
+        },
+        FileResult::Rejected(error) => {
+            file_transfer_response.set_status(control::FileTransferResponse_Status::REJECTED);
+            file_transfer_response.set_error(error.into());
+        },
+        FileResult::Failed { error, size } => {
+            file_transfer_response.set_status(control::FileTransferResponse_Status::FAILED);
+            file_transfer_response.set_error(error.into());
+            file_transfer_response.set_size(size);
+        },
+    }
+
+    let mut response = control::ServerMessage::new();
+    response.set_file_transfer_response(file_transfer_response);
+    response.set_in_response_to(in_response_to);
+    response
+}
@@ -1,25 +1,30 @@
 use std;
 use tokio;
-use tokio_codec;
 use futures::{
     self, Stream, Sink, Future,
     sync::mpsc::{channel, Sender, Receiver},
+    sync::oneshot,
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{self, DateTime, Utc};
 use std::net::SocketAddr;
 
-use connectbot_shared::codec::Codec;
 use connectbot_shared::protos::device;
 use connectbot_shared::timed_connection::{TimedConnection, TimedConnectionItem, TimedConnectionOptions};
-
-use tokio_rustls::TlsStream;
-use tokio_rustls::rustls;
+use connectbot_shared::tunnel::TunnelFrame;
+use connectbot_shared::udp::{self, Datagram};
 
 use super::world::{self, SharedWorld};
+use super::{DeviceSink, DeviceStream};
 
 use config::SharedConfig;
+use super::discovery::{Discovery, DiscoveryManager};
+use super::exec::{ExecEvent, ExecManager};
+use super::files::{FileManager, FileRequest, FileResult};
 use super::stream_helpers::{CancelableStream, CancelHandle, PrimarySecondaryStream};
+use super::tunnel::TunnelManager;
+use super::udp_relay::UdpRelayManager;
+use super::update;
 
 /// An active client connection that is currently being processed
 pub struct ClientConnection {
@@ -45,10 +50,26 @@ pub struct ClientConnection {
     back_channel: Option<Receiver<BackchannelMessage>>,
     /// The device ID for this client
     device_id: Option<String>,
+    /// The device that the connection's client certificate was issued to by enrollment. A
+    /// connection made with such a certificate can only be that device.
+    certified_id: Option<String>,
     /// The last time we received any message from this client
     last_message: Option<DateTime<Utc>>,
     /// A handle that will cancel the stream
     cancel_handle: Option<CancelHandle>,
+    /// The listeners and streams for NATIVE forwards
+    tunnels: TunnelManager,
+    /// Temporary storage for the receiving end of the tunnel frames. Once the connection starts,
+    /// this will be taken and replaced with None.
+    tunnel_frames: Option<Receiver<TunnelFrame>>,
+    /// The relays for UDP forwards
+    udp_relays: UdpRelayManager,
+    /// The actions that the device is running for somebody
+    execs: ExecManager,
+    /// The scans that the device is running for somebody
+    discoveries: DiscoveryManager,
+    /// The file chunks that the device is reading or writing for somebody
+    files: FileManager,
 }
 
 /// A handle to an active client connection. Certain messages can be sent on this client's back
@@ -104,6 +125,52 @@ impl ClientConnectionHandle {
             })
     }
 
+    /// Ask the device to run one of its actions. Everything that happens while it runs gets sent
+    /// to `sender`, ending with an event for which `is_final()` is true. If the device disconnects
+    /// before then, `sender` gets dropped instead.
+    pub fn exec(&self, action: &str, sender: Sender<ExecEvent>) -> impl Future<Item=(), Error=()> {
+        self.sender.clone().send(BackchannelMessage::Exec(action.to_string(), sender))
+            .then(|result| {
+                match result {
+                    Ok(_) => Ok(()),
+                    Err(err) => {
+                        println!("Failed to send BackchannelMessage::Exec: {:?}", err);
+                        Err(())
+                    },
+                }
+            })
+    }
+
+    /// Ask the device to look for services on its network. What it finds gets sent to `sender`.
+    /// If the device disconnects before then, `sender` gets dropped instead.
+    pub fn discover(&self, include_subnet: bool, sender: oneshot::Sender<Discovery>) -> impl Future<Item=(), Error=()> {
+        self.sender.clone().send(BackchannelMessage::Discover(include_subnet, sender))
+            .then(|result| {
+                match result {
+                    Ok(_) => Ok(()),
+                    Err(err) => {
+                        println!("Failed to send BackchannelMessage::Discover: {:?}", err);
+                        Err(())
+                    },
+                }
+            })
+    }
+
+    /// Ask the device to read or write a chunk of a file. How it went gets sent to `sender`. If
+    /// the device disconnects before then, `sender` gets dropped instead.
+    pub fn file(&self, request: FileRequest, sender: oneshot::Sender<FileResult>) -> impl Future<Item=(), Error=()> {
+        self.sender.clone().send(BackchannelMessage::File(request, sender))
+            .then(|result| {
+                match result {
+                    Ok(_) => Ok(()),
+                    Err(err) => {
+                        println!("Failed to send BackchannelMessage::File: {:?}", err);
+                        Err(())
+                    },
+                }
+            })
+    }
+
     pub fn disconnect_ssh_no_future(&self, id: &str) {
         let result = self.sender.clone().try_send(BackchannelMessage::SshDisconnect(id.to_string()));
         match result {
@@ -120,22 +187,30 @@ impl ClientConnectionHandle {
 
 impl ClientConnection {
     /// Create a new client
-    pub fn new(id: usize, addr: SocketAddr, world: SharedWorld, config: SharedConfig) -> ClientConnection {
+    pub fn new(id: usize, addr: SocketAddr, certified_id: Option<String>, world: SharedWorld, config: SharedConfig) -> ClientConnection {
         let (back_channel_sender, back_channel_receiver) = channel(5);
         let (socket_sender, socket_receiver) = channel(5);
-
+        let (tunnel_frame_sender, tunnel_frame_receiver) = channel(16);
+        let udp_idle_timeout = udp::idle_timeout(config.ssh.udp_idle_timeout);
         ClientConnection {
             config,
             id,
             world,
             address: addr,
-            socket_sender,
             socket_receiver: Some(socket_receiver),
             back_channel_sender,
             back_channel: Some(back_channel_receiver),
             device_id: None,
+            certified_id,
             last_message: None,
             cancel_handle: None,
+            tunnels: TunnelManager::new(tunnel_frame_sender),
+            tunnel_frames: Some(tunnel_frame_receiver),
+            udp_relays: UdpRelayManager::new(socket_sender.clone(), udp_idle_timeout),
+            execs: ExecManager::new(),
+            discoveries: DiscoveryManager::new(),
+            files: FileManager::new(),
+            socket_sender,
         }
     }
 
@@ -148,30 +223,98 @@ impl ClientConnection {
     }
 
     fn sender_send_connect_ssh(tx: Sender<device::ServerMessage>, ssh_forward: world::SshForwardData, config: SharedConfig) -> impl Future<Item=(), Error=std::io::Error> + Send {
+        let mut ssh_connection = device::SshConnection::new();
+        ssh_connection.set_id(ssh_forward.id.clone().into());
+        match ssh_forward.direction {
+            world::SshForwardDirection::Remote => ssh_connection.set_enable(Self::remote_enable(&ssh_forward, &config)),
+            world::SshForwardDirection::Local { local_port } => ssh_connection.set_local_enable(Self::local_enable(&ssh_forward, local_port, &config)),
+        }
+
+        let mut message = device::ServerMessage::new();
+        message.set_ssh_connection(ssh_connection);
+
+        tx.clone().send(message)
+            .map(|_| ())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send ssh connect: {}", e)))
+    }
+
+    /// Build the message that tells the device to set up a remote (`ssh -R`) forward.
+    fn remote_enable(ssh_forward: &world::SshForwardData, config: &SharedConfig) -> device::SshConnection_Enable {
         let mut enable = device::SshConnection_Enable::new();
 
+        match ssh_forward.transport {
+            world::SshForwardTransport::Ssh => {
+                enable.set_transport(device::SshConnection_Transport::SSH);
+                enable.set_ssh_host(config.ssh.host.as_ref().map(String::as_str).unwrap_or("localhost").into());
+                enable.set_ssh_port(*config.ssh.port.as_ref().unwrap_or(&22) as u32);
+                enable.set_ssh_username(config.ssh.user.as_ref().map(String::as_str).unwrap_or("test").into());
+                if let Some(ref ssh_key) = config.ssh.private_key_data {
+                    enable.set_ssh_key(String::as_str(ssh_key).into());
+                }
+                enable.set_ssh_host_keys(config.ssh.host_keys.iter().map(|key| key.as_str().into()).collect::<Vec<_>>().into());
+            },
+            world::SshForwardTransport::Native => {
+                // The device doesn't need to know anything about SSH, so don't hand out the key.
+                enable.set_transport(device::SshConnection_Transport::NATIVE);
+            },
+        }
+        enable.set_protocol(match ssh_forward.protocol {
+            world::SshForwardProtocol::Tcp => device::SshConnection_Protocol::TCP,
+            world::SshForwardProtocol::Udp => device::SshConnection_Protocol::UDP,
+        });
+        if ssh_forward.protocol == world::SshForwardProtocol::Udp {
+            enable.set_udp_idle_timeout(config.ssh.udp_idle_timeout);
+        }
+
+        enable.set_forward_host(ssh_forward.forward_host.clone().into());
+        enable.set_forward_port(ssh_forward.forward_port as u32);
+        enable.set_remote_port(ssh_forward.remote_port as u32);
+        enable.set_gateway_port(ssh_forward.gateway_port);
+        enable.set_dynamic(ssh_forward.dynamic);
+        enable.set_permitted_destinations(ssh_forward.permitted_destinations.iter().map(|destination| destination.as_str().into()).collect::<Vec<_>>().into());
+
+        enable
+    }
+
+    /// Build the message that tells the device to set up a local (`ssh -L`) forward.
+    fn local_enable(ssh_forward: &world::SshForwardData, local_port: u16, config: &SharedConfig) -> device::SshConnection_LocalEnable {
+        let mut enable = device::SshConnection_LocalEnable::new();
+
         enable.set_ssh_host(config.ssh.host.as_ref().map(String::as_str).unwrap_or("localhost").into());
         enable.set_ssh_port(*config.ssh.port.as_ref().unwrap_or(&22) as u32);
         enable.set_ssh_username(config.ssh.user.as_ref().map(String::as_str).unwrap_or("test").into());
         if let Some(ref ssh_key) = config.ssh.private_key_data {
             enable.set_ssh_key(String::as_str(ssh_key).into());
         }
+        enable.set_ssh_host_keys(config.ssh.host_keys.iter().map(|key| key.as_str().into()).collect::<Vec<_>>().into());
 
         enable.set_forward_host(ssh_forward.forward_host.clone().into());
         enable.set_forward_port(ssh_forward.forward_port as u32);
-        enable.set_remote_port(ssh_forward.remote_port as u32);
+        enable.set_local_port(local_port as u32);
         enable.set_gateway_port(ssh_forward.gateway_port);
 
-        let mut ssh_connection = device::SshConnection::new();
-        ssh_connection.set_id(ssh_forward.id.clone().into());
-        ssh_connection.set_enable(enable);
-
-        let mut message = device::ServerMessage::new();
-        message.set_ssh_connection(ssh_connection);
+        enable
+    }
 
-        tx.clone().send(message)
-            .map(|_| ())
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send ssh connect: {}", e)))
+    /// What the client told us about why an SSH connection failed.
+    fn ssh_failure(ssh_status: &device::SshConnectionStatus) -> world::SshForwardFailure {
+        world::SshForwardFailure {
+            failures: ssh_status.get_failures(),
+            kind: match ssh_status.get_failure_kind() {
+                device::SshConnectionStatus_FailureKind::UNKNOWN_FAILURE => world::SshForwardFailureKind::Unknown,
+                device::SshConnectionStatus_FailureKind::AUTH_DENIED => world::SshForwardFailureKind::AuthDenied,
+                device::SshConnectionStatus_FailureKind::PORT_IN_USE => world::SshForwardFailureKind::PortInUse,
+                device::SshConnectionStatus_FailureKind::HOST_UNREACHABLE => world::SshForwardFailureKind::HostUnreachable,
+                device::SshConnectionStatus_FailureKind::HOST_KEY_MISMATCH => world::SshForwardFailureKind::HostKeyMismatch,
+                device::SshConnectionStatus_FailureKind::TARGET_REFUSED => world::SshForwardFailureKind::TargetRefused,
+            },
+            exit_code: match ssh_status.get_exit_code() {
+                code if code < 0 => None,
+                code => Some(code),
+            },
+            detail: ssh_status.get_failure_detail().to_string(),
+            reported_at: Utc::now(),
+        }
     }
 
     fn sender_send_disconnect_ssh(tx: Sender<device::ServerMessage>, connection_id: &str) -> impl Future<Item=(), Error=std::io::Error> + Send {
@@ -189,10 +332,20 @@ impl ClientConnection {
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send ssh disconnect: {}", e)))
     }
 
+    /// If the forward is carried over this connection rather than by `ssh`, start listening on
+    /// its remote port.
+    fn listen_native(&mut self, forward: &world::SshForwardData) {
+        match (forward.protocol, forward.transport) {
+            (world::SshForwardProtocol::Udp, _) => self.udp_relays.listen(forward),
+            (world::SshForwardProtocol::Tcp, world::SshForwardTransport::Native) => self.tunnels.listen(forward),
+            (world::SshForwardProtocol::Tcp, world::SshForwardTransport::Ssh) => {},
+        }
+    }
+
     /// Handle what happens when when receive a message from a client
     fn on_client_message(mut self, mut message: device::ClientMessage) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
-        if !message.has_ping() && !message.has_pong() {
-            // Log it! (Unless it's from a ping or a pong, then don't. Too noisy.)
+        if !message.has_ping() && !message.has_pong() && !message.has_tunnel_data() && !message.has_tunnel_window_update() && !message.has_udp_datagram() && !message.has_file_result() {
+            // Log it! (Unless it's from a ping or a pong or tunnel data, then don't. Too noisy.)
             println!("↑ {:4}: {:?}", &self.id, message);
         }
 
@@ -213,11 +366,36 @@ impl ClientConnection {
             return Box::new(f);
         }
 
+        if message.has_enroll() {
+            // The device wants a client certificate.
+            let enroll = message.take_enroll();
+            return self.on_enroll(enroll);
+        }
+
         if message.has_initialize() {
             // Oh goody! The client is telling us about itself.
             let mut initialize = message.take_initialize();
             let device_id = initialize.take_id().to_string();
 
+            // A certificate that was issued to one device can't be used to pass as another.
+            if let Some(ref certified_id) = self.certified_id {
+                if *certified_id != device_id {
+                    println!("! {:4}: Closing, because its certificate was issued to {}, but it says it's {}", &self.id, certified_id, device_id);
+                    self.close();
+                    return Box::new(futures::future::ok(self));
+                }
+            }
+            let suggested_name = initialize.take_suggested_name().to_string();
+            let actions: Vec<String> = initialize.get_actions().iter().map(|action| action.to_string()).collect();
+            let file_dirs: Vec<String> = initialize.get_file_dirs().iter().map(|dir| dir.to_string()).collect();
+            let facts = if initialize.has_facts() {
+                Some(device_facts(initialize.get_facts(), Utc::now()))
+            }
+            else {
+                None
+            };
+            let update_offer = facts.as_ref().and_then(|facts| update::offer(&self.config, facts));
+
             // Keep track of which device this connection claims to be.
             self.device_id = Some(device_id.clone());
 
@@ -230,7 +408,20 @@ impl ClientConnection {
             // commands to the device: we only need to send the command down a single network pipe.
             let previous_connection = {
                 let mut world = self.world.write().unwrap();
-                world.connect_device(&device_id, self.get_handle(), &self.address, Utc::now())
+                let previous_connection = world.connect_device(&device_id, self.get_handle(), &self.address, Utc::now());
+                {
+                    let device = world.devices.get_mut(&device_id).unwrap();
+                    device.actions = actions;
+                    device.file_dirs = file_dirs;
+                    if device.name == device.id && !suggested_name.is_empty() {
+                        // Nobody has named the device yet, so go with what it calls itself.
+                        device.name = suggested_name;
+                    }
+                    if facts.is_some() {
+                        device.facts = facts;
+                    }
+                }
+                previous_connection
             };
             if let Some(previous_connection) = previous_connection {
                 // We DO have a previous connection, so disconnect it.
@@ -251,6 +442,10 @@ impl ClientConnection {
                     .collect()
             };
 
+            for forward in forwards.iter() {
+                self.listen_native(forward);
+            }
+
             let future = {
                 let socket_sender = self.socket_sender.clone();
                 let config = self.config.clone();
@@ -258,7 +453,24 @@ impl ClientConnection {
                 futures::future::join_all(futures)
             };
 
-            let future = future.map(|_| self);
+            // Let the device know that it's all set up. And if it should be running a different
+            // version, tell it about that, too.
+            let mut messages = vec![{
+                let mut message = device::ServerMessage::new();
+                message.set_initialized(device::Initialized::new());
+                message
+            }];
+            if let Some(update_offer) = update_offer {
+                println!("! {:4}: Offering {} an update", &self.id, device_id);
+                messages.push(update_offer);
+            }
+            let socket_sender = self.socket_sender.clone();
+            let future = future
+                .and_then(move |_| {
+                    socket_sender.send_all(futures::stream::iter_ok(messages))
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))
+                })
+                .map(|_| self);
 
             return Box::new(future);
         }
@@ -273,6 +485,101 @@ impl ClientConnection {
 
         let device_id = self.device_id.as_ref().unwrap().clone();
 
+        if let Some(frame) = TunnelFrame::from_client_message(&mut message) {
+            // Data for one of the native tunnel streams.
+            self.tunnels.on_frame(frame);
+            return Box::new(futures::future::ok(self));
+        }
+
+        if let Some(datagram) = Datagram::from_client_message(&mut message) {
+            // A reply on one of the UDP forwards.
+            self.udp_relays.on_datagram(datagram);
+            return Box::new(futures::future::ok(self));
+        }
+
+        if let Some((request_id, event)) = ExecEvent::from_client_message(&mut message) {
+            // Something happened with an action that somebody asked the device to run.
+            if let Some((action, started_at, sender)) = self.execs.route(request_id, &event) {
+                if let Some(outcome) = exec_outcome(&event) {
+                    println!("! {:4}: Exec {} on {} finished: {:?}", &self.id, action, device_id, event);
+                    // Keep a record of what ran on the device, for whoever looks after it.
+                    let mut world = self.world.write().unwrap();
+                    if let Some(device) = world.devices.get_mut(&device_id) {
+                        device.exec_history.record(world::ExecHistoryItem {
+                            request_id,
+                            action,
+                            outcome,
+                            started_at,
+                            finished_at: Utc::now(),
+                        });
+                    }
+                }
+                // If whoever asked has gone away, there's nobody to tell. That's fine.
+                let f = sender.send(event)
+                    .then(move |_| Ok::<_, std::io::Error>(self));
+                return Box::new(f);
+            }
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_facts() {
+            // The device is telling us about itself again. Things like uptime and addresses
+            // change.
+            let facts = device_facts(message.get_facts(), Utc::now());
+            {
+                let mut world = self.world.write().unwrap();
+                if let Some(device) = world.devices.get_mut(&device_id) {
+                    device.facts = Some(facts);
+                }
+            }
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_update_chunk_request() {
+            // The device is downloading an update.
+            let sender = self.socket_sender.clone();
+            let f = update::chunk(self.config.clone(), message.take_update_chunk_request())
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to read update chunk"))
+                .and_then(move |message| {
+                    sender.send(message)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send update chunk: {}", e)))
+                })
+                .map(move |_| self);
+            return Box::new(f);
+        }
+
+        if message.has_file_result() {
+            // The device read or wrote a chunk of a file.
+            if !self.files.finish(message.get_file_result()) {
+                println!("! {:4}: Unexpected file result from {}", &self.id, device_id);
+            }
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_discover_result() {
+            // The device finished looking for services.
+            if let Some(found) = self.discoveries.finish(message.get_discover_result()) {
+                println!("! {:4}: Discovery on {} found {} services", &self.id, device_id, found);
+            }
+            return Box::new(futures::future::ok(self));
+        }
+
+        if message.has_metrics() {
+            // The results of the device's probes. Keep them around for graphing.
+            let now = Utc::now();
+            {
+                let mut world = self.world.write().unwrap();
+                if let Some(device) = world.devices.get_mut(&device_id) {
+                    for sample in message.get_metrics().get_samples() {
+                        if sample.get_value().is_finite() {
+                            device.metrics.record(sample.get_name(), now, sample.get_value());
+                        }
+                    }
+                }
+            }
+            return Box::new(futures::future::ok(self));
+        }
+
         if message.has_ssh_status() {
             // The client is telling us about an SSH connection it knows about.
             let ssh_status = message.take_ssh_status();
@@ -290,10 +597,33 @@ impl ClientConnection {
                     device::SshConnectionStatus_State::DISCONNECTING => world::SshForwardClientState::Disconnecting,
                     device::SshConnectionStatus_State::DISCONNECTED => world::SshForwardClientState::Disconnected,
                     device::SshConnectionStatus_State::FAILED => world::SshForwardClientState::Failed,
+                    device::SshConnectionStatus_State::REJECTED => world::SshForwardClientState::Rejected,
                 };
+                if state == device::SshConnectionStatus_State::FAILED {
+                    device.ssh_forwards.record_failure(&connection_id, Self::ssh_failure(&ssh_status));
+                }
+                if state == device::SshConnectionStatus_State::REJECTED {
+                    println!("! {:4}: {} rejected SSH connection {}: {}", &self.id, device_id, connection_id, ssh_status.get_rejected_reason());
+                    device.ssh_forwards.record_rejection(&connection_id, ssh_status.get_rejected_reason().to_string());
+                }
                 match device.ssh_forwards.update_client_state(&connection_id, new_state) {
                     Ok(()) => {
-                        None
+                        // The client might still be holding on to a connection that we gave up on
+                        // while it was away (clients adopt SSH connections that survive a
+                        // restart). If so, it should let it go.
+                        let wanted = device.ssh_forwards.find(&connection_id)
+                            .map(|forward| match forward.server_state {
+                                world::SshForwardServerState::Active { .. } => true,
+                                world::SshForwardServerState::Inactive { .. } => false,
+                            })
+                            .unwrap_or(false);
+                        let running = state == device::SshConnectionStatus_State::CONNECTING || state == device::SshConnectionStatus_State::CONNECTED;
+                        if running && !wanted {
+                            Some(Self::sender_send_disconnect_ssh(self.socket_sender.clone(), connection_id))
+                        }
+                        else {
+                            None
+                        }
                     },
                     Err(()) => {
                         // We don't know about this SSH connection. We should tell the client to
@@ -338,9 +668,7 @@ impl ClientConnection {
 
         match message {
             BackchannelMessage::Disconnect => {
-                if let Some(cancel_handle) = std::mem::replace(&mut self.cancel_handle, None) {
-                    cancel_handle.cancel().unwrap();
-                }
+                self.close();
 
                 Box::new(futures::future::ok(self))
             },
@@ -351,6 +679,7 @@ impl ClientConnection {
                     device.ssh_forwards.find(&id).map(|forward| forward.data())
                 };
                 if let Some(forward) = forward {
+                    self.listen_native(&forward);
                     let future = Self::sender_send_connect_ssh(self.socket_sender.clone(), forward, self.config.clone());
                     let f = future
                         .map(|_| self);
@@ -360,6 +689,28 @@ impl ClientConnection {
                     Box::new(futures::future::ok(self))
                 }
             },
+            BackchannelMessage::Exec(action, sender) => {
+                println!("! {:4}: Exec {} requested on {}", &self.id, action, self.device_id.as_ref().map(String::as_str).unwrap_or("unknown device"));
+                let message = self.execs.start(&action, Utc::now(), sender);
+                let f = self.socket_sender.clone().send(message)
+                    .map(|_| self)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send exec request: {}", e)));
+                Box::new(f)
+            },
+            BackchannelMessage::Discover(include_subnet, sender) => {
+                let message = self.discoveries.start(include_subnet, sender);
+                let f = self.socket_sender.clone().send(message)
+                    .map(|_| self)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send discover request: {}", e)));
+                Box::new(f)
+            },
+            BackchannelMessage::File(request, sender) => {
+                let message = self.files.start(request, sender);
+                let f = self.socket_sender.clone().send(message)
+                    .map(|_| self)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send file request: {}", e)));
+                Box::new(f)
+            },
             BackchannelMessage::SshDisconnect(id) => {
                 let forward = {
                     let world = self.world.read().unwrap();
@@ -367,6 +718,8 @@ impl ClientConnection {
                     device.ssh_forwards.find(&id).map(|forward| forward.data())
                 };
                 if let Some(forward) = forward {
+                    self.tunnels.stop(&forward.id);
+                    self.udp_relays.stop(&forward.id);
                     let future = Self::sender_send_disconnect_ssh(self.socket_sender.clone(), &forward.id);
                     let f = future
                         .map(|_| self);
@@ -379,6 +732,81 @@ impl ClientConnection {
         }
     }
 
+    /// Handle a device asking for a client certificate, and tell it how that went. A device that
+    /// hasn't initialized gets disconnected if it's refused.
+    fn on_enroll(mut self, enroll: device::Enroll) -> Box<dyn Future<Item=Self, Error=std::io::Error> + Send> {
+        let device_id = enroll.get_id().to_string();
+
+        let mut enrolled = device::Enrolled::new();
+        match self.enroll(&enroll) {
+            Ok(certificate) => {
+                println!("! {:4}: Issued a certificate to {}", &self.id, device_id);
+                enrolled.set_certificate(certificate.into());
+            },
+            Err(err) => {
+                println!("! {:4}: Refused to enroll {}: {}", &self.id, device_id, err);
+                enrolled.set_error(err.into());
+                if self.device_id.is_none() {
+                    self.close();
+                }
+            },
+        }
+
+        let mut message = device::ServerMessage::new();
+        message.set_enrolled(enrolled);
+
+        let f = self.socket_sender.clone().send(message)
+            .map(|_| self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send enrolled: {}", e)));
+
+        Box::new(f)
+    }
+
+    /// Issue a certificate for an enrollment request. A bootstrap token is used up by this. A
+    /// request without a token is a renewal, which has to come over a connection made with the
+    /// device's current certificate.
+    fn enroll(&self, enroll: &device::Enroll) -> Result<Vec<u8>, String> {
+        let enrollment = match self.config.enrollment {
+            Some(ref enrollment) => enrollment,
+            None => return Err("This server doesn't enroll devices".to_string()),
+        };
+        let authority = enrollment.authority.as_ref().expect("The CA is loaded at startup");
+
+        let device_id = enroll.get_id();
+        let token = enroll.get_token();
+        if self.device_id.as_ref().map_or(false, |id| id != device_id) {
+            return Err(format!("This connection is for {}", self.device_id.as_ref().unwrap()));
+        }
+
+        let now = Utc::now();
+        // Leave some room for devices whose clocks are a little behind.
+        let not_before = (now - chrono::Duration::hours(1)).timestamp() as u64;
+        let not_after = (now + chrono::Duration::days(enrollment.certificate_days as i64)).timestamp() as u64;
+
+        if token.is_empty() {
+            if self.certified_id.as_ref().map(String::as_str) != Some(device_id) {
+                return Err("A certificate can only be renewed over a connection made with it".to_string());
+            }
+            return authority.issue(enroll.get_csr(), device_id, not_before, not_after)
+                .map_err(|err| err.to_string());
+        }
+
+        let mut world = self.world.write().unwrap();
+        let known = world.devices.contains_key(device_id);
+        world.bootstrap_tokens.check(token, device_id, known, now)?;
+        let certificate = authority.issue(enroll.get_csr(), device_id, not_before, not_after)
+            .map_err(|err| err.to_string())?;
+        world.bootstrap_tokens.remove(token);
+        Ok(certificate)
+    }
+
+    /// Stop handling the connection. Whatever has already been sent still goes out.
+    fn close(&mut self) {
+        if let Some(cancel_handle) = std::mem::replace(&mut self.cancel_handle, None) {
+            cancel_handle.cancel().unwrap();
+        }
+    }
+
     /// Handle what happens when the remote client disconnects.
     fn on_disconnect(self) -> impl Future<Item=(), Error=std::io::Error> {
         let client_id = self.id;
@@ -397,27 +825,18 @@ impl ClientConnection {
         futures::future::ok(())
     }
 
-    /// Handle the TlsStream connection for this client. This consumes the client.
-    pub fn handle_connection<S, C>(mut self, conn: TlsStream<S, C>) -> impl Future<Item=(), Error=std::io::Error>
-        where S: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + 'static,
-              C: rustls::Session + 'static,
-    {
-        // Process socket here.
-        let codec: Codec<device::ServerMessage, device::ClientMessage> = Codec::new();
-        let framed = tokio_codec::Decoder::framed(codec, conn);
-
-        let (client_message_sink, client_message_stream) = framed.split();
-
+    /// Handle the connection for this client, whichever way it came in. This consumes the client.
+    pub fn handle_connection(mut self, client_message_sink: DeviceSink, client_message_stream: DeviceStream) -> impl Future<Item=(), Error=std::io::Error> {
         let client_message_stream = TimedConnection::new(client_message_stream, TimedConnectionOptions { ..Default::default() });
 
         // In order to send things to the client, we set up a channel, and we forward that
-        // receiving end directly to the client on the TlsStream.
+        // receiving end directly to the client on the connection.
         let client_message_sink = client_message_sink.sink_map_err(|err| panic!("{:?}", err));
         let socket_receiver = std::mem::replace(&mut self.socket_receiver, None);
         let socket_receiver = socket_receiver.unwrap().map_err(|err| panic!("{:?}", err));
         let connection_id = self.id.clone();
         let socket_forward = socket_receiver.inspect(move |message| {
-            if !message.has_ping() && !message.has_pong() {
+            if !message.has_ping() && !message.has_pong() && !message.has_tunnel_data() && !message.has_tunnel_window_update() && !message.has_udp_datagram() && !message.has_update_chunk() && !message.has_file_request() {
                 println!("↓ {:4}: {:?}", connection_id, message);
             }
         })
@@ -429,6 +848,15 @@ impl ClientConnection {
                 Ok(())
             });
 
+        // Native tunnel streams produce frames, which get wrapped up and sent to the client just
+        // like any other message.
+        let tunnel_frames = std::mem::replace(&mut self.tunnel_frames, None).unwrap();
+        let tunnel_forward = tunnel_frames
+            .map(TunnelFrame::into_server_message)
+            .forward(self.socket_sender.clone().sink_map_err(|_| ()))
+            .map(|_| ());
+        tokio::spawn(tunnel_forward);
+
         // Combine the back channel and the client messages into a single stream
         let back_channel_stream = std::mem::replace(&mut self.back_channel, None).unwrap();
         let back_channel_stream = back_channel_stream.map(|item| ClientBackchannelCombinedMessage::Backchannel(item))
@@ -469,4 +897,42 @@ enum BackchannelMessage {
     Disconnect,
     SshConnect(String),
     SshDisconnect(String),
+    /// Run an action, and send everything that happens to the sender
+    Exec(String, Sender<ExecEvent>),
+    /// Look for services on the device's network (and its subnet, if true), and send what was
+    /// found to the sender
+    Discover(bool, oneshot::Sender<Discovery>),
+    /// Read or write a chunk of a file, and send how it went to the sender
+    File(FileRequest, oneshot::Sender<FileResult>),
+}
+
+/// How an action ended, if the event is the last one for its request.
+fn exec_outcome(event: &ExecEvent) -> Option<world::ExecOutcome> {
+    match event {
+        ExecEvent::Output { .. } => None,
+        ExecEvent::Exited(exit_code) => Some(world::ExecOutcome::Exited(*exit_code)),
+        ExecEvent::Rejected => Some(world::ExecOutcome::Rejected),
+        ExecEvent::Failed(error) => Some(world::ExecOutcome::Failed(error.clone())),
+    }
+}
+
+/// Convert the facts that a device reported into what we keep in the world.
+fn device_facts(facts: &device::Facts, reported_at: DateTime<Utc>) -> world::DeviceFacts {
+    world::DeviceFacts {
+        hostname: facts.get_hostname().to_string(),
+        os: facts.get_os().to_string(),
+        kernel: facts.get_kernel().to_string(),
+        arch: facts.get_arch().to_string(),
+        client_version: facts.get_client_version().to_string(),
+        uptime: facts.get_uptime(),
+        interfaces: facts.get_interfaces().iter()
+            .map(|interface| world::DeviceInterface {
+                name: interface.get_name().to_string(),
+                addresses: interface.get_addresses().iter().map(|address| address.to_string()).collect(),
+            })
+            .collect(),
+        ssh_version: facts.get_ssh_version().to_string(),
+        server_endpoint: facts.get_server_endpoint().to_string(),
+        reported_at,
+    }
 }
@@ -0,0 +1,83 @@
+//! Looking for services on a device's network. The control server asks, the device scans, and
+//! the device tells us everything it found in one go. We hand it on to whoever asked.
+
+use std::collections::HashMap;
+use futures::sync::oneshot;
+
+use connectbot_shared::protos::device;
+
+/// A port that accepted a connection on the device's network.
+#[derive(Debug, Clone)]
+pub struct DiscoveredService {
+    /// "localhost" for the device itself, otherwise an IP address
+    pub host: String,
+    pub port: u16,
+    /// The first line that the service sent, if any
+    pub banner: String,
+    /// The title of the page, if the service spoke HTTP
+    pub http_title: String,
+}
+
+/// What a scan found, or why it couldn't be done.
+pub type Discovery = Result<Vec<DiscoveredService>, String>;
+
+impl DiscoveredService {
+    fn from_proto(service: &device::DiscoverResult_Service) -> DiscoveredService {
+        DiscoveredService {
+            host: service.get_host().to_string(),
+            port: service.get_port() as u16,
+            banner: service.get_banner().to_string(),
+            http_title: service.get_http_title().to_string(),
+        }
+    }
+}
+
+/// All of the scans that are running on a single device connection.
+pub struct DiscoveryManager {
+    next_request_id: u32,
+    pending: HashMap<u32, oneshot::Sender<Discovery>>,
+}
+
+impl DiscoveryManager {
+    pub fn new() -> DiscoveryManager {
+        DiscoveryManager {
+            next_request_id: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Keep track of a new request, and build the message that asks the device to scan. What the
+    /// device finds gets sent to `sender`.
+    pub fn start(&mut self, include_subnet: bool, sender: oneshot::Sender<Discovery>) -> device::ServerMessage {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        self.pending.insert(request_id, sender);
+
+        let mut discover_request = device::DiscoverRequest::new();
+        discover_request.set_request_id(request_id);
+        discover_request.set_include_subnet(include_subnet);
+
+        let mut message = device::ServerMessage::new();
+        message.set_discover_request(discover_request);
+        message
+    }
+
+    /// Hand the device's result to whoever asked for it. Returns how many services were found,
+    /// or None if nobody was waiting for the result.
+    pub fn finish(&mut self, result: &device::DiscoverResult) -> Option<usize> {
+        let sender = self.pending.remove(&result.get_request_id())?;
+
+        let discovery = if result.get_error().is_empty() {
+            Ok(result.get_services().iter().map(DiscoveredService::from_proto).collect())
+        }
+        else {
+            Err(result.get_error().to_string())
+        };
+        let found = discovery.as_ref().map(Vec::len).unwrap_or(0);
+
+        // If whoever asked has gone away, there's nobody to tell. That's fine.
+        let _ = sender.send(discovery);
+        Some(found)
+    }
+}
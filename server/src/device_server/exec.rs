@@ -0,0 +1,121 @@
+//! Running a device's actions. The control server asks for an action by name, the device runs it
+//! (if it's in the device's allowlist), and streams the output back to us. We hand it on to
+//! whoever asked.
+
+use std::collections::HashMap;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::sync::mpsc::Sender;
+
+use connectbot_shared::protos::device;
+
+/// Which output stream some output came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecStream {
+    Stdout,
+    Stderr,
+}
+
+/// Something that happened while running an action.
+#[derive(Debug, Clone)]
+pub enum ExecEvent {
+    /// The action produced some output
+    Output { stream: ExecStream, data: Bytes },
+    /// The action exited with the given exit code
+    Exited(i32),
+    /// The device doesn't allow the action
+    Rejected,
+    /// The action couldn't be run
+    Failed(String),
+}
+
+impl ExecEvent {
+    /// Whether this is the last event for the request.
+    pub fn is_final(&self) -> bool {
+        match self {
+            ExecEvent::Output { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Take the event out of a message that the client sent, if it is one. Returns the ID of the
+    /// request the event belongs to.
+    pub fn from_client_message(message: &mut device::ClientMessage) -> Option<(u32, ExecEvent)> {
+        if message.has_exec_output() {
+            let mut output = message.take_exec_output();
+            let stream = match output.get_stream() {
+                device::ExecOutput_Stream::STDOUT => ExecStream::Stdout,
+                device::ExecOutput_Stream::STDERR => ExecStream::Stderr,
+            };
+            Some((output.get_request_id(), ExecEvent::Output { stream, data: output.take_data() }))
+        }
+        else if message.has_exec_result() {
+            let result = message.take_exec_result();
+            let event = match result.get_status() {
+                device::ExecResult_Status::EXITED => ExecEvent::Exited(result.get_exit_code()),
+                device::ExecResult_Status::REJECTED => ExecEvent::Rejected,
+                device::ExecResult_Status::FAILED => ExecEvent::Failed(result.get_error().to_string()),
+            };
+            Some((result.get_request_id(), event))
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// An action that the device is running for somebody.
+struct PendingExec {
+    action: String,
+    started_at: DateTime<Utc>,
+    sender: Sender<ExecEvent>,
+}
+
+/// All of the actions that are running on a single device connection.
+pub struct ExecManager {
+    next_request_id: u32,
+    pending: HashMap<u32, PendingExec>,
+}
+
+impl ExecManager {
+    pub fn new() -> ExecManager {
+        ExecManager {
+            next_request_id: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Keep track of a new request, and build the message that asks the device to run it. Events
+    /// for the request get sent to `sender`.
+    pub fn start(&mut self, action: &str, now: DateTime<Utc>, sender: Sender<ExecEvent>) -> device::ServerMessage {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        self.pending.insert(request_id, PendingExec {
+            action: action.to_string(),
+            started_at: now,
+            sender,
+        });
+
+        let mut exec_request = device::ExecRequest::new();
+        exec_request.set_request_id(request_id);
+        exec_request.set_action(action.into());
+
+        let mut message = device::ServerMessage::new();
+        message.set_exec_request(exec_request);
+        message
+    }
+
+    /// Find out where an event needs to go. Once the request has finished, we forget about it.
+    /// Returns the action's name and when it was started, along with where to send the event.
+    pub fn route(&mut self, request_id: u32, event: &ExecEvent) -> Option<(String, DateTime<Utc>, Sender<ExecEvent>)> {
+        if event.is_final() {
+            self.pending.remove(&request_id)
+                .map(|pending| (pending.action, pending.started_at, pending.sender))
+        }
+        else {
+            self.pending.get(&request_id)
+                .map(|pending| (pending.action.clone(), pending.started_at, pending.sender.clone()))
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Copying files to and from a device. The control server asks for one chunk at a time, the device
+//! reads or writes it (if the path is in one of the device's allowed directories), and tells us
+//! how it went. We hand it on to whoever asked.
+
+use std;
+use std::collections::HashMap;
+use bytes::Bytes;
+use futures::sync::oneshot;
+
+use connectbot_shared::protos::device;
+
+/// What to do with a file.
+pub enum FileOperation {
+    /// Read a chunk
+    Read,
+    /// Write a chunk. On the last chunk, the checksum of the whole file is set.
+    Write { data: Bytes, sha256: String },
+    /// Find out how much of a partial file the device has
+    Resume,
+}
+
+// Chunks are big, so don't put them in the logs.
+impl std::fmt::Debug for FileOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FileOperation::Read => write!(f, "Read"),
+            FileOperation::Write { data, sha256 } if sha256.is_empty() => write!(f, "Write({} bytes)", data.len()),
+            FileOperation::Write { data, sha256 } => write!(f, "Write({} bytes, sha256 {})", data.len(), sha256),
+            FileOperation::Resume => write!(f, "Resume"),
+        }
+    }
+}
+
+/// A single chunk of a transfer.
+#[derive(Debug)]
+pub struct FileRequest {
+    /// The absolute path of the file on the device
+    pub path: String,
+    pub offset: u64,
+    pub operation: FileOperation,
+}
+
+/// How a chunk went.
+#[derive(Debug)]
+pub enum FileResult {
+    /// It worked. `data` and `sha256` are only set for reads.
+    Success { data: Bytes, size: u64, sha256: String },
+    /// The device doesn't allow the path
+    Rejected(String),
+    /// It didn't work. For writes, `size` is how much of the partial file the device has.
+    Failed { error: String, size: u64 },
+}
+
+impl FileResult {
+    fn from_proto(result: &device::FileResult) -> FileResult {
+        match result.get_status() {
+            device::FileResult_Status::SUCCESS => FileResult::Success {
+                data: result.get_data().into(),
+                size: result.get_size(),
+                sha256: result.get_sha256().to_string(),
+            },
+            device::FileResult_Status::REJECTED => FileResult::Rejected(result.get_error().to_string()),
+            device::FileResult_Status::FAILED => FileResult::Failed {
+                error: result.get_error().to_string(),
+                size: result.get_size(),
+            },
+        }
+    }
+}
+
+/// All of the chunks that are in flight on a single device connection.
+pub struct FileManager {
+    next_request_id: u32,
+    pending: HashMap<u32, oneshot::Sender<FileResult>>,
+}
+
+impl FileManager {
+    pub fn new() -> FileManager {
+        FileManager {
+            next_request_id: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Keep track of a new request, and build the message that asks the device to do it. How it
+    /// went gets sent to `sender`.
+    pub fn start(&mut self, request: FileRequest, sender: oneshot::Sender<FileResult>) -> device::ServerMessage {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        self.pending.insert(request_id, sender);
+
+        let mut file_request = device::FileRequest::new();
+        file_request.set_request_id(request_id);
+        file_request.set_path(request.path.into());
+        file_request.set_offset(request.offset);
+        match request.operation {
+            FileOperation::Read => file_request.set_read(device::FileRequest_Read::new()),
+            FileOperation::Write { data, sha256 } => {
+                let mut write = device::FileRequest_Write::new();
+                write.set_data(data);
+                write.set_sha256(sha256.into());
+                file_request.set_write(write);
+            },
+            FileOperation::Resume => file_request.set_resume(device::FileRequest_Resume::new()),
+        }
+
+        let mut message = device::ServerMessage::new();
+        message.set_file_request(file_request);
+        message
+    }
+
+    /// Hand the device's result to whoever asked for it. Returns false if nobody was waiting for
+    /// the result.
+    pub fn finish(&mut self, result: &device::FileResult) -> bool {
+        match self.pending.remove(&result.get_request_id()) {
+            Some(sender) => {
+                // If whoever asked has gone away, there's nobody to tell. That's fine.
+                let _ = sender.send(FileResult::from_proto(result));
+                true
+            },
+            None => false,
+        }
+    }
+}
@@ -2,27 +2,98 @@
 
 use std;
 use tokio;
-use futures::{Future, Stream};
+use tokio_codec::{self, Decoder, Encoder};
+use futures::{Future, Sink, Stream};
 
 use config::SharedConfig;
+use connectbot_shared::codec::Codec;
+use connectbot_shared::enrollment;
+use connectbot_shared::keys;
+use connectbot_shared::protos::device;
+use connectbot_shared::websocket::{self, WebSocketCodec};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::{
-    TlsStream, ServerConfigExt,
-    rustls::{
-        self, ServerConfig,
-    },
+    ServerConfigExt, TlsStream,
+    rustls::{ServerConfig, ServerSession, Session},
 };
 
 use super::world::{self, SharedWorld};
 
 pub mod client_connection;
+pub mod discovery;
+pub mod exec;
+pub mod files;
 mod stream_helpers;
+mod tunnel;
+mod udp_relay;
+mod update;
 
 use self::client_connection::ClientConnection;
 
+/// The messages that come from a device, whichever way it's connected.
+pub type DeviceStream = Box<dyn Stream<Item=device::ClientMessage, Error=std::io::Error> + Send>;
+/// Where the messages to a device go, whichever way it's connected.
+pub type DeviceSink = Box<dyn Sink<SinkItem=device::ServerMessage, SinkError=std::io::Error> + Send>;
+
+/// How a device connected
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    /// Length-prefixed protocol buffers over TLS
+    Tls,
+    /// The same, as binary WebSocket messages
+    WebSocket,
+}
+
+/// Frame a connection with a codec, and split it into where messages come from and where they
+/// go.
+fn framed<S, C>(codec: C, stream: S) -> (DeviceSink, DeviceStream)
+    where S: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + 'static,
+          C: Decoder<Item=device::ClientMessage, Error=std::io::Error> + Encoder<Item=device::ServerMessage, Error=std::io::Error> + Send + 'static,
+{
+    let (sink, stream) = tokio_codec::Decoder::framed(codec, stream).split();
+    (Box::new(sink), Box::new(stream))
+}
+
+/// The device that the client certificate of a connection was issued to by enrollment, if there
+/// is one.
+fn certified_device<S>(stream: &TlsStream<S, ServerSession>) -> Option<String> {
+    let (_, session) = stream.get_ref();
+    session.get_peer_certificates()
+        .and_then(|certificates| certificates.first().and_then(|certificate| enrollment::certified_device(&certificate.0)))
+}
+
+/// The device that the client certificate passed on by a reverse proxy was issued to by enrollment,
+/// if there is one. The certificate is URL-encoded PEM.
+fn proxied_certified_device(head: &str, header: &str) -> Option<String> {
+    let pem = percent_decode(websocket::header(head, header)?)?;
+    keys::read_certificates(&mut pem.as_bytes()).ok()?
+        .first()
+        .and_then(|certificate| enrollment::certified_device(certificate))
+}
+
+/// Undo URL encoding (`%XX`).
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        }
+        else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
 /// Server information
 pub struct Server {
     /// The next connection ID (we give each connection a unique connection ID)
@@ -31,6 +102,8 @@ pub struct Server {
     world: SharedWorld,
     /// config.toml information
     config: SharedConfig,
+    /// The number of devices that are connected right now
+    connections: Arc<AtomicUsize>,
 }
 
 impl Server {
@@ -40,34 +113,56 @@ impl Server {
             world: world,
             next_connection_id: 1,
             config,
+            connections: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    /// Handle listening on this server. Returns a function that returns once all of the listening
-    /// is done. (Which is pretty much never.)
-    pub fn listen(self, socket_addr: SocketAddr, server_config: ServerConfig) -> impl Future<Item=Self, Error=()> {
+    /// Handle listening on this server, and on the WebSocket address if there is one. Returns a
+    /// function that returns once all of the listening is done. (Which is pretty much never.)
+    pub fn listen(self, socket_addr: SocketAddr, websocket_addr: Option<SocketAddr>, server_config: ServerConfig) -> impl Future<Item=Self, Error=()> {
         let arc_config = Arc::new(server_config);
 
         let listener = TcpListener::bind(&socket_addr).unwrap();
         println!("Client channel listening on {}", &socket_addr);
-        let future = listener.incoming()
+        let mut incoming: Box<dyn Stream<Item=(TcpStream, Transport), Error=std::io::Error> + Send> = Box::new(listener.incoming()
+            .map(|connection| (connection, Transport::Tls)));
+        if let Some(websocket_addr) = websocket_addr {
+            let listener = TcpListener::bind(&websocket_addr).unwrap();
+            println!("WebSocket channel listening on {}", &websocket_addr);
+            incoming = Box::new(incoming.select(listener.incoming()
+                .map(|connection| (connection, Transport::WebSocket))));
+        }
+
+        let future = incoming
             .map_err(|err| println!("Incoming error: {}", err))
-            .fold(self, move |mut server, connection| {
+            .fold(self, move |mut server, (connection, transport)| {
                 // We received a new connection. Log and accept it.
                 let addr = connection.peer_addr().unwrap_or_else(|err| {
                     println!("Failed to get peer address: {}", err);
                     "[::]:0".parse().unwrap()
                 });
-                arc_config.accept_async(connection)
+                server.accept(connection, transport, arc_config.clone())
                     .then(move |stream| {
                         match stream {
-                            Ok(stream) => {
+                            Ok((sink, stream, certified_id)) => {
                                 // Yeah, connection accepted. Make a client connection out of it,
                                 // and then let client connection fully handle it.
                                 let connection_id = server.next_connection_id;
                                 server.next_connection_id = server.next_connection_id.wrapping_add(1);
-                                let future = server.handle_client_connection(connection_id, addr, stream)
-                                    .map_err(|e| println!("Warning: {}", e));
+
+                                if server.is_full() {
+                                    tokio::spawn(server.turn_away(connection_id, addr, sink));
+                                    return Ok(server);
+                                }
+
+                                let connections = server.connections.clone();
+                                connections.fetch_add(1, Ordering::SeqCst);
+                                let future = server.handle_client_connection(connection_id, addr, transport, certified_id, sink, stream)
+                                    .map_err(|e| println!("Warning: {}", e))
+                                    .then(move |result| {
+                                        connections.fetch_sub(1, Ordering::SeqCst);
+                                        result
+                                    });
 
                                 tokio::spawn(future);
 
@@ -86,15 +181,80 @@ impl Server {
         future
     }
 
-    /// Wrap a TLS connection into a future, and let ClientConnection fully handle everything that
+    /// Accept a connection: negotiate TLS, and upgrade it to a WebSocket if that's how the device
+    /// connected. Also returns the device that the connection's client certificate was issued to,
+    /// if it was issued by enrollment.
+    fn accept(&self, connection: TcpStream, transport: Transport, tls_config: Arc<ServerConfig>) -> Box<dyn Future<Item=(DeviceSink, DeviceStream, Option<String>), Error=std::io::Error> + Send> {
+        let settings = match (transport, &self.config.websocket) {
+            (Transport::WebSocket, Some(settings)) => settings,
+            _ => {
+                let future = tls_config.accept_async(connection)
+                    .map(|stream| {
+                        let certified_id = certified_device(&stream);
+                        let (sink, stream) = framed(Codec::new(), stream);
+                        (sink, stream, certified_id)
+                    });
+                return Box::new(future);
+            },
+        };
+
+        let path = settings.path.clone();
+        match settings.tls {
+            true => Box::new(tls_config.accept_async(connection)
+                .and_then(move |stream| {
+                    let certified_id = certified_device(&stream);
+                    websocket::accept(stream, path)
+                        .map(move |(stream, _)| (stream, certified_id))
+                })
+                .map(|(stream, certified_id)| {
+                    let (sink, stream) = framed(WebSocketCodec::server(Codec::new()), stream);
+                    (sink, stream, certified_id)
+                })),
+            // Something in front of us already took care of TLS, so we only see the client's
+            // certificate if it passes it on.
+            false => {
+                let header = settings.client_certificate_header.clone();
+                Box::new(websocket::accept(connection, path)
+                    .map(move |(stream, head)| {
+                        let certified_id = header.and_then(|header| proxied_certified_device(&head, &header));
+                        let (sink, stream) = framed(WebSocketCodec::server(Codec::new()), stream);
+                        (sink, stream, certified_id)
+                    }))
+            },
+        }
+    }
+
+    /// Whether as many devices are connected as the config allows.
+    fn is_full(&self) -> bool {
+        match self.config.max_connections {
+            Some(max_connections) => self.connections.load(Ordering::SeqCst) >= max_connections,
+            None => false,
+        }
+    }
+
+    /// Tell a device that we're too busy for it and when to come back, and hang up.
+    fn turn_away(&self, connection_id: usize, addr: SocketAddr, sink: DeviceSink) -> impl Future<Item=(), Error=()> {
+        println!("! {:4}: turned away {}, because too many devices are connected", connection_id, &addr.ip());
+
+        let mut retry_after = device::RetryAfter::new();
+        retry_after.set_seconds(self.config.retry_after);
+        let mut message = device::ServerMessage::new();
+        message.set_retry_after(retry_after);
+
+        sink.send(message)
+            .map(|_| ())
+            .map_err(move |err| println!("Warning: failed to turn away {}: {}", connection_id, err))
+    }
+
+    /// Wrap a connection into a future, and let ClientConnection fully handle everything that
     /// happens with the client connection.
-    pub fn handle_client_connection<S, C>(&self, connection_id: usize, addr: SocketAddr, stream: TlsStream<S, C>) -> impl Future<Item=(), Error=std::io::Error>
-        where S: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + 'static,
-              C: rustls::Session + 'static,
-    {
-        println!("! {:4}: connected from {}", connection_id, &addr.ip());
-
-        let connection = ClientConnection::new(connection_id, addr, self.world.clone(), self.config.clone());
-        connection.handle_connection(stream)
+    fn handle_client_connection(&self, connection_id: usize, addr: SocketAddr, transport: Transport, certified_id: Option<String>, sink: DeviceSink, stream: DeviceStream) -> impl Future<Item=(), Error=std::io::Error> {
+        match transport {
+            Transport::Tls => println!("! {:4}: connected from {}", connection_id, &addr.ip()),
+            Transport::WebSocket => println!("! {:4}: connected from {} over WebSocket", connection_id, &addr.ip()),
+        }
+
+        let connection = ClientConnection::new(connection_id, addr, certified_id, self.world.clone(), self.config.clone());
+        connection.handle_connection(sink, stream)
     }
 }
@@ -146,3 +146,51 @@ impl<S> Stream for CancelableStream<S>
         self.stream.poll()
     }
 }
+
+/// The future version of CancelableStream. Resolves with `None` if it was canceled before the
+/// inner future finished.
+pub struct CancelableFuture<F> {
+    future: F,
+    receiver: Receiver<()>,
+}
+
+impl<F> CancelableFuture<F>
+    where F: Future
+{
+    /// Create a new cancelable future.
+    pub fn new(future: F) -> (CancelableFuture<F>, CancelHandle) {
+        let (sender, receiver) = channel();
+
+        let future = CancelableFuture {
+            future: future,
+            receiver: receiver,
+        };
+
+        let handle = CancelHandle {
+            sender: sender,
+        };
+
+        (future, handle)
+    }
+}
+
+impl<F> Future for CancelableFuture<F>
+    where F: Future
+{
+    type Item = Option<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Option<F::Item>, F::Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(_)) => {
+                return Ok(Async::Ready(None));
+            },
+            _ => {},
+        }
+
+        match self.future.poll()? {
+            Async::Ready(item) => Ok(Async::Ready(Some(item))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
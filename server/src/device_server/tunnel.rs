@@ -0,0 +1,172 @@
+//! The server half of native tunnels. For every NATIVE forward of a connected device, the server
+//! listens on the forward's remote port and turns each accepted connection into a tunnel stream
+//! that is carried over the device connection.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio;
+use tokio::net::TcpListener;
+use tokio_timer::Delay;
+use futures::{Future, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc::Sender;
+
+use connectbot_shared::tunnel::{event_channel, EventSender, TunnelFrame, TunnelStream};
+
+use super::stream_helpers::{CancelableStream, CancelHandle};
+use super::super::world::SshForwardData;
+
+/// How long to wait before accepting again when accepting fails, e.g. because we're out of file
+/// descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+type Streams = Arc<Mutex<HashMap<u32, EventSender>>>;
+
+/// All of the native tunnel listeners and streams for a single device connection.
+pub struct TunnelManager {
+    /// Where streams send their frames. Something else is responsible for wrapping these up into
+    /// messages for the device.
+    frame_sender: Sender<TunnelFrame>,
+    /// The event channel of every open stream
+    streams: Streams,
+    /// The listener for every NATIVE forward, by connection ID
+    listeners: HashMap<String, CancelHandle>,
+    /// The next stream ID to hand out
+    next_stream_id: Arc<AtomicUsize>,
+}
+
+impl TunnelManager {
+    /// Create a new tunnel manager that sends its frames to the given channel.
+    pub fn new(frame_sender: Sender<TunnelFrame>) -> TunnelManager {
+        TunnelManager {
+            frame_sender,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            listeners: HashMap::new(),
+            next_stream_id: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+
+    /// Start listening on the forward's remote port, if we aren't already. Must be called from
+    /// within a tokio runtime.
+    pub fn listen(&mut self, forward: &SshForwardData) {
+        if self.listeners.contains_key(&forward.id) || forward.remote_port == 0 {
+            return;
+        }
+
+        // A gateway port is exposed externally, just like `ssh -R :port` would do.
+        let bind_address = match forward.gateway_port {
+            true => "0.0.0.0",
+            false => "127.0.0.1",
+        };
+        let socket_addr: SocketAddr = format!("{}:{}", bind_address, forward.remote_port).parse().unwrap();
+        let listener = match TcpListener::bind(&socket_addr) {
+            Ok(listener) => listener,
+            Err(err) => {
+                println!("! Failed to listen on {} for tunnel {}: {}", socket_addr, forward.id, err);
+                return;
+            },
+        };
+        println!("! Tunnel {} listening on {}", forward.id, socket_addr);
+
+        let (incoming, cancel_handle) = CancelableStream::new(listener.incoming());
+        self.listeners.insert(forward.id.clone(), cancel_handle);
+
+        let connection_id = forward.id.clone();
+        let frame_sender = self.frame_sender.clone();
+        let streams = self.streams.clone();
+        let next_stream_id = self.next_stream_id.clone();
+
+        // A failed accept only affects that one connection, so it doesn't stop the listener.
+        let incoming = incoming.then(Ok::<_, ()>);
+
+        let future = incoming.for_each(move |socket| {
+            let socket = match socket {
+                Ok(socket) => socket,
+                Err(err) => {
+                    println!("! Tunnel {} failed to accept a connection: {}", connection_id, err);
+                    let retry = Delay::new(Instant::now() + ACCEPT_RETRY).map_err(|_| ());
+                    return Either::A(retry);
+                },
+            };
+
+            let stream_id = next_stream_id.fetch_add(1, Ordering::Relaxed) as u32;
+            let (event_sender, event_receiver) = event_channel();
+            streams.lock().unwrap().insert(stream_id, event_sender);
+
+            // Let the device know that there is a new stream, so it can connect to the target.
+            // The stream won't get any data from the device until that happens. If the device
+            // connection is busy, we wait for room before accepting anything else, and new
+            // connections queue up in the listen backlog in the meantime.
+            let open = TunnelFrame::Open { stream_id, connection_id: connection_id.clone() };
+            let streams = streams.clone();
+            let frame_sender = frame_sender.clone();
+            Either::B(frame_sender.clone().send(open).then(move |result| {
+                if result.is_err() {
+                    // The device connection is gone. Close the socket, instead of leaving the
+                    // other end waiting for data that never comes.
+                    println!("! Failed to open tunnel stream {}, because the device connection is gone", stream_id);
+                    streams.lock().unwrap().remove(&stream_id);
+                    drop(socket);
+                    return Ok(());
+                }
+
+                let future = TunnelStream::new(stream_id, socket, event_receiver, frame_sender)
+                    .then(move |result| {
+                        streams.lock().unwrap().remove(&stream_id);
+                        result
+                    });
+                tokio::spawn(future);
+
+                Ok(())
+            }))
+        });
+
+        tokio::spawn(future);
+    }
+
+    /// Stop listening for a forward. Streams that are already open stay open until either end
+    /// closes them.
+    pub fn stop(&mut self, connection_id: &str) {
+        if let Some(cancel_handle) = self.listeners.remove(connection_id) {
+            let _ = cancel_handle.cancel();
+            println!("! Tunnel {} stopped listening", connection_id);
+        }
+    }
+
+    /// Hand a frame that the device sent to the stream it belongs to.
+    pub fn on_frame(&self, frame: TunnelFrame) {
+        let stream_id = frame.stream_id();
+        let event = match frame.into_event() {
+            Some(event) => event,
+            None => return,
+        };
+
+        let mut streams = self.streams.lock().unwrap();
+        let failed = match streams.get(&stream_id) {
+            Some(sender) => sender.send(event).is_err(),
+            // The stream is already gone. Nothing to do.
+            None => false,
+        };
+
+        if failed {
+            // The device sent more than the window allows, or the stream is finishing up. Either
+            // way, dropping the sender aborts the stream.
+            println!("! Dropping tunnel stream {}", stream_id);
+            streams.remove(&stream_id);
+        }
+    }
+}
+
+impl Drop for TunnelManager {
+    fn drop(&mut self) {
+        // The device connection is gone, so stop accepting connections that can't go anywhere.
+        // Dropping the streams' senders aborts any open streams.
+        for (_, cancel_handle) in self.listeners.drain() {
+            let _ = cancel_handle.cancel();
+        }
+        self.streams.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,352 @@
+//! The server half of UDP forwards. For every UDP forward of a connected device, the server binds
+//! the forward's remote UDP port and relays datagrams over the device connection.
+
+use std;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use tokio;
+use tokio::net::UdpSocket;
+use tokio_timer::Interval;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sync::mpsc::{channel, Receiver, Sender};
+
+use connectbot_shared::protos::device;
+use connectbot_shared::udp::{self, Datagram, MAX_DATAGRAM_SIZE};
+
+use super::stream_helpers::{CancelableFuture, CancelHandle};
+use super::super::world::SshForwardData;
+
+/// How many datagrams from the device can be waiting to go out of a single relay. If the socket
+/// can't keep up, anything beyond this gets dropped, which is what UDP would do anyway.
+const INCOMING_BUFFER: usize = 64;
+
+type Relays = Arc<Mutex<HashMap<String, Sender<(u32, Bytes)>>>>;
+
+/// All of the UDP relays for a single device connection.
+pub struct UdpRelayManager {
+    /// The channel on which to send messages to the device
+    socket_sender: Sender<device::ServerMessage>,
+    /// The datagram channel for every running relay, by connection ID
+    relays: Relays,
+    /// A handle to stop every running relay, by connection ID
+    cancel_handles: HashMap<String, CancelHandle>,
+    /// How long a peer may go without any traffic before we forget about it
+    idle_timeout: Duration,
+}
+
+impl UdpRelayManager {
+    /// Create a new relay manager that sends datagrams to the device on the given channel, and
+    /// forgets peers that have been idle for `idle_timeout`.
+    pub fn new(socket_sender: Sender<device::ServerMessage>, idle_timeout: Duration) -> UdpRelayManager {
+        UdpRelayManager {
+            socket_sender,
+            relays: Arc::new(Mutex::new(HashMap::new())),
+            cancel_handles: HashMap::new(),
+            idle_timeout,
+        }
+    }
+
+    /// Start relaying the forward's remote port, if we aren't already. Must be called from within
+    /// a tokio runtime.
+    pub fn listen(&mut self, forward: &SshForwardData) {
+        if self.cancel_handles.contains_key(&forward.id) || forward.remote_port == 0 {
+            return;
+        }
+
+        let bind_address = match forward.gateway_port {
+            true => "0.0.0.0",
+            false => "127.0.0.1",
+        };
+        let socket_addr: SocketAddr = format!("{}:{}", bind_address, forward.remote_port).parse().unwrap();
+        let socket = match UdpSocket::bind(&socket_addr) {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("! Failed to bind {} for UDP relay {}: {}", socket_addr, forward.id, err);
+                return;
+            },
+        };
+        println!("! UDP relay {} listening on {}", forward.id, socket_addr);
+
+        let (sender, receiver) = channel(INCOMING_BUFFER);
+        self.relays.lock().unwrap().insert(forward.id.clone(), sender);
+
+        let relay = UdpRelay::new(forward.id.clone(), socket, receiver, self.socket_sender.clone(), self.idle_timeout);
+        let (future, cancel_handle) = CancelableFuture::new(relay);
+        self.cancel_handles.insert(forward.id.clone(), cancel_handle);
+
+        let relays = self.relays.clone();
+        let connection_id = forward.id.clone();
+        let future = future.then(move |_| {
+            relays.lock().unwrap().remove(&connection_id);
+            Ok(())
+        });
+        tokio::spawn(future);
+    }
+
+    /// Stop relaying for a forward.
+    pub fn stop(&mut self, connection_id: &str) {
+        if let Some(cancel_handle) = self.cancel_handles.remove(connection_id) {
+            let _ = cancel_handle.cancel();
+            println!("! UDP relay {} stopped", connection_id);
+        }
+    }
+
+    /// Hand a datagram that the device sent to the relay it belongs to.
+    pub fn on_datagram(&self, datagram: Datagram) {
+        let mut relays = self.relays.lock().unwrap();
+        if let Some(sender) = relays.get_mut(&datagram.connection_id) {
+            // If the relay is backed up, drop the datagram.
+            let _ = sender.try_send((datagram.association_id, datagram.data));
+        }
+    }
+}
+
+impl Drop for UdpRelayManager {
+    fn drop(&mut self) {
+        for (_, cancel_handle) in self.cancel_handles.drain() {
+            let _ = cancel_handle.cancel();
+        }
+    }
+}
+
+/// A remote peer that has sent datagrams to the relay.
+struct Association {
+    peer: SocketAddr,
+    last_activity: Instant,
+}
+
+/// Every peer that a relay has heard from recently.
+struct Associations {
+    /// The peers, by association ID
+    associations: HashMap<u32, Association>,
+    /// The association ID of every peer
+    by_peer: HashMap<SocketAddr, u32>,
+    next_association_id: u32,
+    idle_timeout: Duration,
+}
+
+impl Associations {
+    fn new(idle_timeout: Duration) -> Associations {
+        Associations {
+            associations: HashMap::new(),
+            by_peer: HashMap::new(),
+            next_association_id: 1,
+            idle_timeout,
+        }
+    }
+
+    /// Find the association for a peer that just sent us something, creating one if this is a
+    /// new peer.
+    fn heard_from(&mut self, peer: SocketAddr, now: Instant) -> u32 {
+        if let Some(association_id) = self.by_peer.get(&peer) {
+            if let Some(association) = self.associations.get_mut(association_id) {
+                association.last_activity = now;
+            }
+            return *association_id;
+        }
+
+        let association_id = self.next_association_id;
+        self.next_association_id = self.next_association_id.wrapping_add(1);
+        self.associations.insert(association_id, Association { peer, last_activity: now });
+        self.by_peer.insert(peer, association_id);
+
+        association_id
+    }
+
+    /// Find the peer to send something from the device to. Gives `None` if the association has
+    /// expired.
+    fn peer_for(&mut self, association_id: u32, now: Instant) -> Option<SocketAddr> {
+        self.associations.get_mut(&association_id)
+            .map(|association| {
+                association.last_activity = now;
+                association.peer
+            })
+    }
+
+    /// Forget about peers that we haven't heard from in a while.
+    fn remove_idle(&mut self, now: Instant) {
+        let idle_timeout = self.idle_timeout;
+        let by_peer = &mut self.by_peer;
+
+        self.associations.retain(|_, association| {
+            if udp::is_idle(association.last_activity, now, idle_timeout) {
+                by_peer.remove(&association.peer);
+                false
+            }
+            else {
+                true
+            }
+        });
+    }
+}
+
+/// A future that relays datagrams between a UDP socket and the device connection.
+struct UdpRelay {
+    connection_id: String,
+    socket: UdpSocket,
+    /// Datagrams from the device, with their association ID
+    incoming: Receiver<(u32, Bytes)>,
+    /// The channel on which to send messages to the device
+    outgoing: Sender<device::ServerMessage>,
+    /// Every peer we've heard from recently
+    associations: Associations,
+    /// A message for the device that is waiting for room on `outgoing`
+    pending_message: Option<device::ServerMessage>,
+    /// A datagram from the device that is waiting for the socket
+    pending_send: Option<(SocketAddr, Bytes)>,
+    /// When to look for idle associations
+    cleanup: Interval,
+    /// Where datagrams from peers get read into
+    buffer: Vec<u8>,
+}
+
+impl UdpRelay {
+    fn new(connection_id: String, socket: UdpSocket, incoming: Receiver<(u32, Bytes)>, outgoing: Sender<device::ServerMessage>, idle_timeout: Duration) -> UdpRelay {
+        UdpRelay {
+            connection_id,
+            socket,
+            incoming,
+            outgoing,
+            associations: Associations::new(idle_timeout),
+            pending_message: None,
+            pending_send: None,
+            cleanup: Interval::new_interval(Duration::from_secs(5)),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    fn poll_inner(&mut self) -> Poll<(), std::io::Error> {
+        loop {
+            let mut progress = false;
+
+            while let Ok(Async::Ready(Some(_))) = self.cleanup.poll() {
+                self.associations.remove_idle(Instant::now());
+            }
+
+            // Send anything to the device that is waiting.
+            if let Some(message) = self.pending_message.take() {
+                match self.outgoing.start_send(message).map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))? {
+                    AsyncSink::Ready => progress = true,
+                    AsyncSink::NotReady(message) => self.pending_message = Some(message),
+                }
+            }
+            self.outgoing.poll_complete().map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+
+            // Read a datagram from a peer, if the device has room for it.
+            if self.pending_message.is_none() {
+                if let Async::Ready((n, peer)) = self.socket.poll_recv_from(&mut self.buffer)? {
+                    let data = Bytes::from(&self.buffer[..n]);
+                    let association_id = self.associations.heard_from(peer, Instant::now());
+                    let datagram = Datagram {
+                        connection_id: self.connection_id.clone(),
+                        association_id,
+                        data,
+                    };
+                    self.pending_message = Some(datagram.into_server_message());
+                    progress = true;
+                }
+            }
+
+            // Send a datagram from the device to its peer.
+            if self.pending_send.is_none() {
+                match self.incoming.poll() {
+                    Ok(Async::Ready(Some((association_id, data)))) => {
+                        if let Some(peer) = self.associations.peer_for(association_id, Instant::now()) {
+                            self.pending_send = Some((peer, data));
+                        }
+                        // Otherwise the association has expired, so there's nobody to send to.
+                        progress = true;
+                    },
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        return Ok(Async::Ready(()));
+                    },
+                    Ok(Async::NotReady) => {},
+                }
+            }
+            if let Some((peer, data)) = self.pending_send.take() {
+                match self.socket.poll_send_to(&data, &peer) {
+                    Ok(Async::Ready(_)) => progress = true,
+                    Ok(Async::NotReady) => self.pending_send = Some((peer, data)),
+                    Err(err) => {
+                        // A single peer going away shouldn't take down the whole relay.
+                        println!("! UDP relay {} failed to send to {}: {}", self.connection_id, peer, err);
+                        progress = true;
+                    },
+                }
+            }
+
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+impl Future for UdpRelay {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.poll_inner().map_err(|err| {
+            println!("! UDP relay {} stopped: {}", self.connection_id, err);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    #[test]
+    fn each_peer_gets_an_association_of_its_own() {
+        let now = Instant::now();
+        let mut associations = Associations::new(Duration::from_secs(60));
+
+        let first = associations.heard_from(peer(1000), now);
+        let second = associations.heard_from(peer(1001), now);
+
+        assert_ne!(first, second);
+        assert_eq!(associations.heard_from(peer(1000), now), first);
+        assert_eq!(associations.peer_for(first, now), Some(peer(1000)));
+        assert_eq!(associations.peer_for(second, now), Some(peer(1001)));
+    }
+
+    #[test]
+    fn idle_associations_expire() {
+        let start = Instant::now();
+        let mut associations = Associations::new(Duration::from_secs(60));
+        let association_id = associations.heard_from(peer(1000), start);
+
+        associations.remove_idle(start + Duration::from_secs(60));
+        assert_eq!(associations.peer_for(association_id, start + Duration::from_secs(60)), Some(peer(1000)));
+
+        associations.remove_idle(start + Duration::from_secs(121));
+        assert_eq!(associations.peer_for(association_id, start + Duration::from_secs(121)), None);
+
+        // The peer comes back as a new association.
+        let again = associations.heard_from(peer(1000), start + Duration::from_secs(122));
+        assert_ne!(again, association_id);
+    }
+
+    #[test]
+    fn traffic_in_either_direction_keeps_an_association_alive() {
+        let start = Instant::now();
+        let mut associations = Associations::new(Duration::from_secs(60));
+        let from_device = associations.heard_from(peer(1000), start);
+        let from_peer = associations.heard_from(peer(1001), start);
+
+        associations.peer_for(from_device, start + Duration::from_secs(50));
+        associations.heard_from(peer(1001), start + Duration::from_secs(50));
+        associations.remove_idle(start + Duration::from_secs(100));
+
+        assert_eq!(associations.peer_for(from_device, start + Duration::from_secs(100)), Some(peer(1000)));
+        assert_eq!(associations.peer_for(from_peer, start + Duration::from_secs(100)), Some(peer(1001)));
+    }
+}
@@ -0,0 +1,144 @@
+//! Offering new client binaries to devices. When a device tells us which version it is running,
+//! and the config has a newer version for its architecture, we tell it about it. The device then
+//! downloads it from us a chunk at a time. It checks the signature itself, so all we have to do is
+//! hand out the files.
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use bytes::Bytes;
+use futures::Future;
+use futures::future::poll_fn;
+use tokio_threadpool::blocking;
+
+use connectbot_shared::protos::device;
+use connectbot_shared::update::compare_versions;
+
+use config::{ApplicationConfig, SharedConfig, Update};
+use super::super::world::DeviceFacts;
+
+/// How much of the binary to send at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Find the update to offer a device, if there is one. The first update in the config for the
+/// device's architecture is the version it should be running. Devices never go back to an older
+/// version (they'd refuse it anyway).
+fn find_update<'a>(updates: &'a [Update], arch: &str, client_version: &str) -> Option<&'a Update> {
+    updates.iter()
+        .find(|update| update.arch.as_ref().map_or(true, |update_arch| update_arch == arch))
+        .filter(|update| compare_versions(&update.version, client_version) == Ordering::Greater)
+}
+
+/// Build the message that tells the device about an update, if there is one for it.
+pub fn offer(config: &ApplicationConfig, facts: &DeviceFacts) -> Option<device::ServerMessage> {
+    // Clients that are too old to report their version can't update themselves anyway.
+    if facts.client_version.is_empty() {
+        return None;
+    }
+
+    let update = find_update(&config.updates, &facts.arch, &facts.client_version)?;
+
+    let size = match File::open(&update.binary).and_then(|file| file.metadata()) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => {
+            println!("! Failed to read update {}: {}", update.binary, err);
+            return None;
+        },
+    };
+
+    let mut signature = Vec::new();
+    if let Err(err) = File::open(&update.signature).and_then(|mut file| file.read_to_end(&mut signature)) {
+        println!("! Failed to read update signature {}: {}", update.signature, err);
+        return None;
+    }
+
+    let mut update_available = device::UpdateAvailable::new();
+    update_available.set_version(update.version.as_str().into());
+    update_available.set_size(size);
+    update_available.set_signature(Bytes::from(signature));
+
+    let mut message = device::ServerMessage::new();
+    message.set_update_available(update_available);
+    Some(message)
+}
+
+/// Build the message with the requested piece of an update, without blocking the runtime. Reading
+/// the file blocks.
+pub fn chunk(config: SharedConfig, request: device::UpdateChunkRequest) -> impl Future<Item=device::ServerMessage, Error=()> {
+    poll_fn(move || {
+        blocking(|| chunk_blocking(&config, &request))
+    }).map_err(|_| panic!("the threadpool shut down"))
+}
+
+fn chunk_blocking(config: &ApplicationConfig, request: &device::UpdateChunkRequest) -> device::ServerMessage {
+    let mut update_chunk = device::UpdateChunk::new();
+    update_chunk.set_version(request.get_version().into());
+    update_chunk.set_offset(request.get_offset());
+
+    match read_chunk(config, request.get_version(), request.get_offset()) {
+        Ok(data) => update_chunk.set_data(Bytes::from(data)),
+        Err(err) => update_chunk.set_error(err.into()),
+    }
+
+    let mut message = device::ServerMessage::new();
+    message.set_update_chunk(update_chunk);
+    message
+}
+
+fn read_chunk(config: &ApplicationConfig, version: &str, offset: u64) -> Result<Vec<u8>, String> {
+    let update = config.updates.iter()
+        .find(|update| update.version == version)
+        .ok_or_else(|| format!("Version {} is no longer available", version))?;
+
+    let mut file = File::open(&update.binary)
+        .map_err(|err| format!("Failed to open the update: {}", err))?;
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|err| format!("Failed to read the update: {}", err))?;
+
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    file.take(CHUNK_SIZE as u64).read_to_end(&mut data)
+        .map_err(|err| format!("Failed to read the update: {}", err))?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(version: &str, arch: Option<&str>) -> Update {
+        Update {
+            version: version.to_string(),
+            arch: arch.map(|arch| arch.to_string()),
+            binary: format!("connectbot-client-{}", version),
+            signature: format!("connectbot-client-{}.sig", version),
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_offered() {
+        let updates = vec![update("0.2.0", None)];
+
+        assert_eq!(find_update(&updates, "x86_64", "0.1.0").map(|update| update.version.as_str()), Some("0.2.0"));
+        assert_eq!(find_update(&updates, "x86_64", "0.1.9-rc.1").map(|update| update.version.as_str()), Some("0.2.0"));
+    }
+
+    #[test]
+    fn the_same_or_older_versions_are_not_offered() {
+        let updates = vec![update("0.2.0", None)];
+
+        assert!(find_update(&updates, "x86_64", "0.2.0").is_none());
+        assert!(find_update(&updates, "x86_64", "0.10.0").is_none());
+    }
+
+    #[test]
+    fn the_first_update_for_the_architecture_wins() {
+        let updates = vec![update("0.3.0", Some("arm")), update("0.2.0", Some("x86_64")), update("0.4.0", None)];
+
+        assert_eq!(find_update(&updates, "arm", "0.1.0").map(|update| update.version.as_str()), Some("0.3.0"));
+        assert_eq!(find_update(&updates, "x86_64", "0.1.0").map(|update| update.version.as_str()), Some("0.2.0"));
+        assert_eq!(find_update(&updates, "aarch64", "0.1.0").map(|update| update.version.as_str()), Some("0.4.0"));
+        // The architecture's version is what it should run, even if a later entry is newer.
+        assert!(find_update(&updates, "x86_64", "0.2.0").is_none());
+    }
+}
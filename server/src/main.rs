@@ -38,21 +38,34 @@ use chrono::Utc;
 use std::sync::Arc;
 
 use std::io::{BufReader, Read};
-use std::fs::{self, File};
+use std::fs;
 
 use tokio_rustls::{
     rustls::{
         Certificate, PrivateKey, ServerConfig, NoClientAuth, AllowAnyAnonymousOrAuthenticatedClient, RootCertStore,
-        internal::pemfile::{ certs, pkcs8_private_keys }
     },
 };
+use connectbot_shared::enrollment::CertificateAuthority;
+use connectbot_shared::keys;
 
 fn load_certs(path: &str) -> Vec<Certificate> {
-    certs(&mut BufReader::new(File::open(path).expect("Failed to open cert"))).expect("Failed to read cert")
+    match keys::load_certificates(Path::new(path)) {
+        Ok(certs) => certs.into_iter().map(Certificate).collect(),
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn load_keys(path: &str) -> Vec<PrivateKey> {
-    pkcs8_private_keys(&mut BufReader::new(File::open(path).expect("Failed to open key"))).expect("Failed to read key")
+fn load_key(path: &str) -> keys::PrivateKey {
+    match keys::load_private_key(Path::new(path)) {
+        Ok(key) => key,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
@@ -93,15 +106,55 @@ fn main() {
         config.ssh.private_key_data = Some(data);
     }
 
+    for key in &config.ssh.host_keys {
+        if key.split_whitespace().count() < 2 || key.contains('\n') {
+            println!("ssh.host_keys: {:?} doesn't look like a public key (TYPE BASE64)", key);
+            std::process::exit(1);
+        }
+    }
+    if let Some(ref destinations) = config.ssh.dynamic_destinations {
+        // Checking what's asked for against nothing just checks that it's valid.
+        if let Err(err) = world::permitted_destinations(destinations, None) {
+            println!("ssh.dynamic_destinations: {}", err);
+            std::process::exit(1);
+        }
+    }
+    if config.ssh.host_keys.is_empty() {
+        println!("Warning: ssh.host_keys isn't set, so devices using the ssh backend can't connect to the SSH server");
+    }
+
+    if let Some(ref websocket) = config.websocket {
+        let required = config.client_authentication.as_ref().map_or(false, |client_authentication| client_authentication.required);
+        if !websocket.tls && required && websocket.client_certificate_header.is_none() {
+            println!("websocket: with tls = false, the server never sees client certificates, but client_authentication.required is set. Set client_certificate_header to the header that the reverse proxy passes them in, or turn tls back on.");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(ref mut enrollment) = config.enrollment {
+        match CertificateAuthority::load(Path::new(&enrollment.ca_certificate), Path::new(&enrollment.ca_key)) {
+            Ok(authority) => enrollment.authority = Some(Arc::new(authority)),
+            Err(err) => {
+                println!("enrollment: {}", err);
+                std::process::exit(1);
+            }
+        }
+        if config.client_authentication.as_ref().map_or(true, |client_authentication| !client_authentication.required) {
+            println!("Warning: enrollment is set up, but client_authentication isn't required, so devices won't be asked for their certificates");
+        }
+    }
+
     let config = Arc::new(config);
     let world = world::World::shared(config.clone());
-    let control_server = control_server::Server::new(world.clone());
+    let control_server = control_server::Server::new(world.clone(), config.clone());
     let device_server = device_server::Server::new(world.clone(), config.clone());
 
     // Create a future that hands all of the work the device server does.
     let device_server_future = {
         let addr = &config.address;
         let socket_addr = addr.parse().expect("address must be a valid socket address");
+        let websocket_addr = config.websocket.as_ref()
+            .map(|websocket| websocket.address.parse().expect("websocket.address must be a valid socket address"));
         let cert_file = &config.tls.certificate;
         let key_file = &config.tls.key;
 
@@ -115,10 +168,14 @@ fn main() {
             _ => ServerConfig::new(NoClientAuth::new()),
         };
 
-        config.set_single_cert(load_certs(&cert_file), load_keys(&key_file).remove(0))
-            .expect("invalid key or certificate");
+        let key = load_key(key_file);
+        let kind = key.kind;
+        if let Err(err) = config.set_single_cert(load_certs(cert_file), PrivateKey(key.der)) {
+            println!("The {} key in {} can't be used with the certificate in {} ({:?}). TLS keys can be RSA or ECDSA.", kind, key_file, cert_file, err);
+            std::process::exit(1);
+        }
 
-        device_server.listen(socket_addr, config)
+        device_server.listen(socket_addr, websocket_addr, config)
             .map(|_server| ())
     };
 
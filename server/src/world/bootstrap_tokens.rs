@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Duration, Utc};
+use uuid;
+
+/// One-time tokens that let a device that doesn't have a client certificate yet get one. They're
+/// only kept in memory, so restarting the server throws them away.
+#[derive(Debug)]
+pub struct BootstrapTokens(HashMap<String, BootstrapToken>);
+
+/// A token that hasn't been used yet.
+#[derive(Debug)]
+struct BootstrapToken {
+    /// The device that may use the token. If this isn't set, any device that the server doesn't
+    /// know yet may.
+    device_id: Option<String>,
+    /// When the token stops working
+    expires: DateTime<Utc>,
+}
+
+impl BootstrapTokens {
+    /// Create a new, empty set of tokens.
+    pub fn new() -> BootstrapTokens {
+        BootstrapTokens(HashMap::new())
+    }
+
+    /// Create a token that works once until `lifetime` from now. Returns the token and when it
+    /// expires.
+    pub fn create(&mut self, device_id: Option<String>, now: DateTime<Utc>, lifetime: Duration) -> (String, DateTime<Utc>) {
+        let token = uuid::Uuid::new_v4().to_simple().to_string();
+        let expires = now + lifetime;
+        self.0.insert(token.clone(), BootstrapToken { device_id, expires });
+        (token, expires)
+    }
+
+    /// Check whether the token may be used by the device. `known` is whether the server knows the
+    /// device already: a token that isn't for a particular device can't be used to take over a
+    /// device that exists. The token isn't used up until it's removed, so that a failure to issue
+    /// a certificate doesn't waste it.
+    pub fn check(&self, token: &str, device_id: &str, known: bool, now: DateTime<Utc>) -> Result<(), String> {
+        match self.0.get(token) {
+            None => Err("The token isn't valid (it may have been used already)".to_string()),
+            Some(token) if token.expires <= now => Err("The token has expired".to_string()),
+            Some(BootstrapToken { device_id: Some(ref id), .. }) if id != device_id => {
+                Err(format!("The token is for another device ({})", id))
+            },
+            Some(BootstrapToken { device_id: None, .. }) if known => {
+                Err(format!("{} is known already, so it needs a token that's made for it (create-token --device {})", device_id, device_id))
+            },
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Use up a token.
+    pub fn remove(&mut self, token: &str) {
+        self.0.remove(token);
+    }
+
+    /// Get rid of tokens that have expired.
+    pub fn cleanup(&mut self, now: DateTime<Utc>) {
+        self.0.retain(|_, token| token.expires > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        "2026-01-01T00:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn a_token_works_until_it_is_removed() {
+        let mut tokens = BootstrapTokens::new();
+        let (token, _) = tokens.create(None, now(), Duration::hours(1));
+
+        assert_eq!(tokens.check(&token, "pi-1", false, now()), Ok(()));
+        assert_eq!(tokens.check(&token, "pi-1", false, now()), Ok(()));
+        tokens.remove(&token);
+        assert!(tokens.check(&token, "pi-1", false, now()).is_err());
+    }
+
+    #[test]
+    fn unknown_tokens_are_refused() {
+        let tokens = BootstrapTokens::new();
+        assert!(tokens.check("nope", "pi-1", false, now()).is_err());
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let mut tokens = BootstrapTokens::new();
+        let (token, expires) = tokens.create(None, now(), Duration::hours(1));
+
+        assert_eq!(expires, now() + Duration::hours(1));
+        assert_eq!(tokens.check(&token, "pi-1", false, expires - Duration::seconds(1)), Ok(()));
+        assert!(tokens.check(&token, "pi-1", false, expires).is_err());
+
+        tokens.cleanup(expires);
+        assert!(tokens.0.is_empty());
+    }
+
+    #[test]
+    fn bound_tokens_only_work_for_their_device() {
+        let mut tokens = BootstrapTokens::new();
+        let (token, _) = tokens.create(Some("pi-1".to_string()), now(), Duration::hours(1));
+
+        assert!(tokens.check(&token, "pi-2", false, now()).is_err());
+        assert_eq!(tokens.check(&token, "pi-1", false, now()), Ok(()));
+        assert_eq!(tokens.check(&token, "pi-1", true, now()), Ok(()));
+    }
+
+    #[test]
+    fn unbound_tokens_only_work_for_new_devices() {
+        let mut tokens = BootstrapTokens::new();
+        let (token, _) = tokens.create(None, now(), Duration::hours(1));
+
+        assert!(tokens.check(&token, "pi-1", true, now()).is_err());
+        assert_eq!(tokens.check(&token, "pi-2", false, now()), Ok(()));
+    }
+}
@@ -0,0 +1,27 @@
+use chrono::{DateTime, Utc};
+
+/// What a device last told us about itself.
+#[derive(Debug, Clone)]
+pub struct DeviceFacts {
+    pub hostname: String,
+    pub os: String,
+    pub kernel: String,
+    pub arch: String,
+    pub client_version: String,
+    /// How long the device had been up when it reported, in seconds.
+    pub uptime: u64,
+    pub interfaces: Vec<DeviceInterface>,
+    pub ssh_version: String,
+    /// The server endpoint that the device is connected to, which isn't necessarily its primary.
+    pub server_endpoint: String,
+    /// When the device reported these facts.
+    pub reported_at: DateTime<Utc>,
+}
+
+/// A network interface on a device.
+#[derive(Debug, Clone)]
+pub struct DeviceInterface {
+    pub name: String,
+    /// The interface's addresses, in CIDR notation.
+    pub addresses: Vec<String>,
+}
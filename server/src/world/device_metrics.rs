@@ -0,0 +1,64 @@
+use std::collections::{BTreeMap, VecDeque};
+use chrono::{DateTime, Utc};
+
+/// The most samples we keep for any one metric. At the client's default probe interval of a
+/// minute, this is a day's worth.
+const MAX_SAMPLES: usize = 1440;
+
+/// The most metrics we keep for any one device, so that a misbehaving client can't make us keep
+/// track of an unbounded number of them.
+const MAX_METRICS: usize = 32;
+
+/// The recent values of a device's metrics, by name.
+#[derive(Debug)]
+pub struct DeviceMetrics(BTreeMap<String, VecDeque<MetricSample>>);
+
+impl DeviceMetrics {
+    /// Create a new, empty set of metrics.
+    pub fn new() -> DeviceMetrics {
+        DeviceMetrics(BTreeMap::new())
+    }
+
+    /// Get the underlying iterator, in order of the metrics' names.
+    pub fn iter(&self) -> ::std::collections::btree_map::Iter<String, VecDeque<MetricSample>> {
+        self.0.iter()
+    }
+
+    /// Record a new value for a metric. If there are too many samples, the oldest is forgotten.
+    /// Values for new metrics are dropped if the device already has too many.
+    pub fn record(&mut self, name: &str, at: DateTime<Utc>, value: f64) {
+        if !self.0.contains_key(name) && self.0.len() >= MAX_METRICS {
+            return;
+        }
+
+        let samples = self.0.entry(name.to_string()).or_insert_with(VecDeque::new);
+        samples.push_back(MetricSample { at, value });
+        while samples.len() > MAX_SAMPLES {
+            samples.pop_front();
+        }
+    }
+
+    /// Get rid of samples that are older than the cutoff, and metrics that have no samples left.
+    pub fn cleanup(&mut self, cutoff: DateTime<Utc>) {
+        for samples in self.0.values_mut() {
+            while samples.front().map_or(false, |sample| sample.at < cutoff) {
+                samples.pop_front();
+            }
+        }
+        let empty: Vec<String> = self.0.iter()
+            .filter(|(_, samples)| samples.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in empty {
+            self.0.remove(&name);
+        }
+    }
+}
+
+/// A single value of a metric.
+#[derive(Debug, Clone, Copy)]
+pub struct MetricSample {
+    /// When the server received the value.
+    pub at: DateTime<Utc>,
+    pub value: f64,
+}
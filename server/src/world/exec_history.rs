@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use chrono::{DateTime, Utc};
+
+/// The most actions we remember for any one device.
+const MAX_ITEMS: usize = 100;
+
+/// The actions that a device has run in recent memory, oldest first, so that there's a record of
+/// who ran what.
+#[derive(Debug)]
+pub struct ExecHistory(VecDeque<ExecHistoryItem>);
+
+impl ExecHistory {
+    /// Create a new, empty history.
+    pub fn new() -> ExecHistory {
+        ExecHistory(VecDeque::new())
+    }
+
+    /// Get the underlying iterator.
+    pub fn iter(&self) -> ::std::collections::vec_deque::Iter<ExecHistoryItem> {
+        self.0.iter()
+    }
+
+    /// Remember an action that finished. If there are too many, the oldest is forgotten.
+    pub fn record(&mut self, item: ExecHistoryItem) {
+        self.0.push_back(item);
+        while self.0.len() > MAX_ITEMS {
+            self.0.pop_front();
+        }
+    }
+
+    /// Get rid of actions that finished before the cutoff.
+    pub fn cleanup(&mut self, cutoff: DateTime<Utc>) {
+        self.0.retain(|item| item.finished_at >= cutoff);
+    }
+}
+
+/// A single action that a device ran.
+#[derive(Debug, Clone)]
+pub struct ExecHistoryItem {
+    /// The ID of the request. This is only unique to the connection the device had at the time.
+    pub request_id: u32,
+    /// The name of the action
+    pub action: String,
+    /// How it ended
+    pub outcome: ExecOutcome,
+    /// When the server asked the device to run it
+    pub started_at: DateTime<Utc>,
+    /// When the device said it was done
+    pub finished_at: DateTime<Utc>,
+}
+
+/// How an action ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecOutcome {
+    /// The action exited with the given exit code
+    Exited(i32),
+    /// The device doesn't allow the action
+    Rejected,
+    /// The action couldn't be run, or was killed
+    Failed(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn now() -> DateTime<Utc> {
+        "2026-01-01T00:00:00Z".parse().unwrap()
+    }
+
+    fn item(request_id: u32, finished_at: DateTime<Utc>) -> ExecHistoryItem {
+        ExecHistoryItem {
+            request_id,
+            action: "uptime".to_string(),
+            outcome: ExecOutcome::Exited(0),
+            started_at: finished_at - Duration::seconds(1),
+            finished_at,
+        }
+    }
+
+    #[test]
+    fn record_forgets_the_oldest_actions_once_there_are_too_many() {
+        let now = now();
+        let mut history = ExecHistory::new();
+        for request_id in 0..(MAX_ITEMS as u32 + 5) {
+            history.record(item(request_id, now));
+        }
+
+        let ids: Vec<u32> = history.iter().map(|item| item.request_id).collect();
+        assert_eq!(ids.len(), MAX_ITEMS);
+        assert_eq!(ids[0], 5);
+        assert_eq!(ids[MAX_ITEMS - 1], MAX_ITEMS as u32 + 4);
+    }
+
+    #[test]
+    fn cleanup_forgets_actions_that_finished_before_the_cutoff() {
+        let now = now();
+        let mut history = ExecHistory::new();
+        history.record(item(1, now - Duration::days(3)));
+        history.record(item(2, now - Duration::hours(1)));
+        history.record(item(3, now));
+
+        history.cleanup(now - Duration::days(2));
+
+        let ids: Vec<u32> = history.iter().map(|item| item.request_id).collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+}
@@ -10,10 +10,18 @@ use std;
 
 mod connection_history;
 pub use self::connection_history::{ConnectionHistory, ConnectionHistoryItem};
+mod exec_history;
+pub use self::exec_history::{ExecHistory, ExecHistoryItem, ExecOutcome};
+mod device_facts;
+pub use self::device_facts::{DeviceFacts, DeviceInterface};
+mod device_metrics;
+pub use self::device_metrics::DeviceMetrics;
 mod ssh_forward;
-pub use self::ssh_forward::{SshForwards, SshForward, SshForwardData, SshForwardClientState, SshForwardServerState};
+pub use self::ssh_forward::{SshForwards, SshForward, SshForwardData, SshForwardClientState, SshForwardFailure, SshForwardFailureKind, SshForwardServerState, SshForwardTransport, SshForwardProtocol, SshForwardDirection, permitted_destinations};
 mod port_allocator;
 pub use self::port_allocator::RemotePort;
+mod bootstrap_tokens;
+pub use self::bootstrap_tokens::BootstrapTokens;
 use self::port_allocator::{PortAllocator, PortAllocatorSettings};
 
 pub type SharedWorld = Arc<RwLock<World>>;
@@ -23,6 +31,8 @@ pub type SharedWorld = Arc<RwLock<World>>;
 pub struct World {
     /// Map from device ID to the device
     pub devices: HashMap<String, Device>,
+    /// Tokens that devices can use to get a client certificate
+    pub bootstrap_tokens: BootstrapTokens,
     port_allocator: PortAllocator,
 }
 
@@ -31,11 +41,14 @@ impl World {
     pub fn new(config: super::config::SharedConfig) -> World {
         World {
             devices: HashMap::new(),
+            bootstrap_tokens: BootstrapTokens::new(),
             port_allocator: PortAllocator::new(PortAllocatorSettings {
                 web_start: config.ssh.web_port_start,
                 web_end: config.ssh.web_port_end,
                 other_start: config.ssh.port_start,
                 other_end: config.ssh.port_end,
+                udp_start: config.ssh.udp_port_start,
+                udp_end: config.ssh.udp_port_end,
             }),
         }
     }
@@ -53,8 +66,12 @@ impl World {
         for device in self.devices.values_mut() {
             let active_connection = device.active_connection.clone();
             device.connection_history.cleanup(connection_history_cutoff);
+            device.metrics.cleanup(connection_history_cutoff);
+            device.exec_history.cleanup(connection_history_cutoff);
             device.ssh_forwards.cleanup(now, forwards_cutoff, active_connection);
         }
+
+        self.bootstrap_tokens.cleanup(now);
     }
 
     /// Create a device manually
@@ -134,6 +151,17 @@ pub struct Device {
     pub active_connection: Option<ClientConnectionHandle>,
     /// Connection history
     pub connection_history: ConnectionHistory,
+    /// The actions that the device said it is willing to run, the last time it connected
+    pub actions: Vec<String>,
+    /// The actions that the device has run recently, and how they ended
+    pub exec_history: ExecHistory,
+    /// The directories that the device said files may be copied to and from, the last time it
+    /// connected
+    pub file_dirs: Vec<String>,
+    /// What the device last told us about itself, if it has told us anything
+    pub facts: Option<DeviceFacts>,
+    /// The recent values of the device's probes
+    pub metrics: DeviceMetrics,
 }
 
 impl Device {
@@ -145,6 +173,11 @@ impl Device {
             ssh_forwards: SshForwards::new(allocator),
             active_connection: None,
             connection_history: ConnectionHistory::new(),
+            actions: Vec::new(),
+            exec_history: ExecHistory::new(),
+            file_dirs: Vec::new(),
+            facts: None,
+            metrics: DeviceMetrics::new(),
         }
     }
 
@@ -22,6 +22,9 @@ enum PortType {
     Web,
     /// The port is, well, not a web port. Any other type of port.
     Other,
+    /// The port is a UDP port. UDP ports don't conflict with TCP ports, so they get their own
+    /// pool.
+    Udp,
 }
 
 /// Settings from the configuration file about which port ranges to use for web ports and all other
@@ -35,6 +38,10 @@ pub struct PortAllocatorSettings {
     pub other_start: u16,
     /// The last port (inclusive) to use for other ports
     pub other_end: u16,
+    /// The first port to use for UDP ports
+    pub udp_start: u16,
+    /// The last port (inclusive) to use for UDP ports
+    pub udp_end: u16,
 }
 
 impl PortAllocator {
@@ -54,6 +61,11 @@ impl PortAllocator {
     pub fn allocate(&self) -> Result<RemotePort, PortAllocationError> {
         self.port_allocator.allocate(PortType::Other, self.port_allocator.clone())
     }
+
+    /// Try to allocate a single UDP port
+    pub fn allocate_udp(&self) -> Result<RemotePort, PortAllocationError> {
+        self.port_allocator.allocate(PortType::Udp, self.port_allocator.clone())
+    }
 }
 
 /// Internal data about the port allocator. PortAllocator itself stores an Arc to this, so that it
@@ -62,6 +74,7 @@ impl PortAllocator {
 struct PrivatePortAllocator {
     web_port_range: RwLock<ReservablePortRange>,
     other_port_range: RwLock<ReservablePortRange>,
+    udp_port_range: RwLock<ReservablePortRange>,
 }
 
 impl PrivatePortAllocator {
@@ -70,6 +83,7 @@ impl PrivatePortAllocator {
         PrivatePortAllocator {
             web_port_range: RwLock::new(ReservablePortRange::new(settings.web_start, settings.web_end)),
             other_port_range: RwLock::new(ReservablePortRange::new(settings.other_start, settings.other_end)),
+            udp_port_range: RwLock::new(ReservablePortRange::new(settings.udp_start, settings.udp_end)),
         }
     }
 
@@ -78,6 +92,7 @@ impl PrivatePortAllocator {
         let next = match port_type {
             PortType::Web => self.web_port_range.write().unwrap().take_next(),
             PortType::Other => self.other_port_range.write().unwrap().take_next(),
+            PortType::Udp => self.udp_port_range.write().unwrap().take_next(),
         };
         match next {
             Some(port) => {
@@ -93,6 +108,7 @@ impl PrivatePortAllocator {
         match port.port_type {
             PortType::Web => self.web_port_range.write().unwrap().return_port(port.port_value),
             PortType::Other => self.other_port_range.write().unwrap().return_port(port.port_value),
+            PortType::Udp => self.udp_port_range.write().unwrap().return_port(port.port_value),
         }
     }
 }
@@ -15,6 +15,17 @@ pub struct SshForward {
     pub forward_port: u16,
     pub remote_port: Option<RemotePort>,
     pub gateway_port: bool,
+    pub transport: SshForwardTransport,
+    pub protocol: SshForwardProtocol,
+    pub direction: SshForwardDirection,
+    /// Whether the device acts as a SOCKS5 exit instead of forwarding to forward_host:forward_port
+    pub dynamic: bool,
+    /// For dynamic forwards, the destinations that may be reached. Empty means anything.
+    pub permitted_destinations: Vec<String>,
+    /// Why the client last failed to keep the forward up, if it hasn't connected since
+    pub last_failure: Option<SshForwardFailure>,
+    /// Why the client refused to set up the forward, if it did
+    pub rejected_reason: Option<String>,
 }
 
 impl SshForward {
@@ -25,6 +36,11 @@ impl SshForward {
             forward_port: self.forward_port,
             remote_port: self.remote_port.as_ref().map_or(0, |item| item.value()),
             gateway_port: self.gateway_port,
+            transport: self.transport,
+            protocol: self.protocol,
+            direction: self.direction,
+            dynamic: self.dynamic,
+            permitted_destinations: self.permitted_destinations.clone(),
         }
     }
 }
@@ -36,6 +52,41 @@ pub struct SshForwardData {
     pub forward_port: u16,
     pub remote_port: u16,
     pub gateway_port: bool,
+    pub transport: SshForwardTransport,
+    pub protocol: SshForwardProtocol,
+    pub direction: SshForwardDirection,
+    pub dynamic: bool,
+    pub permitted_destinations: Vec<String>,
+}
+
+/// How a forward gets carried between the server and the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshForwardTransport {
+    /// The device spawns `ssh -R` and connects to the server's SSH service.
+    Ssh,
+    /// The server listens on the remote port itself, and the traffic is multiplexed over the
+    /// device connection.
+    Native,
+}
+
+/// Which protocol a forward carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshForwardProtocol {
+    Tcp,
+    /// UDP can't go through `ssh -R`, so UDP forwards are always relayed over the device
+    /// connection.
+    Udp,
+}
+
+/// Which way a forward goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshForwardDirection {
+    /// `ssh -R`: the remote port on the server reaches `forward_host:forward_port` on the device's
+    /// network.
+    Remote,
+    /// `ssh -L`: a port on the device reaches `forward_host:forward_port` as seen from the server.
+    /// There is no remote port.
+    Local { local_port: u16 },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -62,6 +113,34 @@ pub enum SshForwardClientState {
     Disconnected,
     /// The client has reported that it tried and failed to establish an SSH session.
     Failed,
+    /// The client has reported that it won't set up the forward, because its local policy doesn't
+    /// allow it.
+    Rejected,
+}
+
+/// Why the client failed to keep a forward up, as the client reported it to us.
+#[derive(Debug, Clone)]
+pub struct SshForwardFailure {
+    /// How many times in a row the client had failed
+    pub failures: u32,
+    pub kind: SshForwardFailureKind,
+    /// The exit code of `ssh`, if it exited
+    pub exit_code: Option<i32>,
+    /// The last few lines that `ssh` had to say about it
+    pub detail: String,
+    /// When the client told us
+    pub reported_at: DateTime<Utc>,
+}
+
+/// What went wrong with a forward, as far as the client could tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshForwardFailureKind {
+    Unknown,
+    AuthDenied,
+    PortInUse,
+    HostUnreachable,
+    HostKeyMismatch,
+    TargetRefused,
 }
 
 /// All of the SSH forwards for a device
@@ -93,13 +172,19 @@ impl SshForwards {
         self.forwards.iter()
     }
 
-    pub fn create(&mut self, forward_host: String, forward_port: u16, gateway_port: bool) -> &SshForward {
+    pub fn create(&mut self, forward_host: String, forward_port: u16, gateway_port: bool, transport: SshForwardTransport, protocol: SshForwardProtocol) -> &SshForward {
         let id = format!("{}", uuid::Uuid::new_v4());
 
         // TODO: Propagate error
-        let remote_port = match forward_port {
-            80 => self.allocator.allocate_web().expect("Ran out of ports"),
-            _ => self.allocator.allocate().expect("Ran out of ports"),
+        let remote_port = match (protocol, forward_port) {
+            (SshForwardProtocol::Udp, _) => self.allocator.allocate_udp().expect("Ran out of ports"),
+            (SshForwardProtocol::Tcp, 80) => self.allocator.allocate_web().expect("Ran out of ports"),
+            (SshForwardProtocol::Tcp, _) => self.allocator.allocate().expect("Ran out of ports"),
+        };
+
+        let transport = match protocol {
+            SshForwardProtocol::Udp => SshForwardTransport::Native,
+            SshForwardProtocol::Tcp => transport,
         };
 
         let until = Utc::now() + Duration::days(1);
@@ -112,6 +197,76 @@ impl SshForwards {
             forward_port,
             remote_port: Some(remote_port),
             gateway_port,
+            transport,
+            protocol,
+            direction: SshForwardDirection::Remote,
+            dynamic: false,
+            permitted_destinations: Vec::new(),
+            last_failure: None,
+            rejected_reason: None,
+        };
+
+        self.forwards.push(forward);
+
+        &self.forwards[self.forwards.len() - 1]
+    }
+
+    /// Create a local forward, which lets the device reach `forward_host:forward_port` through
+    /// `local_port` on the device. Local forwards don't need a port on the server, and are always
+    /// carried by SSH.
+    pub fn create_local(&mut self, forward_host: String, forward_port: u16, local_port: u16, gateway_port: bool) -> &SshForward {
+        let id = format!("{}", uuid::Uuid::new_v4());
+
+        let until = Utc::now() + Duration::days(1);
+
+        let forward = SshForward {
+            id: id.clone(),
+            client_state: SshForwardClientState::Requested,
+            server_state: SshForwardServerState::Active { until },
+            forward_host,
+            forward_port,
+            remote_port: None,
+            gateway_port,
+            transport: SshForwardTransport::Ssh,
+            protocol: SshForwardProtocol::Tcp,
+            direction: SshForwardDirection::Local { local_port },
+            dynamic: false,
+            permitted_destinations: Vec::new(),
+            last_failure: None,
+            rejected_reason: None,
+        };
+
+        self.forwards.push(forward);
+
+        &self.forwards[self.forwards.len() - 1]
+    }
+
+    /// Create a dynamic forward, where the device acts as a SOCKS5 exit into its network. Like
+    /// UDP forwards are always native, dynamic forwards are always carried by SSH: OpenSSH does
+    /// the SOCKS part for us.
+    pub fn create_dynamic(&mut self, gateway_port: bool, permitted_destinations: Vec<String>) -> &SshForward {
+        let id = format!("{}", uuid::Uuid::new_v4());
+
+        // TODO: Propagate error
+        let remote_port = self.allocator.allocate().expect("Ran out of ports");
+
+        let until = Utc::now() + Duration::days(1);
+
+        let forward = SshForward {
+            id: id.clone(),
+            client_state: SshForwardClientState::Requested,
+            server_state: SshForwardServerState::Active { until },
+            forward_host: String::new(),
+            forward_port: 0,
+            remote_port: Some(remote_port),
+            gateway_port,
+            transport: SshForwardTransport::Ssh,
+            protocol: SshForwardProtocol::Tcp,
+            direction: SshForwardDirection::Remote,
+            dynamic: true,
+            permitted_destinations,
+            last_failure: None,
+            rejected_reason: None,
         };
 
         self.forwards.push(forward);
@@ -127,7 +282,11 @@ impl SshForwards {
             if item.id == id {
                 item.client_state = client_state;
                 match item.client_state {
-                    SshForwardClientState::Disconnected => {
+                    SshForwardClientState::Connected => {
+                        item.last_failure = None;
+                        item.rejected_reason = None;
+                    },
+                    SshForwardClientState::Disconnected | SshForwardClientState::Rejected => {
                         item.remote_port = None;
                     },
                     _ => {},
@@ -143,6 +302,20 @@ impl SshForwards {
         }
     }
 
+    /// Remember why the client last failed to keep a forward up.
+    pub fn record_failure(&mut self, id: &str, failure: SshForwardFailure) {
+        if let Some(item) = self.forwards.iter_mut().find(|item| item.id == id) {
+            item.last_failure = Some(failure);
+        }
+    }
+
+    /// Remember why the client refused to set up a forward.
+    pub fn record_rejection(&mut self, id: &str, reason: String) {
+        if let Some(item) = self.forwards.iter_mut().find(|item| item.id == id) {
+            item.rejected_reason = Some(reason);
+        }
+    }
+
     pub fn disconnect(&mut self, id: &str) -> bool {
         let mut success = false;
         for item in self.forwards.iter_mut() {
@@ -199,3 +372,145 @@ impl SshForwards {
         });
     }
 }
+
+/// Work out which destinations a dynamic forward may reach, given what was asked for and what the
+/// configuration allows. An empty list means anything.
+pub fn permitted_destinations(requested: &[String], allowed: Option<&[String]>) -> Result<Vec<String>, String> {
+    let requested_destinations = requested.iter()
+        .map(|destination| Destination::parse(destination))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match allowed {
+        None => Ok(requested.to_vec()),
+        Some(allowed) if allowed.is_empty() => Err("Dynamic forwards are not allowed".to_string()),
+        Some(allowed) if requested.is_empty() => Ok(allowed.to_vec()),
+        Some(allowed) => {
+            // The configuration was checked when it was loaded, so everything in it parses.
+            let allowed: Vec<Destination> = allowed.iter()
+                .filter_map(|destination| Destination::parse(destination).ok())
+                .collect();
+            let refused = requested.iter().zip(&requested_destinations)
+                .find(|(_, destination)| !allowed.iter().any(|allowed| allowed.covers(destination)));
+            match refused {
+                Some((destination, _)) => Err(format!("Destination {:?} is not allowed", destination)),
+                None => Ok(requested.to_vec()),
+            }
+        },
+    }
+}
+
+/// A destination of a dynamic forward, `host:port`, where either may be `*`. This matches the
+/// way that clients check forwards against their local policy.
+#[derive(Debug, Clone, PartialEq)]
+struct Destination {
+    /// The host, or None for any host
+    host: Option<String>,
+    /// The port, or None for any port
+    port: Option<u16>,
+}
+
+impl Destination {
+    fn parse(destination: &str) -> Result<Destination, String> {
+        let invalid = || format!("Invalid destination {:?}, expected host:port", destination);
+
+        let index = destination.rfind(':').ok_or_else(invalid)?;
+        let (host, port) = (&destination[..index], &destination[index + 1..]);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.chars().any(char::is_whitespace) {
+            return Err(invalid());
+        }
+
+        Ok(Destination {
+            host: match host {
+                "*" => None,
+                host => Some(host.to_lowercase()),
+            },
+            port: match port {
+                "*" => None,
+                port => match port.parse::<u16>() {
+                    Ok(port) if port != 0 => Some(port),
+                    _ => return Err(invalid()),
+                },
+            },
+        })
+    }
+
+    /// Whether everything that `other` matches, this matches too.
+    fn covers(&self, other: &Destination) -> bool {
+        let host = self.host.is_none() || self.host == other.host;
+        let port = self.port.is_none() || self.port == other.port;
+        host && port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(destinations: &[&str]) -> Vec<String> {
+        destinations.iter().map(|destination| destination.to_string()).collect()
+    }
+
+    #[test]
+    fn any_host_on_a_port() {
+        let allowed = strings(&["*:80"]);
+
+        assert_eq!(permitted_destinations(&strings(&["db:80"]), Some(&allowed)), Ok(strings(&["db:80"])));
+        assert_eq!(permitted_destinations(&strings(&["web:80", "*:80"]), Some(&allowed)), Ok(strings(&["web:80", "*:80"])));
+        assert!(permitted_destinations(&strings(&["db:5432"]), Some(&allowed)).is_err());
+        assert!(permitted_destinations(&strings(&["db:*"]), Some(&allowed)).is_err());
+    }
+
+    #[test]
+    fn any_port_on_a_host() {
+        let allowed = strings(&["db:*"]);
+
+        assert_eq!(permitted_destinations(&strings(&["db:5432"]), Some(&allowed)), Ok(strings(&["db:5432"])));
+        assert_eq!(permitted_destinations(&strings(&["DB:22"]), Some(&allowed)), Ok(strings(&["DB:22"])));
+        assert!(permitted_destinations(&strings(&["web:80"]), Some(&allowed)).is_err());
+        assert!(permitted_destinations(&strings(&["*:5432"]), Some(&allowed)).is_err());
+    }
+
+    #[test]
+    fn exact_match() {
+        let allowed = strings(&["db:5432", "[fd00::1]:22"]);
+
+        assert_eq!(permitted_destinations(&strings(&["db:5432"]), Some(&allowed)), Ok(strings(&["db:5432"])));
+        assert_eq!(permitted_destinations(&strings(&["[fd00::1]:22"]), Some(&allowed)), Ok(strings(&["[fd00::1]:22"])));
+        assert!(permitted_destinations(&strings(&["db:5433"]), Some(&allowed)).is_err());
+        assert!(permitted_destinations(&strings(&["db:5432", "web:80"]), Some(&allowed)).is_err());
+    }
+
+    #[test]
+    fn refusals_name_the_destination() {
+        let allowed = strings(&["db:5432"]);
+
+        assert_eq!(permitted_destinations(&strings(&["db:5432", "web:80"]), Some(&allowed)), Err("Destination \"web:80\" is not allowed".to_string()));
+    }
+
+    #[test]
+    fn wildcards_are_only_allowed_by_wildcards() {
+        assert!(permitted_destinations(&strings(&["*:*"]), Some(&strings(&["db:*", "*:80"]))).is_err());
+        assert_eq!(permitted_destinations(&strings(&["*:*"]), Some(&strings(&["*:*"]))), Ok(strings(&["*:*"])));
+        assert_eq!(permitted_destinations(&strings(&["db:80"]), Some(&strings(&["*:*"]))), Ok(strings(&["db:80"])));
+    }
+
+    #[test]
+    fn rejections() {
+        // Dynamic forwards are turned off.
+        assert!(permitted_destinations(&[], Some(&[])).is_err());
+        // Nonsense is never allowed.
+        assert!(permitted_destinations(&strings(&["db"]), None).is_err());
+        assert!(permitted_destinations(&strings(&["db:0"]), None).is_err());
+        assert!(permitted_destinations(&strings(&["db:65536"]), None).is_err());
+        assert!(permitted_destinations(&strings(&[":80"]), None).is_err());
+    }
+
+    #[test]
+    fn nothing_requested_gets_everything_allowed() {
+        let allowed = strings(&["*:80", "db:5432"]);
+
+        assert_eq!(permitted_destinations(&[], Some(&allowed)), Ok(allowed.clone()));
+        assert_eq!(permitted_destinations(&[], None), Ok(Vec::new()));
+    }
+}
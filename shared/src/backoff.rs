@@ -0,0 +1,118 @@
+//! How long to wait before trying again after something failed.
+//!
+//! The wait doubles with every failure in a row, starting at the shortest wait and never going
+//! over the longest wait. With jitter, the actual wait is a random one between the shortest wait
+//! and that, so that a fleet of devices that lost their connections at the same moment (say,
+//! because the server restarted) doesn't come back all at once.
+
+use std::time::Duration;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// A backoff policy: exponential backoff with a cap, and full jitter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    /// The shortest wait, and the wait after the first failure
+    pub min_delay: Duration,
+    /// The longest wait
+    pub max_delay: Duration,
+    /// Whether to pick a random wait up to the exponential one, instead of the exponential one
+    pub jitter: bool,
+}
+
+impl BackoffPolicy {
+    /// Create a new backoff policy.
+    pub fn new(min_delay: Duration, max_delay: Duration, jitter: bool) -> BackoffPolicy {
+        BackoffPolicy {
+            min_delay,
+            max_delay: max_delay.max(min_delay),
+            jitter,
+        }
+    }
+
+    /// The longest that we'd wait after the given number of failures in a row: the shortest wait,
+    /// doubled for every failure after the first, up to the longest wait.
+    pub fn ceiling(&self, failures: usize) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31) as u32;
+        self.min_delay
+            .checked_mul(1 << doublings)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// How long to wait after the given number of failures in a row.
+    pub fn delay(&self, failures: usize) -> Duration {
+        let ceiling = self.ceiling(failures);
+        if !self.jitter || ceiling <= self.min_delay {
+            return ceiling;
+        }
+
+        let spread = duration_millis(ceiling - self.min_delay);
+        self.min_delay + Duration::from_millis(random_u64() % (spread + 1))
+    }
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy::new(Duration::from_secs(5), Duration::from_secs(120), true)
+    }
+}
+
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000 + u64::from(duration.subsec_millis())
+}
+
+fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    // If there's no randomness to be had, no jitter is better than no wait.
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return 0;
+    }
+    bytes.iter().fold(0, |value, byte| (value << 8) | u64::from(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ceiling_doubles_up_to_the_longest_wait() {
+        let policy = BackoffPolicy::new(Duration::from_secs(5), Duration::from_secs(120), false);
+
+        assert_eq!(policy.ceiling(0), Duration::from_secs(5));
+        assert_eq!(policy.ceiling(1), Duration::from_secs(5));
+        assert_eq!(policy.ceiling(2), Duration::from_secs(10));
+        assert_eq!(policy.ceiling(3), Duration::from_secs(20));
+        assert_eq!(policy.ceiling(5), Duration::from_secs(80));
+        assert_eq!(policy.ceiling(6), Duration::from_secs(120));
+        assert_eq!(policy.ceiling(1_000), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn delay_without_jitter_is_the_ceiling() {
+        let policy = BackoffPolicy::new(Duration::from_secs(1), Duration::from_secs(60), false);
+
+        for failures in 0..20 {
+            assert_eq!(policy.delay(failures), policy.ceiling(failures));
+        }
+    }
+
+    #[test]
+    fn delay_with_jitter_stays_between_the_shortest_wait_and_the_ceiling() {
+        let policy = BackoffPolicy::new(Duration::from_secs(5), Duration::from_secs(120), true);
+
+        for failures in 0..20 {
+            for _ in 0..50 {
+                let delay = policy.delay(failures);
+                assert!(delay >= policy.min_delay);
+                assert!(delay <= policy.ceiling(failures));
+            }
+        }
+    }
+
+    #[test]
+    fn longest_wait_is_never_shorter_than_the_shortest_wait() {
+        let policy = BackoffPolicy::new(Duration::from_secs(30), Duration::from_secs(10), true);
+
+        assert_eq!(policy.delay(10), Duration::from_secs(30));
+    }
+}
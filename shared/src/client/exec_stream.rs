@@ -0,0 +1,98 @@
+use std;
+use futures::prelude::*;
+use tokio::net::TcpStream;
+use tokio_codec;
+use tokio_dns;
+
+use super::codec;
+use super::protos;
+
+type ClientCodec = codec::Codec<protos::control::ClientMessage, protos::control::ServerMessage>;
+type ClientFramed = tokio_codec::Framed<TcpStream, ClientCodec>;
+
+/// A stream of everything that happens while a device runs an action. Like RequestResponseFuture,
+/// except that the server responds more than once: with output as the action runs, and then with
+/// how it finished. The stream ends after that.
+pub struct ExecResponseStream {
+    state: ExecResponseStreamState,
+}
+
+impl ExecResponseStream {
+    /// Create a new stream that will send the given message, and will yield each exec response
+    /// to it.
+    pub fn new(addr: &str, message: protos::control::ClientMessage) -> ExecResponseStream {
+        let f = tokio_dns::TcpStream::connect(&addr[..])
+            .and_then(move |stream| {
+                let framed = tokio_codec::Decoder::framed(ClientCodec::new(), stream);
+                framed.send(message)
+            });
+
+        ExecResponseStream {
+            state: ExecResponseStreamState::Sending(Box::new(f)),
+        }
+    }
+}
+
+/// Internal state machine of the ExecResponse stream.
+enum ExecResponseStreamState {
+    /// Connecting to the server and sending the request
+    Sending(Box<dyn Future<Item=ClientFramed, Error=std::io::Error> + Send>),
+    /// Waiting for the server to respond
+    Receiving(ClientFramed),
+    /// The action has finished
+    Done,
+}
+
+impl Stream for ExecResponseStream {
+    type Item = protos::control::ExecResponse;
+    type Error = std::io::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        loop {
+            let state = std::mem::replace(&mut self.state, ExecResponseStreamState::Done);
+
+            match state {
+                ExecResponseStreamState::Sending(mut f) => {
+                    match f.poll()? {
+                        Async::Ready(framed) => {
+                            // The request has been sent. Now the responses come in.
+                            self.state = ExecResponseStreamState::Receiving(framed);
+                        },
+                        Async::NotReady => {
+                            self.state = ExecResponseStreamState::Sending(f);
+                            return Ok(Async::NotReady);
+                        },
+                    }
+                },
+                ExecResponseStreamState::Receiving(mut framed) => {
+                    match framed.poll()? {
+                        Async::Ready(Some(mut message)) => {
+                            if message.get_in_response_to() != 1 || !message.has_exec_response() {
+                                // Not for us. Keep waiting.
+                                self.state = ExecResponseStreamState::Receiving(framed);
+                                continue;
+                            }
+
+                            let response = message.take_exec_response();
+                            if response.get_status() == protos::control::ExecResponse_Status::OUTPUT {
+                                // There's more to come.
+                                self.state = ExecResponseStreamState::Receiving(framed);
+                            }
+                            return Ok(Async::Ready(Some(response)));
+                        },
+                        Async::Ready(None) => {
+                            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Stream ended unexpectedly"));
+                        },
+                        Async::NotReady => {
+                            self.state = ExecResponseStreamState::Receiving(framed);
+                            return Ok(Async::NotReady);
+                        },
+                    }
+                },
+                ExecResponseStreamState::Done => {
+                    return Ok(Async::Ready(None));
+                },
+            }
+        }
+    }
+}
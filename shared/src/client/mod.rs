@@ -8,8 +8,10 @@ use super::protos as protos;
 use super::codec as codec;
 
 mod request_response_future;
+mod exec_stream;
 
 use self::request_response_future::RequestResponseFuture;
+pub use self::exec_stream::ExecResponseStream;
 
 /// A client that can talk to the server to get information from the server or to tell the server
 /// to send messages to the client.
@@ -41,14 +43,18 @@ impl Client {
         }
     }
 
-    /// Tell the server to establish an SSH connection to a specific device.
-    pub fn connect_device(&self, device_id: &str, forward_host: &str, port: u16) -> impl Future<Item=protos::control::SshConnectionResponse, Error=std::io::Error> {
+    /// Tell the server to establish an SSH connection to a specific device. The transport decides
+    /// whether the device uses `ssh` or tunnels the forward over its existing connection. UDP
+    /// forwards are always tunneled.
+    pub fn connect_device(&self, device_id: &str, forward_host: &str, port: u16, transport: protos::control::SshConnection_Transport, protocol: protos::control::SshConnection_Protocol) -> impl Future<Item=protos::control::SshConnectionResponse, Error=std::io::Error> {
         let mut message = protos::control::ClientMessage::new();
         let mut ssh_connection = protos::control::SshConnection::new();
         let mut enable = protos::control::SshConnection_Enable::new();
         enable.set_forward_host(forward_host.into());
         enable.set_forward_port(port as u32);
         enable.set_gateway_port(true);
+        enable.set_transport(transport);
+        enable.set_protocol(protocol);
         ssh_connection.set_device_id(device_id.into());
         ssh_connection.set_enable(enable);
         message.set_message_id(1);
@@ -58,6 +64,45 @@ impl Client {
             .map(|mut response| response.take_ssh_connection_response())
     }
 
+    /// Tell the server to establish a dynamic (SOCKS5) forward to a specific device, which can reach
+    /// the given destinations (`host:port`, where either may be `*`). If there aren't any
+    /// destinations, the server decides.
+    pub fn connect_device_dynamic(&self, device_id: &str, permitted_destinations: &[String]) -> impl Future<Item=protos::control::SshConnectionResponse, Error=std::io::Error> {
+        let mut message = protos::control::ClientMessage::new();
+        let mut ssh_connection = protos::control::SshConnection::new();
+        let mut enable = protos::control::SshConnection_Enable::new();
+        enable.set_gateway_port(true);
+        enable.set_dynamic(true);
+        enable.set_permitted_destinations(permitted_destinations.iter().map(|destination| destination.as_str().into()).collect::<Vec<_>>().into());
+        ssh_connection.set_device_id(device_id.into());
+        ssh_connection.set_enable(enable);
+        message.set_message_id(1);
+        message.set_ssh_connection(ssh_connection);
+
+        RequestResponseFuture::new(&self.addr, message)
+            .map(|mut response| response.take_ssh_connection_response())
+    }
+
+    /// Tell the server to give a specific device access to `forward_host:forward_port` (as seen
+    /// from the server) on `local_port` of the device. Disconnect or extend it like any other SSH
+    /// connection.
+    pub fn connect_device_local(&self, device_id: &str, forward_host: &str, forward_port: u16, local_port: u16) -> impl Future<Item=protos::control::SshConnectionResponse, Error=std::io::Error> {
+        let mut message = protos::control::ClientMessage::new();
+        let mut ssh_connection = protos::control::SshConnection::new();
+        let mut local_enable = protos::control::SshConnection_LocalEnable::new();
+        local_enable.set_forward_host(forward_host.into());
+        local_enable.set_forward_port(forward_port as u32);
+        local_enable.set_local_port(local_port as u32);
+        local_enable.set_gateway_port(false);
+        ssh_connection.set_device_id(device_id.into());
+        ssh_connection.set_local_enable(local_enable);
+        message.set_message_id(1);
+        message.set_ssh_connection(ssh_connection);
+
+        RequestResponseFuture::new(&self.addr, message)
+            .map(|mut response| response.take_ssh_connection_response())
+    }
+
     /// Tell the server to disconnect and stop establishing a specific SSH connection.
     pub fn disconnect_connection(&self, device_id: &str, connection_id: &str) -> impl Future<Item=protos::control::SshConnectionResponse, Error=std::io::Error> {
         let mut message = protos::control::ClientMessage::new();
@@ -101,6 +146,21 @@ impl Client {
             .map(|mut response| response.take_create_device_response())
     }
 
+    /// Ask the server for a one-time token that a device can enroll with. If a device id is given,
+    /// only that device can use the token.
+    pub fn create_bootstrap_token(&self, device_id: Option<&str>) -> impl Future<Item=protos::control::CreateBootstrapTokenResponse, Error=std::io::Error> {
+        let mut message = protos::control::ClientMessage::new();
+        let mut create_bootstrap_token = protos::control::CreateBootstrapToken::new();
+        if let Some(device_id) = device_id {
+            create_bootstrap_token.set_device_id(device_id.into());
+        }
+        message.set_message_id(1);
+        message.set_create_bootstrap_token(create_bootstrap_token);
+
+        RequestResponseFuture::new(&self.addr, message)
+            .map(|mut response| response.take_create_bootstrap_token_response())
+    }
+
     /// Tell the server to remove a device from its list. Note that if the device checks in again,
     /// it will be re-added.
     pub fn remove_device(&self, device_id: &str) -> impl Future<Item=protos::control::RemoveDeviceResponse, Error=std::io::Error> {
@@ -126,6 +186,87 @@ impl Client {
         RequestResponseFuture::new(&self.addr, message)
             .map(|mut response| response.take_set_name_response())
     }
+
+    /// Ask a device to look for services on its network. This waits until the device has
+    /// finished scanning.
+    pub fn discover(&self, device_id: &str, include_subnet: bool) -> impl Future<Item=protos::control::DiscoverResponse, Error=std::io::Error> {
+        let mut message = protos::control::ClientMessage::new();
+        let mut discover = protos::control::Discover::new();
+        discover.set_device_id(device_id.into());
+        discover.set_include_subnet(include_subnet);
+        message.set_message_id(1);
+        message.set_discover(discover);
+
+        RequestResponseFuture::new(&self.addr, message)
+            .map(|mut response| response.take_discover_response())
+    }
+
+    /// Read a chunk of a file on a device, starting at `offset`. At the end of the file, the
+    /// response has no data and the checksum of the whole file.
+    pub fn read_file(&self, device_id: &str, path: &str, offset: u64) -> impl Future<Item=protos::control::FileTransferResponse, Error=std::io::Error> {
+        let mut file_transfer = protos::control::FileTransfer::new();
+        file_transfer.set_read(protos::control::FileTransfer_Read::new());
+
+        self.file_transfer(device_id, path, offset, file_transfer)
+    }
+
+    /// Write a chunk of a file on a device, starting at `offset`. Set `sha256` to the checksum of
+    /// the whole file on the last chunk, and leave it empty otherwise.
+    pub fn write_file(&self, device_id: &str, path: &str, offset: u64, data: Vec<u8>, sha256: &str) -> impl Future<Item=protos::control::FileTransferResponse, Error=std::io::Error> {
+        let mut write = protos::control::FileTransfer_Write::new();
+        write.set_data(data.into());
+        write.set_sha256(sha256.into());
+        let mut file_transfer = protos::control::FileTransfer::new();
+        file_transfer.set_write(write);
+
+        self.file_transfer(device_id, path, offset, file_transfer)
+    }
+
+    /// Find out how much of a file that is being written a device already has, to pick up a write
+    /// where it left off.
+    pub fn resume_file(&self, device_id: &str, path: &str) -> impl Future<Item=protos::control::FileTransferResponse, Error=std::io::Error> {
+        let mut file_transfer = protos::control::FileTransfer::new();
+        file_transfer.set_resume(protos::control::FileTransfer_Resume::new());
+
+        self.file_transfer(device_id, path, 0, file_transfer)
+    }
+
+    fn file_transfer(&self, device_id: &str, path: &str, offset: u64, mut file_transfer: protos::control::FileTransfer) -> impl Future<Item=protos::control::FileTransferResponse, Error=std::io::Error> {
+        let mut message = protos::control::ClientMessage::new();
+        file_transfer.set_device_id(device_id.into());
+        file_transfer.set_path(path.into());
+        file_transfer.set_offset(offset);
+        message.set_message_id(1);
+        message.set_file_transfer(file_transfer);
+
+        RequestResponseFuture::new(&self.addr, message)
+            .map(|mut response| response.take_file_transfer_response())
+    }
+
+    /// Get the recent values of a device's metrics.
+    pub fn get_metrics(&self, device_id: &str) -> impl Future<Item=protos::control::MetricsResponse, Error=std::io::Error> {
+        let mut message = protos::control::ClientMessage::new();
+        let mut metrics_request = protos::control::MetricsRequest::new();
+        metrics_request.set_device_id(device_id.into());
+        message.set_message_id(1);
+        message.set_metrics_request(metrics_request);
+
+        RequestResponseFuture::new(&self.addr, message)
+            .map(|mut response| response.take_metrics_response())
+    }
+
+    /// Tell the server to have a device run one of its actions. The stream yields the action's
+    /// output as it runs, and ends with how it finished.
+    pub fn exec(&self, device_id: &str, action: &str) -> ExecResponseStream {
+        let mut message = protos::control::ClientMessage::new();
+        let mut exec = protos::control::Exec::new();
+        exec.set_device_id(device_id.into());
+        exec.set_action(action.into());
+        message.set_message_id(1);
+        message.set_exec(exec);
+
+        ExecResponseStream::new(&self.addr, message)
+    }
 }
 
 /// A future that resolves to a list of clients and their states.
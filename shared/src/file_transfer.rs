@@ -0,0 +1,55 @@
+//! Copying files to and from devices
+//!
+//! Files are moved a chunk at a time, with a request and a response for every chunk, so a transfer
+//! never holds more than a chunk in memory on the device and can pick up where it left off after a
+//! disconnect. Pushed files are written next to their destination with a `.part` suffix, and only
+//! moved into place once the SHA-256 of the whole file checks out.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use ring::digest;
+
+/// The most that gets sent in a single chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The suffix of a file that is still being pushed.
+pub const PARTIAL_SUFFIX: &str = ".part";
+
+/// A SHA-256 checksum that is calculated as the data goes by.
+pub struct Checksum {
+    context: digest::Context,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum {
+            context: digest::Context::new(&digest::SHA256),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.context.update(data);
+    }
+
+    /// The checksum, as lowercase hex.
+    pub fn finish(self) -> String {
+        self.context.finish().as_ref().iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// The checksum of everything in a file.
+    pub fn of_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+        let mut file = File::open(path)?;
+        let mut checksum = Checksum::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(checksum.finish());
+            }
+            checksum.update(&buffer[..n]);
+        }
+    }
+}
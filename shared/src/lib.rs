@@ -4,14 +4,24 @@
 extern crate bytes;
 extern crate futures;
 extern crate protobuf;
+extern crate ring;
 extern crate tokio;
 extern crate tokio_io;
 extern crate tokio_codec;
 extern crate tokio_dns;
 extern crate tokio_timer;
+extern crate untrusted;
 
 /// Protocol buffer definitions.
 pub mod protos;
+pub mod backoff;
 pub mod codec;
+pub mod enrollment;
 pub mod client;
+pub mod file_transfer;
+pub mod keys;
 pub mod timed_connection;
+pub mod tunnel;
+pub mod udp;
+pub mod update;
+pub mod websocket;
@@ -0,0 +1,767 @@
+//! Native tunnels
+//!
+//! A native tunnel carries a TCP forward over the device connection itself, instead of spawning an
+//! `ssh -R` process. The server listens on the remote port, and every connection it accepts
+//! becomes a *stream*. Both ends of a stream exchange `TunnelData` messages, and every stream has
+//! a simple window: an end may only have `INITIAL_WINDOW` bytes in flight until the other end
+//! sends a `TunnelWindowUpdate` saying it has written the data out. That way a single slow stream
+//! can't fill up the shared device connection.
+//!
+//! Both the server and the client use `TunnelStream` to pump data between a local socket and the
+//! device connection. Each end is responsible for turning `TunnelFrame`s into its own protocol
+//! buffer messages.
+
+use std;
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sync::mpsc::{unbounded, Sender, UnboundedReceiver, UnboundedSender};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::protos::device;
+
+/// How many bytes an end may send on a stream before it has to wait for a window update.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// The largest chunk of data that gets put into a single `TunnelData` message.
+pub const MAX_DATA_SIZE: usize = 16 * 1024;
+
+
+/// A single tunnel message, independent of which direction it travels.
+#[derive(Debug, Clone)]
+pub enum TunnelFrame {
+    /// A new stream was accepted on the server (server to client only)
+    Open { stream_id: u32, connection_id: String },
+    /// Data for a stream
+    Data { stream_id: u32, data: Bytes },
+    /// The sending end has written out `increment` bytes and is willing to receive that many more
+    WindowUpdate { stream_id: u32, increment: u32 },
+    /// The sending end has no more data for the stream
+    Close { stream_id: u32 },
+}
+
+impl TunnelFrame {
+    /// The stream that the frame belongs to.
+    pub fn stream_id(&self) -> u32 {
+        match self {
+            TunnelFrame::Open { stream_id, .. } => *stream_id,
+            TunnelFrame::Data { stream_id, .. } => *stream_id,
+            TunnelFrame::WindowUpdate { stream_id, .. } => *stream_id,
+            TunnelFrame::Close { stream_id } => *stream_id,
+        }
+    }
+
+    /// Wrap the frame up in a message that the server can send to the client.
+    pub fn into_server_message(self) -> device::ServerMessage {
+        let mut message = device::ServerMessage::new();
+
+        match self {
+            TunnelFrame::Open { stream_id, connection_id } => {
+                let mut open = device::TunnelOpen::new();
+                open.set_stream_id(stream_id);
+                open.set_connection_id(connection_id.into());
+                message.set_tunnel_open(open);
+            },
+            TunnelFrame::Data { stream_id, data } => {
+                let mut tunnel_data = device::TunnelData::new();
+                tunnel_data.set_stream_id(stream_id);
+                tunnel_data.set_data(data);
+                message.set_tunnel_data(tunnel_data);
+            },
+            TunnelFrame::WindowUpdate { stream_id, increment } => {
+                let mut window_update = device::TunnelWindowUpdate::new();
+                window_update.set_stream_id(stream_id);
+                window_update.set_increment(increment);
+                message.set_tunnel_window_update(window_update);
+            },
+            TunnelFrame::Close { stream_id } => {
+                let mut close = device::TunnelClose::new();
+                close.set_stream_id(stream_id);
+                message.set_tunnel_close(close);
+            },
+        }
+
+        message
+    }
+
+    /// Wrap the frame up in a message that the client can send to the server. Clients can't open
+    /// streams, so there's no message for an `Open` frame.
+    pub fn into_client_message(self) -> Option<device::ClientMessage> {
+        let mut message = device::ClientMessage::new();
+
+        match self {
+            TunnelFrame::Open { .. } => return None,
+            TunnelFrame::Data { stream_id, data } => {
+                let mut tunnel_data = device::TunnelData::new();
+                tunnel_data.set_stream_id(stream_id);
+                tunnel_data.set_data(data);
+                message.set_tunnel_data(tunnel_data);
+            },
+            TunnelFrame::WindowUpdate { stream_id, increment } => {
+                let mut window_update = device::TunnelWindowUpdate::new();
+                window_update.set_stream_id(stream_id);
+                window_update.set_increment(increment);
+                message.set_tunnel_window_update(window_update);
+            },
+            TunnelFrame::Close { stream_id } => {
+                let mut close = device::TunnelClose::new();
+                close.set_stream_id(stream_id);
+                message.set_tunnel_close(close);
+            },
+        }
+
+        Some(message)
+    }
+
+    /// Take the tunnel frame out of a message that the server sent, if it is one.
+    pub fn from_server_message(message: &mut device::ServerMessage) -> Option<TunnelFrame> {
+        if message.has_tunnel_open() {
+            let mut open = message.take_tunnel_open();
+            return Some(TunnelFrame::Open {
+                stream_id: open.get_stream_id(),
+                connection_id: open.take_connection_id().to_string(),
+            });
+        }
+
+        if message.has_tunnel_data() {
+            let mut tunnel_data = message.take_tunnel_data();
+            return Some(TunnelFrame::Data {
+                stream_id: tunnel_data.get_stream_id(),
+                data: tunnel_data.take_data(),
+            });
+        }
+
+        if message.has_tunnel_window_update() {
+            let window_update = message.take_tunnel_window_update();
+            return Some(TunnelFrame::WindowUpdate {
+                stream_id: window_update.get_stream_id(),
+                increment: window_update.get_increment(),
+            });
+        }
+
+        if message.has_tunnel_close() {
+            let close = message.take_tunnel_close();
+            return Some(TunnelFrame::Close { stream_id: close.get_stream_id() });
+        }
+
+        None
+    }
+
+    /// Take the tunnel frame out of a message that the client sent, if it is one.
+    pub fn from_client_message(message: &mut device::ClientMessage) -> Option<TunnelFrame> {
+        if message.has_tunnel_data() {
+            let mut tunnel_data = message.take_tunnel_data();
+            return Some(TunnelFrame::Data {
+                stream_id: tunnel_data.get_stream_id(),
+                data: tunnel_data.take_data(),
+            });
+        }
+
+        if message.has_tunnel_window_update() {
+            let window_update = message.take_tunnel_window_update();
+            return Some(TunnelFrame::WindowUpdate {
+                stream_id: window_update.get_stream_id(),
+                increment: window_update.get_increment(),
+            });
+        }
+
+        if message.has_tunnel_close() {
+            let close = message.take_tunnel_close();
+            return Some(TunnelFrame::Close { stream_id: close.get_stream_id() });
+        }
+
+        None
+    }
+
+    /// Turn a frame received from the other end into the event its stream cares about. `Open`
+    /// frames don't belong to a running stream, so they return None.
+    pub fn into_event(self) -> Option<TunnelEvent> {
+        match self {
+            TunnelFrame::Open { .. } => None,
+            TunnelFrame::Data { data, .. } => Some(TunnelEvent::Data(data)),
+            TunnelFrame::WindowUpdate { increment, .. } => Some(TunnelEvent::WindowUpdate(increment)),
+            TunnelFrame::Close { .. } => Some(TunnelEvent::Close),
+        }
+    }
+}
+
+/// Something the other end of the tunnel did to a single stream.
+#[derive(Debug)]
+pub enum TunnelEvent {
+    /// The other end sent data
+    Data(Bytes),
+    /// The other end is willing to receive more data
+    WindowUpdate(u32),
+    /// The other end will not send any more data
+    Close,
+}
+
+/// Create the channel that carries a stream's events from the other end to its `TunnelStream`.
+///
+/// The other end may send data in chunks as small as it likes, so the channel doesn't limit how
+/// many events it holds. It limits how much data it holds instead: everything the other end sent
+/// that the stream hasn't acknowledged yet has to fit in the stream's window.
+pub fn event_channel() -> (EventSender, EventReceiver) {
+    let (sender, receiver) = unbounded();
+    let unacknowledged = Arc::new(AtomicUsize::new(0));
+
+    let sender = EventSender {
+        sender,
+        unacknowledged: unacknowledged.clone(),
+    };
+    let receiver = EventReceiver {
+        receiver,
+        unacknowledged,
+    };
+
+    (sender, receiver)
+}
+
+/// Where events from the other end go to reach a stream.
+pub struct EventSender {
+    sender: UnboundedSender<TunnelEvent>,
+    /// How many bytes of data the other end sent that the stream hasn't acknowledged yet
+    unacknowledged: Arc<AtomicUsize>,
+}
+
+impl EventSender {
+    /// Hand an event to the stream. Fails if the other end sent more data than the window allows,
+    /// or the stream is gone.
+    pub fn send(&self, event: TunnelEvent) -> Result<(), TunnelEvent> {
+        if let TunnelEvent::Data(ref data) = event {
+            let unacknowledged = self.unacknowledged.fetch_add(data.len(), Ordering::SeqCst) + data.len();
+            if unacknowledged > INITIAL_WINDOW as usize {
+                return Err(event);
+            }
+        }
+
+        self.sender.unbounded_send(event)
+            .map_err(|err| err.into_inner())
+    }
+}
+
+/// The events from the other end for a single stream.
+pub struct EventReceiver {
+    receiver: UnboundedReceiver<TunnelEvent>,
+    unacknowledged: Arc<AtomicUsize>,
+}
+
+impl EventReceiver {
+    /// Make room in the window again, once we're about to tell the other end that it may send
+    /// more.
+    fn acknowledge(&self, increment: u32) {
+        self.unacknowledged.fetch_sub(increment as usize, Ordering::SeqCst);
+    }
+}
+
+impl Stream for EventReceiver {
+    type Item = TunnelEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<TunnelEvent>, ()> {
+        self.receiver.poll()
+    }
+}
+
+/// A future that pumps data between a local socket and one stream of a tunnel.
+///
+/// Data read from the socket is sent as `TunnelFrame`s on `frames`, and events from the other end
+/// arrive on `events`. The future resolves once both directions are closed. If `events` ends
+/// without a `Close` (because the device connection went away), the stream is aborted right away.
+pub struct TunnelStream<S> {
+    stream_id: u32,
+    io: S,
+    events: EventReceiver,
+    frames: Sender<TunnelFrame>,
+    /// A frame that is waiting for room on `frames`
+    pending_frame: Option<TunnelFrame>,
+    /// Data from the other end that still needs to be written to the socket
+    write_buffer: VecDeque<Bytes>,
+    /// How many bytes we may still send before the other end needs to update our window
+    send_window: u32,
+    /// How many bytes we have written to the socket but not yet told the other end about
+    unacknowledged: u32,
+    /// The socket gave us EOF
+    read_closed: bool,
+    /// We have told the other end that we are done sending
+    close_sent: bool,
+    /// The other end told us that it is done sending
+    remote_closed: bool,
+    /// We have shut down the write half of the socket
+    write_shutdown: bool,
+}
+
+impl<S> TunnelStream<S>
+    where S: AsyncRead + AsyncWrite
+{
+    /// Create a new tunnel stream around a connected socket.
+    pub fn new(stream_id: u32, io: S, events: EventReceiver, frames: Sender<TunnelFrame>) -> TunnelStream<S> {
+        TunnelStream {
+            stream_id,
+            io,
+            events,
+            frames,
+            pending_frame: None,
+            write_buffer: VecDeque::new(),
+            send_window: INITIAL_WINDOW,
+            unacknowledged: 0,
+            read_closed: false,
+            close_sent: false,
+            remote_closed: false,
+            write_shutdown: false,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.close_sent && self.pending_frame.is_none() && self.remote_closed && self.write_shutdown
+    }
+
+    /// Tell the other end that we're gone, without waiting for room in the channel. Used when the
+    /// socket fails and we're about to give up on the stream anyway.
+    fn abort(&mut self) {
+        if !self.close_sent {
+            let _ = self.frames.try_send(TunnelFrame::Close { stream_id: self.stream_id });
+            self.close_sent = true;
+        }
+    }
+
+    fn poll_inner(&mut self) -> Poll<(), std::io::Error> {
+        loop {
+            let mut progress = false;
+
+            // Anything that we couldn't send last time goes first.
+            if let Some(frame) = self.pending_frame.take() {
+                match self.frames.start_send(frame).map_err(to_io_error)? {
+                    AsyncSink::Ready => {
+                        progress = true;
+                    },
+                    AsyncSink::NotReady(frame) => {
+                        self.pending_frame = Some(frame);
+                    },
+                }
+            }
+            self.frames.poll_complete().map_err(to_io_error)?;
+
+            // Pick up everything that the other end has sent. The event channel holds the other end
+            // to our window, so this can't grow without bounds. Window updates keep coming after
+            // the other end closes, for as long as we're still sending.
+            loop {
+                match self.events.poll() {
+                    Ok(Async::Ready(Some(TunnelEvent::Data(_)))) if self.remote_closed => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "data after the other end closed"));
+                    },
+                    Ok(Async::Ready(Some(TunnelEvent::Data(data)))) => {
+                        self.write_buffer.push_back(data);
+                        progress = true;
+                    },
+                    Ok(Async::Ready(Some(TunnelEvent::WindowUpdate(increment)))) => {
+                        self.send_window = self.send_window.saturating_add(increment);
+                        progress = true;
+                    },
+                    Ok(Async::Ready(Some(TunnelEvent::Close))) => {
+                        self.remote_closed = true;
+                        progress = true;
+                    },
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        // The device connection is gone, so nobody is listening anymore.
+                        return Err(std::io::ErrorKind::ConnectionAborted.into());
+                    },
+                    Ok(Async::NotReady) => break,
+                }
+            }
+
+            // Write whatever we have to the socket.
+            while let Some(mut chunk) = self.write_buffer.pop_front() {
+                match self.io.poll_write(&chunk)? {
+                    Async::Ready(0) => {
+                        return Err(std::io::ErrorKind::WriteZero.into());
+                    },
+                    Async::Ready(n) => {
+                        let _ = chunk.split_to(n);
+                        self.unacknowledged += n as u32;
+                        progress = true;
+                        if !chunk.is_empty() {
+                            self.write_buffer.push_front(chunk);
+                        }
+                    },
+                    Async::NotReady => {
+                        self.write_buffer.push_front(chunk);
+                        break;
+                    },
+                }
+            }
+
+            if self.write_buffer.is_empty() {
+                if self.remote_closed && !self.write_shutdown {
+                    if let Async::Ready(()) = self.io.shutdown()? {
+                        self.write_shutdown = true;
+                        progress = true;
+                    }
+                }
+                else if !self.remote_closed {
+                    self.io.poll_flush()?;
+                }
+            }
+
+            // Let the other end know that it can send more. Batch these up a little so we aren't
+            // sending a window update for every single chunk.
+            if self.pending_frame.is_none() && self.unacknowledged > 0 && (self.unacknowledged >= INITIAL_WINDOW / 4 || self.write_buffer.is_empty()) {
+                self.events.acknowledge(self.unacknowledged);
+                self.pending_frame = Some(TunnelFrame::WindowUpdate { stream_id: self.stream_id, increment: self.unacknowledged });
+                self.unacknowledged = 0;
+                progress = true;
+            }
+
+            // Read from the socket, but only if the other end has room for it.
+            if self.pending_frame.is_none() && !self.read_closed && self.send_window > 0 {
+                let size = cmp::min(self.send_window as usize, MAX_DATA_SIZE);
+                let mut buf = vec![0; size];
+                match self.io.poll_read(&mut buf)? {
+                    Async::Ready(0) => {
+                        self.read_closed = true;
+                        self.pending_frame = Some(TunnelFrame::Close { stream_id: self.stream_id });
+                        self.close_sent = true;
+                        progress = true;
+                    },
+                    Async::Ready(n) => {
+                        buf.truncate(n);
+                        self.send_window -= n as u32;
+                        self.pending_frame = Some(TunnelFrame::Data { stream_id: self.stream_id, data: buf.into() });
+                        progress = true;
+                    },
+                    Async::NotReady => {},
+                }
+            }
+
+            if self.is_done() {
+                return Ok(Async::Ready(()));
+            }
+
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+impl<S> Future for TunnelStream<S>
+    where S: AsyncRead + AsyncWrite
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.poll_inner() {
+            Ok(Async::Ready(())) => Ok(Async::Ready(())),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                println!("! Tunnel stream {} closed: {}", self.stream_id, err);
+                self.abort();
+                Ok(Async::Ready(()))
+            },
+        }
+    }
+}
+
+fn to_io_error<E: std::fmt::Display>(err: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, format!("{}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+    use futures::executor::{self, Notify, Spawn};
+    use futures::sync::mpsc::{channel, Receiver};
+
+    struct Noop;
+
+    impl Notify for Noop {
+        fn notify(&self, _id: usize) {}
+    }
+
+    static NOOP: Noop = Noop;
+
+    /// What the socket under a stream has to read, and what got written to it.
+    #[derive(Default)]
+    struct Socket {
+        input: VecDeque<u8>,
+        eof: bool,
+        output: Vec<u8>,
+        /// How many more bytes the socket takes before it blocks
+        room: usize,
+        shut_down: bool,
+    }
+
+    #[derive(Clone)]
+    struct MockSocket(Rc<RefCell<Socket>>);
+
+    impl Read for MockSocket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut socket = self.0.borrow_mut();
+            if socket.input.is_empty() {
+                return match socket.eof {
+                    true => Ok(0),
+                    false => Err(io::ErrorKind::WouldBlock.into()),
+                };
+            }
+            let n = cmp::min(buf.len(), socket.input.len());
+            for (byte, input) in buf.iter_mut().zip(socket.input.drain(..n)) {
+                *byte = input;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for MockSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut socket = self.0.borrow_mut();
+            if socket.room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = cmp::min(buf.len(), socket.room);
+            socket.output.extend_from_slice(&buf[..n]);
+            socket.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MockSocket {}
+
+    impl AsyncWrite for MockSocket {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            self.0.borrow_mut().shut_down = true;
+            Ok(Async::Ready(()))
+        }
+    }
+
+    struct Harness {
+        socket: Rc<RefCell<Socket>>,
+        stream: Spawn<TunnelStream<MockSocket>>,
+        events: EventSender,
+        frames: Spawn<Receiver<TunnelFrame>>,
+    }
+
+    impl Harness {
+        fn new(socket: Socket) -> Harness {
+            let socket = Rc::new(RefCell::new(socket));
+            let (events, event_receiver) = event_channel();
+            let (frame_sender, frames) = channel(4);
+            Harness {
+                stream: executor::spawn(TunnelStream::new(7, MockSocket(socket.clone()), event_receiver, frame_sender)),
+                socket,
+                events,
+                frames: executor::spawn(frames),
+            }
+        }
+
+        /// Run the stream until it's stuck, and collect the frames that it sent on the way.
+        /// Returns whether the stream is done, too.
+        fn run(&mut self) -> (Vec<TunnelFrame>, bool) {
+            let mut frames = Vec::new();
+            loop {
+                let done = self.stream.poll_future_notify(&&NOOP, 0) == Ok(Async::Ready(()));
+                let before = frames.len();
+                while let Ok(Async::Ready(Some(frame))) = self.frames.poll_stream_notify(&&NOOP, 0) {
+                    frames.push(frame);
+                }
+                if done || frames.len() == before {
+                    return (frames, done);
+                }
+            }
+        }
+
+        fn send(&mut self, event: TunnelEvent) {
+            self.events.send(event).unwrap();
+        }
+    }
+
+    fn data_sent(frames: &[TunnelFrame]) -> usize {
+        frames.iter()
+            .map(|frame| match frame {
+                TunnelFrame::Data { stream_id: 7, data } => data.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn window_updates(frames: &[TunnelFrame]) -> Vec<u32> {
+        frames.iter()
+            .filter_map(|frame| match frame {
+                TunnelFrame::WindowUpdate { stream_id: 7, increment } => Some(*increment),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sending_stops_when_the_window_is_used_up() {
+        let mut harness = Harness::new(Socket {
+            input: vec![1; INITIAL_WINDOW as usize + 5000].into(),
+            ..Socket::default()
+        });
+
+        let (frames, done) = harness.run();
+        assert!(!done);
+        assert_eq!(data_sent(&frames), INITIAL_WINDOW as usize);
+        assert!(frames.iter().all(|frame| match frame {
+            TunnelFrame::Data { data, .. } => data.len() <= MAX_DATA_SIZE,
+            _ => true,
+        }));
+
+        harness.send(TunnelEvent::WindowUpdate(1000));
+        let (frames, _) = harness.run();
+        assert_eq!(data_sent(&frames), 1000);
+
+        harness.send(TunnelEvent::WindowUpdate(10_000));
+        let (frames, _) = harness.run();
+        assert_eq!(data_sent(&frames), 4000);
+    }
+
+    #[test]
+    fn data_is_acknowledged_once_it_is_written() {
+        let mut harness = Harness::new(Socket::default());
+
+        harness.send(TunnelEvent::Data(vec![2; 3000].into()));
+        let (frames, _) = harness.run();
+        assert!(window_updates(&frames).is_empty());
+
+        // Small writes are batched up until everything is written.
+        harness.socket.borrow_mut().room = 1000;
+        let (frames, _) = harness.run();
+        assert!(window_updates(&frames).is_empty());
+
+        harness.socket.borrow_mut().room = 10_000;
+        let (frames, _) = harness.run();
+        assert_eq!(window_updates(&frames), vec![3000]);
+        assert_eq!(harness.socket.borrow().output, vec![2; 3000]);
+
+        // Big ones are acknowledged while the rest is still waiting to be written.
+        for _ in 0..(INITIAL_WINDOW as usize / MAX_DATA_SIZE) {
+            harness.send(TunnelEvent::Data(vec![5; MAX_DATA_SIZE].into()));
+        }
+        harness.socket.borrow_mut().room = INITIAL_WINDOW as usize / 4;
+        let (frames, _) = harness.run();
+        assert_eq!(window_updates(&frames), vec![INITIAL_WINDOW / 4]);
+    }
+
+    #[test]
+    fn the_stream_finishes_once_both_ends_close() {
+        let mut harness = Harness::new(Socket {
+            input: vec![3; 10].into(),
+            eof: true,
+            room: 100,
+            ..Socket::default()
+        });
+
+        let (frames, done) = harness.run();
+        assert!(!done);
+        assert_eq!(data_sent(&frames), 10);
+        match frames.last() {
+            Some(TunnelFrame::Close { stream_id: 7 }) => {},
+            other => panic!("expected a close, got {:?}", other),
+        }
+
+        harness.send(TunnelEvent::Data(vec![4; 5].into()));
+        harness.send(TunnelEvent::Close);
+        let (_, done) = harness.run();
+        assert!(done);
+        assert_eq!(harness.socket.borrow().output, vec![4; 5]);
+        assert!(harness.socket.borrow().shut_down);
+    }
+
+    #[test]
+    fn the_stream_is_aborted_when_the_events_stop() {
+        let mut harness = Harness::new(Socket::default());
+        let (events, _) = event_channel();
+        drop(::std::mem::replace(&mut harness.events, events));
+
+        let (frames, done) = harness.run();
+        assert!(done);
+        match frames.last() {
+            Some(TunnelFrame::Close { stream_id: 7 }) => {},
+            other => panic!("expected a close, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lots_of_small_data_frames_fit_while_the_socket_is_blocked() {
+        let mut harness = Harness::new(Socket::default());
+
+        // Interactive sessions send tiny chunks, and they can all arrive before the stream gets
+        // to run.
+        for _ in 0..1000 {
+            harness.send(TunnelEvent::Data(vec![6; 1].into()));
+        }
+        let (frames, done) = harness.run();
+        assert!(!done);
+        assert!(frames.is_empty());
+
+        for _ in 0..1000 {
+            harness.send(TunnelEvent::Data(vec![6; 1].into()));
+        }
+        harness.socket.borrow_mut().room = 10_000;
+        let (frames, done) = harness.run();
+        assert!(!done);
+        assert_eq!(window_updates(&frames), vec![2000]);
+        assert_eq!(harness.socket.borrow().output, vec![6; 2000]);
+    }
+
+    #[test]
+    fn the_other_end_may_not_send_more_than_the_window() {
+        let mut harness = Harness::new(Socket::default());
+
+        for _ in 0..(INITIAL_WINDOW as usize / MAX_DATA_SIZE) {
+            harness.send(TunnelEvent::Data(vec![8; MAX_DATA_SIZE].into()));
+        }
+        assert!(harness.events.send(TunnelEvent::Data(vec![8; 1].into())).is_err());
+    }
+
+    #[test]
+    fn acknowledged_data_makes_room_in_the_window() {
+        let mut harness = Harness::new(Socket::default());
+
+        harness.send(TunnelEvent::Data(vec![9; INITIAL_WINDOW as usize].into()));
+        harness.run();
+        harness.socket.borrow_mut().room = INITIAL_WINDOW as usize;
+        let (frames, _) = harness.run();
+        assert_eq!(window_updates(&frames), vec![INITIAL_WINDOW]);
+
+        harness.send(TunnelEvent::Data(vec![9; INITIAL_WINDOW as usize].into()));
+        assert!(harness.events.send(TunnelEvent::Data(vec![9; 1].into())).is_err());
+    }
+
+    #[test]
+    fn window_updates_still_arrive_after_the_other_end_closes() {
+        let mut harness = Harness::new(Socket {
+            input: vec![1; INITIAL_WINDOW as usize + 5000].into(),
+            ..Socket::default()
+        });
+
+        harness.send(TunnelEvent::Close);
+        let (frames, done) = harness.run();
+        assert!(!done);
+        assert_eq!(data_sent(&frames), INITIAL_WINDOW as usize);
+
+        harness.send(TunnelEvent::WindowUpdate(5000));
+        let (frames, _) = harness.run();
+        assert_eq!(data_sent(&frames), 5000);
+    }
+
+    #[test]
+    fn clients_have_no_message_for_open_frames() {
+        assert!(TunnelFrame::Open { stream_id: 1, connection_id: "abc".to_string() }.into_client_message().is_none());
+
+        let message = TunnelFrame::WindowUpdate { stream_id: 1, increment: 5 }.into_client_message().unwrap();
+        assert_eq!(message.get_tunnel_window_update().get_increment(), 5);
+    }
+}
@@ -0,0 +1,169 @@
+//! UDP relays
+//!
+//! `ssh -R` can only forward TCP, so UDP forwards are always relayed over the device connection.
+//! The server listens on the forward's remote UDP port, and every remote peer that sends to it
+//! becomes an *association*. Datagrams are passed along one at a time, so their boundaries are
+//! preserved. An association that goes without any traffic for the forward's idle timeout is
+//! forgotten by both ends.
+
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+
+use super::protos::device;
+
+/// How long an association may go without any traffic, when the server doesn't say.
+pub fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// The idle timeout for a forward, from the number of seconds the server gave. Zero means the
+/// server didn't say.
+pub fn idle_timeout(seconds: u32) -> Duration {
+    match seconds {
+        0 => default_idle_timeout(),
+        seconds => Duration::from_secs(seconds as u64),
+    }
+}
+
+/// Whether an association that last saw traffic at `last_activity` should be forgotten.
+pub fn is_idle(last_activity: Instant, now: Instant, idle_timeout: Duration) -> bool {
+    last_activity + idle_timeout < now
+}
+
+/// The largest UDP payload we'll ever see (65,535 minus the IPv4 and UDP headers).
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// A single datagram on a UDP forward.
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    /// The ID of the forward
+    pub connection_id: String,
+    /// The association, as picked by the server
+    pub association_id: u32,
+    /// The payload
+    pub data: Bytes,
+}
+
+impl Datagram {
+    fn into_proto(self) -> device::UdpDatagram {
+        let mut datagram = device::UdpDatagram::new();
+        datagram.set_connection_id(self.connection_id.into());
+        datagram.set_association_id(self.association_id);
+        datagram.set_data(self.data);
+        datagram
+    }
+
+    fn from_proto(mut datagram: device::UdpDatagram) -> Datagram {
+        Datagram {
+            connection_id: datagram.take_connection_id().to_string(),
+            association_id: datagram.get_association_id(),
+            data: datagram.take_data(),
+        }
+    }
+
+    /// Wrap the datagram up in a message that the server can send to the client.
+    pub fn into_server_message(self) -> device::ServerMessage {
+        let mut message = device::ServerMessage::new();
+        message.set_udp_datagram(self.into_proto());
+        message
+    }
+
+    /// Wrap the datagram up in a message that the client can send to the server.
+    pub fn into_client_message(self) -> device::ClientMessage {
+        let mut message = device::ClientMessage::new();
+        message.set_udp_datagram(self.into_proto());
+        message
+    }
+
+    /// Take the datagram out of a message that the server sent, if it is one.
+    pub fn from_server_message(message: &mut device::ServerMessage) -> Option<Datagram> {
+        if message.has_udp_datagram() {
+            Some(Datagram::from_proto(message.take_udp_datagram()))
+        }
+        else {
+            None
+        }
+    }
+
+    /// Take the datagram out of a message that the client sent, if it is one.
+    pub fn from_client_message(message: &mut device::ClientMessage) -> Option<Datagram> {
+        if message.has_udp_datagram() {
+            Some(Datagram::from_proto(message.take_udp_datagram()))
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(data: &[u8]) -> Datagram {
+        Datagram {
+            connection_id: "udp-1".to_string(),
+            association_id: 7,
+            data: Bytes::from(data),
+        }
+    }
+
+    #[test]
+    fn datagrams_survive_the_trip_from_the_server() {
+        let mut message = datagram(b"hello").into_server_message();
+        let received = Datagram::from_server_message(&mut message).unwrap();
+
+        assert_eq!(received.connection_id, "udp-1");
+        assert_eq!(received.association_id, 7);
+        assert_eq!(&received.data[..], b"hello");
+    }
+
+    #[test]
+    fn datagrams_survive_the_trip_from_the_client() {
+        let mut message = datagram(b"world").into_client_message();
+        let received = Datagram::from_client_message(&mut message).unwrap();
+
+        assert_eq!(received.connection_id, "udp-1");
+        assert_eq!(received.association_id, 7);
+        assert_eq!(&received.data[..], b"world");
+    }
+
+    #[test]
+    fn datagram_boundaries_are_kept_for_empty_and_full_sized_payloads() {
+        let empty = Datagram::from_client_message(&mut datagram(b"").into_client_message()).unwrap();
+        assert!(empty.data.is_empty());
+
+        let full = vec![0xa5; MAX_DATAGRAM_SIZE];
+        let received = Datagram::from_server_message(&mut datagram(&full).into_server_message()).unwrap();
+        assert_eq!(&received.data[..], &full[..]);
+    }
+
+    #[test]
+    fn other_messages_are_not_datagrams() {
+        let mut message = device::ServerMessage::new();
+        message.set_ping(device::Ping::new());
+        assert!(Datagram::from_server_message(&mut message).is_none());
+        assert!(message.has_ping());
+
+        let mut message = device::ClientMessage::new();
+        message.set_pong(device::Pong::new());
+        assert!(Datagram::from_client_message(&mut message).is_none());
+        assert!(message.has_pong());
+    }
+
+    #[test]
+    fn idle_timeout_falls_back_to_the_default_when_the_server_does_not_say() {
+        assert_eq!(idle_timeout(0), default_idle_timeout());
+        assert_eq!(idle_timeout(5), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn associations_expire_only_once_the_idle_timeout_has_passed() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(30);
+
+        assert!(!is_idle(start, start, timeout));
+        assert!(!is_idle(start, start + timeout, timeout));
+        assert!(is_idle(start, start + timeout + Duration::from_millis(1), timeout));
+    }
+}
@@ -0,0 +1,89 @@
+//! What the server and the client agree on about client updates.
+//!
+//! An update's Ed25519 signature covers its version as well as the binary: the signature is of the
+//! version (as UTF-8), followed by the SHA-256 digest of the binary. That way an old binary can't
+//! be handed out as if it were a newer version, and clients refuse versions older than their own,
+//! so an old, validly signed binary can't be replayed to downgrade them either.
+
+use std::cmp::Ordering;
+use ring::digest;
+
+/// What the signature of an update covers.
+pub fn signed_message(version: &str, binary: &[u8]) -> Vec<u8> {
+    let mut message = version.as_bytes().to_vec();
+    message.extend_from_slice(digest::digest(&digest::SHA256, binary).as_ref());
+    message
+}
+
+/// Compare two versions, like `1.10.0` and `1.9.2`. The parts are compared as numbers, and a
+/// version with a pre-release (`1.2.0-rc.1`) comes before the version without one.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_release, a_pre) = split_pre_release(a);
+    let (b_release, b_pre) = split_pre_release(b);
+
+    let mut a_parts = a_release.split('.');
+    let mut b_parts = b_release.split('.');
+    loop {
+        let ordering = match (a_parts.next(), b_parts.next()) {
+            (None, None) => break,
+            (a_part, b_part) => compare_parts(a_part.unwrap_or("0"), b_part.unwrap_or("0")),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a_pre), Some(b_pre)) => a_pre.cmp(b_pre),
+    }
+}
+
+/// Split `1.2.0-rc.1+build` into `1.2.0` and `rc.1`. Build metadata doesn't count.
+fn split_pre_release(version: &str) -> (&str, Option<&str>) {
+    let version = version.trim();
+    let version = version.split('+').next().unwrap_or(version);
+    let mut parts = version.splitn(2, '-');
+    (parts.next().unwrap_or(""), parts.next())
+}
+
+fn compare_parts(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_compare_as_numbers() {
+        assert_eq!(compare_versions("1.10.0", "1.9.2"), Ordering::Greater);
+        assert_eq!(compare_versions("0.1.0", "0.2.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.2.3", "1.2.3"), Ordering::Equal);
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("2", "1.99.99"), Ordering::Greater);
+    }
+
+    #[test]
+    fn pre_releases_come_first() {
+        assert_eq!(compare_versions("1.2.0-rc.1", "1.2.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.2.0-rc.2", "1.2.0-rc.1"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.0-rc.1", "1.1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.0+build.5", "1.2.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn the_signed_message_covers_the_version_and_the_binary() {
+        let message = signed_message("1.2.0", b"binary");
+
+        assert!(message.starts_with(b"1.2.0"));
+        assert_eq!(message.len(), "1.2.0".len() + 32);
+        assert_ne!(message, signed_message("1.1.0", b"binary"));
+        assert_ne!(message, signed_message("1.2.0", b"other binary"));
+    }
+}
@@ -0,0 +1,423 @@
+//! Carrying the device protocol over WebSocket, for networks that only let HTTPS out.
+//!
+//! Each message is the same frame that `Codec` makes (a protocol buffer prefixed by its length),
+//! sent as one binary WebSocket message. This is just enough of RFC 6455 for that: the opening
+//! handshake, binary messages (fragmented or not), and close. Neither end sends pings, because the
+//! device protocol has its own, so pings and pongs from anybody in between are skipped.
+
+use std::io;
+use std::time::Duration;
+use bytes::{BufMut, BytesMut};
+use futures::Future;
+use futures::future::{self, Loop};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Decoder, Encoder};
+use tokio_io::io::{read_exact, write_all};
+use tokio_timer::Timeout;
+
+use keys::base64_encode;
+
+/// What gets appended to the client's key to make the server's accept key (RFC 6455, 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The longest HTTP request or response head that we'll read.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// How long the other end has to finish the opening handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest message that we'll accept.
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Do the client's half of the opening handshake: ask the server at `host` to upgrade the request
+/// for `path` to a WebSocket.
+pub fn connect<S>(stream: S, host: &str, path: &str) -> impl Future<Item=S, Error=io::Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let key = client_key();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key);
+
+    let future = write_all(stream, request.into_bytes())
+        .and_then(|(stream, _)| read_head(stream))
+        .and_then(move |(stream, head)| check_response(&head, &key).map(|_| stream));
+    with_deadline(future)
+}
+
+/// Do the server's half of the opening handshake for a request for `path`. Anything that isn't a
+/// WebSocket upgrade for that path gets an error response, and the error. Returns the stream and
+/// the request's HTTP head, for whatever else the caller wants from it (see `header`).
+pub fn accept<S>(stream: S, path: String) -> impl Future<Item=(S, String), Error=io::Error> + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let future = read_head(stream)
+        .and_then(move |(stream, head)| {
+            let (response, result) = match check_request(&head, &path) {
+                Ok(key) => (format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    accept_key(&key)), Ok(())),
+                Err((status, err)) => (format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status), Err(err)),
+            };
+            write_all(stream, response.into_bytes())
+                .and_then(move |(stream, _)| result.map(|_| (stream, head)))
+        });
+    with_deadline(future)
+}
+
+/// Give up on a handshake that the other end doesn't finish in time, so that a peer that connects
+/// and then says nothing doesn't hold on to the connection forever.
+fn with_deadline<F>(future: F) -> impl Future<Item=F::Item, Error=io::Error> + Send
+    where F: Future<Error=io::Error> + Send
+{
+    Timeout::new(future, HANDSHAKE_TIMEOUT)
+        .map_err(|err| {
+            if err.is_elapsed() {
+                io::Error::new(io::ErrorKind::TimedOut, "The WebSocket handshake took too long")
+            }
+            else if err.is_inner() {
+                err.into_inner().unwrap()
+            }
+            else {
+                io::Error::new(io::ErrorKind::Other, format!("The WebSocket handshake timer failed: {}", err))
+            }
+        })
+}
+
+/// The accept key that the server answers a client's key with.
+pub fn accept_key(key: &str) -> String {
+    let hash = digest::digest(&digest::SHA1, format!("{}{}", key, GUID).as_bytes());
+    base64_encode(hash.as_ref())
+}
+
+/// A random key for the client to send.
+fn client_key() -> String {
+    let mut bytes = [0; 16];
+    // The key doesn't need to be secret, just different every time; proxies in between cache by
+    // it.
+    let _ = SystemRandom::new().fill(&mut bytes);
+    base64_encode(&bytes)
+}
+
+fn handshake_error<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Read an HTTP head (up to and including the blank line), a byte at a time so that we don't read
+/// past it.
+fn read_head<S: AsyncRead + Send + 'static>(stream: S) -> impl Future<Item=(S, String), Error=io::Error> + Send {
+    future::loop_fn((stream, Vec::new()), |(stream, mut head)| {
+        read_exact(stream, [0; 1])
+            .and_then(move |(stream, byte)| {
+                head.push(byte[0]);
+                if head.ends_with(b"\r\n\r\n") {
+                    let head = String::from_utf8(head)
+                        .map_err(|_| handshake_error("The HTTP head isn't UTF-8"))?;
+                    Ok(Loop::Break((stream, head)))
+                }
+                else if head.len() > MAX_HEAD {
+                    Err(handshake_error("The HTTP head is too long"))
+                }
+                else {
+                    Ok(Loop::Continue((stream, head)))
+                }
+            })
+    })
+}
+
+/// The value of a header in an HTTP head, if it's there. Header names aren't case sensitive.
+pub fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Whether a header holds a token, like `Connection: keep-alive, Upgrade`.
+fn header_has(head: &str, name: &str, token: &str) -> bool {
+    header(head, name)
+        .map(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false)
+}
+
+/// Check that a request is a WebSocket upgrade for the path. Returns the client's key, or the
+/// status to answer with and why.
+fn check_request(head: &str, path: &str) -> Result<String, (&'static str, io::Error)> {
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(request_path)) if request_path == path => {},
+        (Some("GET"), Some(request_path)) => return Err(("404 Not Found", handshake_error(format!("Nothing to upgrade at {}", request_path)))),
+        _ => return Err(("405 Method Not Allowed", handshake_error("The request isn't a GET"))),
+    }
+
+    if !header_has(head, "Upgrade", "websocket") || !header_has(head, "Connection", "Upgrade") {
+        return Err(("426 Upgrade Required", handshake_error("The request isn't a WebSocket upgrade")));
+    }
+    if header(head, "Sec-WebSocket-Version") != Some("13") {
+        return Err(("426 Upgrade Required", handshake_error("The request isn't for WebSocket version 13")));
+    }
+    header(head, "Sec-WebSocket-Key")
+        .map(|key| key.to_string())
+        .ok_or_else(|| ("400 Bad Request", handshake_error("The request doesn't have a Sec-WebSocket-Key")))
+}
+
+/// Check that the server agreed to upgrade to a WebSocket.
+fn check_response(head: &str, key: &str) -> io::Result<()> {
+    let status_line = head.lines().next().unwrap_or("");
+    if status_line.split_whitespace().nth(1) != Some("101") {
+        return Err(handshake_error(format!("The server didn't upgrade to a WebSocket: {}", status_line)));
+    }
+    if header(head, "Sec-WebSocket-Accept") != Some(accept_key(key).as_str()) {
+        return Err(handshake_error("The server answered the WebSocket handshake with the wrong key"));
+    }
+    Ok(())
+}
+
+/// A codec that carries whatever another codec makes, each item as one binary WebSocket message.
+pub struct WebSocketCodec<C> {
+    inner: C,
+    /// Whether to mask what we send. Clients have to, servers may not.
+    mask: bool,
+    /// The fragments of a message that isn't complete yet
+    fragments: Option<BytesMut>,
+}
+
+impl<C> WebSocketCodec<C> {
+    /// A codec for the client's end of the connection.
+    pub fn client(inner: C) -> WebSocketCodec<C> {
+        WebSocketCodec {
+            inner,
+            mask: true,
+            fragments: None,
+        }
+    }
+
+    /// A codec for the server's end of the connection.
+    pub fn server(inner: C) -> WebSocketCodec<C> {
+        WebSocketCodec {
+            inner,
+            mask: false,
+            fragments: None,
+        }
+    }
+}
+
+impl<C: Decoder<Error=io::Error>> Decoder for WebSocketCodec<C> {
+    type Item = C::Item;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<C::Item>, io::Error> {
+        loop {
+            let (fin, opcode, payload) = match decode_frame(buf)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            let mut message = match opcode {
+                OPCODE_BINARY if self.fragments.is_some() => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "A WebSocket message started before the last one ended"));
+                },
+                OPCODE_BINARY if fin => payload,
+                OPCODE_BINARY => {
+                    self.fragments = Some(payload);
+                    continue;
+                },
+                OPCODE_CONTINUATION => {
+                    let mut fragments = self.fragments.take()
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "A WebSocket message continued without starting"))?;
+                    if fragments.len() + payload.len() > MAX_MESSAGE {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "A WebSocket message is too long"));
+                    }
+                    fragments.extend_from_slice(&payload);
+                    if !fin {
+                        self.fragments = Some(fragments);
+                        continue;
+                    }
+                    fragments
+                },
+                OPCODE_PING | OPCODE_PONG => continue,
+                OPCODE_CLOSE => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "The other end closed the WebSocket")),
+                OPCODE_TEXT => return Err(io::Error::new(io::ErrorKind::InvalidData, "Got a text WebSocket message, but only binary messages are used")),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown WebSocket opcode {}", opcode))),
+            };
+
+            return match self.inner.decode(&mut message)? {
+                Some(item) if message.is_empty() => Ok(Some(item)),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "A WebSocket message didn't hold exactly one frame")),
+            };
+        }
+    }
+}
+
+impl<C: Encoder<Error=io::Error>> Encoder for WebSocketCodec<C> {
+    type Item = C::Item;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: C::Item, buf: &mut BytesMut) -> Result<(), io::Error> {
+        let mut payload = BytesMut::new();
+        self.inner.encode(item, &mut payload)?;
+
+        let mask_bit = match self.mask {
+            true => 0x80,
+            false => 0x00,
+        };
+        buf.reserve(payload.len() + 14);
+        buf.put_u8(0x80 | OPCODE_BINARY);
+        if payload.len() < 126 {
+            buf.put_u8(mask_bit | payload.len() as u8);
+        }
+        else if payload.len() <= 0xffff {
+            buf.put_u8(mask_bit | 126);
+            buf.put_u16_be(payload.len() as u16);
+        }
+        else {
+            buf.put_u8(mask_bit | 127);
+            buf.put_u64_be(payload.len() as u64);
+        }
+
+        if self.mask {
+            let mut key = [0; 4];
+            SystemRandom::new().fill(&mut key)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to make a WebSocket mask"))?;
+            buf.put_slice(&key);
+            apply_mask(&mut payload, key);
+        }
+        buf.put_slice(&payload);
+
+        Ok(())
+    }
+}
+
+/// Take one frame off the front of the buffer, if it's all there. Returns whether it's the last
+/// frame of its message, its opcode and its (unmasked) payload.
+fn decode_frame(buf: &mut BytesMut) -> Result<Option<(bool, u8, BytesMut)>, io::Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (length_len, length) = match buf[1] & 0x7f {
+        126 => (2, None),
+        127 => (8, None),
+        length => (0, Some(length as usize)),
+    };
+    let mask_len = if masked { 4 } else { 0 };
+    let header_len = 2 + length_len + mask_len;
+    if buf.len() < header_len {
+        return Ok(None);
+    }
+
+    let length = match length {
+        Some(length) => length,
+        None => buf[2..2 + length_len].iter().fold(0u64, |length, byte| (length << 8) | u64::from(*byte)) as usize,
+    };
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "A WebSocket message is too long"));
+    }
+    if buf.len() < header_len + length {
+        return Ok(None);
+    }
+
+    let mut frame = buf.split_to(header_len + length);
+    let mut payload = frame.split_off(header_len);
+    if masked {
+        let mut key = [0; 4];
+        key.copy_from_slice(&frame[header_len - 4..header_len]);
+        apply_mask(&mut payload, key);
+    }
+
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// Mask or unmask a payload (it's the same thing).
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::protos::device;
+
+    #[test]
+    fn accept_key_matches_the_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn client_messages_get_to_the_server() {
+        let mut ping = device::ClientMessage::new();
+        ping.set_ping(device::Ping::new());
+
+        let mut client: WebSocketCodec<Codec<device::ClientMessage, device::ServerMessage>> = WebSocketCodec::client(Codec::new());
+        let mut server: WebSocketCodec<Codec<device::ServerMessage, device::ClientMessage>> = WebSocketCodec::server(Codec::new());
+
+        let mut buf = BytesMut::new();
+        client.encode(ping.clone(), &mut buf).unwrap();
+        client.encode(ping.clone(), &mut buf).unwrap();
+        assert_eq!(buf[1] & 0x80, 0x80);
+
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(ping.clone()));
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(ping));
+        assert_eq!(server.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn fragments_and_pings_are_put_together() {
+        let mut ping = device::ServerMessage::new();
+        ping.set_ping(device::Ping::new());
+
+        let mut payload = BytesMut::new();
+        Codec::<device::ServerMessage, device::ClientMessage>::new().encode(ping.clone(), &mut payload).unwrap();
+        let (first, second) = payload.split_at(2);
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[OPCODE_BINARY, first.len() as u8]);
+        buf.put_slice(first);
+        buf.put_slice(&[0x80 | OPCODE_PING, 0]);
+        buf.put_slice(&[0x80 | OPCODE_CONTINUATION, second.len() as u8]);
+        buf.put_slice(second);
+
+        let mut client: WebSocketCodec<Codec<device::ClientMessage, device::ServerMessage>> = WebSocketCodec::client(Codec::new());
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(ping));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn close_ends_the_connection() {
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[0x80 | OPCODE_CLOSE, 0]);
+
+        let mut client: WebSocketCodec<Codec<device::ClientMessage, device::ServerMessage>> = WebSocketCodec::client(Codec::new());
+        assert_eq!(client.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn requests_get_checked() {
+        let request = "GET /device HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+        assert_eq!(check_request(request, "/device").unwrap(), "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(check_request(request, "/").unwrap_err().0, "404 Not Found");
+        assert_eq!(check_request("GET /device HTTP/1.1\r\n\r\n", "/device").unwrap_err().0, "426 Upgrade Required");
+    }
+}
@@ -15,6 +15,12 @@ pub struct Device {
     pub connections: Vec<DeviceConnection>,
     /// The history of when the device has been connected and disconnected
     pub connection_history: Vec<DeviceHistoryItem>,
+    /// The actions that the device is willing to run
+    pub actions: Vec<String>,
+    /// The directories that files may be copied to and from
+    pub file_dirs: Vec<String>,
+    /// What the device last reported about itself
+    pub facts: Option<DeviceFacts>,
 }
 
 impl From<control::ClientsResponse_Client> for Device {
@@ -31,6 +37,23 @@ impl From<control::ClientsResponse_Client> for Device {
             .map(Into::into)
             .collect();
 
+        let actions = client.get_actions()
+            .iter()
+            .map(|action| action.to_string())
+            .collect();
+
+        let file_dirs = client.get_file_dirs()
+            .iter()
+            .map(|dir| dir.to_string())
+            .collect();
+
+        let facts = if client.has_facts() {
+            Some(client.take_facts().into())
+        }
+        else {
+            None
+        };
+
         let address = client.take_address().to_string();
         let address = if address == "" {
             None
@@ -45,6 +68,56 @@ impl From<control::ClientsResponse_Client> for Device {
             address,
             connections,
             connection_history,
+            actions,
+            file_dirs,
+            facts,
+        }
+    }
+}
+
+/// Inventory information that a device reported about itself
+#[derive(Serialize, Debug)]
+pub struct DeviceFacts {
+    pub hostname: String,
+    pub os: String,
+    pub kernel: String,
+    pub arch: String,
+    pub client_version: String,
+    /// How long the device had been up when it reported, in seconds
+    pub uptime: u64,
+    pub interfaces: Vec<DeviceInterface>,
+    pub ssh_version: String,
+    /// The server endpoint that the device is connected to
+    pub server_endpoint: String,
+    /// When the device reported the facts
+    pub reported_at: String,
+}
+
+/// A network interface on a device
+#[derive(Serialize, Debug)]
+pub struct DeviceInterface {
+    pub name: String,
+    pub addresses: Vec<String>,
+}
+
+impl From<control::ClientsResponse_Facts> for DeviceFacts {
+    fn from(facts: control::ClientsResponse_Facts) -> Self {
+        DeviceFacts {
+            hostname: facts.get_hostname().to_string(),
+            os: facts.get_os().to_string(),
+            kernel: facts.get_kernel().to_string(),
+            arch: facts.get_arch().to_string(),
+            client_version: facts.get_client_version().to_string(),
+            uptime: facts.get_uptime(),
+            interfaces: facts.get_interfaces().iter()
+                .map(|interface| DeviceInterface {
+                    name: interface.get_name().to_string(),
+                    addresses: interface.get_addresses().iter().map(|address| address.to_string()).collect(),
+                })
+                .collect(),
+            ssh_version: facts.get_ssh_version().to_string(),
+            server_endpoint: facts.get_server_endpoint().to_string(),
+            reported_at: Utc.timestamp(facts.get_reported_at() as i64, 0).to_rfc3339(),
         }
     }
 }
@@ -111,6 +184,70 @@ pub struct DeviceConnection {
     pub is_ssh: bool,
     /// The time at which the connection will expire
     pub active_until: Option<String>,
+    /// How the connection is carried between the server and the device
+    pub transport: DeviceConnectionTransport,
+    /// The protocol that is being forwarded
+    pub protocol: DeviceConnectionProtocol,
+    /// Which way the connection goes
+    pub direction: DeviceConnectionDirection,
+    /// For local connections, the port on the device that reaches the forwarded host and port
+    pub local_port: u16,
+    /// Whether the connection is a SOCKS5 proxy into the device's network
+    pub is_dynamic: bool,
+    /// For SOCKS5 proxies, the destinations that can be reached. Empty means anything.
+    pub permitted_destinations: Vec<String>,
+    /// Why the device last failed to keep the connection up, if it hasn't connected since
+    pub last_failure: Option<DeviceConnectionFailure>,
+    /// Why the device refused to set up the connection, if it did
+    pub rejected_reason: Option<String>,
+}
+
+/// Why the device failed to keep a connection up, according to the device.
+#[derive(Serialize, Debug)]
+pub struct DeviceConnectionFailure {
+    /// How many times in a row the device had failed
+    pub failures: u32,
+    pub kind: DeviceConnectionFailureKind,
+    /// The exit code of `ssh`, if it exited
+    pub exit_code: Option<i32>,
+    /// The last few lines that `ssh` had to say about it
+    pub detail: String,
+    /// When the device reported it
+    pub reported_at: String,
+}
+
+/// What went wrong with a connection, according to the device.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceConnectionFailureKind {
+    Unknown,
+    AuthDenied,
+    PortInUse,
+    HostUnreachable,
+    HostKeyMismatch,
+    TargetRefused,
+}
+
+impl From<&control::ClientsResponse_Failure> for DeviceConnectionFailure {
+    fn from(failure: &control::ClientsResponse_Failure) -> Self {
+        DeviceConnectionFailure {
+            failures: failure.get_failures(),
+            kind: match failure.get_kind() {
+                control::ClientsResponse_FailureKind::UNKNOWN_FAILURE => DeviceConnectionFailureKind::Unknown,
+                control::ClientsResponse_FailureKind::AUTH_DENIED => DeviceConnectionFailureKind::AuthDenied,
+                control::ClientsResponse_FailureKind::PORT_IN_USE => DeviceConnectionFailureKind::PortInUse,
+                control::ClientsResponse_FailureKind::HOST_UNREACHABLE => DeviceConnectionFailureKind::HostUnreachable,
+                control::ClientsResponse_FailureKind::HOST_KEY_MISMATCH => DeviceConnectionFailureKind::HostKeyMismatch,
+                control::ClientsResponse_FailureKind::TARGET_REFUSED => DeviceConnectionFailureKind::TargetRefused,
+            },
+            exit_code: match failure.get_exit_code() {
+                code if code < 0 => None,
+                code => Some(code),
+            },
+            detail: failure.get_detail().to_string(),
+            reported_at: Utc.timestamp(failure.get_reported_at() as i64, 0).to_rfc3339(),
+        }
+    }
 }
 
 /// The state of the connection, according to the device.
@@ -123,6 +260,33 @@ pub enum DeviceConnectionClientState {
     Disconnecting,
     Disconnected,
     Failed,
+    Rejected,
+}
+
+/// How the connection is carried between the server and the device.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceConnectionTransport {
+    Ssh,
+    Native,
+}
+
+/// The protocol that is being forwarded.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceConnectionProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Which way the connection goes.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceConnectionDirection {
+    /// A port on the server reaches a service on the device's network
+    Remote,
+    /// A port on the device reaches a service that the server can reach
+    Local,
 }
 
 /// Information about whether the connection is currently active or not.
@@ -142,6 +306,8 @@ impl From<control::ClientsResponse_Connection> for DeviceConnection {
             Some(Utc.timestamp(connection.get_active_until() as i64, 0).to_rfc3339())
         };
 
+        let is_remote = connection.get_direction() == control::SshConnection_Direction::REMOTE;
+
         DeviceConnection {
             id: connection.get_id().to_string(),
             client_state: match connection.get_state() {
@@ -152,6 +318,7 @@ impl From<control::ClientsResponse_Connection> for DeviceConnection {
                 control::ClientsResponse_ClientState::DISCONNECTING => DeviceConnectionClientState::Disconnecting,
                 control::ClientsResponse_ClientState::DISCONNECTED => DeviceConnectionClientState::Disconnected,
                 control::ClientsResponse_ClientState::FAILED => DeviceConnectionClientState::Failed,
+                control::ClientsResponse_ClientState::REJECTED => DeviceConnectionClientState::Rejected,
             },
             active_state: match connection.get_active() {
                 control::ClientsResponse_ActiveState::UNKNOWN_ACTIVE_STATE => DeviceConnectionActiveState::Inactive,
@@ -161,9 +328,87 @@ impl From<control::ClientsResponse_Connection> for DeviceConnection {
             forward_port: connection.get_forward_port() as u16,
             forward_host: connection.get_forward_host().to_string(),
             remote_port: connection.get_remote_port() as u16,
-            is_http: connection.get_forward_port() == 80,
-            is_ssh: connection.get_forward_port() == 22,
+            // Links and commands only make sense for ports on the server.
+            is_http: is_remote && !connection.get_dynamic() && connection.get_forward_port() == 80,
+            is_ssh: is_remote && !connection.get_dynamic() && connection.get_forward_port() == 22,
             active_until,
+            transport: match connection.get_transport() {
+                control::SshConnection_Transport::SSH => DeviceConnectionTransport::Ssh,
+                control::SshConnection_Transport::NATIVE => DeviceConnectionTransport::Native,
+            },
+            protocol: match connection.get_protocol() {
+                control::SshConnection_Protocol::TCP => DeviceConnectionProtocol::Tcp,
+                control::SshConnection_Protocol::UDP => DeviceConnectionProtocol::Udp,
+            },
+            direction: match connection.get_direction() {
+                control::SshConnection_Direction::REMOTE => DeviceConnectionDirection::Remote,
+                control::SshConnection_Direction::LOCAL => DeviceConnectionDirection::Local,
+            },
+            local_port: connection.get_local_port() as u16,
+            is_dynamic: connection.get_dynamic(),
+            permitted_destinations: connection.get_permitted_destinations().iter().map(|destination| destination.to_string()).collect(),
+            last_failure: match connection.has_last_failure() {
+                true => Some(connection.get_last_failure().into()),
+                false => None,
+            },
+            rejected_reason: match connection.get_rejected_reason() {
+                "" => None,
+                reason => Some(reason.to_string()),
+            },
+        }
+    }
+}
+
+/// The recent values of one of a device's metrics
+#[derive(Serialize, Debug)]
+pub struct DeviceMetric {
+    pub name: String,
+    /// The samples, oldest first
+    pub samples: Vec<DeviceMetricSample>,
+}
+
+/// A single value of a metric
+#[derive(Serialize, Debug)]
+pub struct DeviceMetricSample {
+    /// When the server received the value
+    pub timestamp: String,
+    pub value: f64,
+}
+
+impl From<control::MetricsResponse_Series> for DeviceMetric {
+    fn from(series: control::MetricsResponse_Series) -> Self {
+        DeviceMetric {
+            name: series.get_name().to_string(),
+            samples: series.get_samples().iter()
+                .map(|sample| DeviceMetricSample {
+                    timestamp: Utc.timestamp(sample.get_timestamp() as i64, 0).to_rfc3339(),
+                    value: sample.get_value(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A service that a device found on its network
+#[derive(Serialize, Debug)]
+pub struct DiscoveredService {
+    /// "localhost" for the device itself, otherwise an IP address
+    pub host: String,
+    pub port: u16,
+    pub banner: String,
+    pub http_title: String,
+    /// Whether the service is on the device itself
+    pub is_localhost: bool,
+}
+
+impl From<control::DiscoverResponse_Service> for DiscoveredService {
+    fn from(service: control::DiscoverResponse_Service) -> Self {
+        DiscoveredService {
+            host: service.get_host().to_string(),
+            port: service.get_port() as u16,
+            banner: service.get_banner().to_string(),
+            http_title: service.get_http_title().to_string(),
+            is_localhost: service.get_host() == "localhost",
         }
     }
 }
@@ -1,7 +1,9 @@
 //! The routes and implementations of the routes of the webserver
 
 use connectbot_shared::client::Client;
+use connectbot_shared::file_transfer::Checksum;
 use connectbot_shared::protos::control;
+use futures::future::{self, Loop};
 use http;
 // use connectbot_shared::state::{self, Pattern};
 use std::sync::Arc;
@@ -17,7 +19,7 @@ use tower_web::{
 };
 
 mod device;
-use self::device::Device;
+use self::device::{Device, DeviceMetric, DiscoveredService};
 
 /// This type will be part of the web service as a resource.
 #[derive(Clone, Debug)]
@@ -62,12 +64,56 @@ impl From<control::ClientsResponse> for DevicesResponse {
     }
 }
 
+/// The metrics.json output for a single device
+#[derive(Response, Debug)]
+struct MetricsResponse {
+    metrics: Vec<DeviceMetric>,
+}
+
+/// Information used by the discovery.hbs template
+#[derive(Debug, Response)]
+struct DiscoveryResponse {
+    device_id: String,
+    services: Vec<DiscoveredService>,
+    error: Option<String>,
+}
+
+/// Post data for the discover route
+#[derive(Extract, Debug)]
+struct Discover {
+    subnet: Option<String>,
+}
+
+/// Query string for the file download route
+#[derive(Extract, Debug)]
+struct DownloadFile {
+    /// The absolute path of the file on the device
+    path: String,
+}
+
 /// Post data for the create connection route
 #[derive(Extract, Debug)]
 struct CreateConnection {
     host: String,
     host_value: String,
     port: u16,
+    transport: Option<String>,
+    protocol: Option<String>,
+}
+
+/// Post data for the create dynamic connection route
+#[derive(Extract, Debug)]
+struct CreateDynamicConnection {
+    /// Permitted destinations, one per line
+    destinations: Option<String>,
+}
+
+/// Post data for the create local connection route
+#[derive(Extract, Debug)]
+struct CreateLocalConnection {
+    host: String,
+    port: u16,
+    local_port: Option<u16>,
 }
 
 impl_web! {
@@ -122,6 +168,43 @@ impl_web! {
             self.device(device_id)
         }
 
+        #[get("/d/:device_id/metrics.json")]
+        #[content_type("json")]
+        /// The JSON response for a single device's metrics. This is polled from a device page.
+        fn device_metrics_json(&self, device_id: String) -> impl Future<Item=MetricsResponse, Error=std::io::Error> + Send {
+            self.client.get_metrics(&device_id).and_then(|mut response| {
+                if response.get_status() != control::MetricsResponse_Status::SUCCESS {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "Device not found".to_string()));
+                }
+
+                let series: Vec<control::MetricsResponse_Series> = response.take_series().into();
+                Ok(MetricsResponse {
+                    metrics: series.into_iter().map(Into::into).collect(),
+                })
+            })
+        }
+
+        #[post("/d/:device_id/discover")]
+        #[content_type("html")]
+        #[web(template = "discovery")]
+        /// Ask the device what's on its network, and suggest forwards
+        fn post_discover(&self, device_id: String, body: Discover) -> impl Future<Item=DiscoveryResponse, Error=std::io::Error> + Send {
+            let include_subnet = body.subnet.is_some();
+            self.client.discover(&device_id, include_subnet).map(move |mut response| {
+                let services: Vec<control::DiscoverResponse_Service> = response.take_services().into();
+                let error = match response.get_status() {
+                    control::DiscoverResponse_Status::SUCCESS => None,
+                    _ => Some(response.get_error().to_string()),
+                };
+
+                DiscoveryResponse {
+                    device_id,
+                    services: services.into_iter().map(Into::into).collect(),
+                    error,
+                }
+            })
+        }
+
         #[post("/d/:device_id/connections")]
         /// Create a new connection
         fn post_connections(&self, device_id: String, body: CreateConnection) -> impl Future<Item=http::Response<&'static str>, Error=std::io::Error> + Send {
@@ -129,7 +212,15 @@ impl_web! {
                 "remote" => body.host_value,
                 "localhost" | _ => "localhost".to_string(),
             };
-            self.client.connect_device(&device_id, &host, body.port).and_then(move |_| {
+            let transport = match body.transport.as_ref().map(String::as_str) {
+                Some("native") => control::SshConnection_Transport::NATIVE,
+                _ => control::SshConnection_Transport::SSH,
+            };
+            let protocol = match body.protocol.as_ref().map(String::as_str) {
+                Some("udp") => control::SshConnection_Protocol::UDP,
+                _ => control::SshConnection_Protocol::TCP,
+            };
+            self.client.connect_device(&device_id, &host, body.port, transport, protocol).and_then(move |_| {
                 let response = http::Response::builder()
                     .header("location", format!("/d/{}", device_id))
                     .status(http::StatusCode::SEE_OTHER)
@@ -140,6 +231,127 @@ impl_web! {
             })
         }
 
+        #[post("/d/:device_id/dynamic-connections")]
+        /// Create a new SOCKS5 proxy into the device's network
+        fn post_dynamic_connections(&self, device_id: String, body: CreateDynamicConnection) -> impl Future<Item=http::Response<&'static str>, Error=std::io::Error> + Send {
+            let permitted_destinations: Vec<String> = body.destinations.as_ref().map(String::as_str).unwrap_or("")
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect();
+            self.client.connect_device_dynamic(&device_id, &permitted_destinations).and_then(move |response| {
+                if response.get_status() != control::SshConnectionResponse_Status::SUCCESS {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, response.get_error().to_string()));
+                }
+
+                let response = http::Response::builder()
+                    .header("location", format!("/d/{}", device_id))
+                    .status(http::StatusCode::SEE_OTHER)
+                    .body("")
+                    .unwrap();
+
+                Ok(response)
+            })
+        }
+
+        #[post("/d/:device_id/local-connections")]
+        /// Create a new local connection, giving the device access to a service the server can
+        /// reach
+        fn post_local_connections(&self, device_id: String, body: CreateLocalConnection) -> impl Future<Item=http::Response<&'static str>, Error=std::io::Error> + Send {
+            let local_port = body.local_port.unwrap_or(body.port);
+            self.client.connect_device_local(&device_id, &body.host, body.port, local_port).and_then(move |_| {
+                let response = http::Response::builder()
+                    .header("location", format!("/d/{}", device_id))
+                    .status(http::StatusCode::SEE_OTHER)
+                    .body("")
+                    .unwrap();
+
+                Ok(response)
+            })
+        }
+
+        #[post("/d/:device_id/exec/:action")]
+        /// Run one of the device's actions, and show everything it printed
+        fn post_exec(&self, device_id: String, action: String) -> impl Future<Item=http::Response<String>, Error=std::io::Error> + Send {
+            self.client.exec(&device_id, &action).fold(String::new(), |mut output, response| {
+                match response.get_status() {
+                    control::ExecResponse_Status::OUTPUT => {
+                        output.push_str(&String::from_utf8_lossy(response.get_data()));
+                    },
+                    control::ExecResponse_Status::EXITED => {
+                        output.push_str(&format!("\n[exited with code {}]\n", response.get_exit_code()));
+                    },
+                    control::ExecResponse_Status::REJECTED => {
+                        output.push_str("\n[the device does not allow this action]\n");
+                    },
+                    control::ExecResponse_Status::FAILED | control::ExecResponse_Status::UNKNOWN_STATUS => {
+                        output.push_str(&format!("\n[failed: {}]\n", response.get_error()));
+                    },
+                }
+                Ok::<_, std::io::Error>(output)
+            }).map(|output| {
+                http::Response::builder()
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .status(http::StatusCode::OK)
+                    .body(output)
+                    .unwrap()
+            })
+        }
+
+        #[get("/d/:device_id/file")]
+        /// Download a file from the device, a chunk at a time
+        fn download_file(&self, device_id: String, query_string: DownloadFile) -> impl Future<Item=http::Response<Vec<u8>>, Error=std::io::Error> + Send {
+            let client = self.client.clone();
+            let path = query_string.path;
+            let file_name = path.rsplit('/').next().unwrap_or("").replace('"', "");
+
+            future::loop_fn((Vec::new(), Checksum::new()), move |(mut contents, mut checksum)| {
+                client.read_file(&device_id, &path, contents.len() as u64).map(move |response| {
+                    match response.get_status() {
+                        control::FileTransferResponse_Status::SUCCESS => {},
+                        control::FileTransferResponse_Status::REJECTED => {
+                            return Loop::Break(Err((http::StatusCode::FORBIDDEN, format!("The device does not allow this file: {}", response.get_error()))));
+                        },
+                        control::FileTransferResponse_Status::FAILED | control::FileTransferResponse_Status::UNKNOWN_STATUS => {
+                            return Loop::Break(Err((http::StatusCode::BAD_GATEWAY, response.get_error().to_string())));
+                        },
+                    }
+
+                    if response.get_data().is_empty() {
+                        // That's everything. Make sure it all made it.
+                        let sha256 = checksum.finish();
+                        if sha256 != response.get_sha256() {
+                            return Loop::Break(Err((http::StatusCode::BAD_GATEWAY, "The checksum does not match. The file may have changed on the device; try again.".to_string())));
+                        }
+                        return Loop::Break(Ok(contents));
+                    }
+
+                    checksum.update(response.get_data());
+                    contents.extend_from_slice(response.get_data());
+                    Loop::Continue((contents, checksum))
+                })
+            }).map(move |result| {
+                match result {
+                    Ok(contents) => {
+                        http::Response::builder()
+                            .header("content-type", "application/octet-stream")
+                            .header("content-disposition", format!("attachment; filename=\"{}\"", file_name))
+                            .status(http::StatusCode::OK)
+                            .body(contents)
+                            .unwrap()
+                    },
+                    Err((status, error)) => {
+                        http::Response::builder()
+                            .header("content-type", "text/plain; charset=utf-8")
+                            .status(status)
+                            .body(error.into_bytes())
+                            .unwrap()
+                    },
+                }
+            })
+        }
+
         #[post("/d/:device_id/connections/:connection_id/delete")]
         /// Delete an existing connection
         fn delete_connection(&self, device_id: String, connection_id: String) -> impl Future<Item=http::Response<&'static str>, Error=std::io::Error> + Send {